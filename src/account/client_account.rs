@@ -1,42 +1,162 @@
 /// This file defines structs and methods associated with a client account in
 /// the payments engine.
 use log::warn;
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
 use std::{
     collections::{BTreeSet, HashMap},
     fmt,
 };
 
+use crate::account::transaction_state::{HoldKind, TransactionDetail, TransactionState};
 use crate::errors::PaymentsTransactionError;
+use crate::money::Money;
+use crate::payments_engine::config::WithdrawalDisputePolicy;
+use crate::payments_engine::events::DomainEvent;
 use crate::transaction::{Transaction, TransactionType};
 
 /// Representation of a client account's history of processed transactions
-/// with their amount totals and type.
-#[derive(Debug, Default)]
+/// with their amount totals, type and lifecycle state.
+#[derive(Debug, Default, Clone)]
 pub struct ClientTransactionArchive {
-    /// The set of transaction IDs associated with this account.
-    pub history: BTreeSet<u32>,
-    /// Map of the set of transaction IDs to (amount, type of transaction)
+    /// Map of the set of transaction IDs to their archived details
     /// for this account.
-    pub details: HashMap<u32, (f64, TransactionType)>,
-    /// The set of disputed transactions for this account.
-    pub disputes: BTreeSet<u32>,
+    pub(crate) details: HashMap<u32, TransactionDetail>,
+}
+
+/// Serializes as a list of details ordered by transaction ID, so snapshots of
+/// the same state are byte-identical.
+impl Serialize for ClientTransactionArchive {
+    fn serialize<S>(&self, s: S) -> Result<S::Ok, S::Error>
+    where
+        S: Serializer,
+    {
+        let mut details: Vec<&TransactionDetail> = self.details.values().collect();
+        details.sort_by_key(|detail| detail.tx);
+        details.serialize(s)
+    }
+}
+
+impl<'de> Deserialize<'de> for ClientTransactionArchive {
+    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
+    where
+        D: Deserializer<'de>,
+    {
+        let details = Vec::<TransactionDetail>::deserialize(deserializer)?;
+        Ok(ClientTransactionArchive {
+            details: details
+                .into_iter()
+                .map(|detail| (detail.tx, detail))
+                .collect(),
+        })
+    }
+}
+
+impl ClientTransactionArchive {
+    /// Whether this account has applied a transaction with the given ID.
+    pub fn contains(&self, tx: u32) -> bool {
+        self.details.contains_key(&tx)
+    }
+
+    /// Returns the archived details of a transaction.
+    pub fn get(&self, tx: u32) -> Option<&TransactionDetail> {
+        self.details.get(&tx)
+    }
+
+    /// Iterates over every archived transaction, in no particular order.
+    pub fn transactions(&self) -> impl Iterator<Item = (u32, &TransactionDetail)> {
+        self.details.iter().map(|(tx, detail)| (*tx, detail))
+    }
+
+    /// Number of transactions this account has applied.
+    pub fn len(&self) -> usize {
+        self.details.len()
+    }
+
+    /// Whether this account has not applied any transactions yet.
+    pub fn is_empty(&self) -> bool {
+        self.details.is_empty()
+    }
+
+    /// Whether the given transaction is currently under dispute.
+    pub fn is_disputed(&self, tx: u32) -> bool {
+        self.get(tx)
+            .is_some_and(|detail| detail.state == TransactionState::Disputed)
+    }
+
+    /// IDs of every transaction currently under dispute, in ascending order.
+    pub fn open_disputes(&self) -> BTreeSet<u32> {
+        self.transactions()
+            .filter(|(_, detail)| detail.state == TransactionState::Disputed)
+            .map(|(tx, _)| tx)
+            .collect()
+    }
+
+    /// Returns the transaction `row` refers to, after checking that `event` is a
+    /// legal next step in that transaction's lifecycle.
+    fn referenced_detail(
+        &self,
+        event: TransactionType,
+        row: &Transaction,
+    ) -> Result<&TransactionDetail, PaymentsTransactionError> {
+        let detail = self.details.get(&row.tx).ok_or(
+            PaymentsTransactionError::TransactionDetailDoesNotExist(row.tx.to_string()),
+        )?;
+        detail.check_transition(event)?;
+        Ok(detail)
+    }
+
+    /// Moves the transaction `row` refers to along its lifecycle.
+    fn transition(
+        &mut self,
+        event: TransactionType,
+        row: Transaction,
+    ) -> Result<TransactionState, PaymentsTransactionError> {
+        self.details
+            .get_mut(&row.tx)
+            .ok_or(PaymentsTransactionError::TransactionDetailDoesNotExist(
+                row.tx.to_string(),
+            ))?
+            .transition(event, row)
+    }
 }
 
 /// Representation of a client's account details in the engine.
 /// The engine uses this for reporting output to stdout.
-#[derive(Debug, Default)]
+#[derive(Debug, Default, Clone, Serialize, Deserialize)]
 pub struct ClientAccountDetails {
-    pub available_funds: f64,
-    pub held_funds: f64,
-    pub total_funds: f64,
-    pub is_account_locked: bool,
+    pub(crate) available_funds: Money,
+    pub(crate) held_funds: Money,
+    pub(crate) total_funds: Money,
+    pub(crate) is_account_locked: bool,
+}
+
+impl ClientAccountDetails {
+    /// Funds available for trading, staking, withdrawal, etc.
+    pub fn available_funds(&self) -> Money {
+        self.available_funds
+    }
+
+    /// Funds held for dispute.
+    pub fn held_funds(&self) -> Money {
+        self.held_funds
+    }
+
+    /// Available plus held funds.
+    pub fn total_funds(&self) -> Money {
+        self.total_funds
+    }
+
+    /// Whether the account has been locked by a chargeback.
+    pub fn is_locked(&self) -> bool {
+        self.is_account_locked
+    }
 }
 
 impl fmt::Display for ClientAccountDetails {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         write!(
             f,
-            "{:.4},{:.4},{:.4},{}",
+            "{},{},{},{}",
             self.available_funds, self.held_funds, self.total_funds, self.is_account_locked
         )
     }
@@ -44,15 +164,33 @@ impl fmt::Display for ClientAccountDetails {
 
 /// Representation of a client's account in the payments engine.
 /// A client account is defined by its funds' details and lock status,
-/// the set of transactions and their ID associated with this client,
-/// and the set of transaction IDs that are currently under dispute
-/// account that the payments engine has previously processed.
-#[derive(Debug, Default)]
+/// and the archive of transactions the payments engine has previously
+/// processed for this client, each with its current lifecycle state.
+#[derive(Debug, Default, Clone, Serialize, Deserialize)]
 pub struct ClientAccount {
     /// Balance details and lock status for this account.
-    pub account_details: ClientAccountDetails,
+    #[serde(rename = "details")]
+    pub(crate) account_details: ClientAccountDetails,
     /// Transaction history and details for this account.
-    pub account_transaction_archive: ClientTransactionArchive,
+    #[serde(rename = "archive")]
+    pub(crate) account_transaction_archive: ClientTransactionArchive,
+    /// Every lock and unlock applied to this account, oldest first.
+    pub(crate) lock_history: Vec<LockEvent>,
+    /// Timestamp of the latest timestamped transaction applied to this account.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub(crate) last_timestamp: Option<u64>,
+    /// Events raised by the handlers and not yet taken by the engine.
+    #[serde(skip)]
+    pub(crate) events: Vec<DomainEvent>,
+}
+
+/// A change to a client account's lock status.
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq, Eq)]
+pub enum LockEvent {
+    /// The account was locked by a chargeback of the given transaction ID.
+    Locked { tx: u32 },
+    /// The account was unlocked by an administrator for the given reason.
+    Unlocked { reason: String },
 }
 
 /// Methods for a `ClientAccount`. Note that process_transaction() for the PaymentsEngine
@@ -60,25 +198,86 @@ pub struct ClientAccount {
 /// guard against repeat attacks. Each of the handler functions here also do this to maintain
 /// modularity for testing.
 impl ClientAccount {
+    /// Opens an empty account for `client`, raising `AccountOpened`.
+    pub fn open(client: u16) -> Self {
+        let mut account = Self::default();
+        account.raise(DomainEvent::AccountOpened { client });
+        account
+    }
+
+    /// Records an event for the engine to pass on to its subscribers.
+    fn raise(&mut self, event: DomainEvent) {
+        self.events.push(event);
+    }
+
+    /// Returns the events raised since they were last taken, oldest first.
+    pub fn take_events(&mut self) -> Vec<DomainEvent> {
+        std::mem::take(&mut self.events)
+    }
+
+    /// Balance details and lock status for this account.
+    pub fn details(&self) -> &ClientAccountDetails {
+        &self.account_details
+    }
+
+    /// Transaction history and details for this account.
+    pub fn transaction_archive(&self) -> &ClientTransactionArchive {
+        &self.account_transaction_archive
+    }
+
+    /// Every lock and unlock applied to this account, oldest first.
+    pub fn lock_history(&self) -> &[LockEvent] {
+        &self.lock_history
+    }
+
+    /// Timestamp of the latest timestamped transaction applied to this
+    /// account, if there has been one.
+    pub fn last_timestamp(&self) -> Option<u64> {
+        self.last_timestamp
+    }
+
+    /// Unlocks an account frozen by a chargeback and records the reason in the
+    /// account's lock history. Unlocking an account that isn't locked does nothing.
+    pub fn unlock(&mut self, reason: &str) {
+        if !self.account_details.is_account_locked {
+            warn!("Unlock requested for an account that is not locked, ignoring.");
+            return;
+        }
+        self.account_details.is_account_locked = false;
+        self.lock_history.push(LockEvent::Unlocked {
+            reason: reason.to_string(),
+        });
+    }
+
     /// A deposit is a credit to the client's asset account, meaning it
     /// should increase the available and total funds of the client account.
     /// Additionally, since total funds are mutated on a successful deposit,
     /// the account's transaction history is updated as well.
+    ///
+    /// Deposits of zero or a negative amount are rejected as `InvalidAmount`.
     pub fn handle_deposit(&mut self, tx: Transaction) -> Result<(), PaymentsTransactionError> {
-        if !self.account_transaction_archive.history.contains(&tx.tx) {
+        if !self.account_transaction_archive.contains(tx.tx) {
             if let Some(amount) = tx.amount {
-                self.account_details.available_funds += amount;
-                self.account_details.total_funds += amount;
+                if amount <= Money::ZERO {
+                    warn!("Deposit with a non-positive amount seen: {}", &tx);
+                    return Err(PaymentsTransactionError::InvalidAmount(amount.to_string()));
+                }
+                let available_funds = self.account_details.available_funds.checked_add(amount)?;
+                let total_funds = self.account_details.total_funds.checked_add(amount)?;
+                self.account_details.available_funds = available_funds;
+                self.account_details.total_funds = total_funds;
 
                 self.account_transaction_archive
                     .details
-                    .insert(tx.tx, (amount, tx.tx_type));
-                self.account_transaction_archive.history.insert(tx.tx);
+                    .insert(tx.tx, TransactionDetail::new(tx.tx, amount, tx.tx_type));
+                self.raise(DomainEvent::FundsCredited {
+                    client: tx.client,
+                    tx: tx.tx,
+                    amount,
+                });
             } else {
-                warn!(
-                    "Duplicate {} transaction ID {} seen for client {}",
-                    tx.tx_type, tx.tx, tx.client
-                );
+                warn!("Deposit without an amount seen: {}", &tx);
+                return Err(PaymentsTransactionError::MissingAmount(tx.tx));
             }
         }
         Ok(())
@@ -90,23 +289,38 @@ impl ClientAccount {
     /// the account's transaction history is updated as well.
     ///
     /// If a client does not have sufficient available funds, the withdrawal
-    /// will fail and the total amount of funds will not change.
+    /// will fail and the total amount of funds will not change. Withdrawals of
+    /// zero or a negative amount are rejected as `InvalidAmount`.
     pub fn handle_withdrawal(&mut self, tx: Transaction) -> Result<(), PaymentsTransactionError> {
-        if !self.account_transaction_archive.history.contains(&tx.tx) {
+        if !self.account_transaction_archive.contains(tx.tx) {
             if let Some(amount) = tx.amount {
+                if amount <= Money::ZERO {
+                    warn!("Withdrawal with a non-positive amount seen: {}", &tx);
+                    return Err(PaymentsTransactionError::InvalidAmount(amount.to_string()));
+                }
                 if self.account_details.available_funds >= amount {
-                    self.account_details.available_funds -= amount;
-                    self.account_details.total_funds -= amount;
+                    let available_funds =
+                        self.account_details.available_funds.checked_sub(amount)?;
+                    let total_funds = self.account_details.total_funds.checked_sub(amount)?;
+                    self.account_details.available_funds = available_funds;
+                    self.account_details.total_funds = total_funds;
 
                     self.account_transaction_archive
                         .details
-                        .insert(tx.tx, (amount, tx.tx_type));
-                    self.account_transaction_archive.history.insert(tx.tx);
+                        .insert(tx.tx, TransactionDetail::new(tx.tx, amount, tx.tx_type));
+                    self.raise(DomainEvent::FundsDebited {
+                        client: tx.client,
+                        tx: tx.tx,
+                        amount,
+                    });
                 } else {
                     return Err(PaymentsTransactionError::NotEnoughAvailableFunds(
                         tx.client.to_string(),
                     ));
                 }
+            } else {
+                warn!("Withdrawal without an amount seen: {}", &tx);
+                return Err(PaymentsTransactionError::MissingAmount(tx.tx));
             }
             Ok(())
         } else {
@@ -116,90 +330,124 @@ impl ClientAccount {
             ))
         }
     }
-    /// A dispute references the transaction that is disputed by ID.
-    /// The client's available funds should decrease by the amount disputed.
-    /// Held funds should increase by the amount disputed. Since an account's
-    /// total funds are not impacted by initiating a dispute, a dispute transaction
-    /// will not go into a `ClientAccount`'s transaction history.
+
+    /// Computes the available, held and total funds after adding the given
+    /// signed deltas, without applying them. Balances are only written once every
+    /// step of a handler has succeeded, so a failure never leaves them half-updated.
+    fn adjusted_funds(
+        &self,
+        available: Money,
+        held: Money,
+        total: Money,
+    ) -> Result<(Money, Money, Money), PaymentsTransactionError> {
+        Ok((
+            self.account_details
+                .available_funds
+                .checked_add(available)?,
+            self.account_details.held_funds.checked_add(held)?,
+            self.account_details.total_funds.checked_add(total)?,
+        ))
+    }
+
+    /// Writes balances previously computed by `adjusted_funds`.
+    fn set_funds(&mut self, (available, held, total): (Money, Money, Money)) {
+        self.account_details.available_funds = available;
+        self.account_details.held_funds = held;
+        self.account_details.total_funds = total;
+    }
+
+    /// A dispute references the transaction that is disputed by ID, using the
+    /// default `WithdrawalDisputePolicy`. See `handle_dispute_with_policy`.
     pub fn handle_dispute(&mut self, tx: Transaction) -> Result<(), PaymentsTransactionError> {
-        let disputed_tx = &tx.tx;
-        let has_tx_happened = self
-            .account_transaction_archive
-            .history
-            .contains(disputed_tx);
-        let is_tx_not_being_disputed = !self
+        self.handle_dispute_with_policy(tx, WithdrawalDisputePolicy::default())
+    }
+
+    /// A dispute references the transaction that is disputed by ID.
+    /// For a disputed deposit, the client's available funds should decrease by the
+    /// amount disputed and held funds should increase by the amount disputed.
+    /// Since an account's total funds are not impacted by initiating a dispute, a
+    /// dispute transaction only moves the disputed transaction to the `Disputed` state.
+    ///
+    /// Disputes against withdrawals are rejected or held according to `policy`.
+    pub fn handle_dispute_with_policy(
+        &mut self,
+        tx: Transaction,
+        policy: WithdrawalDisputePolicy,
+    ) -> Result<(), PaymentsTransactionError> {
+        // If the tx specified by the dispute doesn't exist, or is in a state that can't
+        // be disputed, return an error for this transaction's processing output.
+        let disputed_tx = self
             .account_transaction_archive
-            .disputes
-            .contains(disputed_tx);
-
-        if has_tx_happened && is_tx_not_being_disputed {
-            // Get the disputed transaction's details first.
-            let tx_archive = &self.account_transaction_archive;
-            let disputed_tx_details = tx_archive.details.get(disputed_tx).ok_or(
-                PaymentsTransactionError::TransactionDetailDoesNotExist(disputed_tx.to_string()),
-            )?;
-            let disputed_tx_amount = disputed_tx_details.0;
-
-            self.account_details.available_funds -= disputed_tx_amount;
-            self.account_details.held_funds += disputed_tx_amount;
-
-            // No need to update the transaction history and details here. We're not mutating total funds,
-            // only temporarily holding them. This dispute might get resolved or it might not,
-            // so it doesn't make sense to update history here yet. We'll add this transaction to the
-            // set of disputed ones and return here.
-            self.account_transaction_archive
-                .disputes
-                .insert(*disputed_tx);
+            .referenced_detail(TransactionType::Dispute, &tx)
+            .inspect_err(|e| warn!("Ignoring dispute {}: {}", &tx, e))?;
+        let disputed_tx_amount = disputed_tx.amount;
 
-            Ok(())
-        } else {
-            // If the tx specified by the dispute doesn't exist this means this is a dispute
-            // for a transaction that never happened. Return an error for this transaction's
-            // processing output.
-            warn!("Duplicate dispute transaction seen: {}", &tx);
-            Err(PaymentsTransactionError::TransactionDetailDoesNotExist(
-                tx.tx.to_string(),
-            ))
+        let hold = match (disputed_tx.tx_type, policy) {
+            (TransactionType::Withdrawal, WithdrawalDisputePolicy::Reject) => {
+                warn!("Ignoring dispute {}: withdrawals cannot be disputed", &tx);
+                return Err(PaymentsTransactionError::WithdrawalNotDisputable(tx.tx));
+            }
+            (TransactionType::Withdrawal, WithdrawalDisputePolicy::Reversal) => HoldKind::Reversal,
+            _ => HoldKind::FromAvailable,
+        };
+
+        let negated_amount = Money::ZERO.checked_sub(disputed_tx_amount)?;
+        let funds = match hold {
+            HoldKind::FromAvailable => {
+                self.adjusted_funds(negated_amount, disputed_tx_amount, Money::ZERO)?
+            }
+            HoldKind::Reversal => {
+                self.adjusted_funds(Money::ZERO, disputed_tx_amount, disputed_tx_amount)?
+            }
+        };
+
+        self.account_transaction_archive
+            .transition(TransactionType::Dispute, tx)?;
+        if let Some(detail) = self.account_transaction_archive.details.get_mut(&tx.tx) {
+            detail.hold = hold;
         }
+        self.set_funds(funds);
+        self.raise(DomainEvent::FundsHeld {
+            client: tx.client,
+            tx: tx.tx,
+            amount: disputed_tx_amount,
+        });
+        Ok(())
     }
 
     /// Resolves refer to a transaction that was under dispute by ID.
     /// The clients held funds should decrease by the amount no longer disputed,
     /// their available funds should increase by the amount no longer disputed,
-    /// and their total funds should remain the same.
+    /// and their total funds should remain the same. A withdrawal held as a
+    /// reversal instead leaves held and total funds, since the withdrawal stands.
     pub fn handle_resolve(&mut self, tx: Transaction) -> Result<(), PaymentsTransactionError> {
-        let disputed_tx = &tx.tx;
-        let has_tx_happened = self
-            .account_transaction_archive
-            .history
-            .contains(disputed_tx);
-        let is_tx_being_disputed = self
+        // If the tx isn't under dispute, reject the resolve and assume this
+        // is an error on our partner's side.
+        let disputed_tx = self
             .account_transaction_archive
-            .disputes
-            .contains(disputed_tx);
-        if has_tx_happened && is_tx_being_disputed {
-            // Get the transaction details associated with the dispute being resolved.
-            let tx_archive = &self.account_transaction_archive;
-            let disputed_tx_details = tx_archive.details.get(disputed_tx).ok_or(
-                PaymentsTransactionError::TransactionDetailDoesNotExist(disputed_tx.to_string()),
-            )?;
-            let disputed_tx_amount = disputed_tx_details.0;
-
-            self.account_details.held_funds -= disputed_tx_amount;
-            self.account_details.available_funds += disputed_tx_amount;
-
-            // Funds that were previously disputed are no longer disputed.
-            self.account_transaction_archive
-                .disputes
-                .remove(disputed_tx);
-        } else {
-            // If the tx isn't under dispute, we can ignore the resolve and assume this
-            // is an error on our partner's side.
-            warn!(
-                "Resolve referenced transaction ID {} does not exist for client {}, ignoring.",
-                &tx.tx, &tx.client
-            );
-        }
+            .referenced_detail(TransactionType::Resolve, &tx)
+            .inspect_err(|e| warn!("Ignoring resolve {}: {}", &tx, e))?;
+        let disputed_tx_amount = disputed_tx.amount;
+        let hold = disputed_tx.hold;
+
+        let negated_amount = Money::ZERO.checked_sub(disputed_tx_amount)?;
+        let funds = match hold {
+            HoldKind::FromAvailable => {
+                self.adjusted_funds(disputed_tx_amount, negated_amount, Money::ZERO)?
+            }
+            HoldKind::Reversal => {
+                self.adjusted_funds(Money::ZERO, negated_amount, negated_amount)?
+            }
+        };
+
+        self.account_transaction_archive
+            .transition(TransactionType::Resolve, tx)?;
+        self.set_funds(funds);
+        self.raise(DomainEvent::FundsReleased {
+            client: tx.client,
+            tx: tx.tx,
+            amount: disputed_tx_amount,
+        });
         Ok(())
     }
 
@@ -207,41 +455,44 @@ impl ClientAccount {
     /// A chargeback is the final state of a dispute and represents the client reversing a transaction.
     /// If a chargeback occurs the client's account should be immediately frozen.
     /// The client's held funds and total funds should decrease by the amount previously disputed.
+    /// A withdrawal held as a reversal instead has its held amount released into available funds.
     pub fn handle_chargeback(&mut self, tx: Transaction) -> Result<(), PaymentsTransactionError> {
-        let disputed_tx = &tx.tx;
-        let has_tx_happened = self
+        // If the chargeback tx isn't under dispute or isn't in this account's history,
+        // reject it and assume this is an error on our partner's side.
+        let disputed_tx = self
             .account_transaction_archive
-            .history
-            .contains(disputed_tx);
-        let is_tx_being_disputed = self
-            .account_transaction_archive
-            .disputes
-            .contains(disputed_tx);
-        if has_tx_happened && is_tx_being_disputed {
-            self.account_details.is_account_locked = true;
-
-            // Get the transaction details associated with the dispute concluding with a chargeback.
-            let tx_archive = &self.account_transaction_archive;
-            let disputed_tx_details = tx_archive.details.get(disputed_tx).ok_or(
-                PaymentsTransactionError::TransactionDetailDoesNotExist(disputed_tx.to_string()),
-            )?;
+            .referenced_detail(TransactionType::Chargeback, &tx)
+            .inspect_err(|e| warn!("Ignoring chargeback {}: {}", &tx, e))?;
+        let disputed_tx_amount = disputed_tx.amount;
+        let hold = disputed_tx.hold;
 
-            let disputed_tx_amount = disputed_tx_details.0;
-
-            self.account_details.held_funds -= disputed_tx_amount;
-            self.account_details.total_funds -= disputed_tx_amount;
+        let negated_amount = Money::ZERO.checked_sub(disputed_tx_amount)?;
+        let funds = match hold {
+            HoldKind::FromAvailable => {
+                self.adjusted_funds(Money::ZERO, negated_amount, negated_amount)?
+            }
+            HoldKind::Reversal => {
+                self.adjusted_funds(disputed_tx_amount, negated_amount, Money::ZERO)?
+            }
+        };
 
-            // Funds that were previously disputed are no longer disputed.
-            self.account_transaction_archive
-                .disputes
-                .remove(disputed_tx);
-        } else {
-            // If the chargeback tx isn't under dispute or isn't in this account's history,
-            // ignore the resolve and assume this is an error on our partner's side.
-            warn!(
-                "Chargeback referenced transaction ID {} does not exist for client {}, ignoring.",
-                &tx.tx, &tx.client
-            );
+        self.account_transaction_archive
+            .transition(TransactionType::Chargeback, tx)?;
+        self.set_funds(funds);
+        self.raise(DomainEvent::ChargedBack {
+            client: tx.client,
+            tx: tx.tx,
+            amount: disputed_tx_amount,
+        });
+        // An account that is already locked stays locked, so only the first
+        // chargeback is recorded as locking it.
+        if !self.account_details.is_account_locked {
+            self.account_details.is_account_locked = true;
+            self.lock_history.push(LockEvent::Locked { tx: tx.tx });
+            self.raise(DomainEvent::AccountLocked {
+                client: tx.client,
+                tx: tx.tx,
+            });
         }
         Ok(())
     }
@@ -250,19 +501,21 @@ impl ClientAccount {
 #[cfg(test)]
 mod account_tests {
     use crate::account::client_account::ClientAccountDetails;
+    use crate::money::Money;
 
     /// The `Display` implementation for `ClientAccountDetails` shows balances to four decimal places.
     /// Includes the lock status in the output.
     /// Verifies that formatting is consistent and human-readable.
     #[test]
     fn test_display_client_account_details() {
-        let mut details = ClientAccountDetails::default();
-        details.available_funds = 10.123456;
-        details.held_funds = 5.5;
-        details.total_funds = 15.623456;
-        details.is_account_locked = true;
+        let details = ClientAccountDetails {
+            available_funds: Money::from_scaled(101_234),
+            held_funds: Money::from_scaled(55_000),
+            total_funds: Money::from_scaled(156_234),
+            is_account_locked: true,
+        };
 
         let display = details.to_string();
-        assert_eq!(display, "10.1235,5.5000,15.6235,true");
+        assert_eq!(display, "10.1234,5.5000,15.6234,true");
     }
 }
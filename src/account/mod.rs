@@ -1,4 +1,6 @@
 pub mod client_account;
+pub mod store;
+pub mod transaction_state;
 
 #[cfg(test)]
 mod tests;
@@ -0,0 +1,828 @@
+/// This file defines where the payments engine keeps client accounts, their
+/// archived transactions and the index of which client owns each transaction
+/// ID: in memory, or in an on-disk key-value store for archives too large to
+/// hold in RAM.
+use redb::{Database, Durability, TableDefinition};
+use std::borrow::Cow;
+use std::collections::{BTreeMap, HashMap};
+use std::fmt;
+use std::path::Path;
+
+use crate::account::client_account::{ClientAccount, ClientAccountDetails};
+use crate::account::transaction_state::TransactionDetail;
+use crate::errors::PaymentsTransactionError;
+
+/// Archived transactions of every client, keyed by client and transaction ID.
+const ARCHIVE_TABLE: TableDefinition<(u16, u32), &[u8]> = TableDefinition::new("archive");
+
+/// Owner of every claimed transaction ID, keyed by transaction ID.
+const OWNER_TABLE: TableDefinition<u32, u16> = TableDefinition::new("owners");
+
+/// Archived transactions and index entries `DiskAccountStore` buffers before
+/// writing them out.
+const PENDING_LIMIT: usize = 10_000;
+
+/// Every owned transaction ID with its owner, in transaction ID order.
+pub type Owners<'a> = Box<dyn Iterator<Item = Result<(u32, u16), PaymentsTransactionError>> + 'a>;
+
+/// Storage for client accounts, their archived transactions and the index of
+/// which client owns each deposit and withdrawal transaction ID.
+///
+/// The engine applies a transaction by taking the client's account out of the
+/// store, applying the transaction to it, and putting it back. Only the
+/// archived transaction the row refers to has to come along, so a store is
+/// free to keep the rest of an account's archive, and the index, somewhere
+/// other than memory.
+pub trait AccountStore: fmt::Debug + Send {
+    /// Number of client accounts in the store.
+    fn len(&self) -> usize;
+
+    /// Whether the store has no client accounts.
+    fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Every client ID with an account, in no particular order.
+    fn clients(&self) -> Vec<u16>;
+
+    /// Balances and lock status of a client's account.
+    fn details(&self, client: u16) -> Option<&ClientAccountDetails>;
+
+    /// A client's account with its whole transaction archive.
+    fn account(
+        &self,
+        client: u16,
+    ) -> Result<Option<Cow<'_, ClientAccount>>, PaymentsTransactionError>;
+
+    /// A transaction from a client's archive.
+    fn transaction(
+        &self,
+        client: u16,
+        tx: u32,
+    ) -> Result<Option<Cow<'_, TransactionDetail>>, PaymentsTransactionError>;
+
+    /// Takes a client's account out of the store to apply a transaction to it.
+    /// The account carries its balances, lock history and, if `tx` is given,
+    /// that archived transaction; the rest of its archive may be left behind.
+    fn take(
+        &mut self,
+        client: u16,
+        tx: Option<u32>,
+    ) -> Result<Option<ClientAccount>, PaymentsTransactionError>;
+
+    /// Puts back an account returned by `take`, or adds a new one, along with
+    /// every archived transaction it carries.
+    fn put(&mut self, client: u16, account: ClientAccount) -> Result<(), PaymentsTransactionError>;
+
+    /// Drops a transaction from a client's archive, if it is there.
+    fn evict(&mut self, client: u16, tx: u32) -> Result<(), PaymentsTransactionError>;
+
+    /// The client that first applied a deposit or withdrawal with this
+    /// transaction ID, across all accounts.
+    fn owner(&self, tx: u32) -> Result<Option<u16>, PaymentsTransactionError>;
+
+    /// Records `client` as the owner of a transaction ID that has none.
+    fn claim(&mut self, tx: u32, client: u16) -> Result<(), PaymentsTransactionError>;
+
+    /// Drops a transaction ID from the index, if it is there.
+    fn release(&mut self, tx: u32) -> Result<(), PaymentsTransactionError>;
+
+    /// Every owned transaction ID with its owner, in transaction ID order.
+    fn owners(&self) -> Result<Owners<'_>, PaymentsTransactionError>;
+
+    /// Replaces every account in the store with `accounts`, along with every
+    /// archived transaction they carry, and the index with `owners`. Either
+    /// all of them are swapped in or, on error, the store is left as it was.
+    fn replace(
+        &mut self,
+        accounts: HashMap<u16, ClientAccount>,
+        owners: HashMap<u32, u16>,
+    ) -> Result<(), PaymentsTransactionError>;
+
+    /// The store as an `InMemoryAccountStore`, if it is one. Batches are only
+    /// sharded across worker threads when accounts are kept in memory.
+    fn as_in_memory_mut(&mut self) -> Option<&mut InMemoryAccountStore> {
+        None
+    }
+}
+
+/// Keeps every account, its whole archive and the index in `HashMap`s. This
+/// is the engine's default store.
+#[derive(Debug, Default)]
+pub struct InMemoryAccountStore {
+    /// Maps a client ID to its account.
+    pub(crate) accounts: HashMap<u16, ClientAccount>,
+    /// Maps every owned transaction ID to its owner.
+    pub(crate) owners: HashMap<u32, u16>,
+}
+
+impl InMemoryAccountStore {
+    /// Creates a store with no accounts.
+    pub fn new() -> Self {
+        Self::default()
+    }
+}
+
+impl AccountStore for InMemoryAccountStore {
+    fn len(&self) -> usize {
+        self.accounts.len()
+    }
+
+    fn clients(&self) -> Vec<u16> {
+        self.accounts.keys().copied().collect()
+    }
+
+    fn details(&self, client: u16) -> Option<&ClientAccountDetails> {
+        self.accounts.get(&client).map(ClientAccount::details)
+    }
+
+    fn account(
+        &self,
+        client: u16,
+    ) -> Result<Option<Cow<'_, ClientAccount>>, PaymentsTransactionError> {
+        Ok(self.accounts.get(&client).map(Cow::Borrowed))
+    }
+
+    fn transaction(
+        &self,
+        client: u16,
+        tx: u32,
+    ) -> Result<Option<Cow<'_, TransactionDetail>>, PaymentsTransactionError> {
+        Ok(self
+            .accounts
+            .get(&client)
+            .and_then(|account| account.account_transaction_archive.get(tx))
+            .map(Cow::Borrowed))
+    }
+
+    fn take(
+        &mut self,
+        client: u16,
+        _tx: Option<u32>,
+    ) -> Result<Option<ClientAccount>, PaymentsTransactionError> {
+        Ok(self.accounts.remove(&client))
+    }
+
+    fn put(&mut self, client: u16, account: ClientAccount) -> Result<(), PaymentsTransactionError> {
+        self.accounts.insert(client, account);
+        Ok(())
+    }
+
+    fn evict(&mut self, client: u16, tx: u32) -> Result<(), PaymentsTransactionError> {
+        if let Some(account) = self.accounts.get_mut(&client) {
+            account.account_transaction_archive.details.remove(&tx);
+        }
+        Ok(())
+    }
+
+    fn owner(&self, tx: u32) -> Result<Option<u16>, PaymentsTransactionError> {
+        Ok(self.owners.get(&tx).copied())
+    }
+
+    fn claim(&mut self, tx: u32, client: u16) -> Result<(), PaymentsTransactionError> {
+        self.owners.insert(tx, client);
+        Ok(())
+    }
+
+    fn release(&mut self, tx: u32) -> Result<(), PaymentsTransactionError> {
+        self.owners.remove(&tx);
+        Ok(())
+    }
+
+    fn owners(&self) -> Result<Owners<'_>, PaymentsTransactionError> {
+        let mut owners: Vec<(u32, u16)> = self
+            .owners
+            .iter()
+            .map(|(&tx, &client)| (tx, client))
+            .collect();
+        owners.sort_unstable();
+        Ok(Box::new(owners.into_iter().map(Ok)))
+    }
+
+    fn replace(
+        &mut self,
+        accounts: HashMap<u16, ClientAccount>,
+        owners: HashMap<u32, u16>,
+    ) -> Result<(), PaymentsTransactionError> {
+        self.accounts = accounts;
+        self.owners = owners;
+        Ok(())
+    }
+
+    fn as_in_memory_mut(&mut self) -> Option<&mut InMemoryAccountStore> {
+        Some(self)
+    }
+}
+
+/// Keeps balances and lock histories in memory but archived transactions and
+/// the transaction index in an embedded key-value file, so both can grow past
+/// available RAM.
+///
+/// Newly archived transactions and claimed IDs are buffered and written out
+/// in batches. The
+/// file is scratch space for one run: it is emptied when the store is created,
+/// and snapshots and the journal remain the way to persist engine state.
+pub struct DiskAccountStore {
+    db: Database,
+    /// Every account, with its archive emptied out into `pending` or the file.
+    accounts: HashMap<u16, ClientAccount>,
+    /// Archived transactions not yet written to the file, or `None` for ones
+    /// evicted since the file was last written.
+    pending: HashMap<(u16, u32), Option<TransactionDetail>>,
+    /// Transaction IDs claimed since the file was last written, with their
+    /// owners, or `None` for ones released since, in ID order.
+    pending_owners: BTreeMap<u32, Option<u16>>,
+    /// Which write of a flush to fail, counting from zero, so tests can see
+    /// what a failure partway through leaves behind.
+    #[cfg(test)]
+    fail_at_write: Option<usize>,
+}
+
+impl fmt::Debug for DiskAccountStore {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("DiskAccountStore")
+            .field("accounts", &self.accounts.len())
+            .field("pending", &self.pending.len())
+            .field("pending_owners", &self.pending_owners.len())
+            .finish()
+    }
+}
+
+/// Wraps an error from the key-value file.
+fn store_error(e: impl Into<redb::Error>) -> PaymentsTransactionError {
+    PaymentsTransactionError::AccountStore(e.into().to_string())
+}
+
+/// Wraps an error encoding or decoding an archived transaction.
+fn encoding_error(e: serde_json::Error) -> PaymentsTransactionError {
+    PaymentsTransactionError::AccountStore(e.to_string())
+}
+
+impl DiskAccountStore {
+    /// Creates an empty store backed by the file at `path`, replacing whatever
+    /// archive the file already held.
+    pub fn create<P: AsRef<Path>>(path: P) -> Result<Self, PaymentsTransactionError> {
+        let mut store = DiskAccountStore {
+            db: Database::create(path).map_err(store_error)?,
+            accounts: HashMap::new(),
+            pending: HashMap::new(),
+            pending_owners: BTreeMap::new(),
+            #[cfg(test)]
+            fail_at_write: None,
+        };
+        store.replace(HashMap::new(), HashMap::new())?;
+        Ok(store)
+    }
+
+    /// Writes every buffered archived transaction, eviction and claimed ID to
+    /// the file. The buffers are only emptied once the write has committed,
+    /// so after an error they still hold everything and a retry writes it.
+    pub fn flush(&mut self) -> Result<(), PaymentsTransactionError> {
+        if self.pending.is_empty() && self.pending_owners.is_empty() {
+            return Ok(());
+        }
+        let mut txn = self.db.begin_write().map_err(store_error)?;
+        // The file only has to outlive the run, not a crash.
+        txn.set_durability(Durability::Eventual);
+        let mut writes = 0;
+        {
+            let mut table = txn.open_table(ARCHIVE_TABLE).map_err(store_error)?;
+            for (&key, detail) in &self.pending {
+                self.count_write(&mut writes)?;
+                match detail {
+                    Some(detail) => {
+                        let bytes = serde_json::to_vec(detail).map_err(encoding_error)?;
+                        table.insert(key, bytes.as_slice()).map_err(store_error)?;
+                    }
+                    None => {
+                        table.remove(key).map_err(store_error)?;
+                    }
+                }
+            }
+        }
+        {
+            let mut table = txn.open_table(OWNER_TABLE).map_err(store_error)?;
+            for (&tx, &client) in &self.pending_owners {
+                self.count_write(&mut writes)?;
+                match client {
+                    Some(client) => {
+                        table.insert(tx, client).map_err(store_error)?;
+                    }
+                    None => {
+                        table.remove(tx).map_err(store_error)?;
+                    }
+                }
+            }
+        }
+        txn.commit().map_err(store_error)?;
+        self.pending.clear();
+        self.pending_owners.clear();
+        Ok(())
+    }
+
+    /// Counts one write of a flush, failing it if a test asked for that one
+    /// to fail.
+    fn count_write(&self, writes: &mut usize) -> Result<(), PaymentsTransactionError> {
+        #[cfg(test)]
+        if self.fail_at_write == Some(*writes) {
+            return Err(PaymentsTransactionError::AccountStore(
+                "injected write failure".to_string(),
+            ));
+        }
+        *writes += 1;
+        Ok(())
+    }
+
+    /// Writes the buffers out once they have grown past `PENDING_LIMIT`.
+    fn flush_if_full(&mut self) -> Result<(), PaymentsTransactionError> {
+        if self.pending.len() + self.pending_owners.len() >= PENDING_LIMIT {
+            self.flush()?;
+        }
+        Ok(())
+    }
+
+    /// Reads an archived transaction, preferring one not yet written out.
+    fn load_transaction(
+        &self,
+        client: u16,
+        tx: u32,
+    ) -> Result<Option<TransactionDetail>, PaymentsTransactionError> {
+        if let Some(detail) = self.pending.get(&(client, tx)) {
+            return Ok(detail.clone());
+        }
+        let txn = self.db.begin_read().map_err(store_error)?;
+        let table = txn.open_table(ARCHIVE_TABLE).map_err(store_error)?;
+        let Some(bytes) = table.get((client, tx)).map_err(store_error)? else {
+            return Ok(None);
+        };
+        serde_json::from_slice(bytes.value())
+            .map(Some)
+            .map_err(encoding_error)
+    }
+}
+
+impl AccountStore for DiskAccountStore {
+    fn len(&self) -> usize {
+        self.accounts.len()
+    }
+
+    fn clients(&self) -> Vec<u16> {
+        self.accounts.keys().copied().collect()
+    }
+
+    fn details(&self, client: u16) -> Option<&ClientAccountDetails> {
+        self.accounts.get(&client).map(ClientAccount::details)
+    }
+
+    fn account(
+        &self,
+        client: u16,
+    ) -> Result<Option<Cow<'_, ClientAccount>>, PaymentsTransactionError> {
+        let Some(account) = self.accounts.get(&client) else {
+            return Ok(None);
+        };
+        let mut account = account.clone();
+        let archive = &mut account.account_transaction_archive.details;
+
+        let txn = self.db.begin_read().map_err(store_error)?;
+        let table = txn.open_table(ARCHIVE_TABLE).map_err(store_error)?;
+        for entry in table
+            .range((client, 0)..=(client, u32::MAX))
+            .map_err(store_error)?
+        {
+            let (key, bytes) = entry.map_err(store_error)?;
+            let detail: TransactionDetail =
+                serde_json::from_slice(bytes.value()).map_err(encoding_error)?;
+            archive.insert(key.value().1, detail);
+        }
+        for (&(owner, tx), detail) in &self.pending {
+            if owner != client {
+                continue;
+            }
+            match detail {
+                Some(detail) => archive.insert(tx, detail.clone()),
+                None => archive.remove(&tx),
+            };
+        }
+        Ok(Some(Cow::Owned(account)))
+    }
+
+    fn transaction(
+        &self,
+        client: u16,
+        tx: u32,
+    ) -> Result<Option<Cow<'_, TransactionDetail>>, PaymentsTransactionError> {
+        Ok(self.load_transaction(client, tx)?.map(Cow::Owned))
+    }
+
+    fn take(
+        &mut self,
+        client: u16,
+        tx: Option<u32>,
+    ) -> Result<Option<ClientAccount>, PaymentsTransactionError> {
+        if !self.accounts.contains_key(&client) {
+            return Ok(None);
+        }
+        let detail = match tx {
+            Some(tx) => self.load_transaction(client, tx)?,
+            None => None,
+        };
+        let mut account = self.accounts.remove(&client);
+        if let (Some(account), Some(detail)) = (&mut account, detail) {
+            account
+                .account_transaction_archive
+                .details
+                .insert(detail.tx, detail);
+        }
+        Ok(account)
+    }
+
+    fn put(
+        &mut self,
+        client: u16,
+        mut account: ClientAccount,
+    ) -> Result<(), PaymentsTransactionError> {
+        for (tx, detail) in account.account_transaction_archive.details.drain() {
+            self.pending.insert((client, tx), Some(detail));
+        }
+        self.accounts.insert(client, account);
+        self.flush_if_full()
+    }
+
+    fn evict(&mut self, client: u16, tx: u32) -> Result<(), PaymentsTransactionError> {
+        self.pending.insert((client, tx), None);
+        self.flush_if_full()
+    }
+
+    fn owner(&self, tx: u32) -> Result<Option<u16>, PaymentsTransactionError> {
+        if let Some(&client) = self.pending_owners.get(&tx) {
+            return Ok(client);
+        }
+        let txn = self.db.begin_read().map_err(store_error)?;
+        let table = txn.open_table(OWNER_TABLE).map_err(store_error)?;
+        Ok(table
+            .get(tx)
+            .map_err(store_error)?
+            .map(|client| client.value()))
+    }
+
+    fn claim(&mut self, tx: u32, client: u16) -> Result<(), PaymentsTransactionError> {
+        self.pending_owners.insert(tx, Some(client));
+        self.flush_if_full()
+    }
+
+    fn release(&mut self, tx: u32) -> Result<(), PaymentsTransactionError> {
+        self.pending_owners.insert(tx, None);
+        self.flush_if_full()
+    }
+
+    fn owners(&self) -> Result<Owners<'_>, PaymentsTransactionError> {
+        let txn = self.db.begin_read().map_err(store_error)?;
+        let table = txn.open_table(OWNER_TABLE).map_err(store_error)?;
+        let mut stored = table
+            .range::<u32>(..)
+            .map_err(store_error)?
+            .map(|entry| {
+                entry
+                    .map(|(tx, client)| (tx.value(), client.value()))
+                    .map_err(store_error)
+            })
+            .peekable();
+        let mut pending = self
+            .pending_owners
+            .iter()
+            .map(|(&tx, &client)| (tx, client))
+            .peekable();
+        // Merge the two in ID order, with buffered claims and releases taking
+        // the place of what the file holds for the same ID.
+        Ok(Box::new(std::iter::from_fn(move || {
+            loop {
+                let stored_tx = match stored.peek() {
+                    Some(Ok((tx, _))) => Some(*tx),
+                    Some(Err(_)) => return stored.next(),
+                    None => None,
+                };
+                let pending_tx = pending.peek().map(|(tx, _)| *tx);
+                match (stored_tx, pending_tx) {
+                    (Some(stored_tx), Some(pending_tx)) if stored_tx < pending_tx => {
+                        return stored.next();
+                    }
+                    (Some(_), None) => return stored.next(),
+                    (None, None) => return None,
+                    (stored_tx, Some(pending_tx)) => {
+                        if stored_tx == Some(pending_tx) {
+                            stored.next();
+                        }
+                        let (tx, client) = pending.next()?;
+                        if let Some(client) = client {
+                            return Some(Ok((tx, client)));
+                        }
+                    }
+                }
+            }
+        })))
+    }
+
+    fn replace(
+        &mut self,
+        mut accounts: HashMap<u16, ClientAccount>,
+        owners: HashMap<u32, u16>,
+    ) -> Result<(), PaymentsTransactionError> {
+        // The whole archive and index are rewritten in one write transaction,
+        // and the accounts in memory only swapped once it commits, so a
+        // failure partway through leaves both the file and the store as they
+        // were.
+        let mut txn = self.db.begin_write().map_err(store_error)?;
+        txn.set_durability(Durability::Eventual);
+        txn.delete_table(ARCHIVE_TABLE).map_err(store_error)?;
+        {
+            let mut table = txn.open_table(ARCHIVE_TABLE).map_err(store_error)?;
+            for (&client, account) in &mut accounts {
+                for (tx, detail) in account.account_transaction_archive.details.drain() {
+                    let bytes = serde_json::to_vec(&detail).map_err(encoding_error)?;
+                    table
+                        .insert((client, tx), bytes.as_slice())
+                        .map_err(store_error)?;
+                }
+            }
+        }
+        txn.delete_table(OWNER_TABLE).map_err(store_error)?;
+        {
+            let mut table = txn.open_table(OWNER_TABLE).map_err(store_error)?;
+            for (tx, client) in owners {
+                table.insert(tx, client).map_err(store_error)?;
+            }
+        }
+        txn.commit().map_err(store_error)?;
+        self.accounts = accounts;
+        self.pending.clear();
+        self.pending_owners.clear();
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod store_tests {
+    use super::*;
+    use crate::PaymentsEngine;
+    use crate::generator::{GeneratorConfig, TransactionGenerator};
+    use crate::transaction::{Transaction, TransactionType};
+    use tempfile::TempDir;
+
+    fn deposit(client: u16, tx: u32) -> Transaction {
+        Transaction {
+            tx_type: TransactionType::Deposit,
+            client,
+            tx,
+            amount: Some("2.5".parse().unwrap()),
+            timestamp: None,
+        }
+    }
+
+    /// An engine backed by the disk store ends up in exactly the state an
+    /// in-memory engine does, across enough rows to flush a batch.
+    /// Batches on a disk store are applied sequentially even with workers.
+    #[test]
+    fn test_disk_store_matches_in_memory() {
+        let dir = TempDir::new().unwrap();
+        let rows: Vec<Transaction> = TransactionGenerator::new(GeneratorConfig {
+            transactions: 15_000,
+            clients: 500,
+            error_rate: 0.1,
+            seed: Some(11),
+            ..GeneratorConfig::default()
+        })
+        .collect();
+        assert!(rows.len() > PENDING_LIMIT);
+
+        let mut in_memory = PaymentsEngine::new();
+        let mut on_disk = PaymentsEngine::new()
+            .with_workers(4)
+            .with_store(DiskAccountStore::create(dir.path().join("accounts.redb")).unwrap());
+        let expected: Vec<(usize, &str)> = rows
+            .iter()
+            .enumerate()
+            .filter_map(|(i, tx)| {
+                in_memory
+                    .process_transaction(*tx)
+                    .into_result()
+                    .err()
+                    .map(|e| (i, e.kind()))
+            })
+            .collect();
+        let rejected: Vec<(usize, &str)> = on_disk
+            .process_batch(rows.iter().copied().enumerate())
+            .unwrap()
+            .into_iter()
+            .map(|(i, e)| (i, e.kind()))
+            .collect();
+
+        assert_eq!(rejected, expected);
+        let (mut expected_snapshot, mut snapshot) = (Vec::new(), Vec::new());
+        in_memory.save_snapshot(&mut expected_snapshot).unwrap();
+        on_disk.save_snapshot(&mut snapshot).unwrap();
+        assert_eq!(snapshot, expected_snapshot);
+    }
+
+    /// `take` brings along only the requested archived transaction, while
+    /// `account` and `transaction` see both flushed and buffered ones.
+    #[test]
+    fn test_disk_store_take_and_put() {
+        let dir = TempDir::new().unwrap();
+        let mut store = DiskAccountStore::create(dir.path().join("accounts.redb")).unwrap();
+        let mut account = ClientAccount::default();
+        account.handle_deposit(deposit(1, 1)).unwrap();
+        account.handle_deposit(deposit(1, 2)).unwrap();
+        store.put(1, account).unwrap();
+        store.flush().unwrap();
+        let mut account = store.take(1, None).unwrap().unwrap();
+        account.handle_deposit(deposit(1, 3)).unwrap();
+        store.put(1, account).unwrap();
+
+        let taken = store.take(1, Some(2)).unwrap().unwrap();
+        assert_eq!(taken.transaction_archive().len(), 1);
+        assert!(taken.transaction_archive().contains(2));
+        store.put(1, taken).unwrap();
+
+        let account = store.account(1).unwrap().unwrap();
+        assert_eq!(account.transaction_archive().len(), 3);
+        assert_eq!(account.details().total_funds(), "7.5".parse().unwrap());
+        assert!(store.transaction(1, 1).unwrap().is_some());
+        assert!(store.transaction(1, 3).unwrap().is_some());
+        assert!(store.transaction(2, 1).unwrap().is_none());
+        assert!(store.take(2, Some(1)).unwrap().is_none());
+    }
+
+    /// Evicting drops a transaction whether it was flushed or still buffered.
+    #[test]
+    fn test_disk_store_evict() {
+        let dir = TempDir::new().unwrap();
+        let mut store = DiskAccountStore::create(dir.path().join("accounts.redb")).unwrap();
+        let mut account = ClientAccount::default();
+        account.handle_deposit(deposit(1, 1)).unwrap();
+        account.handle_deposit(deposit(1, 2)).unwrap();
+        store.put(1, account).unwrap();
+        store.flush().unwrap();
+        let mut account = store.take(1, None).unwrap().unwrap();
+        account.handle_deposit(deposit(1, 3)).unwrap();
+        store.put(1, account).unwrap();
+
+        store.evict(1, 1).unwrap();
+        store.evict(1, 3).unwrap();
+        assert!(store.transaction(1, 1).unwrap().is_none());
+        let taken = store.take(1, Some(3)).unwrap().unwrap();
+        assert!(taken.transaction_archive().is_empty());
+        store.put(1, taken).unwrap();
+        store.flush().unwrap();
+
+        let account = store.account(1).unwrap().unwrap();
+        assert_eq!(
+            account
+                .transaction_archive()
+                .transactions()
+                .map(|(tx, _)| tx)
+                .collect::<Vec<_>>(),
+            vec![2]
+        );
+    }
+
+    /// Replacing the store's accounts drops every archived transaction it held,
+    /// flushed or buffered, and brings in the new accounts' archives.
+    #[test]
+    fn test_disk_store_replace() {
+        let dir = TempDir::new().unwrap();
+        let mut store = DiskAccountStore::create(dir.path().join("accounts.redb")).unwrap();
+        let mut account = ClientAccount::default();
+        account.handle_deposit(deposit(1, 1)).unwrap();
+        store.put(1, account).unwrap();
+        store.flush().unwrap();
+        let mut account = store.take(1, None).unwrap().unwrap();
+        account.handle_deposit(deposit(1, 2)).unwrap();
+        store.put(1, account).unwrap();
+
+        store.claim(1, 1).unwrap();
+
+        let mut replacement = ClientAccount::default();
+        replacement.handle_deposit(deposit(2, 3)).unwrap();
+        store
+            .replace(HashMap::from([(2, replacement)]), HashMap::from([(3, 2)]))
+            .unwrap();
+
+        assert_eq!(store.clients(), vec![2]);
+        assert_eq!(store.owner(1).unwrap(), None);
+        assert_eq!(store.owner(3).unwrap(), Some(2));
+        assert!(store.transaction(1, 1).unwrap().is_none());
+        assert!(store.transaction(1, 2).unwrap().is_none());
+        let account = store.account(2).unwrap().unwrap();
+        assert_eq!(account.transaction_archive().len(), 1);
+        assert!(account.transaction_archive().contains(3));
+    }
+
+    /// Claimed IDs are found and listed in ID order, and released ones are
+    /// dropped, whether they were flushed or are still buffered.
+    #[test]
+    fn test_disk_store_owners() {
+        let dir = TempDir::new().unwrap();
+        let mut store = DiskAccountStore::create(dir.path().join("accounts.redb")).unwrap();
+        store.claim(4, 1).unwrap();
+        store.claim(2, 2).unwrap();
+        store.claim(5, 2).unwrap();
+        store.flush().unwrap();
+        store.claim(3, 3).unwrap();
+        store.claim(1, 3).unwrap();
+        store.release(2).unwrap();
+        store.release(3).unwrap();
+
+        assert_eq!(store.owner(2).unwrap(), None);
+        assert_eq!(store.owner(3).unwrap(), None);
+        assert_eq!(store.owner(4).unwrap(), Some(1));
+        assert_eq!(store.owner(6).unwrap(), None);
+        let owners = vec![(1, 3), (4, 1), (5, 2)];
+        assert_eq!(
+            store
+                .owners()
+                .unwrap()
+                .collect::<Result<Vec<_>, _>>()
+                .unwrap(),
+            owners
+        );
+        store.flush().unwrap();
+        assert_eq!(
+            store
+                .owners()
+                .unwrap()
+                .collect::<Result<Vec<_>, _>>()
+                .unwrap(),
+            owners
+        );
+    }
+
+    /// A flush that fails partway through keeps every buffered write, eviction
+    /// and claim, and writes all of them when it's retried.
+    #[test]
+    fn test_disk_store_flush_failure_keeps_buffers() {
+        let dir = TempDir::new().unwrap();
+        let mut store = DiskAccountStore::create(dir.path().join("accounts.redb")).unwrap();
+        let mut account = ClientAccount::default();
+        account.handle_deposit(deposit(1, 1)).unwrap();
+        store.put(1, account).unwrap();
+        store.claim(1, 1).unwrap();
+        store.flush().unwrap();
+        let mut account = store.take(1, None).unwrap().unwrap();
+        for tx in 2..5 {
+            account.handle_deposit(deposit(1, tx)).unwrap();
+            store.claim(tx, 1).unwrap();
+        }
+        store.put(1, account).unwrap();
+        store.evict(1, 1).unwrap();
+        store.release(1).unwrap();
+
+        // Three archive writes and an eviction, then four index writes.
+        for fail_at_write in [2, 5] {
+            store.fail_at_write = Some(fail_at_write);
+            assert!(matches!(
+                store.flush(),
+                Err(PaymentsTransactionError::AccountStore(_))
+            ));
+        }
+        store.fail_at_write = None;
+        store.flush().unwrap();
+
+        assert!(store.pending.is_empty() && store.pending_owners.is_empty());
+        let account = store.account(1).unwrap().unwrap();
+        let mut archived: Vec<u32> = account
+            .transaction_archive()
+            .transactions()
+            .map(|(tx, _)| tx)
+            .collect();
+        archived.sort_unstable();
+        assert_eq!(archived, vec![2, 3, 4]);
+        assert_eq!(
+            store
+                .owners()
+                .unwrap()
+                .collect::<Result<Vec<_>, _>>()
+                .unwrap(),
+            vec![(2, 1), (3, 1), (4, 1)]
+        );
+    }
+
+    /// Creating a store over an existing file starts from an empty archive.
+    #[test]
+    fn test_disk_store_create_replaces_existing_archive() {
+        let dir = TempDir::new().unwrap();
+        let path = dir.path().join("accounts.redb");
+        let mut store = DiskAccountStore::create(&path).unwrap();
+        let mut account = ClientAccount::default();
+        account.handle_deposit(deposit(1, 1)).unwrap();
+        store.put(1, account).unwrap();
+        store.claim(1, 1).unwrap();
+        store.flush().unwrap();
+        drop(store);
+
+        let store = DiskAccountStore::create(&path).unwrap();
+        assert!(store.is_empty());
+        assert!(store.transaction(1, 1).unwrap().is_none());
+        assert_eq!(store.owner(1).unwrap(), None);
+    }
+}
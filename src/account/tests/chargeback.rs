@@ -4,10 +4,17 @@
 #[cfg(test)]
 mod chargeback_tests {
     use crate::{
-        account::client_account::ClientAccount,
+        account::{client_account::ClientAccount, transaction_state::TransactionState},
+        errors::PaymentsTransactionError,
+        money::Money,
+        payments_engine::events::DomainEvent,
         transaction::{Transaction, TransactionType},
     };
 
+    fn money(amount: &str) -> Money {
+        amount.parse().unwrap()
+    }
+
     /// Test that a chargeback on a valid disputed transaction
     /// removes the funds from held and total balances and locks the account.
     #[test]
@@ -17,32 +24,35 @@ mod chargeback_tests {
             tx_type: TransactionType::Deposit,
             client: 1,
             tx: 1,
-            amount: Some(100.0),
+            amount: Some(money("100.0")),
+            timestamp: None,
         };
         let dispute = Transaction {
             tx_type: TransactionType::Dispute,
             client: 1,
             tx: 1,
-            amount: Some(100.0),
+            amount: Some(money("100.0")),
+            timestamp: None,
         };
         let chargeback = Transaction {
             tx_type: TransactionType::Chargeback,
             client: 1,
             tx: 1,
-            amount: Some(0.0),
+            amount: Some(money("0.0")),
+            timestamp: None,
         };
         acct.handle_deposit(deposit).unwrap();
         acct.handle_dispute(dispute).unwrap();
         acct.handle_chargeback(chargeback).unwrap();
 
-        assert_eq!(acct.account_details.held_funds, 0.0);
-        assert_eq!(acct.account_details.total_funds, 0.0);
+        assert_eq!(acct.account_details.held_funds, money("0.0"));
+        assert_eq!(acct.account_details.total_funds, money("0.0"));
         assert!(acct.account_details.is_account_locked);
-        assert!(!acct.account_transaction_archive.disputes.contains(&1));
+        assert!(!acct.account_transaction_archive.is_disputed(1));
     }
 
     /// Test that a chargeback on a transaction that does not exist
-    /// is safely ignored without modifying balances.
+    /// is rejected without modifying balances.
     #[test]
     fn test_chargeback_nonexistent_transaction() {
         let mut acct = ClientAccount::default();
@@ -50,18 +60,22 @@ mod chargeback_tests {
             tx_type: TransactionType::Chargeback,
             client: 1,
             tx: 1,
-            amount: Some(0.0),
+            amount: Some(money("0.0")),
+            timestamp: None,
         };
 
         let result = acct.handle_chargeback(chargeback);
-        assert!(result.is_ok());
-        assert_eq!(acct.account_details.held_funds, 0.0);
-        assert_eq!(acct.account_details.total_funds, 0.0);
+        assert!(matches!(
+            result,
+            Err(PaymentsTransactionError::TransactionDetailDoesNotExist(_))
+        ));
+        assert_eq!(acct.account_details.held_funds, money("0.0"));
+        assert_eq!(acct.account_details.total_funds, money("0.0"));
         assert!(!acct.account_details.is_account_locked);
     }
 
-    /// Test that a chargeback on a transaction that is not disputed
-    /// does not modify balances or the disputes set.
+    /// Test that a chargeback on a transaction that is not disputed is an
+    /// illegal transition and does not modify balances or the disputes set.
     #[test]
     fn test_chargeback_not_disputed_transaction_is_ignored() {
         let mut acct = ClientAccount::default();
@@ -70,22 +84,30 @@ mod chargeback_tests {
             tx_type: TransactionType::Deposit,
             client: 1,
             tx: 1,
-            amount: Some(100.0),
+            amount: Some(money("100.0")),
+            timestamp: None,
         };
         let chargeback = Transaction {
             tx_type: TransactionType::Chargeback,
             client: 1,
             tx: 1,
-            amount: Some(0.0),
+            amount: Some(money("0.0")),
+            timestamp: None,
         };
 
-        acct.handle_deposit(deposit.clone()).unwrap();
-        acct.handle_chargeback(chargeback).unwrap();
+        acct.handle_deposit(deposit).unwrap();
+        assert!(matches!(
+            acct.handle_chargeback(chargeback),
+            Err(PaymentsTransactionError::IllegalStateTransition {
+                from: TransactionState::Processed,
+                ..
+            })
+        ));
 
-        assert_eq!(acct.account_details.held_funds, 0.0);
-        assert_eq!(acct.account_details.total_funds, 100.0);
+        assert_eq!(acct.account_details.held_funds, money("0.0"));
+        assert_eq!(acct.account_details.total_funds, money("100.0"));
         assert!(!acct.account_details.is_account_locked);
-        assert!(!acct.account_transaction_archive.disputes.contains(&1));
+        assert!(!acct.account_transaction_archive.is_disputed(1));
     }
 
     /// Test that a successful chargeback removes the transaction
@@ -97,40 +119,43 @@ mod chargeback_tests {
             tx_type: TransactionType::Deposit,
             client: 1,
             tx: 1,
-            amount: Some(100.0),
+            amount: Some(money("100.0")),
+            timestamp: None,
         };
         let dispute = Transaction {
             tx_type: TransactionType::Dispute,
             client: 1,
             tx: 1,
-            amount: Some(0.0),
+            amount: Some(money("0.0")),
+            timestamp: None,
         };
         let chargeback = Transaction {
             tx_type: TransactionType::Chargeback,
             client: 1,
             tx: 1,
-            amount: Some(0.0),
+            amount: Some(money("0.0")),
+            timestamp: None,
         };
         acct.handle_deposit(deposit).unwrap();
         acct.handle_dispute(dispute).unwrap();
 
-        assert_eq!(acct.account_details.held_funds, 100.0);
-        assert_eq!(acct.account_details.total_funds, 100.0);
-        assert_eq!(acct.account_details.available_funds, 0.0);
+        assert_eq!(acct.account_details.held_funds, money("100.0"));
+        assert_eq!(acct.account_details.total_funds, money("100.0"));
+        assert_eq!(acct.account_details.available_funds, money("0.0"));
 
         acct.handle_chargeback(chargeback).unwrap();
 
-        assert_eq!(acct.account_details.held_funds, 0.0);
-        assert_eq!(acct.account_details.total_funds, 0.0);
-        assert_eq!(acct.account_details.available_funds, 0.0);
+        assert_eq!(acct.account_details.held_funds, money("0.0"));
+        assert_eq!(acct.account_details.total_funds, money("0.0"));
+        assert_eq!(acct.account_details.available_funds, money("0.0"));
         assert!(acct.account_details.is_account_locked);
 
         // Disputed transactions should no longer be disputed.
-        assert!(!acct.account_transaction_archive.disputes.contains(&1));
+        assert!(!acct.account_transaction_archive.is_disputed(1));
     }
 
     /// Test that multiple chargeback calls on the same transaction
-    /// do not further modify balances after the first call.
+    /// are rejected and do not further modify balances after the first call.
     #[test]
     fn test_multiple_chargebacks_are_ignored() {
         let mut acct = ClientAccount::default();
@@ -138,28 +163,127 @@ mod chargeback_tests {
             tx_type: TransactionType::Deposit,
             client: 1,
             tx: 1,
-            amount: Some(100.0),
+            amount: Some(money("100.0")),
+            timestamp: None,
         };
         let dispute = Transaction {
             tx_type: TransactionType::Dispute,
             client: 1,
             tx: 1,
-            amount: Some(0.0),
+            amount: Some(money("0.0")),
+            timestamp: None,
         };
         let chargeback = Transaction {
             tx_type: TransactionType::Chargeback,
             client: 1,
             tx: 1,
-            amount: Some(0.0),
+            amount: Some(money("0.0")),
+            timestamp: None,
         };
         acct.handle_deposit(deposit).unwrap();
         acct.handle_dispute(dispute).unwrap();
 
-        acct.handle_chargeback(chargeback.clone()).unwrap();
-        acct.handle_chargeback(chargeback).unwrap(); // ignored
+        acct.handle_chargeback(chargeback).unwrap();
+        assert!(acct.handle_chargeback(chargeback).is_err()); // already charged back
 
-        assert_eq!(acct.account_details.held_funds, 0.0);
-        assert_eq!(acct.account_details.total_funds, 0.0);
+        assert_eq!(acct.account_details.held_funds, money("0.0"));
+        assert_eq!(acct.account_details.total_funds, money("0.0"));
         assert!(acct.account_details.is_account_locked);
     }
+
+    /// Test that a charged back transaction cannot be disputed again,
+    /// and that its lifecycle is logged with the triggering rows.
+    #[test]
+    fn test_charged_back_transaction_cannot_be_redisputed() {
+        let mut acct = ClientAccount::default();
+        let deposit = Transaction {
+            tx_type: TransactionType::Deposit,
+            client: 1,
+            tx: 1,
+            amount: Some(money("100.0")),
+            timestamp: None,
+        };
+        let dispute = Transaction {
+            tx_type: TransactionType::Dispute,
+            amount: None,
+            ..deposit
+        };
+        let chargeback = Transaction {
+            tx_type: TransactionType::Chargeback,
+            amount: None,
+            ..deposit
+        };
+        acct.handle_deposit(deposit).unwrap();
+        acct.handle_dispute(dispute).unwrap();
+        acct.handle_chargeback(chargeback).unwrap();
+
+        assert!(matches!(
+            acct.handle_dispute(dispute),
+            Err(PaymentsTransactionError::IllegalStateTransition {
+                from: TransactionState::ChargedBack,
+                ..
+            })
+        ));
+        assert_eq!(acct.account_details.held_funds, money("0.0"));
+        assert_eq!(acct.account_details.available_funds, money("0.0"));
+
+        let detail = acct.account_transaction_archive.get(1).unwrap();
+        assert_eq!(detail.state(), TransactionState::ChargedBack);
+        let rows: Vec<_> = detail.transitions().iter().map(|t| t.row).collect();
+        assert_eq!(rows, vec![dispute, chargeback]);
+    }
+
+    /// Test that a chargeback raises `ChargedBack`, and `AccountLocked` only
+    /// when it is the one that locks the account.
+    #[test]
+    fn test_chargeback_raises_events() {
+        let mut acct = ClientAccount::open(1);
+        for tx in [1, 2] {
+            acct.handle_deposit(Transaction {
+                tx_type: TransactionType::Deposit,
+                client: 1,
+                tx,
+                amount: Some(money("10.0")),
+                timestamp: None,
+            })
+            .unwrap();
+            acct.handle_dispute(Transaction {
+                tx_type: TransactionType::Dispute,
+                client: 1,
+                tx,
+                amount: None,
+                timestamp: None,
+            })
+            .unwrap();
+        }
+        assert_eq!(acct.take_events().len(), 5);
+
+        for tx in [1, 2] {
+            acct.handle_chargeback(Transaction {
+                tx_type: TransactionType::Chargeback,
+                client: 1,
+                tx,
+                amount: None,
+                timestamp: None,
+            })
+            .unwrap();
+        }
+
+        assert_eq!(
+            acct.take_events(),
+            vec![
+                DomainEvent::ChargedBack {
+                    client: 1,
+                    tx: 1,
+                    amount: money("10.0"),
+                },
+                DomainEvent::AccountLocked { client: 1, tx: 1 },
+                DomainEvent::ChargedBack {
+                    client: 1,
+                    tx: 2,
+                    amount: money("10.0"),
+                },
+            ]
+        );
+    }
 }
@@ -5,9 +5,15 @@
 mod deposit_tests {
     use crate::{
         account::client_account::ClientAccount,
+        errors::PaymentsTransactionError,
+        money::Money,
         transaction::{Transaction, TransactionType},
     };
 
+    fn money(amount: &str) -> Money {
+        amount.parse().unwrap()
+    }
+
     fn sample_account() -> ClientAccount {
         ClientAccount::default()
     }
@@ -21,17 +27,21 @@ mod deposit_tests {
             tx_type: TransactionType::Deposit,
             client: 1,
             tx: 1,
-            amount: Some(50.0),
+            amount: Some(money("50.0")),
+            timestamp: None,
         };
 
         account.handle_deposit(tx).unwrap();
 
-        assert_eq!(account.account_details.available_funds, 50.0);
-        assert_eq!(account.account_details.total_funds, 50.0);
-        assert!(account.account_transaction_archive.history.contains(&1));
+        assert_eq!(account.account_details.available_funds, money("50.0"));
+        assert_eq!(account.account_details.total_funds, money("50.0"));
+        assert!(account.account_transaction_archive.contains(1));
         assert_eq!(
-            account.account_transaction_archive.details.get(&1),
-            Some(&(50.0, TransactionType::Deposit))
+            account
+                .account_transaction_archive
+                .get(1)
+                .map(|d| (d.amount(), d.tx_type())),
+            Some((money("50.0"), TransactionType::Deposit))
         );
     }
 
@@ -44,12 +54,13 @@ mod deposit_tests {
             tx_type: TransactionType::Deposit,
             client: 1,
             tx: 2,
-            amount: Some(25.5),
+            amount: Some(money("25.5")),
+            timestamp: None,
         };
         account.handle_deposit(tx).unwrap();
 
-        assert_eq!(account.account_details.available_funds, 25.5);
-        assert_eq!(account.account_details.total_funds, 25.5);
+        assert_eq!(account.account_details.available_funds, money("25.5"));
+        assert_eq!(account.account_details.total_funds, money("25.5"));
     }
 
     /// Multiple deposits accumulate correctly in available and total funds.
@@ -61,22 +72,24 @@ mod deposit_tests {
             tx_type: TransactionType::Deposit,
             client: 1,
             tx: 1,
-            amount: Some(10.0),
+            amount: Some(money("10.0")),
+            timestamp: None,
         };
         let tx2 = Transaction {
             tx_type: TransactionType::Deposit,
             client: 1,
             tx: 2,
-            amount: Some(15.0),
+            amount: Some(money("15.0")),
+            timestamp: None,
         };
 
         account.handle_deposit(tx1).unwrap();
         account.handle_deposit(tx2).unwrap();
 
-        assert_eq!(account.account_details.available_funds, 25.0);
-        assert_eq!(account.account_details.total_funds, 25.0);
-        assert!(account.account_transaction_archive.history.contains(&1));
-        assert!(account.account_transaction_archive.history.contains(&2));
+        assert_eq!(account.account_details.available_funds, money("25.0"));
+        assert_eq!(account.account_details.total_funds, money("25.0"));
+        assert!(account.account_transaction_archive.contains(1));
+        assert!(account.account_transaction_archive.contains(2));
     }
 
     /// Depositing with a duplicate transaction ID does not overwrite the previous amount in details.
@@ -88,13 +101,15 @@ mod deposit_tests {
             tx_type: TransactionType::Deposit,
             client: 1,
             tx: 1,
-            amount: Some(10.0),
+            amount: Some(money("10.0")),
+            timestamp: None,
         };
         let tx2 = Transaction {
             tx_type: TransactionType::Deposit,
             client: 1,
             tx: 1, // same tx ID
-            amount: Some(20.0),
+            amount: Some(money("20.0")),
+            timestamp: None,
         };
 
         account.handle_deposit(tx1).unwrap();
@@ -102,32 +117,41 @@ mod deposit_tests {
 
         // The last deposit does not overwrite the amount in details
         assert_eq!(
-            account.account_transaction_archive.details.get(&1),
-            Some(&(10.0, TransactionType::Deposit))
+            account
+                .account_transaction_archive
+                .get(1)
+                .map(|d| (d.amount(), d.tx_type())),
+            Some((money("10.0"), TransactionType::Deposit))
         );
 
         // History still only contains tx ID once
-        assert_eq!(account.account_transaction_archive.history.len(), 1);
-        assert!(account.account_transaction_archive.history.contains(&1));
+        assert_eq!(account.account_transaction_archive.len(), 1);
+        assert!(account.account_transaction_archive.contains(1));
     }
 
-    /// A deposit with zero amount leaves balances unchanged.
-    /// The transaction ID is still recorded in history.
-    /// Ensures zero-value deposits are logged but do not affect funds.
+    /// Deposits of zero or a negative amount are rejected as `InvalidAmount`.
+    /// Balances are unchanged and the transaction ID is not recorded, so a
+    /// negative deposit can't be used to drain an account.
     #[test]
-    fn test_zero_amount_deposit() {
+    fn test_non_positive_deposit_rejected() {
         let mut account = sample_account();
-        let tx = Transaction {
-            tx_type: TransactionType::Deposit,
-            client: 1,
-            tx: 3,
-            amount: Some(0.0),
-        };
-        account.handle_deposit(tx).unwrap();
-
-        assert_eq!(account.account_details.available_funds, 0.0);
-        assert_eq!(account.account_details.total_funds, 0.0);
-        assert!(account.account_transaction_archive.history.contains(&3));
+        for (tx, amount) in [(3, "0.0"), (4, "-5")] {
+            let result = account.handle_deposit(Transaction {
+                tx_type: TransactionType::Deposit,
+                client: 1,
+                tx,
+                amount: Some(money(amount)),
+                timestamp: None,
+            });
+
+            assert!(matches!(
+                result,
+                Err(PaymentsTransactionError::InvalidAmount(_))
+            ));
+            assert!(!account.account_transaction_archive.contains(tx));
+        }
+        assert_eq!(account.account_details.available_funds, Money::ZERO);
+        assert_eq!(account.account_details.total_funds, Money::ZERO);
     }
 
     /// Very large deposits update balances correctly without overflow.
@@ -140,12 +164,16 @@ mod deposit_tests {
             tx_type: TransactionType::Deposit,
             client: 1,
             tx: 4,
-            amount: Some(1e12),
+            amount: Some(money("1000000000000")),
+            timestamp: None,
         };
 
         account.handle_deposit(tx).unwrap();
 
-        assert_eq!(account.account_details.available_funds, 1e12);
-        assert_eq!(account.account_details.total_funds, 1e12);
+        assert_eq!(
+            account.account_details.available_funds,
+            money("1000000000000")
+        );
+        assert_eq!(account.account_details.total_funds, money("1000000000000"));
     }
 }
@@ -5,9 +5,16 @@
 mod dispute_tests {
     use crate::{
         account::client_account::ClientAccount,
+        errors::PaymentsTransactionError,
+        money::Money,
+        payments_engine::config::WithdrawalDisputePolicy,
         transaction::{Transaction, TransactionType},
     };
 
+    fn money(amount: &str) -> Money {
+        amount.parse().unwrap()
+    }
+
     /// Test that disputing a valid past transaction correctly moves its funds
     /// from the account's available balance into the held balance.
     #[test]
@@ -17,7 +24,8 @@ mod dispute_tests {
             tx_type: TransactionType::Deposit,
             client: 1,
             tx: 1,
-            amount: Some(100.0),
+            amount: Some(money("100.0")),
+            timestamp: None,
         };
 
         acct.handle_deposit(deposit).unwrap();
@@ -29,8 +37,8 @@ mod dispute_tests {
 
         acct.handle_dispute(dispute).unwrap();
 
-        assert_eq!(acct.account_details.available_funds, 0.0);
-        assert_eq!(acct.account_details.held_funds, 100.0);
+        assert_eq!(acct.account_details.available_funds, money("0.0"));
+        assert_eq!(acct.account_details.held_funds, money("100.0"));
     }
 
     /// Test that disputing a transaction that was never recorded returns an error
@@ -42,7 +50,8 @@ mod dispute_tests {
             tx_type: TransactionType::Dispute,
             client: 1,
             tx: 99,
-            amount: Some(50.0),
+            amount: Some(money("50.0")),
+            timestamp: None,
         };
 
         let res = acct.handle_dispute(dispute);
@@ -58,21 +67,22 @@ mod dispute_tests {
             tx_type: TransactionType::Deposit,
             client: 1,
             tx: 1,
-            amount: Some(100.0),
+            amount: Some(money("100.0")),
+            timestamp: None,
         };
-        acct.handle_deposit(deposit.clone()).unwrap();
+        acct.handle_deposit(deposit).unwrap();
 
         let dispute = Transaction {
             tx_type: TransactionType::Dispute,
-            ..deposit.clone()
+            ..deposit
         };
 
         acct.handle_dispute(dispute).unwrap();
         assert!(acct.handle_dispute(dispute).is_err()); // second dispute ignored
 
-        assert_eq!(acct.account_details.held_funds, 100.0);
-        assert!(acct.account_transaction_archive.disputes.contains(&1));
-        assert_eq!(acct.account_transaction_archive.disputes.len(), 1);
+        assert_eq!(acct.account_details.held_funds, money("100.0"));
+        assert!(acct.account_transaction_archive.is_disputed(1));
+        assert_eq!(acct.account_transaction_archive.open_disputes().len(), 1);
     }
 
     /// Test that a successfully disputed transaction gets recorded
@@ -84,16 +94,113 @@ mod dispute_tests {
             tx_type: TransactionType::Deposit,
             client: 1,
             tx: 1,
-            amount: Some(100.0),
+            amount: Some(money("100.0")),
+            timestamp: None,
         };
         acct.handle_deposit(deposit).unwrap();
 
         let dispute = Transaction {
             tx_type: TransactionType::Dispute,
-            ..deposit.clone()
+            ..deposit
         };
 
         acct.handle_dispute(dispute).unwrap();
-        assert!(acct.account_transaction_archive.disputes.contains(&1));
+        assert!(acct.account_transaction_archive.is_disputed(1));
+    }
+
+    /// Helper that deposits 100.0 (tx 1), withdraws 40.0 (tx 2), and returns
+    /// the account along with a dispute row for the withdrawal.
+    fn account_with_withdrawal() -> (ClientAccount, Transaction) {
+        let mut acct = ClientAccount::default();
+        let deposit = Transaction {
+            tx_type: TransactionType::Deposit,
+            client: 1,
+            tx: 1,
+            amount: Some(money("100.0")),
+            timestamp: None,
+        };
+        let withdrawal = Transaction {
+            tx_type: TransactionType::Withdrawal,
+            tx: 2,
+            amount: Some(money("40.0")),
+            ..deposit
+        };
+        acct.handle_deposit(deposit).unwrap();
+        acct.handle_withdrawal(withdrawal).unwrap();
+        let dispute = Transaction {
+            tx_type: TransactionType::Dispute,
+            amount: None,
+            ..withdrawal
+        };
+        (acct, dispute)
+    }
+
+    /// Test that withdrawals cannot be disputed under the default policy.
+    #[test]
+    fn test_dispute_withdrawal_rejected_by_default() {
+        let (mut acct, dispute) = account_with_withdrawal();
+
+        assert!(matches!(
+            acct.handle_dispute(dispute),
+            Err(PaymentsTransactionError::WithdrawalNotDisputable(2))
+        ));
+        assert_eq!(acct.account_details.available_funds, money("60.0"));
+        assert_eq!(acct.account_details.held_funds, money("0.0"));
+        assert!(!acct.account_transaction_archive.is_disputed(2));
+    }
+
+    /// Test that the mirror-deposit policy holds a disputed withdrawal out of
+    /// available funds, the same way a disputed deposit is held.
+    #[test]
+    fn test_dispute_withdrawal_mirror_deposit_policy() {
+        let (mut acct, dispute) = account_with_withdrawal();
+
+        acct.handle_dispute_with_policy(dispute, WithdrawalDisputePolicy::MirrorDeposit)
+            .unwrap();
+
+        assert_eq!(acct.account_details.available_funds, money("20.0"));
+        assert_eq!(acct.account_details.held_funds, money("40.0"));
+        assert_eq!(acct.account_details.total_funds, money("60.0"));
+    }
+
+    /// Test that the reversal policy credits a disputed withdrawal into held and
+    /// total funds, and that a chargeback returns it to the client.
+    #[test]
+    fn test_dispute_withdrawal_reversal_policy_chargeback() {
+        let (mut acct, dispute) = account_with_withdrawal();
+
+        acct.handle_dispute_with_policy(dispute, WithdrawalDisputePolicy::Reversal)
+            .unwrap();
+        assert_eq!(acct.account_details.available_funds, money("60.0"));
+        assert_eq!(acct.account_details.held_funds, money("40.0"));
+        assert_eq!(acct.account_details.total_funds, money("100.0"));
+
+        acct.handle_chargeback(Transaction {
+            tx_type: TransactionType::Chargeback,
+            ..dispute
+        })
+        .unwrap();
+        assert_eq!(acct.account_details.available_funds, money("100.0"));
+        assert_eq!(acct.account_details.held_funds, money("0.0"));
+        assert_eq!(acct.account_details.total_funds, money("100.0"));
+        assert!(acct.account_details.is_account_locked);
+    }
+
+    /// Test that resolving a withdrawal held as a reversal lets the withdrawal stand.
+    #[test]
+    fn test_dispute_withdrawal_reversal_policy_resolve() {
+        let (mut acct, dispute) = account_with_withdrawal();
+
+        acct.handle_dispute_with_policy(dispute, WithdrawalDisputePolicy::Reversal)
+            .unwrap();
+        acct.handle_resolve(Transaction {
+            tx_type: TransactionType::Resolve,
+            ..dispute
+        })
+        .unwrap();
+
+        assert_eq!(acct.account_details.available_funds, money("60.0"));
+        assert_eq!(acct.account_details.held_funds, money("0.0"));
+        assert_eq!(acct.account_details.total_funds, money("60.0"));
     }
 }
@@ -4,10 +4,16 @@
 #[cfg(test)]
 mod resolve_tests {
     use crate::{
-        account::client_account::ClientAccount,
+        account::{client_account::ClientAccount, transaction_state::TransactionState},
+        errors::PaymentsTransactionError,
+        money::Money,
         transaction::{Transaction, TransactionType},
     };
 
+    fn money(amount: &str) -> Money {
+        amount.parse().unwrap()
+    }
+
     /// Test that resolving a valid disputed transaction moves funds
     /// from held back to available balance.
     #[test]
@@ -17,26 +23,28 @@ mod resolve_tests {
             tx_type: TransactionType::Deposit,
             client: 1,
             tx: 1,
-            amount: Some(100.0),
+            amount: Some(money("100.0")),
+            timestamp: None,
         };
         let dispute = Transaction {
             tx_type: TransactionType::Dispute,
             client: 1,
             tx: 1,
-            amount: Some(0.0),
+            amount: Some(money("0.0")),
+            timestamp: None,
         };
         acct.handle_deposit(deposit).unwrap();
         acct.handle_dispute(dispute).unwrap();
 
         acct.handle_resolve(dispute).unwrap();
 
-        assert_eq!(acct.account_details.available_funds, 100.0);
-        assert_eq!(acct.account_details.held_funds, 0.0);
-        assert!(!acct.account_transaction_archive.disputes.contains(&1));
+        assert_eq!(acct.account_details.available_funds, money("100.0"));
+        assert_eq!(acct.account_details.held_funds, money("0.0"));
+        assert!(!acct.account_transaction_archive.is_disputed(1));
     }
 
     /// Test that resolving a transaction that was never recorded
-    /// does not modify balances and is safely ignored.
+    /// does not modify balances and is rejected.
     #[test]
     fn test_resolve_nonexistent_transaction() {
         let mut acct = ClientAccount::default();
@@ -44,17 +52,21 @@ mod resolve_tests {
             tx_type: TransactionType::Resolve,
             client: 1,
             tx: 99,
-            amount: Some(0.0),
+            amount: Some(money("0.0")),
+            timestamp: None,
         };
 
         let result = acct.handle_resolve(resolve);
-        assert!(result.is_ok());
-        assert_eq!(acct.account_details.available_funds, 0.0);
-        assert_eq!(acct.account_details.held_funds, 0.0);
+        assert!(matches!(
+            result,
+            Err(PaymentsTransactionError::TransactionDetailDoesNotExist(_))
+        ));
+        assert_eq!(acct.account_details.available_funds, money("0.0"));
+        assert_eq!(acct.account_details.held_funds, money("0.0"));
     }
 
-    /// Test that resolving a transaction that is not currently disputed
-    /// does not affect account balances or the disputes set.
+    /// Test that resolving a transaction that is not currently disputed is an
+    /// illegal transition and does not affect account balances or the disputes set.
     #[test]
     fn test_resolve_not_disputed_transaction_is_ignored() {
         let mut acct = ClientAccount::default();
@@ -62,21 +74,29 @@ mod resolve_tests {
             tx_type: TransactionType::Deposit,
             client: 1,
             tx: 1,
-            amount: Some(100.0),
+            amount: Some(money("100.0")),
+            timestamp: None,
         };
         let resolve = Transaction {
             // transaction exists but not disputed
             tx_type: TransactionType::Resolve,
             client: 1,
             tx: 1,
-            amount: Some(0.0),
+            amount: Some(money("0.0")),
+            timestamp: None,
         };
         acct.handle_deposit(deposit).unwrap();
-        acct.handle_resolve(resolve).unwrap();
+        assert!(matches!(
+            acct.handle_resolve(resolve),
+            Err(PaymentsTransactionError::IllegalStateTransition {
+                from: TransactionState::Processed,
+                ..
+            })
+        ));
 
-        assert_eq!(acct.account_details.available_funds, 100.0);
-        assert_eq!(acct.account_details.held_funds, 0.0);
-        assert!(!acct.account_transaction_archive.disputes.contains(&1));
+        assert_eq!(acct.account_details.available_funds, money("100.0"));
+        assert_eq!(acct.account_details.held_funds, money("0.0"));
+        assert!(!acct.account_transaction_archive.is_disputed(1));
     }
 
     /// Test that resolving a transaction removes it from the disputes set.
@@ -88,30 +108,33 @@ mod resolve_tests {
             tx_type: TransactionType::Deposit,
             client: 1,
             tx: 1,
-            amount: Some(100.0),
+            amount: Some(money("100.0")),
+            timestamp: None,
         };
         let dispute = Transaction {
             tx_type: TransactionType::Dispute,
             client: 1,
             tx: 1,
-            amount: Some(100.0),
+            amount: Some(money("100.0")),
+            timestamp: None,
         };
         let resolve = Transaction {
             // transaction exists but not disputed
             tx_type: TransactionType::Resolve,
             client: 1,
             tx: 1,
-            amount: Some(0.0),
+            amount: Some(money("0.0")),
+            timestamp: None,
         };
         acct.handle_deposit(deposit).unwrap();
         acct.handle_dispute(dispute).unwrap();
         acct.handle_resolve(resolve).unwrap();
 
-        assert!(!acct.account_transaction_archive.disputes.contains(&1));
+        assert!(!acct.account_transaction_archive.is_disputed(1));
     }
 
-    /// Test that multiple resolve calls on the same transaction
-    /// do not incorrectly modify balances after the first resolve.
+    /// Test that multiple resolve calls on the same transaction are rejected
+    /// and do not incorrectly modify balances after the first resolve.
     #[test]
     fn test_multiple_resolves_are_ignored() {
         let mut acct = ClientAccount::default();
@@ -119,29 +142,38 @@ mod resolve_tests {
             tx_type: TransactionType::Deposit,
             client: 1,
             tx: 1,
-            amount: Some(100.0),
+            amount: Some(money("100.0")),
+            timestamp: None,
         };
         let dispute = Transaction {
             tx_type: TransactionType::Dispute,
             client: 1,
             tx: 1,
-            amount: Some(100.0),
+            amount: Some(money("100.0")),
+            timestamp: None,
         };
         let resolve = Transaction {
             // transaction exists but not disputed
             tx_type: TransactionType::Resolve,
             client: 1,
             tx: 1,
-            amount: Some(0.0),
+            amount: Some(money("0.0")),
+            timestamp: None,
         };
         acct.handle_deposit(deposit).unwrap();
         acct.handle_dispute(dispute).unwrap();
 
         acct.handle_resolve(resolve).unwrap();
-        acct.handle_resolve(resolve).unwrap(); // ignored second call
+        assert!(matches!(
+            acct.handle_resolve(resolve),
+            Err(PaymentsTransactionError::IllegalStateTransition {
+                from: TransactionState::Resolved,
+                ..
+            })
+        )); // rejected second call
 
-        assert_eq!(acct.account_details.available_funds, 100.0);
-        assert_eq!(acct.account_details.held_funds, 0.0);
-        assert!(!acct.account_transaction_archive.disputes.contains(&1));
+        assert_eq!(acct.account_details.available_funds, money("100.0"));
+        assert_eq!(acct.account_details.held_funds, money("0.0"));
+        assert!(!acct.account_transaction_archive.is_disputed(1));
     }
 }
@@ -6,10 +6,15 @@ mod withdrawal_tests {
     use crate::{
         account::client_account::ClientAccount,
         errors::PaymentsTransactionError,
+        money::Money,
         transaction::{Transaction, TransactionType},
     };
 
-    fn sample_account_with_balance(balance: f64) -> ClientAccount {
+    fn money(amount: &str) -> Money {
+        amount.parse().unwrap()
+    }
+
+    fn sample_account_with_balance(balance: Money) -> ClientAccount {
         let mut account = ClientAccount::default();
         account.account_details.available_funds = balance;
         account.account_details.total_funds = balance;
@@ -21,21 +26,25 @@ mod withdrawal_tests {
     /// This is the basic happy path test for withdrawals.
     #[test]
     fn test_single_withdrawal() {
-        let mut account = sample_account_with_balance(100.0);
+        let mut account = sample_account_with_balance(money("100.0"));
         let tx = Transaction {
             tx_type: TransactionType::Withdrawal,
             client: 1,
             tx: 1,
-            amount: Some(40.0),
+            amount: Some(money("40.0")),
+            timestamp: None,
         };
         account.handle_withdrawal(tx).unwrap();
 
-        assert_eq!(account.account_details.available_funds, 60.0);
-        assert_eq!(account.account_details.total_funds, 60.0);
-        assert!(account.account_transaction_archive.history.contains(&1));
+        assert_eq!(account.account_details.available_funds, money("60.0"));
+        assert_eq!(account.account_details.total_funds, money("60.0"));
+        assert!(account.account_transaction_archive.contains(1));
         assert_eq!(
-            account.account_transaction_archive.details.get(&1),
-            Some(&(40.0, TransactionType::Withdrawal))
+            account
+                .account_transaction_archive
+                .get(1)
+                .map(|d| (d.amount(), d.tx_type())),
+            Some((money("40.0"), TransactionType::Withdrawal))
         );
     }
 
@@ -44,12 +53,13 @@ mod withdrawal_tests {
     /// This prevents accounts from going negative.
     #[test]
     fn test_withdrawal_insufficient_funds() {
-        let mut account = sample_account_with_balance(20.0);
+        let mut account = sample_account_with_balance(money("20.0"));
         let tx = Transaction {
             tx_type: TransactionType::Withdrawal,
             client: 1,
             tx: 2,
-            amount: Some(50.0),
+            amount: Some(money("50.0")),
+            timestamp: None,
         };
         let result = account.handle_withdrawal(tx);
 
@@ -57,10 +67,10 @@ mod withdrawal_tests {
             result,
             Err(PaymentsTransactionError::NotEnoughAvailableFunds(_))
         ));
-        assert_eq!(account.account_details.available_funds, 20.0);
-        assert_eq!(account.account_details.total_funds, 20.0);
+        assert_eq!(account.account_details.available_funds, money("20.0"));
+        assert_eq!(account.account_details.total_funds, money("20.0"));
         // A failed withdrawal should not go into the set of successful withdrawals and deposits.
-        assert!(!account.account_transaction_archive.history.contains(&2));
+        assert!(!account.account_transaction_archive.contains(2));
     }
 
     /// Multiple withdrawals reduce balances correctly when funds are available.
@@ -68,49 +78,56 @@ mod withdrawal_tests {
     /// Confirms successive withdrawals accumulate properly.
     #[test]
     fn test_multiple_withdrawals_accumulate() {
-        let mut account = sample_account_with_balance(100.0);
+        let mut account = sample_account_with_balance(money("100.0"));
         let tx1 = Transaction {
             tx_type: TransactionType::Withdrawal,
             client: 1,
             tx: 3,
-            amount: Some(30.0),
+            amount: Some(money("30.0")),
+            timestamp: None,
         };
         let tx2 = Transaction {
             tx_type: TransactionType::Withdrawal,
             client: 1,
             tx: 4,
-            amount: Some(20.0),
+            amount: Some(money("20.0")),
+            timestamp: None,
         };
 
         account.handle_withdrawal(tx1).unwrap();
-        assert_eq!(account.account_details.available_funds, 70.0);
-        assert_eq!(account.account_details.total_funds, 70.0);
-        assert!(account.account_transaction_archive.history.contains(&3));
+        assert_eq!(account.account_details.available_funds, money("70.0"));
+        assert_eq!(account.account_details.total_funds, money("70.0"));
+        assert!(account.account_transaction_archive.contains(3));
 
         account.handle_withdrawal(tx2).unwrap();
-        assert_eq!(account.account_details.available_funds, 50.0);
-        assert_eq!(account.account_details.total_funds, 50.0);
-        assert!(account.account_transaction_archive.history.contains(&3));
-        assert!(account.account_transaction_archive.history.contains(&4));
+        assert_eq!(account.account_details.available_funds, money("50.0"));
+        assert_eq!(account.account_details.total_funds, money("50.0"));
+        assert!(account.account_transaction_archive.contains(3));
+        assert!(account.account_transaction_archive.contains(4));
     }
 
-    /// A withdrawal with zero amount leaves balances unchanged.
-    /// The transaction ID is still recorded in the account's history.
-    /// Ensures zero-value withdrawals are logged but do not affect funds.
+    /// Withdrawals of zero or a negative amount are rejected as
+    /// `InvalidAmount`. A negative withdrawal must not credit the account.
     #[test]
-    fn test_zero_amount_withdrawal() {
-        let mut account = sample_account_with_balance(100.0);
-        let tx = Transaction {
-            tx_type: TransactionType::Withdrawal,
-            client: 1,
-            tx: 5,
-            amount: Some(0.0),
-        };
-        account.handle_withdrawal(tx).unwrap();
-
-        assert_eq!(account.account_details.available_funds, 100.0);
-        assert_eq!(account.account_details.total_funds, 100.0);
-        assert!(account.account_transaction_archive.history.contains(&5));
+    fn test_non_positive_withdrawal_rejected() {
+        let mut account = sample_account_with_balance(money("10.0"));
+        for (tx, amount) in [(5, "0.0"), (6, "-1000")] {
+            let result = account.handle_withdrawal(Transaction {
+                tx_type: TransactionType::Withdrawal,
+                client: 1,
+                tx,
+                amount: Some(money(amount)),
+                timestamp: None,
+            });
+
+            assert!(matches!(
+                result,
+                Err(PaymentsTransactionError::InvalidAmount(_))
+            ));
+            assert!(!account.account_transaction_archive.contains(tx));
+        }
+        assert_eq!(account.account_details.available_funds, money("10.0"));
+        assert_eq!(account.account_details.total_funds, money("10.0"));
     }
 
     /// Very large withdrawal works as long as there are enough funds.
@@ -118,17 +135,21 @@ mod withdrawal_tests {
     /// Ensures the system can process high-value withdrawals.
     #[test]
     fn test_large_withdrawal() {
-        let mut account = sample_account_with_balance(1e12);
+        let mut account = sample_account_with_balance(money("1000000000000"));
         let tx = Transaction {
             tx_type: TransactionType::Withdrawal,
             client: 1,
             tx: 6,
-            amount: Some(5e11),
+            amount: Some(money("500000000000")),
+            timestamp: None,
         };
         account.handle_withdrawal(tx).unwrap();
 
-        assert_eq!(account.account_details.available_funds, 5e11);
-        assert_eq!(account.account_details.total_funds, 5e11);
+        assert_eq!(
+            account.account_details.available_funds,
+            money("500000000000")
+        );
+        assert_eq!(account.account_details.total_funds, money("500000000000"));
     }
 
     /// Duplicate withdrawal transaction IDs do not update balances a second time.
@@ -136,35 +157,70 @@ mod withdrawal_tests {
     /// Confirms that duplicate transactions are ignored safely.
     #[test]
     fn test_duplicate_withdrawal_transaction_id() {
-        let mut account = sample_account_with_balance(100.0);
+        let mut account = sample_account_with_balance(money("100.0"));
 
         let tx1 = Transaction {
             tx_type: TransactionType::Withdrawal,
             client: 1,
             tx: 7,
-            amount: Some(25.0),
+            amount: Some(money("25.0")),
+            timestamp: None,
         };
         let tx2 = Transaction {
             tx_type: TransactionType::Withdrawal,
             client: 1,
             tx: 7, // same tx id
-            amount: Some(20.0),
+            amount: Some(money("20.0")),
+            timestamp: None,
         };
 
         account.handle_withdrawal(tx1).unwrap();
         assert!(account.handle_withdrawal(tx2).is_err());
         // Only the first withdrawal should apply
-        assert_eq!(account.account_details.available_funds, 75.0);
-        assert_eq!(account.account_details.total_funds, 75.0);
+        assert_eq!(account.account_details.available_funds, money("75.0"));
+        assert_eq!(account.account_details.total_funds, money("75.0"));
 
         // History contains tx ID once
-        assert_eq!(account.account_transaction_archive.history.len(), 1);
-        assert!(account.account_transaction_archive.history.contains(&7));
+        assert_eq!(account.account_transaction_archive.len(), 1);
+        assert!(account.account_transaction_archive.contains(7));
 
         // Details match the first withdrawal
         assert_eq!(
-            account.account_transaction_archive.details.get(&7),
-            Some(&(25.0, TransactionType::Withdrawal))
+            account
+                .account_transaction_archive
+                .get(7)
+                .map(|d| (d.amount(), d.tx_type())),
+            Some((money("25.0"), TransactionType::Withdrawal))
         );
     }
+
+    /// Withdrawing the exact balance built up from fractional deposits succeeds.
+    /// With binary floating point, 0.1 + 0.2 falls just short of 0.3 and the
+    /// withdrawal used to be rejected.
+    #[test]
+    fn test_exact_balance_withdrawal_after_fractional_deposits() {
+        let mut account = ClientAccount::default();
+        for (tx, amount) in [(1, "0.1"), (2, "0.2")] {
+            account
+                .handle_deposit(Transaction {
+                    tx_type: TransactionType::Deposit,
+                    client: 1,
+                    tx,
+                    amount: Some(money(amount)),
+                    timestamp: None,
+                })
+                .unwrap();
+        }
+        let withdrawal = Transaction {
+            tx_type: TransactionType::Withdrawal,
+            client: 1,
+            tx: 3,
+            amount: Some(money("0.3")),
+            timestamp: None,
+        };
+        account.handle_withdrawal(withdrawal).unwrap();
+
+        assert_eq!(account.account_details.available_funds, Money::ZERO);
+        assert_eq!(account.account_details.total_funds, Money::ZERO);
+    }
 }
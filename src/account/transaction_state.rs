@@ -0,0 +1,279 @@
+/// This file defines the lifecycle of an archived transaction: the states it can
+/// be in, the single transition function that moves it between them, and the
+/// log of transitions kept for auditing.
+use serde::{Deserialize, Serialize};
+use strum::Display;
+
+use crate::errors::PaymentsTransactionError;
+use crate::money::Money;
+use crate::transaction::{Transaction, TransactionType};
+
+/// Lifecycle state of an archived deposit or withdrawal.
+#[derive(Debug, Display, Serialize, Deserialize, Clone, Copy, PartialEq, Eq)]
+pub enum TransactionState {
+    /// Applied to the account and not under dispute.
+    Processed,
+    /// Under dispute, with its funds held.
+    Disputed,
+    /// A dispute was resolved and its held funds released.
+    Resolved,
+    /// A dispute ended in a chargeback. This state is final.
+    ChargedBack,
+}
+
+impl TransactionState {
+    /// Returns the state reached when a row of the given type is applied in this
+    /// state, or `None` if that edge is not part of the lifecycle:
+    ///
+    /// - Processed --dispute--> Disputed
+    /// - Disputed --resolve--> Resolved
+    /// - Disputed --chargeback--> ChargedBack
+    /// - Resolved --dispute--> Disputed
+    pub fn next(self, event: TransactionType) -> Option<TransactionState> {
+        match (self, event) {
+            (TransactionState::Processed, TransactionType::Dispute) => {
+                Some(TransactionState::Disputed)
+            }
+            (TransactionState::Disputed, TransactionType::Resolve) => {
+                Some(TransactionState::Resolved)
+            }
+            (TransactionState::Disputed, TransactionType::Chargeback) => {
+                Some(TransactionState::ChargedBack)
+            }
+            (TransactionState::Resolved, TransactionType::Dispute) => {
+                Some(TransactionState::Disputed)
+            }
+            _ => None,
+        }
+    }
+}
+
+/// How the funds of a disputed transaction were put on hold, so that a later
+/// resolve or chargeback can undo exactly what the dispute did.
+#[derive(Debug, Default, Serialize, Deserialize, Clone, Copy, PartialEq, Eq)]
+pub enum HoldKind {
+    /// The amount was moved from available into held funds. Used for deposits,
+    /// and for withdrawals under `WithdrawalDisputePolicy::MirrorDeposit`.
+    #[default]
+    FromAvailable,
+    /// The withdrawn amount was provisionally credited back into held and total
+    /// funds. Used for withdrawals under `WithdrawalDisputePolicy::Reversal`.
+    Reversal,
+}
+
+/// A single validated state change and the row that triggered it.
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Eq)]
+pub struct StateTransition {
+    pub from: TransactionState,
+    pub to: TransactionState,
+    pub row: Transaction,
+}
+
+/// An archived deposit or withdrawal along with its current state and
+/// every state change it has gone through.
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq, Eq)]
+pub struct TransactionDetail {
+    pub(crate) tx: u32,
+    pub(crate) amount: Money,
+    pub(crate) tx_type: TransactionType,
+    pub(crate) state: TransactionState,
+    pub(crate) hold: HoldKind,
+    pub(crate) transitions: Vec<StateTransition>,
+    /// Position of the row that applied the transaction among every row the
+    /// engine has processed, starting at 1. Zero if it was never recorded.
+    pub(crate) position: u64,
+    /// Timestamp of the row that applied the transaction, if it had one.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub(crate) timestamp: Option<u64>,
+}
+
+impl TransactionDetail {
+    /// Archives a freshly applied transaction in the `Processed` state.
+    pub fn new(tx: u32, amount: Money, tx_type: TransactionType) -> Self {
+        TransactionDetail {
+            tx,
+            amount,
+            tx_type,
+            state: TransactionState::Processed,
+            hold: HoldKind::default(),
+            transitions: Vec::new(),
+            position: 0,
+            timestamp: None,
+        }
+    }
+
+    /// ID of the original transaction.
+    pub fn tx(&self) -> u32 {
+        self.tx
+    }
+
+    /// Amount of the original transaction.
+    pub fn amount(&self) -> Money {
+        self.amount
+    }
+
+    /// Type of the original transaction.
+    pub fn tx_type(&self) -> TransactionType {
+        self.tx_type
+    }
+
+    /// Current lifecycle state.
+    pub fn state(&self) -> TransactionState {
+        self.state
+    }
+
+    /// How the funds were held by the most recent dispute.
+    pub fn hold(&self) -> HoldKind {
+        self.hold
+    }
+
+    /// Every state change applied to this transaction, oldest first.
+    pub fn transitions(&self) -> &[StateTransition] {
+        &self.transitions
+    }
+
+    /// Position of the row that applied this transaction among every row the
+    /// engine has processed, which the dispute window is measured from.
+    pub fn position(&self) -> u64 {
+        self.position
+    }
+
+    /// When the transaction happened, as a Unix timestamp in seconds, if its
+    /// row had a timestamp.
+    pub fn timestamp(&self) -> Option<u64> {
+        self.timestamp
+    }
+
+    /// Returns the state `event` would move this transaction to, without applying it.
+    pub fn check_transition(
+        &self,
+        event: TransactionType,
+    ) -> Result<TransactionState, PaymentsTransactionError> {
+        self.state
+            .next(event)
+            .ok_or(PaymentsTransactionError::IllegalStateTransition {
+                tx: self.tx,
+                from: self.state,
+                event,
+            })
+    }
+
+    /// Moves this transaction to the state `event` leads to and records the change
+    /// along with the row that triggered it. Every lifecycle change goes through
+    /// here so illegal edges are always caught.
+    pub fn transition(
+        &mut self,
+        event: TransactionType,
+        row: Transaction,
+    ) -> Result<TransactionState, PaymentsTransactionError> {
+        let to = self.check_transition(event)?;
+        self.transitions.push(StateTransition {
+            from: self.state,
+            to,
+            row,
+        });
+        self.state = to;
+        Ok(to)
+    }
+}
+
+#[cfg(test)]
+mod transaction_state_tests {
+    use super::*;
+
+    fn row(tx_type: TransactionType) -> Transaction {
+        Transaction {
+            tx_type,
+            client: 1,
+            tx: 1,
+            amount: None,
+            timestamp: None,
+        }
+    }
+
+    /// Every legal edge moves to the expected state and is logged with its row.
+    #[test]
+    fn test_full_lifecycle_is_logged() {
+        let mut detail =
+            TransactionDetail::new(1, Money::from_scaled(10_000), TransactionType::Deposit);
+
+        detail
+            .transition(TransactionType::Dispute, row(TransactionType::Dispute))
+            .unwrap();
+        detail
+            .transition(TransactionType::Resolve, row(TransactionType::Resolve))
+            .unwrap();
+        detail
+            .transition(TransactionType::Dispute, row(TransactionType::Dispute))
+            .unwrap();
+        detail
+            .transition(
+                TransactionType::Chargeback,
+                row(TransactionType::Chargeback),
+            )
+            .unwrap();
+
+        assert_eq!(detail.state(), TransactionState::ChargedBack);
+        let states: Vec<_> = detail
+            .transitions()
+            .iter()
+            .map(|t| (t.from, t.to))
+            .collect();
+        assert_eq!(
+            states,
+            vec![
+                (TransactionState::Processed, TransactionState::Disputed),
+                (TransactionState::Disputed, TransactionState::Resolved),
+                (TransactionState::Resolved, TransactionState::Disputed),
+                (TransactionState::Disputed, TransactionState::ChargedBack),
+            ]
+        );
+        assert_eq!(
+            detail.transitions()[3].row,
+            row(TransactionType::Chargeback)
+        );
+    }
+
+    /// A charged back transaction cannot be disputed again.
+    #[test]
+    fn test_charged_back_is_final() {
+        let mut detail =
+            TransactionDetail::new(1, Money::from_scaled(10_000), TransactionType::Deposit);
+        detail
+            .transition(TransactionType::Dispute, row(TransactionType::Dispute))
+            .unwrap();
+        detail
+            .transition(
+                TransactionType::Chargeback,
+                row(TransactionType::Chargeback),
+            )
+            .unwrap();
+
+        for event in [
+            TransactionType::Dispute,
+            TransactionType::Resolve,
+            TransactionType::Chargeback,
+        ] {
+            assert!(matches!(
+                detail.transition(event, row(event)),
+                Err(PaymentsTransactionError::IllegalStateTransition {
+                    from: TransactionState::ChargedBack,
+                    ..
+                })
+            ));
+        }
+        assert_eq!(detail.transitions().len(), 2);
+    }
+
+    /// Deposits and withdrawals are never lifecycle events.
+    #[test]
+    fn test_funds_movements_are_not_transitions() {
+        for event in [TransactionType::Deposit, TransactionType::Withdrawal] {
+            assert_eq!(TransactionState::Processed.next(event), None);
+        }
+        assert_eq!(
+            TransactionState::Processed.next(TransactionType::Resolve),
+            None
+        );
+    }
+}
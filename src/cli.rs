@@ -0,0 +1,307 @@
+/// This file defines the payments engine's command-line interface: its
+/// subcommands, their flags, and the exit codes the binary reports.
+use clap::{Args, Parser, Subcommand, ValueEnum};
+use log::LevelFilter;
+use std::process::ExitCode;
+
+use take_home_assignment::generator::{GeneratorConfig, TransactionMix};
+use take_home_assignment::utils::STDIN_SOURCE;
+use take_home_assignment::{DisputeWindow, JournalSync, Money, ReportOrder};
+
+/// A toy payments engine that applies deposits, withdrawals, disputes,
+/// resolves and chargebacks and reports the resulting client balances.
+///
+/// Running without a subcommand is the same as `process`, so
+/// `take_home_assignment transactions.csv > accounts.csv` keeps working.
+#[derive(Debug, Parser)]
+#[command(
+    version,
+    args_conflicts_with_subcommands = true,
+    subcommand_negates_reqs = true
+)]
+pub struct Cli {
+    #[command(subcommand)]
+    pub command: Option<Command>,
+
+    #[command(flatten)]
+    pub process: ProcessArgs,
+
+    /// Log level: off, error, warn, info, debug or trace. Defaults to RUST_LOG,
+    /// or errors only if that is unset.
+    #[arg(long, global = true, value_name = "LEVEL")]
+    pub log_level: Option<LevelFilter>,
+}
+
+impl Cli {
+    /// The subcommand to run, falling back to `process` with the top-level flags.
+    pub fn into_command(self) -> Command {
+        self.command.unwrap_or(Command::Process(self.process))
+    }
+}
+
+/// Subcommands the binary supports.
+#[derive(Debug, Subcommand)]
+pub enum Command {
+    /// Apply every transaction and write the resulting account report.
+    Process(ProcessArgs),
+    /// Write a randomized transactions CSV for local testing.
+    Generate(GenerateArgs),
+    /// Check that every row of the input parses, without applying any of them.
+    Validate(InputArgs),
+    /// Apply every transaction and write a per-row trace of its outcome and
+    /// the client's balances afterwards.
+    Replay(ReplayArgs),
+    /// Run a TCP server that applies CSV or JSON Lines transaction rows
+    /// streamed over any number of concurrent connections, acknowledging each
+    /// row and answering `REPORT` with the current account report. Optionally
+    /// also serve an HTTP/JSON API for the same engine.
+    Serve(ServeArgs),
+}
+
+/// Flags shared by every subcommand that reads transactions.
+#[derive(Debug, Args)]
+pub struct InputArgs {
+    /// Transactions file, optionally gzipped, or `-` for stdin.
+    // Required, so running with no arguments prints usage instead of waiting
+    // on stdin. The hidden default only fills in the top-level `process` flags
+    // when a subcommand is given instead.
+    #[arg(
+        value_name = "INPUT",
+        required = true,
+        default_value = STDIN_SOURCE,
+        hide_default_value = true
+    )]
+    pub input: String,
+
+    /// Format of the transactions input.
+    #[arg(long, value_enum, default_value_t = InputFormat::Csv)]
+    pub input_format: InputFormat,
+
+    /// Write every rejected row to this file, as JSON if it ends in `.json`
+    /// and as CSV otherwise.
+    #[arg(long, value_name = "FILE")]
+    pub rejects: Option<String>,
+
+    /// Stop at the first rejected or malformed row instead of recording it and
+    /// carrying on.
+    #[arg(long)]
+    pub strict: bool,
+}
+
+/// Flags for `process`.
+#[derive(Debug, Args)]
+pub struct ProcessArgs {
+    #[command(flatten)]
+    pub input: InputArgs,
+
+    /// Write the account report to this file instead of stdout.
+    #[arg(short, long, value_name = "FILE")]
+    pub output: Option<String>,
+
+    /// Format of the account report.
+    #[arg(long, value_enum, default_value_t = OutputFormat::Csv)]
+    pub output_format: OutputFormat,
+
+    /// Order of the account report rows: client-id, total or first-seen.
+    #[arg(long, value_name = "ORDER", default_value_t = ReportOrder::ClientId)]
+    pub order: ReportOrder,
+
+    /// Number of worker threads to process transactions with. With `--strict`,
+    /// rows are applied one at a time regardless, so processing can stop at
+    /// the first bad one.
+    #[arg(long, value_name = "N", default_value_t = 1, value_parser = clap::value_parser!(u16).range(1..))]
+    pub workers: u16,
+
+    /// Hold back up to N rows and apply them in `timestamp` order, for feeds
+    /// that are slightly out of order. Zero applies rows as they arrive.
+    #[arg(long, value_name = "N", default_value_t = 0)]
+    pub reorder_buffer: usize,
+
+    /// Save the engine state to this snapshot once every row has been applied,
+    /// emptying the `--journal` if there is one.
+    #[arg(long, value_name = "FILE")]
+    pub save_snapshot: Option<String>,
+
+    #[command(flatten)]
+    pub engine: EngineArgs,
+}
+
+/// Flags that set up the engine rows are applied to, shared by `process` and
+/// `serve`.
+#[derive(Debug, Args)]
+pub struct EngineArgs {
+    /// How long deposits and withdrawals can be disputed: unbounded, for N
+    /// further rows, or for a span of `timestamp` time like 90d, 12h, 30m or
+    /// 45s. Transactions past their window are dropped from the archive.
+    #[arg(long, value_name = "WINDOW", default_value = "unbounded")]
+    pub dispute_window: DisputeWindow,
+
+    /// Start from the engine state in this snapshot instead of an empty engine,
+    /// so only the transactions since it was taken need applying.
+    #[arg(long, value_name = "FILE")]
+    pub resume: Option<String>,
+
+    /// Append every transaction to this write-ahead journal before applying
+    /// it. Transactions already in the journal from a crashed run are replayed
+    /// on top of the `--resume` snapshot first.
+    #[arg(long, value_name = "FILE")]
+    pub journal: Option<String>,
+
+    /// Keep archived transactions in this scratch file instead of in memory,
+    /// for inputs whose history doesn't fit in RAM. Anything already in the
+    /// file is discarded. Rows are then applied on a single thread.
+    #[arg(long, value_name = "FILE")]
+    pub disk_store: Option<String>,
+
+    /// When to flush the journal to disk: always, never, or after every N
+    /// records.
+    #[arg(
+        long,
+        value_name = "POLICY",
+        default_value = "always",
+        requires = "journal"
+    )]
+    pub journal_sync: JournalSync,
+
+    /// Append a JSON line to this file for every domain event the engine
+    /// raises, such as funds credited or an account locked. Rows are then
+    /// applied on a single thread, so events come out in input order.
+    #[arg(long, value_name = "FILE")]
+    pub events: Option<String>,
+}
+
+/// Flags for `serve`.
+#[derive(Debug, Args)]
+pub struct ServeArgs {
+    /// Address to listen for transaction streams on.
+    #[arg(long, value_name = "ADDR", default_value = "127.0.0.1:7878")]
+    pub listen: String,
+
+    /// Also serve the HTTP/JSON API for the same engine on this address.
+    #[arg(long, value_name = "ADDR")]
+    pub http: Option<String>,
+
+    #[command(flatten)]
+    pub engine: EngineArgs,
+}
+
+/// Flags for `generate`.
+#[derive(Debug, Args)]
+pub struct GenerateArgs {
+    /// Number of transactions to generate.
+    #[arg(long, value_name = "N", default_value_t = 100)]
+    pub transactions: u32,
+
+    /// Number of distinct clients the transactions are spread across.
+    #[arg(long, value_name = "N", default_value_t = 10, value_parser = clap::value_parser!(u16).range(1..))]
+    pub clients: u16,
+
+    /// Relative weight of each transaction type, as comma-separated type=weight
+    /// pairs. Unlisted types get no rows.
+    #[arg(
+        long,
+        value_name = "MIX",
+        default_value = "deposit=60,withdrawal=25,dispute=9,resolve=4,chargeback=2"
+    )]
+    pub mix: TransactionMix,
+
+    /// Largest amount a deposit may carry.
+    #[arg(long, value_name = "AMOUNT", default_value = "100")]
+    pub max_amount: Money,
+
+    /// Fraction of rows, from 0.0 to 1.0, replaced with rows the engine should
+    /// reject (overdrafts, reused IDs, disputes of unknown or foreign transactions).
+    #[arg(long, value_name = "RATE", default_value_t = 0.0, value_parser = parse_rate)]
+    pub error_rate: f64,
+
+    /// Seed for reproducible output. A random seed is used if not given.
+    #[arg(long, value_name = "SEED")]
+    pub seed: Option<u64>,
+
+    /// Write the CSV to this file instead of stdout.
+    #[arg(short, long, value_name = "FILE")]
+    pub output: Option<String>,
+}
+
+impl GenerateArgs {
+    /// The generator settings these flags describe.
+    pub fn generator_config(&self) -> GeneratorConfig {
+        GeneratorConfig {
+            transactions: self.transactions,
+            clients: self.clients,
+            mix: self.mix,
+            max_amount: self.max_amount,
+            error_rate: self.error_rate,
+            seed: self.seed,
+        }
+    }
+}
+
+/// Parses a fraction between 0.0 and 1.0 inclusive.
+fn parse_rate(s: &str) -> Result<f64, String> {
+    let rate: f64 = s.parse().map_err(|_| format!("{} is not a number", s))?;
+    if (0.0..=1.0).contains(&rate) {
+        Ok(rate)
+    } else {
+        Err(format!("{} is not between 0.0 and 1.0", rate))
+    }
+}
+
+/// Flags for `replay`.
+#[derive(Debug, Args)]
+pub struct ReplayArgs {
+    #[command(flatten)]
+    pub input: InputArgs,
+
+    /// Only trace rows for this client.
+    #[arg(long, value_name = "ID")]
+    pub client: Option<u16>,
+
+    /// Write the trace to this file instead of stdout.
+    #[arg(short, long, value_name = "FILE")]
+    pub output: Option<String>,
+}
+
+/// Formats transactions can be read in.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum InputFormat {
+    /// `type,client,tx,amount` rows with a header, plus an optional
+    /// `timestamp` column.
+    Csv,
+    /// One JSON transaction object per line (JSON Lines / NDJSON), with the
+    /// same fields as the CSV columns. Amounts may be strings or numbers.
+    #[value(alias = "ndjson")]
+    Jsonl,
+}
+
+/// Formats the account report can be written in.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum OutputFormat {
+    /// `client,available,held,total,locked` rows with a header.
+    Csv,
+    /// A single JSON array of account objects.
+    Json,
+    /// One JSON account object per line.
+    Jsonl,
+}
+
+/// Exit codes reported by the binary.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ExitStatus {
+    /// Every row was applied.
+    Success = 0,
+    /// An unexpected failure, such as being unable to write the output.
+    Failure = 1,
+    /// The command line couldn't be parsed.
+    Usage = 2,
+    /// The input couldn't be opened or read, or `--strict` hit a bad row.
+    InputError = 3,
+    /// The run finished but at least one row was rejected.
+    PartiallyRejected = 4,
+}
+
+impl From<ExitStatus> for ExitCode {
+    fn from(status: ExitStatus) -> Self {
+        ExitCode::from(status as u8)
+    }
+}
@@ -0,0 +1,379 @@
+/// This file implements each of the binary's subcommands on top of the
+/// payments engine library.
+use anyhow::{Context, Result};
+use log::{debug, info, warn};
+use serde::Serialize;
+use std::fs::File;
+use std::io::{self, BufReader, BufWriter, Write};
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+
+use take_home_assignment::generator::TransactionGenerator;
+use take_home_assignment::payments_engine::report::{
+    write_csv_report, write_json_report, write_jsonl_report,
+};
+use take_home_assignment::utils::{
+    InputRow, STDIN_SOURCE, initialize_csv_reader, input_rows, jsonl_rows, open_transaction_source,
+};
+use take_home_assignment::{
+    ApiServer, DiskAccountStore, IngestServer, JsonlEventSink, Money, PaymentsEngine,
+    PaymentsTransactionError, Rejection, RejectionReport, ReorderBuffer, Transaction,
+};
+
+use crate::cli::{
+    EngineArgs, ExitStatus, GenerateArgs, InputArgs, InputFormat, OutputFormat, ProcessArgs,
+    ReplayArgs, ServeArgs,
+};
+
+/// Opens `path` for writing, or stdout if no path (or `-`) is given.
+fn output_writer(path: Option<&str>) -> Result<Box<dyn Write>> {
+    match path {
+        None | Some(STDIN_SOURCE) => Ok(Box::new(io::stdout().lock())),
+        Some(path) => {
+            let file = File::create(path).with_context(|| format!("error creating {}", path))?;
+            Ok(Box::new(BufWriter::new(file)))
+        }
+    }
+}
+
+/// Writes the rejected rows to `path`, choosing JSON or CSV by extension.
+fn write_rejects(report: &RejectionReport, path: &str) -> Result<()> {
+    let writer = output_writer(Some(path))?;
+    if Path::new(path).extension().and_then(|ext| ext.to_str()) == Some("json") {
+        report.write_json(writer)
+    } else {
+        report.write_csv(writer)
+    }
+}
+
+/// Loads the engine state saved in the snapshot at `path`.
+fn resume_from(engine: &mut PaymentsEngine, path: &str) -> Result<()> {
+    let file = File::open(path).with_context(|| format!("error opening snapshot {}", path))?;
+    engine
+        .load_snapshot(BufReader::new(file))
+        .with_context(|| format!("error loading snapshot {}", path))
+}
+
+/// Records a rejected row, or fails the run with it under `--strict`.
+fn reject(args: &InputArgs, report: &mut RejectionReport, rejection: Rejection) -> Result<()> {
+    warn!(
+        "Rejected row {} ({}): {}",
+        rejection.line, rejection.raw, rejection.error
+    );
+    if args.strict {
+        let context = format!("line {}: {}", rejection.line, rejection.raw);
+        return Err(anyhow::Error::new(rejection.error).context(context));
+    }
+    report.record(rejection);
+    Ok(())
+}
+
+/// Writes the rejects file if one was asked for, summarizes the rejections on
+/// stderr, and returns the exit status the run should end with.
+fn finish(args: &InputArgs, report: &RejectionReport) -> Result<ExitStatus> {
+    if let Some(rejects_file) = &args.rejects {
+        write_rejects(report, rejects_file)?;
+    }
+    if report.is_empty() {
+        return Ok(ExitStatus::Success);
+    }
+    eprintln!("Rejected {} transaction(s):", report.len());
+    for (kind, count) in report.summary() {
+        eprintln!("  {}: {}", kind, count);
+    }
+    Ok(ExitStatus::PartiallyRejected)
+}
+
+/// Reads the data rows of the input in whichever format it is in. Only
+/// errors after which nothing more can be read are yielded as `Err`.
+fn input_rows_of(args: &InputArgs) -> Result<Box<dyn Iterator<Item = Result<InputRow>>>> {
+    Ok(match args.input_format {
+        InputFormat::Csv => Box::new(
+            input_rows(initialize_csv_reader(&args.input)?)?.map(|row| row.map_err(Into::into)),
+        ),
+        InputFormat::Jsonl => Box::new(
+            jsonl_rows(BufReader::new(open_transaction_source(&args.input)?))
+                .map(|row| row.map_err(Into::into)),
+        ),
+    })
+}
+
+/// Reads the data rows of the input, passing them through a reorder buffer of
+/// `reorder_buffer` rows so they come out in timestamp order.
+fn ordered_rows(
+    args: &InputArgs,
+    reorder_buffer: usize,
+) -> Result<impl Iterator<Item = Result<InputRow>>> {
+    let rows = input_rows_of(args)?;
+    Ok(ReorderBuffer::new(rows, reorder_buffer, |row| {
+        row.as_ref()
+            .ok()
+            .and_then(|row| row.parsed.as_ref().ok())
+            .and_then(|tx| tx.timestamp)
+    }))
+}
+
+/// Applies every row of the input to `engine`, recording the ones it refuses.
+/// `after_row` is called after each row with the parsed transaction (if any)
+/// and the error it was rejected with (if any).
+fn apply_rows(
+    args: &InputArgs,
+    reorder_buffer: usize,
+    engine: &mut PaymentsEngine,
+    report: &mut RejectionReport,
+    mut after_row: impl FnMut(
+        u64,
+        Option<Transaction>,
+        Option<&PaymentsTransactionError>,
+        &PaymentsEngine,
+    ) -> Result<()>,
+) -> Result<()> {
+    /*
+    From the assignment spec:
+    - The client ID will be unique per client though are not guaranteed to be ordered.
+    - Can assume transactions occur chronologically in the file.
+    - Whitespaces and decimal precisions (up to four places past the decimal) must be accepted.
+    */
+    for row in ordered_rows(args, reorder_buffer)? {
+        let InputRow { line, raw, parsed } = row?;
+        let rejection = match parsed {
+            Ok(tx) => {
+                debug!("{:?}", tx);
+                let outcome = engine.process_transaction(tx);
+                after_row(line, Some(tx), outcome.error(), engine)?;
+                outcome
+                    .into_result()
+                    .err()
+                    .map(|e| Rejection::new(line, raw, &tx, e))
+            }
+            Err(e) => {
+                after_row(line, None, Some(&e), engine)?;
+                Some(Rejection::malformed(line, raw, e))
+            }
+        };
+        // A rejected row shouldn't stop the rest of the input from being processed.
+        if let Some(rejection) = rejection {
+            reject(args, report, rejection)?;
+        }
+    }
+    Ok(())
+}
+
+/// Applies every row of the input with `process_batch`, so clients are sharded
+/// across the engine's workers. Rejections are recorded in input order once
+/// the whole input has been applied.
+fn apply_rows_sharded(
+    args: &InputArgs,
+    reorder_buffer: usize,
+    engine: &mut PaymentsEngine,
+    report: &mut RejectionReport,
+) -> Result<()> {
+    let mut rejections = Vec::new();
+    let mut read_error = None;
+    let transactions = ordered_rows(args, reorder_buffer)?
+        .map_while(|row| row.map_err(|e| read_error = Some(e)).ok())
+        .filter_map(|InputRow { line, raw, parsed }| match parsed {
+            Ok(tx) => Some(((line, raw, tx), tx)),
+            Err(e) => {
+                rejections.push(Rejection::malformed(line, raw, e));
+                None
+            }
+        });
+    // A failed worker is the engine's fault, not the input's, so it's reported
+    // as a plain failure rather than a transaction error.
+    let rejected = engine
+        .process_batch(transactions)
+        .map_err(|e| anyhow::anyhow!("Error processing transactions: {}", e))?;
+    if let Some(e) = read_error {
+        return Err(e);
+    }
+
+    rejections.extend(
+        rejected
+            .into_iter()
+            .map(|((line, raw, tx), e)| Rejection::new(line, raw, &tx, e)),
+    );
+    rejections.sort_by_key(|rejection| rejection.line);
+    for rejection in rejections {
+        reject(args, report, rejection)?;
+    }
+    Ok(())
+}
+
+/// Builds the engine rows are applied to: configured, backed by the chosen
+/// store, and restored from the snapshot and journal if any were given.
+fn build_engine(args: &EngineArgs, workers: usize) -> Result<PaymentsEngine> {
+    let mut engine = PaymentsEngine::new()
+        .with_workers(workers)
+        .with_dispute_window(args.dispute_window);
+    if let Some(path) = &args.disk_store {
+        let store = DiskAccountStore::create(path)
+            .with_context(|| format!("error creating account store {}", path))?;
+        engine = engine.with_store(store);
+    }
+    if let Some(path) = &args.events {
+        let sink = JsonlEventSink::open(path)
+            .with_context(|| format!("error opening event file {}", path))?;
+        engine = engine.with_subscriber(sink);
+    }
+    if let Some(journal) = &args.journal {
+        let snapshot = args.resume.as_deref().map(Path::new);
+        let recovery = engine
+            .recover(snapshot, journal, args.journal_sync)
+            .with_context(|| format!("error recovering from journal {}", journal))?;
+        if recovery.truncated_bytes > 0 {
+            warn!(
+                "Truncated a torn final record ({} bytes) from journal {}",
+                recovery.truncated_bytes, journal
+            );
+        }
+        info!(
+            "Replayed {} journaled transaction(s) from {}",
+            recovery.replayed, journal
+        );
+    } else if let Some(snapshot) = &args.resume {
+        resume_from(&mut engine, snapshot)?;
+    }
+    Ok(engine)
+}
+
+/// `process`: applies every transaction and writes the account report.
+pub fn process(args: ProcessArgs) -> Result<ExitStatus> {
+    let mut engine = build_engine(&args.engine, usize::from(args.workers))?;
+    let mut report = RejectionReport::new();
+    // A sharded batch is applied in full before any rejection is seen, so
+    // `--strict` applies rows one at a time to stop at the first bad one.
+    if engine.workers() > 1 && !args.input.strict {
+        apply_rows_sharded(&args.input, args.reorder_buffer, &mut engine, &mut report)?;
+    } else {
+        apply_rows(
+            &args.input,
+            args.reorder_buffer,
+            &mut engine,
+            &mut report,
+            |_, _, _, _| Ok(()),
+        )?;
+    }
+
+    let summaries = engine.sorted_account_summaries(args.order);
+    let mut writer = output_writer(args.output.as_deref())?;
+    match args.output_format {
+        OutputFormat::Csv => write_csv_report(&summaries, &mut writer)?,
+        OutputFormat::Jsonl => write_jsonl_report(&summaries, &mut writer)?,
+        OutputFormat::Json => {
+            write_json_report(&summaries, &mut writer)?;
+            writeln!(writer)?;
+        }
+    }
+    writer.flush()?;
+    if let Some(snapshot) = &args.save_snapshot {
+        engine.checkpoint(snapshot)?;
+    }
+    finish(&args.input, &report)
+}
+
+/// `serve`: applies rows streamed over TCP, and optionally answers the HTTP
+/// API, until the process is stopped.
+pub fn serve(args: ServeArgs) -> Result<ExitStatus> {
+    let engine = Arc::new(Mutex::new(build_engine(&args.engine, 1)?));
+    let server = IngestServer::bind_shared(&args.listen, Arc::clone(&engine))
+        .with_context(|| format!("error listening on {}", args.listen))?;
+    eprintln!("Listening on {}", server.local_addr()?);
+    // The API runs on its own thread for as long as the process does.
+    let _api = match &args.http {
+        Some(addr) => {
+            let api = ApiServer::bind_shared(addr, engine)
+                .with_context(|| format!("error listening on {}", addr))?;
+            eprintln!("Serving the HTTP API on {}", api.local_addr()?);
+            Some(api.spawn()?)
+        }
+        None => None,
+    };
+    server.serve()?;
+    Ok(ExitStatus::Success)
+}
+
+/// `generate`: writes a randomized, realistic transactions CSV.
+pub fn generate(args: GenerateArgs) -> Result<ExitStatus> {
+    let writer = output_writer(args.output.as_deref())?;
+    let written = TransactionGenerator::new(args.generator_config()).write_csv(writer)?;
+    if written < args.transactions {
+        warn!(
+            "Stopped after {} of {} transactions: every client was locked by a chargeback",
+            written, args.transactions
+        );
+    }
+    Ok(ExitStatus::Success)
+}
+
+/// `validate`: parses every row without applying any of them.
+pub fn validate(args: InputArgs) -> Result<ExitStatus> {
+    let mut report = RejectionReport::new();
+    let mut total_rows = 0;
+    for row in input_rows_of(&args)? {
+        let InputRow { line, raw, parsed } = row?;
+        total_rows += 1;
+        if let Err(e) = parsed {
+            reject(&args, &mut report, Rejection::malformed(line, raw, e))?;
+        }
+    }
+    eprintln!(
+        "Validated {} row(s), {} malformed",
+        total_rows,
+        report.len()
+    );
+    finish(&args, &report)
+}
+
+/// One line of `replay` output: a row, its outcome, and its client's balances
+/// after it was applied. Balances are empty if the client has no account.
+#[derive(Serialize)]
+struct ReplayRecord {
+    line: u64,
+    #[serde(rename = "type")]
+    tx_type: Option<String>,
+    client: Option<u16>,
+    tx: Option<u32>,
+    amount: Option<Money>,
+    timestamp: Option<u64>,
+    outcome: &'static str,
+    available: Option<String>,
+    held: Option<String>,
+    total: Option<String>,
+    locked: Option<bool>,
+}
+
+/// `replay`: applies every transaction and writes a per-row trace.
+pub fn replay(args: ReplayArgs) -> Result<ExitStatus> {
+    let mut engine = PaymentsEngine::new();
+    let mut report = RejectionReport::new();
+    let mut wtr = csv::Writer::from_writer(output_writer(args.output.as_deref())?);
+    apply_rows(
+        &args.input,
+        0,
+        &mut engine,
+        &mut report,
+        |line, tx, error, engine| {
+            if args.client.is_some() && tx.map(|tx| tx.client) != args.client {
+                return Ok(());
+            }
+            let details = tx.and_then(|tx| engine.account_details(tx.client));
+            wtr.serialize(ReplayRecord {
+                line,
+                tx_type: tx.map(|tx| tx.tx_type.to_string()),
+                client: tx.map(|tx| tx.client),
+                tx: tx.map(|tx| tx.tx),
+                amount: tx.and_then(|tx| tx.amount),
+                timestamp: tx.and_then(|tx| tx.timestamp),
+                outcome: error.map_or("Applied", PaymentsTransactionError::kind),
+                available: details.map(|d| d.available_funds().to_string()),
+                held: details.map(|d| d.held_funds().to_string()),
+                total: details.map(|d| d.total_funds().to_string()),
+                locked: details.map(|d| d.is_locked()),
+            })
+            .context("Error writing replay row")
+        },
+    )?;
+    wtr.flush()?;
+    finish(&args.input, &report)
+}
@@ -1,9 +1,13 @@
 /// This file defines a `PaymentsTransactionError` type that is conditionally
 /// reported by the engine when a given payments engine error state has occured.
+use strum::IntoStaticStr;
 use thiserror::Error;
 
+use crate::account::transaction_state::TransactionState;
+use crate::transaction::TransactionType;
+
 /// Custom payments engine error type
-#[derive(Debug, Error)]
+#[derive(Debug, Error, IntoStaticStr)]
 pub enum PaymentsTransactionError {
     #[error("Not enough available funds for client {0}")]
     NotEnoughAvailableFunds(String),
@@ -11,10 +15,64 @@ pub enum PaymentsTransactionError {
     TransactionDetailDoesNotExist(String),
     #[error("Transaction CSV file {0} does not exist {0}")]
     TransactionCsvDoesNotExist(String),
-    #[error("Argument must be a CSV file {0}")]
-    InvalidTransactionFileExtension(String),
+    #[error("Transaction input is missing required header column(s): {0}")]
+    InvalidTransactionHeader(String),
     #[error("I/O error: {0}")]
     Io(#[from] std::io::Error),
+    #[error("CSV error: {0}")]
+    Csv(#[from] csv::Error),
+    #[error("Malformed row: {0}")]
+    MalformedRow(String),
+    #[error("Snapshot format version {found} is not supported, expected {expected}")]
+    UnsupportedSnapshotVersion { found: u64, expected: u64 },
+    #[error("Invalid snapshot: {0}")]
+    InvalidSnapshot(String),
+    #[error("Journal is corrupt: {0}")]
+    CorruptJournal(String),
+    #[error("Account store error: {0}")]
+    AccountStore(String),
     #[error("Duplicate transaction ID seen: {0}")]
     DuplicateTransactionId(String),
+    #[error("Transaction {tx} for client {client} reuses an ID already used by client {owner}")]
+    CrossClientDuplicateTransactionId { tx: u32, client: u16, owner: u16 },
+    #[error("Transaction {tx} referenced by client {client} belongs to client {owner}")]
+    TransactionClientMismatch { tx: u32, client: u16, owner: u16 },
+    #[error("Transaction {tx} cannot go from {from} on a {event}")]
+    IllegalStateTransition {
+        tx: u32,
+        from: TransactionState,
+        event: TransactionType,
+    },
+    #[error("Withdrawal {0} cannot be disputed")]
+    WithdrawalNotDisputable(u32),
+    #[error("Transaction {0} is outside the dispute window")]
+    DisputeWindowExpired(u32),
+    #[error("Timestamp {timestamp} for client {client} is before its last transaction at {last}")]
+    TimestampOutOfOrder {
+        client: u16,
+        timestamp: u64,
+        last: u64,
+    },
+    #[error("Account for client {0} is locked")]
+    AccountLocked(u16),
+    #[error("Client {0} does not exist")]
+    ClientDoesNotExist(u16),
+    #[error("Transaction {0} is missing an amount")]
+    MissingAmount(u32),
+    #[error("Invalid transaction amount {0}")]
+    InvalidAmount(String),
+    #[error("Amount overflow while computing {0}")]
+    AmountOverflow(String),
+    #[error("The engine has stopped and is no longer accepting transactions")]
+    EngineStopped,
+    #[error("Shard worker failed: {0}")]
+    ShardFailed(String),
+}
+
+impl PaymentsTransactionError {
+    /// Name of the error variant, e.g. `NotEnoughAvailableFunds`. Used to group
+    /// rejections by cause in reports.
+    pub fn kind(&self) -> &'static str {
+        self.into()
+    }
 }
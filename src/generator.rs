@@ -0,0 +1,485 @@
+/// This file defines a seeded generator of realistic transaction workloads,
+/// used to produce test input for the payments engine.
+use anyhow::{Context, Result};
+use csv::Writer;
+use rand::rngs::StdRng;
+use rand::seq::IndexedRandom;
+use rand::{Rng, SeedableRng};
+use std::collections::BTreeMap;
+use std::io::Write;
+use std::str::FromStr;
+
+use crate::money::Money;
+use crate::transaction::{Transaction, TransactionType};
+
+/// Relative weights of each transaction type in a generated workload.
+///
+/// Dispute rows only target deposits the client has made, and resolve and
+/// chargeback rows only follow up open disputes, so when a type has nothing
+/// valid to reference the generator emits a deposit instead.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct TransactionMix {
+    pub deposit: u32,
+    pub withdrawal: u32,
+    pub dispute: u32,
+    pub resolve: u32,
+    pub chargeback: u32,
+}
+
+impl Default for TransactionMix {
+    fn default() -> Self {
+        TransactionMix {
+            deposit: 60,
+            withdrawal: 25,
+            dispute: 9,
+            resolve: 4,
+            chargeback: 2,
+        }
+    }
+}
+
+impl TransactionMix {
+    /// Weight of one transaction type.
+    pub fn weight(&self, tx_type: TransactionType) -> u32 {
+        match tx_type {
+            TransactionType::Deposit => self.deposit,
+            TransactionType::Withdrawal => self.withdrawal,
+            TransactionType::Dispute => self.dispute,
+            TransactionType::Resolve => self.resolve,
+            TransactionType::Chargeback => self.chargeback,
+        }
+    }
+
+    fn weight_mut(&mut self, tx_type: TransactionType) -> &mut u32 {
+        match tx_type {
+            TransactionType::Deposit => &mut self.deposit,
+            TransactionType::Withdrawal => &mut self.withdrawal,
+            TransactionType::Dispute => &mut self.dispute,
+            TransactionType::Resolve => &mut self.resolve,
+            TransactionType::Chargeback => &mut self.chargeback,
+        }
+    }
+}
+
+/// Parses a comma-separated list of `type=weight` pairs, e.g.
+/// `deposit=70,withdrawal=30`. Types that aren't listed get a weight of zero.
+impl FromStr for TransactionMix {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut mix = TransactionMix {
+            deposit: 0,
+            withdrawal: 0,
+            dispute: 0,
+            resolve: 0,
+            chargeback: 0,
+        };
+        for pair in s.split(',') {
+            let (tx_type, weight) = pair
+                .split_once('=')
+                .ok_or_else(|| format!("expected type=weight, got {:?}", pair))?;
+            let tx_type: TransactionType = tx_type
+                .trim()
+                .parse()
+                .map_err(|_| format!("unknown transaction type {:?}", tx_type))?;
+            *mix.weight_mut(tx_type) = weight
+                .trim()
+                .parse()
+                .map_err(|_| format!("invalid weight {:?}", weight))?;
+        }
+        if mix.deposit == 0 {
+            return Err("the deposit weight must be greater than zero".to_string());
+        }
+        Ok(mix)
+    }
+}
+
+/// Settings for a generated workload.
+#[derive(Debug, Clone)]
+pub struct GeneratorConfig {
+    /// Number of rows to generate.
+    pub transactions: u32,
+    /// Number of distinct clients, with IDs starting at 1.
+    pub clients: u16,
+    /// Relative weights of each transaction type.
+    pub mix: TransactionMix,
+    /// Largest amount a deposit may carry.
+    pub max_amount: Money,
+    /// Fraction of rows, from 0.0 to 1.0, replaced with a row the engine
+    /// is expected to reject.
+    pub error_rate: f64,
+    /// Seed for the random number generator. The same seed and settings always
+    /// produce the same workload; `None` seeds from the OS.
+    pub seed: Option<u64>,
+}
+
+impl Default for GeneratorConfig {
+    fn default() -> Self {
+        GeneratorConfig {
+            transactions: 100,
+            clients: 10,
+            mix: TransactionMix::default(),
+            max_amount: Money::from_scaled(1_000_000),
+            error_rate: 0.0,
+            seed: None,
+        }
+    }
+}
+
+/// What the generator knows about one client's account so far.
+#[derive(Debug, Default)]
+struct ClientState {
+    available: Money,
+    /// Deposits that can currently be disputed, by ID and amount.
+    disputable: Vec<(u32, Money)>,
+    locked: bool,
+}
+
+/// Produces transactions by simulating the accounts it writes rows for, so
+/// that withdrawals stay within the available balance and every dispute,
+/// resolve and chargeback references a real transaction of the same client.
+#[derive(Debug)]
+pub struct TransactionGenerator {
+    config: GeneratorConfig,
+    rng: StdRng,
+    /// Ordered so that the same seed walks the clients in the same order.
+    clients: BTreeMap<u16, ClientState>,
+    /// Disputes that haven't been resolved or charged back yet.
+    open_disputes: Vec<(u16, u32, Money)>,
+    /// IDs of the deposits and withdrawals written so far that the engine applies.
+    applied: Vec<u32>,
+    next_tx: u32,
+    generated: u32,
+}
+
+impl TransactionGenerator {
+    /// Creates a generator for the given settings.
+    pub fn new(config: GeneratorConfig) -> Self {
+        let rng = match config.seed {
+            Some(seed) => StdRng::seed_from_u64(seed),
+            None => StdRng::from_os_rng(),
+        };
+        TransactionGenerator {
+            config,
+            rng,
+            clients: BTreeMap::new(),
+            open_disputes: Vec::new(),
+            applied: Vec::new(),
+            next_tx: 1,
+            generated: 0,
+        }
+    }
+
+    /// Writes the whole workload as CSV with a header row, returning the number
+    /// of rows written.
+    pub fn write_csv<W: Write>(self, writer: W) -> Result<u32> {
+        let mut wtr = Writer::from_writer(writer);
+        let mut written = 0;
+        for tx in self {
+            wtr.serialize(tx)
+                .context("Error writing transaction to CSV")?;
+            written += 1;
+        }
+        wtr.flush().context("Error flushing CSV writer")?;
+        Ok(written)
+    }
+
+    fn take_tx_id(&mut self) -> u32 {
+        let tx = self.next_tx;
+        self.next_tx += 1;
+        tx
+    }
+
+    fn random_amount(&mut self, max: Money) -> Money {
+        Money::from_scaled(self.rng.random_range(1..=max.scaled().max(1)))
+    }
+
+    fn random_unlocked_client(&mut self) -> Option<u16> {
+        let unlocked: Vec<u16> = (1..=self.config.clients)
+            .filter(|client| !self.clients.get(client).is_some_and(|state| state.locked))
+            .collect();
+        unlocked.choose(&mut self.rng).copied()
+    }
+
+    fn pick_type(&mut self) -> TransactionType {
+        let mix = self.config.mix;
+        let types = [
+            TransactionType::Deposit,
+            TransactionType::Withdrawal,
+            TransactionType::Dispute,
+            TransactionType::Resolve,
+            TransactionType::Chargeback,
+        ];
+        *types
+            .choose_weighted(&mut self.rng, |tx_type| mix.weight(*tx_type))
+            .unwrap_or(&TransactionType::Deposit)
+    }
+
+    fn deposit(&mut self, client: u16) -> Transaction {
+        let tx = self.take_tx_id();
+        let amount = self.random_amount(self.config.max_amount);
+        let state = self.clients.entry(client).or_default();
+        state.available = state
+            .available
+            .checked_add(amount)
+            .unwrap_or(state.available);
+        state.disputable.push((tx, amount));
+        self.applied.push(tx);
+        Transaction {
+            tx_type: TransactionType::Deposit,
+            client,
+            tx,
+            amount: Some(amount),
+            timestamp: None,
+        }
+    }
+
+    fn withdrawal(&mut self, client: u16) -> Option<Transaction> {
+        let available = self.clients.get(&client)?.available;
+        if available <= Money::ZERO {
+            return None;
+        }
+        let tx = self.take_tx_id();
+        let amount = self.random_amount(available);
+        let state = self.clients.get_mut(&client)?;
+        state.available = available.checked_sub(amount).ok()?;
+        self.applied.push(tx);
+        Some(Transaction {
+            tx_type: TransactionType::Withdrawal,
+            client,
+            tx,
+            amount: Some(amount),
+            timestamp: None,
+        })
+    }
+
+    fn dispute(&mut self) -> Option<Transaction> {
+        let candidates: Vec<u16> = self
+            .clients
+            .iter()
+            .filter(|(_, state)| !state.disputable.is_empty())
+            .map(|(client, _)| *client)
+            .collect();
+        let client = *candidates.choose(&mut self.rng)?;
+        let state = self.clients.get_mut(&client)?;
+        let index = self.rng.random_range(0..state.disputable.len());
+        let (tx, amount) = state.disputable.swap_remove(index);
+        state.available = state.available.checked_sub(amount).ok()?;
+        self.open_disputes.push((client, tx, amount));
+        Some(Transaction {
+            tx_type: TransactionType::Dispute,
+            client,
+            tx,
+            amount: None,
+            timestamp: None,
+        })
+    }
+
+    fn follow_up(&mut self, tx_type: TransactionType) -> Option<Transaction> {
+        if self.open_disputes.is_empty() {
+            return None;
+        }
+        let index = self.rng.random_range(0..self.open_disputes.len());
+        let (client, tx, amount) = self.open_disputes.swap_remove(index);
+        let state = self.clients.get_mut(&client)?;
+        if tx_type == TransactionType::Resolve {
+            // A resolved deposit can be disputed again.
+            state.available = state.available.checked_add(amount).ok()?;
+            state.disputable.push((tx, amount));
+        } else {
+            state.locked = true;
+        }
+        Some(Transaction {
+            tx_type,
+            client,
+            tx,
+            amount: None,
+            timestamp: None,
+        })
+    }
+
+    /// A row the engine should reject: an overdraft, a dispute of a transaction
+    /// that doesn't exist or belongs to another client, or a reused ID.
+    fn injected_error(&mut self) -> Option<Transaction> {
+        let client = self.random_unlocked_client()?;
+        match self.rng.random_range(0..4) {
+            0 => {
+                let available = self
+                    .clients
+                    .get(&client)
+                    .map_or(Money::ZERO, |state| state.available.max(Money::ZERO));
+                let overdraft = available.checked_add(self.random_amount(self.config.max_amount));
+                Some(Transaction {
+                    tx_type: TransactionType::Withdrawal,
+                    client,
+                    tx: self.take_tx_id(),
+                    amount: overdraft.ok(),
+                    timestamp: None,
+                })
+            }
+            1 if !self.applied.is_empty() => Some(Transaction {
+                tx_type: TransactionType::Deposit,
+                client,
+                tx: *self.applied.choose(&mut self.rng)?,
+                amount: Some(self.random_amount(self.config.max_amount)),
+                timestamp: None,
+            }),
+            2 => {
+                let foreign: Vec<u32> = self
+                    .clients
+                    .iter()
+                    .filter(|(other, _)| **other != client)
+                    .flat_map(|(_, state)| state.disputable.iter().map(|(tx, _)| *tx))
+                    .collect();
+                let tx = *foreign.choose(&mut self.rng)?;
+                Some(Transaction {
+                    tx_type: TransactionType::Dispute,
+                    client,
+                    tx,
+                    amount: None,
+                    timestamp: None,
+                })
+            }
+            _ => Some(Transaction {
+                tx_type: TransactionType::Dispute,
+                client,
+                // IDs are handed out from 1 upwards, so this one never exists.
+                tx: u32::MAX - self.generated,
+                amount: None,
+                timestamp: None,
+            }),
+        }
+    }
+}
+
+impl Iterator for TransactionGenerator {
+    type Item = Transaction;
+
+    /// Ends early if every client has been locked by a chargeback, since no
+    /// further deposits could be applied.
+    fn next(&mut self) -> Option<Transaction> {
+        if self.generated >= self.config.transactions {
+            return None;
+        }
+        let client = self.random_unlocked_client()?;
+        self.generated += 1;
+
+        if self.rng.random_bool(self.config.error_rate.clamp(0.0, 1.0))
+            && let Some(tx) = self.injected_error()
+        {
+            return Some(tx);
+        }
+        let generated = match self.pick_type() {
+            TransactionType::Deposit => None,
+            TransactionType::Withdrawal => self.withdrawal(client),
+            TransactionType::Dispute => self.dispute(),
+            tx_type => self.follow_up(tx_type),
+        };
+        Some(generated.unwrap_or_else(|| self.deposit(client)))
+    }
+}
+
+#[cfg(test)]
+mod generator_tests {
+    use super::*;
+    use crate::PaymentsEngine;
+    use std::collections::HashMap;
+
+    fn config(seed: u64) -> GeneratorConfig {
+        GeneratorConfig {
+            transactions: 2_000,
+            clients: 200,
+            seed: Some(seed),
+            ..GeneratorConfig::default()
+        }
+    }
+
+    /// The same seed and settings always produce byte-identical output.
+    #[test]
+    fn test_seed_is_reproducible() {
+        let mut first = Vec::new();
+        let mut second = Vec::new();
+        let written = TransactionGenerator::new(config(7))
+            .write_csv(&mut first)
+            .unwrap();
+        assert_eq!(written, 2_000);
+        TransactionGenerator::new(config(7))
+            .write_csv(&mut second)
+            .unwrap();
+        assert_eq!(first, second);
+
+        let mut other = Vec::new();
+        TransactionGenerator::new(config(8))
+            .write_csv(&mut other)
+            .unwrap();
+        assert_ne!(first, other);
+    }
+
+    /// Without error injection every generated row is accepted by the engine,
+    /// and every dispute references an earlier deposit of the same client.
+    #[test]
+    fn test_workload_without_errors_is_valid() {
+        let mut engine = PaymentsEngine::new();
+        let mut deposits: HashMap<u32, u16> = HashMap::new();
+        let mut disputes = 0;
+        for tx in TransactionGenerator::new(config(42)) {
+            match tx.tx_type {
+                TransactionType::Deposit => {
+                    deposits.insert(tx.tx, tx.client);
+                }
+                TransactionType::Dispute => {
+                    disputes += 1;
+                    assert_eq!(deposits.get(&tx.tx), Some(&tx.client));
+                }
+                _ => {}
+            }
+            engine.process_transaction(tx).into_result().unwrap();
+        }
+        assert!(disputes > 0);
+    }
+
+    /// Error injection produces rows the engine rejects, at roughly the
+    /// configured rate.
+    #[test]
+    fn test_error_injection_rate() {
+        let mut engine = PaymentsEngine::new();
+        let generator = TransactionGenerator::new(GeneratorConfig {
+            error_rate: 0.2,
+            ..config(3)
+        });
+        let rows: Vec<Transaction> = generator.collect();
+        let rejected = rows
+            .iter()
+            .filter(|tx| engine.process_transaction(**tx).into_result().is_err())
+            .count();
+
+        let rate = rejected as f64 / rows.len() as f64;
+        assert!((0.15..0.25).contains(&rate), "rejection rate {}", rate);
+    }
+
+    /// A mix parses from `type=weight` pairs and rejects bad input.
+    #[test]
+    fn test_transaction_mix_from_str() {
+        let mix: TransactionMix = "deposit=70, withdrawal=30".parse().unwrap();
+        assert_eq!(mix.deposit, 70);
+        assert_eq!(mix.withdrawal, 30);
+        assert_eq!(mix.dispute, 0);
+
+        assert!("deposit".parse::<TransactionMix>().is_err());
+        assert!("refund=3,deposit=1".parse::<TransactionMix>().is_err());
+        assert!("withdrawal=1".parse::<TransactionMix>().is_err());
+    }
+
+    /// With only deposits and withdrawals in the mix, no dispute rows appear.
+    #[test]
+    fn test_mix_limits_types() {
+        let generator = TransactionGenerator::new(GeneratorConfig {
+            mix: "deposit=1,withdrawal=1".parse().unwrap(),
+            ..config(1)
+        });
+        assert!(generator.into_iter().all(|tx| matches!(
+            tx.tx_type,
+            TransactionType::Deposit | TransactionType::Withdrawal
+        )));
+    }
+}
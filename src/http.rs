@@ -0,0 +1,999 @@
+/// This file defines an embedded HTTP/JSON API over a shared
+/// `PaymentsEngine`, for submitting transactions and querying accounts.
+///
+/// Endpoints:
+///
+/// - `GET /health`: `{"status":"ok"}`.
+/// - `POST /transactions`: applies one transaction object, or an array of
+///   them in order. One object is answered with its outcome, `200` if it was
+///   applied and `422` if it was ignored or rejected; an array is answered
+///   `200` with the outcome of every element.
+/// - `GET /accounts/{client}`: the client's balances, in the report's shape.
+/// - `GET /accounts/{client}/transactions`: the client's archived
+///   transactions, ordered by ID.
+/// - `GET /accounts/{client}/disputes`: the archived transactions currently
+///   under dispute.
+/// - `GET /report`: every account, ordered by client ID or by the `order`
+///   query parameter (`client-id`, `total` or `first-seen`).
+///
+/// Outcomes are `{"outcome":"Applied","before":{...},"after":{...}}`, with
+/// the account's balances either side of the transaction in the report's
+/// shape, or `Ignored` or `Rejected` with the `error` the transaction failed
+/// with and its `message`. Other failures are
+/// answered with `{"error":"..."}`. A POST carrying an `Idempotency-Key`
+/// header is applied at most once: a retry with the same key and body gets
+/// the first response back, marked with an `Idempotent-Replayed: true`
+/// header, and reusing a key for a different body is refused with `422`. A
+/// retry sent while the first request is still being applied waits for its
+/// response.
+use log::{info, warn};
+use serde::Serialize;
+use sha2::{Digest, Sha256};
+use std::collections::{HashMap, VecDeque};
+use std::io::{self, BufRead, BufReader, BufWriter, Read, Write};
+use std::net::{SocketAddr, TcpListener, TcpStream, ToSocketAddrs};
+use std::sync::atomic::AtomicBool;
+use std::sync::{Arc, Condvar, Mutex, MutexGuard, PoisonError};
+use std::time::Duration;
+
+use crate::account::transaction_state::{TransactionDetail, TransactionState};
+use crate::payments_engine::engine::PaymentsEngine;
+use crate::payments_engine::outcome::{OutcomeReport, TransactionOutcome};
+use crate::payments_engine::report::{AccountSummary, ReportOrder};
+use crate::server::{
+    DEFAULT_IDLE_TIMEOUT, DEFAULT_MAX_CONNECTIONS, ServerHandle, accept_connections, lock_engine,
+};
+use crate::utils::{read_line_limited, transaction_from_json_value};
+
+/// Header naming a POST so retries of it aren't applied twice.
+const IDEMPOTENCY_KEY_HEADER: &str = "idempotency-key";
+
+/// Number of idempotency keys remembered by default.
+const DEFAULT_IDEMPOTENCY_CAPACITY: usize = 10_000;
+
+/// Largest request body accepted.
+const MAX_BODY_BYTES: usize = 16 * 1024 * 1024;
+
+/// Largest number of header lines accepted in one request.
+const MAX_HEADERS: usize = 100;
+
+/// Longest request line or header line accepted, in bytes.
+const MAX_LINE_BYTES: usize = 8 * 1024;
+
+/// Serves the HTTP/JSON API for a shared engine.
+///
+/// Connections are served on their own threads and may send any number of
+/// requests. Each request, including every transaction of a batch, is
+/// applied under a lock on the engine.
+#[derive(Debug)]
+pub struct ApiServer {
+    listener: TcpListener,
+    engine: Arc<Mutex<PaymentsEngine>>,
+    idempotency_capacity: usize,
+    max_connections: usize,
+    idle_timeout: Duration,
+    shutdown: Arc<AtomicBool>,
+}
+
+impl ApiServer {
+    /// Binds the server to `addr`, serving `engine`. Bind to port 0 to have
+    /// the OS pick a free port, and read it back with `local_addr`.
+    pub fn bind<A: ToSocketAddrs>(addr: A, engine: PaymentsEngine) -> io::Result<Self> {
+        Self::bind_shared(addr, Arc::new(Mutex::new(engine)))
+    }
+
+    /// Binds the server to `addr`, serving an engine that other servers may
+    /// be sharing.
+    pub fn bind_shared<A: ToSocketAddrs>(
+        addr: A,
+        engine: Arc<Mutex<PaymentsEngine>>,
+    ) -> io::Result<Self> {
+        Ok(ApiServer {
+            listener: TcpListener::bind(addr)?,
+            engine,
+            idempotency_capacity: DEFAULT_IDEMPOTENCY_CAPACITY,
+            max_connections: DEFAULT_MAX_CONNECTIONS,
+            idle_timeout: DEFAULT_IDLE_TIMEOUT,
+            shutdown: Arc::new(AtomicBool::new(false)),
+        })
+    }
+
+    /// Sets how many idempotency keys are remembered. Once full, the oldest
+    /// key is forgotten to make room for a new one.
+    pub fn with_idempotency_capacity(mut self, capacity: usize) -> Self {
+        self.idempotency_capacity = capacity;
+        self
+    }
+
+    /// Sets how many connections are served at once. Connections accepted
+    /// while that many are open are closed straight away.
+    pub fn with_max_connections(mut self, max_connections: usize) -> Self {
+        self.max_connections = max_connections;
+        self
+    }
+
+    /// Sets how long a connection may wait on a read or a write before it's
+    /// closed, freeing its slot. Must not be zero.
+    pub fn with_idle_timeout(mut self, idle_timeout: Duration) -> Self {
+        self.idle_timeout = idle_timeout;
+        self
+    }
+
+    /// Returns the address the server is listening on.
+    pub fn local_addr(&self) -> io::Result<SocketAddr> {
+        self.listener.local_addr()
+    }
+
+    /// Returns a handle to the engine being served.
+    pub fn engine(&self) -> Arc<Mutex<PaymentsEngine>> {
+        Arc::clone(&self.engine)
+    }
+
+    /// Accepts connections until the server is shut down, serving each one on
+    /// its own thread.
+    pub fn serve(self) -> io::Result<()> {
+        let api = Arc::new(Api {
+            engine: self.engine,
+            idempotency: Mutex::new(IdempotencyCache::new(self.idempotency_capacity)),
+            settled: Condvar::new(),
+            idle_timeout: self.idle_timeout,
+        });
+        let max_connections = self.max_connections;
+        accept_connections(
+            &self.listener,
+            &self.shutdown,
+            max_connections,
+            move |stream| {
+                let peer = stream.peer_addr().ok();
+                match api.serve_connection(&stream) {
+                    Ok(requests) => {
+                        info!("Connection {:?} closed after {} request(s)", peer, requests)
+                    }
+                    Err(e) => warn!("Connection {:?} failed: {}", peer, e),
+                }
+            },
+        );
+        Ok(())
+    }
+
+    /// Serves connections on a background thread, returning a handle that
+    /// reaches the engine and stops the server.
+    pub fn spawn(self) -> io::Result<ServerHandle> {
+        let addr = self.local_addr()?;
+        let engine = self.engine();
+        let shutdown = Arc::clone(&self.shutdown);
+        Ok(ServerHandle::new(addr, engine, shutdown, move || {
+            self.serve()
+        }))
+    }
+}
+
+/// One parsed HTTP request.
+struct Request {
+    method: String,
+    path: String,
+    query: Option<String>,
+    /// Header names lowercased, with their values.
+    headers: HashMap<String, String>,
+    body: Vec<u8>,
+    /// Whether the client asked for the connection to close after this
+    /// request.
+    close: bool,
+}
+
+impl Request {
+    /// Returns the value of a header, by its lowercase name.
+    fn header(&self, name: &str) -> Option<&str> {
+        self.headers.get(name).map(String::as_str)
+    }
+
+    /// Returns the value of a query parameter.
+    fn query_param(&self, name: &str) -> Option<&str> {
+        self.query
+            .as_deref()?
+            .split('&')
+            .filter_map(|pair| pair.split_once('='))
+            .find(|(key, _)| *key == name)
+            .map(|(_, value)| value)
+    }
+}
+
+/// One HTTP response, always with a JSON body.
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct Response {
+    status: u16,
+    body: String,
+    /// Whether this is a stored response replayed for an idempotency key.
+    replayed: bool,
+}
+
+impl Response {
+    /// A response carrying `body` serialized as JSON.
+    fn json<T: Serialize + ?Sized>(status: u16, body: &T) -> Self {
+        match serde_json::to_string(body) {
+            Ok(body) => Response {
+                status,
+                body,
+                replayed: false,
+            },
+            Err(e) => Self::error(500, &e.to_string()),
+        }
+    }
+
+    /// A response carrying `{"error": message}`.
+    fn error(status: u16, message: &str) -> Self {
+        Response {
+            status,
+            body: serde_json::json!({ "error": message }).to_string(),
+            replayed: false,
+        }
+    }
+
+    /// Writes the response, with `Connection: close` if `close` is set.
+    fn write_to<W: Write>(&self, writer: &mut W, close: bool) -> io::Result<()> {
+        write!(
+            writer,
+            "HTTP/1.1 {} {}\r\nContent-Type: application/json\r\nContent-Length: {}\r\n",
+            self.status,
+            reason_phrase(self.status),
+            self.body.len()
+        )?;
+        if self.replayed {
+            write!(writer, "Idempotent-Replayed: true\r\n")?;
+        }
+        if close {
+            write!(writer, "Connection: close\r\n")?;
+        }
+        write!(writer, "\r\n{}", self.body)?;
+        writer.flush()
+    }
+}
+
+/// Returns the reason phrase for the status codes the API answers with.
+fn reason_phrase(status: u16) -> &'static str {
+    match status {
+        200 => "OK",
+        400 => "Bad Request",
+        404 => "Not Found",
+        405 => "Method Not Allowed",
+        422 => "Unprocessable Entity",
+        _ => "Internal Server Error",
+    }
+}
+
+/// SHA-256 of a request body, so a retry can be told apart from a different
+/// request reusing its idempotency key without keeping the body.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct BodyDigest([u8; 32]);
+
+impl BodyDigest {
+    fn of(body: &[u8]) -> Self {
+        BodyDigest(Sha256::digest(body).into())
+    }
+}
+
+/// Responses to POSTs that carried an idempotency key, oldest key first,
+/// and the keys of those still being applied.
+#[derive(Debug)]
+struct IdempotencyCache {
+    capacity: usize,
+    responses: HashMap<String, (BodyDigest, Response)>,
+    order: VecDeque<String>,
+    in_flight: HashMap<String, BodyDigest>,
+}
+
+impl IdempotencyCache {
+    fn new(capacity: usize) -> Self {
+        IdempotencyCache {
+            capacity,
+            responses: HashMap::new(),
+            order: VecDeque::new(),
+            in_flight: HashMap::new(),
+        }
+    }
+
+    /// Remembers the response to the request with `key` and a body with
+    /// digest `body`, forgetting the oldest key if the cache is full.
+    fn insert(&mut self, key: String, body: BodyDigest, response: Response) {
+        if self.capacity == 0 {
+            return;
+        }
+        while self.order.len() >= self.capacity {
+            if let Some(oldest) = self.order.pop_front() {
+                self.responses.remove(&oldest);
+            }
+        }
+        self.order.push_back(key.clone());
+        self.responses.insert(key, (body, response));
+    }
+}
+
+/// Marks a POST's idempotency key as being applied for as long as it's
+/// held, including when the thread applying it panics, and wakes any retries
+/// waiting on it once it's dropped.
+struct InFlight<'a> {
+    api: &'a Api,
+    key: &'a str,
+}
+
+impl InFlight<'_> {
+    /// Remembers the response to the request, if it's one a retry should get
+    /// back. A server error isn't, so a retry is applied afresh.
+    fn settle(self, body: BodyDigest, response: &Response) {
+        if response.status < 500 {
+            self.api
+                .idempotency()
+                .insert(self.key.to_string(), body, response.clone());
+        }
+    }
+}
+
+impl Drop for InFlight<'_> {
+    fn drop(&mut self) {
+        self.api.idempotency().in_flight.remove(self.key);
+        self.api.settled.notify_all();
+    }
+}
+
+/// State shared by every connection of an `ApiServer`.
+struct Api {
+    engine: Arc<Mutex<PaymentsEngine>>,
+    idempotency: Mutex<IdempotencyCache>,
+    /// Signalled whenever a key stops being in flight.
+    settled: Condvar,
+    idle_timeout: Duration,
+}
+
+impl Api {
+    /// Answers requests on `stream` until the client closes it, asks for it
+    /// to be closed or goes idle. Returns how many requests were answered.
+    fn serve_connection(&self, stream: &TcpStream) -> io::Result<u64> {
+        stream.set_read_timeout(Some(self.idle_timeout))?;
+        stream.set_write_timeout(Some(self.idle_timeout))?;
+        let mut reader = BufReader::new(stream);
+        let mut writer = BufWriter::new(stream);
+        let mut requests = 0;
+        loop {
+            let request = match read_request(&mut reader) {
+                Ok(Some(request)) => request,
+                Ok(None) => break,
+                Err(e)
+                    if matches!(
+                        e.kind(),
+                        io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut
+                    ) =>
+                {
+                    info!(
+                        "Closing connection {:?}: idle for {:?}",
+                        stream.peer_addr().ok(),
+                        self.idle_timeout
+                    );
+                    break;
+                }
+                Err(e) if e.kind() == io::ErrorKind::InvalidData => {
+                    Response::error(400, &e.to_string()).write_to(&mut writer, true)?;
+                    break;
+                }
+                Err(e) => return Err(e),
+            };
+            self.respond(&request)
+                .write_to(&mut writer, request.close)?;
+            requests += 1;
+            if request.close {
+                break;
+            }
+        }
+        Ok(requests)
+    }
+
+    /// Routes a request to its endpoint.
+    fn respond(&self, request: &Request) -> Response {
+        let segments: Vec<&str> = request
+            .path
+            .split('/')
+            .filter(|segment| !segment.is_empty())
+            .collect();
+        let method = request.method.as_str();
+        match (method, segments.as_slice()) {
+            ("GET", ["health"]) => Response::json(200, &serde_json::json!({ "status": "ok" })),
+            ("GET", ["report"]) => self.report(request),
+            ("POST", ["transactions"]) => self.submit(request),
+            ("GET", ["accounts", client]) => self.account(client),
+            ("GET", ["accounts", client, "transactions"]) => self.archived(client, |_| true),
+            ("GET", ["accounts", client, "disputes"]) => self.archived(client, |detail| {
+                detail.state() == TransactionState::Disputed
+            }),
+            (_, ["health"] | ["report"] | ["transactions"])
+            | (_, ["accounts", _] | ["accounts", _, "transactions" | "disputes"]) => {
+                Response::error(405, &format!("{} is not allowed here", method))
+            }
+            _ => Response::error(404, &format!("No endpoint at {}", request.path)),
+        }
+    }
+
+    /// Locks the idempotency cache. It's only ever changed a whole entry at
+    /// a time, so it's still usable after a panic elsewhere.
+    fn idempotency(&self) -> MutexGuard<'_, IdempotencyCache> {
+        self.idempotency
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+    }
+
+    /// `POST /transactions`, applied at most once per idempotency key.
+    fn submit(&self, request: &Request) -> Response {
+        let Some(key) = request.header(IDEMPOTENCY_KEY_HEADER) else {
+            return self.apply_body(&request.body);
+        };
+        let digest = BodyDigest::of(&request.body);
+        let reused = || {
+            Response::error(
+                422,
+                &format!("Idempotency key {} was used for a different request", key),
+            )
+        };
+        // Only the key is marked while the body is applied, so requests with
+        // other keys aren't held up, and a concurrent retry waits for the
+        // first one's response instead of being applied too.
+        let mut cache = self.idempotency();
+        loop {
+            if let Some((body, response)) = cache.responses.get(key) {
+                if *body != digest {
+                    return reused();
+                }
+                return Response {
+                    replayed: true,
+                    ..response.clone()
+                };
+            }
+            match cache.in_flight.get(key) {
+                Some(body) if *body != digest => return reused(),
+                Some(_) => {
+                    cache = self
+                        .settled
+                        .wait(cache)
+                        .unwrap_or_else(PoisonError::into_inner)
+                }
+                None => break,
+            }
+        }
+        cache.in_flight.insert(key.to_string(), digest);
+        drop(cache);
+
+        let in_flight = InFlight { api: self, key };
+        let response = self.apply_body(&request.body);
+        in_flight.settle(digest, &response);
+        response
+    }
+
+    /// Applies the transaction or batch of transactions in a POST body.
+    fn apply_body(&self, body: &[u8]) -> Response {
+        let value: serde_json::Value = match serde_json::from_slice(body) {
+            Ok(value) => value,
+            Err(e) => return Response::error(400, &format!("Invalid JSON body: {}", e)),
+        };
+        let mut engine = match lock_engine(&self.engine) {
+            Ok(engine) => engine,
+            Err(e) => return Response::error(500, &e.to_string()),
+        };
+        let mut apply = |value| {
+            let outcome = match transaction_from_json_value(value) {
+                Ok(tx) => engine.process_transaction(tx),
+                Err(e) => TransactionOutcome::from_error(e),
+            };
+            OutcomeReport::new(&outcome)
+        };
+        match value {
+            serde_json::Value::Array(values) => {
+                let outcomes: Vec<OutcomeReport> = values.into_iter().map(apply).collect();
+                Response::json(200, &outcomes)
+            }
+            value => {
+                let outcome = apply(value);
+                let status = if outcome.is_applied() { 200 } else { 422 };
+                Response::json(status, &outcome)
+            }
+        }
+    }
+
+    /// `GET /accounts/{client}`.
+    fn account(&self, client: &str) -> Response {
+        let Ok(client) = client.parse::<u16>() else {
+            return Response::error(400, &format!("Invalid client ID {}", client));
+        };
+        let engine = match lock_engine(&self.engine) {
+            Ok(engine) => engine,
+            Err(e) => return Response::error(500, &e.to_string()),
+        };
+        match engine.account_details(client) {
+            Some(details) => Response::json(200, &AccountSummary::from_details(client, details)),
+            None => Response::error(404, &format!("Client {} does not exist", client)),
+        }
+    }
+
+    /// `GET /accounts/{client}/transactions` and `/disputes`: the archived
+    /// transactions of a client that pass `filter`, ordered by ID.
+    fn archived(&self, client: &str, filter: impl Fn(&TransactionDetail) -> bool) -> Response {
+        let Ok(client) = client.parse::<u16>() else {
+            return Response::error(400, &format!("Invalid client ID {}", client));
+        };
+        let engine = match lock_engine(&self.engine) {
+            Ok(engine) => engine,
+            Err(e) => return Response::error(500, &e.to_string()),
+        };
+        match engine.account(client) {
+            Ok(Some(account)) => {
+                let mut details: Vec<&TransactionDetail> = account
+                    .transaction_archive()
+                    .transactions()
+                    .map(|(_, detail)| detail)
+                    .filter(|detail| filter(detail))
+                    .collect();
+                details.sort_by_key(|detail| detail.tx());
+                Response::json(200, &details)
+            }
+            Ok(None) => Response::error(404, &format!("Client {} does not exist", client)),
+            Err(e) => Response::error(500, &e.to_string()),
+        }
+    }
+
+    /// `GET /report`.
+    fn report(&self, request: &Request) -> Response {
+        let order = match request.query_param("order").map(str::parse::<ReportOrder>) {
+            None => ReportOrder::ClientId,
+            Some(Ok(order)) => order,
+            Some(Err(_)) => return Response::error(400, "Invalid report order"),
+        };
+        match lock_engine(&self.engine) {
+            Ok(engine) => Response::json(200, &engine.sorted_account_summaries(order)),
+            Err(e) => Response::error(500, &e.to_string()),
+        }
+    }
+}
+
+/// Reads one request from `reader`. Returns `None` if the connection closed
+/// before a new request started, and an `InvalidData` error for a request
+/// that can't be parsed or is over the size limits. The body is only
+/// allocated as its bytes arrive.
+fn read_request<R: BufRead>(reader: &mut R) -> io::Result<Option<Request>> {
+    let invalid = |message: &str| io::Error::new(io::ErrorKind::InvalidData, message.to_string());
+
+    let mut line = String::new();
+    // Tolerate blank lines between requests.
+    while line.trim().is_empty() {
+        line.clear();
+        if read_line_limited(reader, &mut line, MAX_LINE_BYTES)? == 0 {
+            return Ok(None);
+        }
+    }
+    let mut parts = line.split_whitespace();
+    let (Some(method), Some(target), Some(version)) = (parts.next(), parts.next(), parts.next())
+    else {
+        return Err(invalid("Malformed request line"));
+    };
+    let (path, query) = match target.split_once('?') {
+        Some((path, query)) => (path.to_string(), Some(query.to_string())),
+        None => (target.to_string(), None),
+    };
+    let method = method.to_ascii_uppercase();
+    let http_1_0 = version.eq_ignore_ascii_case("HTTP/1.0");
+
+    let mut headers = HashMap::new();
+    loop {
+        line.clear();
+        if read_line_limited(reader, &mut line, MAX_LINE_BYTES)? == 0 {
+            return Err(invalid("Connection closed in the middle of the headers"));
+        }
+        let header = line.trim_end();
+        if header.is_empty() {
+            break;
+        }
+        if headers.len() >= MAX_HEADERS {
+            return Err(invalid("Too many headers"));
+        }
+        let (name, value) = header
+            .split_once(':')
+            .ok_or_else(|| invalid("Malformed header"))?;
+        headers.insert(name.trim().to_ascii_lowercase(), value.trim().to_string());
+    }
+
+    if headers.contains_key("transfer-encoding") {
+        return Err(invalid("Chunked request bodies are not supported"));
+    }
+    let length = match headers.get("content-length") {
+        Some(length) => length
+            .parse::<usize>()
+            .map_err(|_| invalid("Malformed Content-Length"))?,
+        None => 0,
+    };
+    if length > MAX_BODY_BYTES {
+        return Err(invalid("Request body is too large"));
+    }
+    let mut body = Vec::new();
+    reader.take(length as u64).read_to_end(&mut body)?;
+    if body.len() < length {
+        return Err(io::ErrorKind::UnexpectedEof.into());
+    }
+
+    let close = match headers.get("connection") {
+        Some(connection) => connection.eq_ignore_ascii_case("close"),
+        None => http_1_0,
+    };
+    Ok(Some(Request {
+        method,
+        path,
+        query,
+        headers,
+        body,
+        close,
+    }))
+}
+
+#[cfg(test)]
+mod http_tests {
+    use super::*;
+    use std::thread;
+
+    /// Sends one request on a new connection and returns the status, the
+    /// headers and the body of the response.
+    fn send(
+        addr: SocketAddr,
+        method: &str,
+        path: &str,
+        headers: &[(&str, &str)],
+        body: &str,
+    ) -> (u16, String, serde_json::Value) {
+        let mut stream = TcpStream::connect(addr).unwrap();
+        let mut request = format!(
+            "{} {} HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\nContent-Length: {}\r\n",
+            method,
+            path,
+            body.len()
+        );
+        for (name, value) in headers {
+            request.push_str(&format!("{}: {}\r\n", name, value));
+        }
+        request.push_str("\r\n");
+        request.push_str(body);
+        stream.write_all(request.as_bytes()).unwrap();
+
+        let mut response = String::new();
+        stream.read_to_string(&mut response).unwrap();
+        let (head, body) = response.split_once("\r\n\r\n").unwrap();
+        let status = head[9..12].parse().unwrap();
+        (
+            status,
+            head.to_string(),
+            serde_json::from_str(body).unwrap(),
+        )
+    }
+
+    fn spawn_server() -> ServerHandle {
+        ApiServer::bind("127.0.0.1:0", PaymentsEngine::new())
+            .unwrap()
+            .spawn()
+            .unwrap()
+    }
+
+    /// Transactions posted one at a time or in a batch are applied in order,
+    /// and accounts, their archives, open disputes and the report can be read
+    /// back.
+    #[test]
+    fn test_submit_and_query() {
+        let server = spawn_server();
+        let addr = server.local_addr();
+
+        let (status, _, body) = send(addr, "GET", "/health", &[], "");
+        assert_eq!((status, body), (200, serde_json::json!({ "status": "ok" })));
+
+        let deposit = r#"{"type":"deposit","client":1,"tx":1,"amount":"2.5"}"#;
+        let (status, _, body) = send(addr, "POST", "/transactions", &[], deposit);
+        assert_eq!(
+            (status, body),
+            (
+                200,
+                serde_json::json!({
+                    "outcome": "Applied",
+                    "before": {
+                        "client": 1,
+                        "available": "0.0000",
+                        "held": "0.0000",
+                        "total": "0.0000",
+                        "locked": false
+                    },
+                    "after": {
+                        "client": 1,
+                        "available": "2.5000",
+                        "held": "0.0000",
+                        "total": "2.5000",
+                        "locked": false
+                    }
+                })
+            )
+        );
+
+        let overdraft = r#"{"type":"withdrawal","client":1,"tx":2,"amount":10}"#;
+        let (status, _, body) = send(addr, "POST", "/transactions", &[], overdraft);
+        assert_eq!(status, 422);
+        assert_eq!(body["outcome"], "Rejected");
+        assert_eq!(body["error"], "NotEnoughAvailableFunds");
+        assert!(body.get("after").is_none());
+
+        let batch = r#"[
+            {"type":"deposit","client":1,"tx":3,"amount":1},
+            {"type":"dispute","client":1,"tx":1},
+            {"type":"resolve","client":1,"tx":3},
+            {"type":"deposit","client":2}
+        ]"#;
+        let (status, _, body) = send(addr, "POST", "/transactions", &[], batch);
+        assert_eq!(status, 200);
+        assert_eq!(body[0]["outcome"], "Applied");
+        assert_eq!(body[1]["outcome"], "Applied");
+        assert_eq!(body[1]["after"]["held"], "2.5000");
+        assert_eq!(
+            (&body[2]["outcome"], &body[2]["error"]),
+            (&"Ignored".into(), &"IllegalStateTransition".into())
+        );
+        assert_eq!(
+            (&body[3]["outcome"], &body[3]["error"]),
+            (&"Rejected".into(), &"MalformedRow".into())
+        );
+
+        let (status, _, body) = send(addr, "GET", "/accounts/1", &[], "");
+        assert_eq!(status, 200);
+        assert_eq!(
+            body,
+            serde_json::json!({
+                "client": 1,
+                "available": "1.0000",
+                "held": "2.5000",
+                "total": "3.5000",
+                "locked": false
+            })
+        );
+
+        let (_, _, body) = send(addr, "GET", "/accounts/1/transactions", &[], "");
+        let txs: Vec<&serde_json::Value> = body
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|detail| &detail["tx"])
+            .collect();
+        assert_eq!(txs, [1, 3]);
+        let (_, _, body) = send(addr, "GET", "/accounts/1/disputes", &[], "");
+        assert_eq!(body.as_array().unwrap().len(), 1);
+        assert_eq!(body[0]["tx"], 1);
+        assert_eq!(body[0]["state"], "Disputed");
+
+        let (status, _, body) = send(addr, "GET", "/report?order=total", &[], "");
+        assert_eq!(status, 200);
+        assert_eq!(body.as_array().unwrap().len(), 1);
+        assert_eq!(body[0]["total"], "3.5000");
+        server.shutdown().unwrap();
+    }
+
+    /// A retried POST with the same idempotency key gets the first response
+    /// back without being applied again, and a reused key with a different
+    /// body is refused.
+    #[test]
+    fn test_idempotency_key() {
+        let server = spawn_server();
+        let addr = server.local_addr();
+        let key = [("Idempotency-Key", "abc-123")];
+        let deposit = r#"{"type":"deposit","client":1,"tx":1,"amount":"2.5"}"#;
+
+        let (status, head, body) = send(addr, "POST", "/transactions", &key, deposit);
+        assert_eq!((status, body["outcome"].as_str()), (200, Some("Applied")));
+        assert!(!head.contains("Idempotent-Replayed"));
+        let (status, head, body) = send(addr, "POST", "/transactions", &key, deposit);
+        assert_eq!((status, body["outcome"].as_str()), (200, Some("Applied")));
+        assert!(head.contains("Idempotent-Replayed: true"));
+
+        let other = r#"{"type":"deposit","client":1,"tx":2,"amount":"2.5"}"#;
+        let (status, _, _) = send(addr, "POST", "/transactions", &key, other);
+        assert_eq!(status, 422);
+
+        // Without a key the same deposit is a duplicate transaction ID.
+        let (status, _, body) = send(addr, "POST", "/transactions", &[], deposit);
+        assert_eq!(
+            (status, body["outcome"].as_str(), body["error"].as_str()),
+            (422, Some("Ignored"), Some("DuplicateTransactionId"))
+        );
+
+        let (_, _, body) = send(addr, "GET", "/accounts/1", &[], "");
+        assert_eq!(body["total"], "2.5000");
+        server.shutdown().unwrap();
+    }
+
+    /// A client replaying its own transaction ID is answered as ignored,
+    /// while reusing another client's ID is rejected.
+    #[test]
+    fn test_duplicate_transaction_ids() {
+        let server = spawn_server();
+        let addr = server.local_addr();
+        let deposit = r#"{"type":"deposit","client":1,"tx":1,"amount":"2.5"}"#;
+        assert_eq!(send(addr, "POST", "/transactions", &[], deposit).0, 200);
+
+        let (status, _, body) = send(addr, "POST", "/transactions", &[], deposit);
+        assert_eq!(
+            (status, body["outcome"].as_str(), body["error"].as_str()),
+            (422, Some("Ignored"), Some("DuplicateTransactionId"))
+        );
+        let reuse = r#"{"type":"deposit","client":2,"tx":1,"amount":"2.5"}"#;
+        let (status, _, body) = send(addr, "POST", "/transactions", &[], reuse);
+        assert_eq!(
+            (status, body["outcome"].as_str(), body["error"].as_str()),
+            (
+                422,
+                Some("Rejected"),
+                Some("CrossClientDuplicateTransactionId")
+            )
+        );
+        server.shutdown().unwrap();
+    }
+
+    /// Concurrent retries of one request are applied once, and each of the
+    /// others gets its response back.
+    #[test]
+    fn test_concurrent_idempotent_retries() {
+        let server = spawn_server();
+        let addr = server.local_addr();
+        let deposit = r#"{"type":"deposit","client":1,"tx":1,"amount":"2.5"}"#;
+
+        let retries: Vec<_> = (0..8)
+            .map(|_| {
+                thread::spawn(move || {
+                    let key = [("Idempotency-Key", "abc-123")];
+                    send(addr, "POST", "/transactions", &key, deposit)
+                })
+            })
+            .collect();
+        let mut applied = 0;
+        for retry in retries {
+            let (status, head, body) = retry.join().unwrap();
+            assert_eq!((status, body["outcome"].as_str()), (200, Some("Applied")));
+            if !head.contains("Idempotent-Replayed") {
+                applied += 1;
+            }
+        }
+        assert_eq!(applied, 1);
+
+        let (_, _, body) = send(addr, "GET", "/accounts/1", &[], "");
+        assert_eq!(body["total"], "2.5000");
+        server.shutdown().unwrap();
+    }
+
+    /// A key being applied only holds up requests with the same key, and a
+    /// different body under it is refused straight away.
+    #[test]
+    fn test_in_flight_key() {
+        let api = Api {
+            engine: Arc::new(Mutex::new(PaymentsEngine::new())),
+            idempotency: Mutex::new(IdempotencyCache::new(10)),
+            settled: Condvar::new(),
+            idle_timeout: DEFAULT_IDLE_TIMEOUT,
+        };
+        let post = |key: &str, body: &str| Request {
+            method: "POST".to_string(),
+            path: "/transactions".to_string(),
+            query: None,
+            headers: HashMap::from([(IDEMPOTENCY_KEY_HEADER.to_string(), key.to_string())]),
+            body: body.as_bytes().to_vec(),
+            close: true,
+        };
+        let deposit = r#"{"type":"deposit","client":1,"tx":1,"amount":"2.5"}"#;
+        api.idempotency()
+            .in_flight
+            .insert("a".to_string(), BodyDigest::of(deposit.as_bytes()));
+
+        assert_eq!(api.submit(&post("b", deposit)).status, 200);
+        let other = r#"{"type":"deposit","client":1,"tx":2,"amount":"2.5"}"#;
+        assert_eq!(api.submit(&post("a", other)).status, 422);
+    }
+
+    /// An idle connection is closed once the idle timeout passes, giving its
+    /// slot to the next connection.
+    #[test]
+    fn test_idle_connection_closed() {
+        let server = ApiServer::bind("127.0.0.1:0", PaymentsEngine::new())
+            .unwrap()
+            .with_max_connections(1)
+            .with_idle_timeout(Duration::from_millis(100))
+            .spawn()
+            .unwrap();
+        let addr = server.local_addr();
+
+        let mut idle = TcpStream::connect(addr).unwrap();
+        let mut response = String::new();
+        idle.read_to_string(&mut response).unwrap();
+        assert_eq!(response, "");
+
+        // Retry until the server thread has given the slot back; a refused
+        // attempt may also be reset mid-write.
+        let try_health = || -> io::Result<String> {
+            let mut stream = TcpStream::connect(addr)?;
+            stream.write_all(b"GET /health HTTP/1.1\r\nConnection: close\r\n\r\n")?;
+            let mut response = String::new();
+            stream.read_to_string(&mut response)?;
+            Ok(response)
+        };
+        let mut response = String::new();
+        for _ in 0..100 {
+            response = try_health().unwrap_or_default();
+            if !response.is_empty() {
+                break;
+            }
+            thread::sleep(Duration::from_millis(10));
+        }
+        assert!(response.starts_with("HTTP/1.1 200 OK\r\n"));
+        server.shutdown().unwrap();
+    }
+
+    /// The oldest idempotency key is forgotten once the cache is full.
+    #[test]
+    fn test_idempotency_cache_capacity() {
+        let mut cache = IdempotencyCache::new(2);
+        for key in ["a", "b", "c"] {
+            cache.insert(
+                key.to_string(),
+                BodyDigest::of(b""),
+                Response::error(400, key),
+            );
+        }
+        assert!(!cache.responses.contains_key("a"));
+        assert!(cache.responses.contains_key("b"));
+        assert!(cache.responses.contains_key("c"));
+    }
+
+    /// Several requests can be sent on one connection, and bad requests get
+    /// the matching error status.
+    #[test]
+    fn test_keep_alive_and_errors() {
+        let server = spawn_server();
+        let addr = server.local_addr();
+
+        let mut stream = TcpStream::connect(addr).unwrap();
+        stream
+            .write_all(
+                b"GET /health HTTP/1.1\r\n\r\n\
+                  GET /accounts/7 HTTP/1.1\r\nConnection: close\r\n\r\n",
+            )
+            .unwrap();
+        let mut response = String::new();
+        stream.read_to_string(&mut response).unwrap();
+        assert!(response.starts_with("HTTP/1.1 200 OK\r\n"));
+        assert!(response.contains("HTTP/1.1 404 Not Found\r\n"));
+
+        assert_eq!(send(addr, "GET", "/nowhere", &[], "").0, 404);
+        assert_eq!(send(addr, "DELETE", "/accounts/1", &[], "").0, 405);
+        assert_eq!(send(addr, "GET", "/accounts/x", &[], "").0, 400);
+        assert_eq!(send(addr, "GET", "/report?order=random", &[], "").0, 400);
+        assert_eq!(send(addr, "POST", "/transactions", &[], "{not json").0, 400);
+        server.shutdown().unwrap();
+    }
+
+    /// Over-long request and header lines are refused with `400`, and a body
+    /// shorter than its `Content-Length` is an error rather than padding.
+    #[test]
+    fn test_request_size_limits() {
+        let server = spawn_server();
+        let addr = server.local_addr();
+        let long = "x".repeat(MAX_LINE_BYTES);
+        assert_eq!(send(addr, "GET", &format!("/{}", long), &[], "").0, 400);
+        assert_eq!(
+            send(addr, "GET", "/health", &[("X-Long", &long)], "").0,
+            400
+        );
+        assert_eq!(
+            send(addr, "GET", "/health", &[("X-Short", "ok")], "").0,
+            200
+        );
+        server.shutdown().unwrap();
+
+        let mut truncated =
+            "POST /transactions HTTP/1.1\r\nContent-Length: 1000000\r\n\r\n{}".as_bytes();
+        assert!(matches!(
+            read_request(&mut truncated),
+            Err(e) if e.kind() == io::ErrorKind::UnexpectedEof
+        ));
+    }
+}
@@ -0,0 +1,43 @@
+//! A payments engine that processes deposits, withdrawals, disputes, resolves and
+//! chargebacks per client account and reports the resulting balances.
+//!
+//! The engine is driven through `PaymentsEngine::process_transaction`, one
+//! `Transaction` at a time, which returns a `TransactionOutcome` saying whether
+//! the row was applied, ignored or rejected. Account state is read back
+//! through the accessor methods on `PaymentsEngine` and `ClientAccount`.
+pub mod account;
+pub mod errors;
+pub mod generator;
+pub mod http;
+pub mod money;
+pub mod payments_engine;
+pub mod server;
+pub mod transaction;
+pub mod utils;
+
+pub use account::client_account::{
+    ClientAccount, ClientAccountDetails, ClientTransactionArchive, LockEvent,
+};
+pub use account::store::{AccountStore, DiskAccountStore, InMemoryAccountStore};
+pub use account::transaction_state::{
+    HoldKind, StateTransition, TransactionDetail, TransactionState,
+};
+pub use errors::PaymentsTransactionError;
+pub use http::ApiServer;
+pub use money::Money;
+pub use payments_engine::config::{
+    DisputeWindow, DuplicateTransactionPolicy, EngineConfig, LockPolicy, WithdrawalDisputePolicy,
+};
+pub use payments_engine::engine::PaymentsEngine;
+pub use payments_engine::events::{DomainEvent, EventSubscriber, JsonlEventSink, MemoryEventSink};
+pub use payments_engine::journal::{Journal, JournalSync, Recovery};
+pub use payments_engine::outcome::TransactionOutcome;
+pub use payments_engine::rejections::{Rejection, RejectionReport};
+pub use payments_engine::reorder::ReorderBuffer;
+pub use payments_engine::report::{AccountSummary, ReportOrder};
+pub use payments_engine::stream::{
+    EngineTask, OutcomeStream, ProcessStream, RowOutcome, TransactionSink, process_stream,
+    spawn_engine,
+};
+pub use server::{IngestServer, ServerHandle};
+pub use transaction::{Transaction, TransactionType};
@@ -1,20 +1,27 @@
-mod account;
-mod transaction;
-use transaction::*;
-mod errors;
-mod payments_engine;
-mod utils;
 use anyhow::Error;
-use anyhow::Result;
-use log::debug;
-use std::collections::HashMap;
-use std::env;
+use clap::Parser;
+use std::process::ExitCode;
 
-use crate::payments_engine::engine::PaymentsEngine;
-use crate::utils::MAX_CLI_ARGS;
-use crate::utils::initialize_csv_reader;
+use take_home_assignment::PaymentsTransactionError;
 
-fn main() -> Result<(), Error> {
+use crate::cli::{Cli, Command, ExitStatus};
+
+mod cli;
+mod commands;
+
+/// Maps an error that ended a run to its exit status: problems with the input
+/// itself are told apart from everything else.
+fn error_status(error: &Error) -> ExitStatus {
+    if error.downcast_ref::<PaymentsTransactionError>().is_some()
+        || error.downcast_ref::<csv::Error>().is_some()
+    {
+        ExitStatus::InputError
+    } else {
+        ExitStatus::Failure
+    }
+}
+
+fn main() -> ExitCode {
     /*
         The original file detection logic was provided by ChatGPT
         and later heavily modified to fit the requirements of the
@@ -26,29 +33,38 @@ fn main() -> Result<(), Error> {
         Please reference past commits on this repository's main branch
         to see how this main() logic has evolved.
     */
-    let mut payments_engine: PaymentsEngine = PaymentsEngine {
-        client_account_lookup: HashMap::new(),
+    let cli = match Cli::try_parse() {
+        Ok(cli) => cli,
+        Err(e) => {
+            // `--help` and `--version` are reported through here too.
+            let _ = e.print();
+            let status = if e.use_stderr() {
+                ExitStatus::Usage
+            } else {
+                ExitStatus::Success
+            };
+            return status.into();
+        }
     };
 
-    let args: Vec<String> = env::args().collect();
-    if args.len() != MAX_CLI_ARGS {
-        panic!("Usage: {} <transactions_file.csv>", args[0]);
+    let mut logger = env_logger::Builder::from_default_env();
+    if let Some(level) = cli.log_level {
+        logger.filter_level(level);
     }
+    logger.init();
 
-    // Open and process transactions from the csv file.
-    // The file must exist and be a CSV.
-    let filename = &args[1];
-    for res in initialize_csv_reader(filename)?.deserialize() {
-        /*
-        From the assignment spec:
-        - The client ID will be unique per client though are not guaranteed to be ordered.
-        - Can assume transactions occur chronologically in the file.
-        - Whitespaces and decimal precisions (up to four places past the decimal) must be accepted.
-        */
-        let curr_transaction: Transaction = res?;
-        debug!("{:?}", curr_transaction);
-        payments_engine.process_transaction(curr_transaction)?;
+    let result = match cli.into_command() {
+        Command::Process(args) => commands::process(args),
+        Command::Generate(args) => commands::generate(args),
+        Command::Validate(args) => commands::validate(args),
+        Command::Replay(args) => commands::replay(args),
+        Command::Serve(args) => commands::serve(args),
+    };
+    match result {
+        Ok(status) => status.into(),
+        Err(e) => {
+            eprintln!("Error: {:#}", e);
+            error_status(&e).into()
+        }
     }
-    println!("{}", payments_engine);
-    Ok(())
 }
@@ -0,0 +1,335 @@
+/// This file defines the `Money` type used for every monetary amount in the
+/// payments engine. Amounts are stored as a signed count of ten-thousandths
+/// so that the four decimal places allowed by the spec are represented exactly.
+use serde::de::{self, Visitor};
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+use std::fmt;
+use std::str::FromStr;
+
+use crate::errors::PaymentsTransactionError;
+
+/// Number of decimal places supported by the engine.
+const DECIMAL_PLACES: usize = 4;
+/// Number of ten-thousandths in a single whole unit.
+const SCALE: i64 = 10_000;
+
+/// Fixed-point monetary amount with four decimal places of precision.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct Money(i64);
+
+impl Money {
+    /// A zero amount.
+    pub const ZERO: Money = Money(0);
+
+    /// Builds a `Money` from a raw count of ten-thousandths.
+    pub const fn from_scaled(scaled: i64) -> Self {
+        Money(scaled)
+    }
+
+    /// Returns the raw count of ten-thousandths.
+    pub const fn scaled(self) -> i64 {
+        self.0
+    }
+
+    /// Adds two amounts, failing with `AmountOverflow` instead of wrapping.
+    pub fn checked_add(self, rhs: Money) -> Result<Money, PaymentsTransactionError> {
+        self.0
+            .checked_add(rhs.0)
+            .map(Money)
+            .ok_or_else(|| PaymentsTransactionError::AmountOverflow(format!("{} + {}", self, rhs)))
+    }
+
+    /// Subtracts two amounts, failing with `AmountOverflow` instead of wrapping.
+    pub fn checked_sub(self, rhs: Money) -> Result<Money, PaymentsTransactionError> {
+        self.0
+            .checked_sub(rhs.0)
+            .map(Money)
+            .ok_or_else(|| PaymentsTransactionError::AmountOverflow(format!("{} - {}", self, rhs)))
+    }
+
+    /// Serializes the amount with exactly four decimal places, for use with
+    /// `#[serde(serialize_with = "Money::serialize_fixed")]` on report fields.
+    pub fn serialize_fixed<S>(money: &Money, s: S) -> Result<S::Ok, S::Error>
+    where
+        S: Serializer,
+    {
+        s.collect_str(money)
+    }
+
+    /// Deserializes an optional amount given either as a decimal string or as
+    /// a JSON number, for use with `#[serde(deserialize_with = ...)]` on JSON
+    /// input. Numbers are read back as the shortest decimal that round-trips,
+    /// so the same four-place limit applies as to strings. As with CSV input,
+    /// the sign is left to the account to check.
+    pub fn deserialize_json_amount<'de, D>(deserializer: D) -> Result<Option<Money>, D::Error>
+    where
+        D: Deserializer<'de>,
+    {
+        struct StringOrNumber(Money);
+
+        impl<'de> Deserialize<'de> for StringOrNumber {
+            fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
+            where
+                D: Deserializer<'de>,
+            {
+                deserializer
+                    .deserialize_any(MoneyVisitor)
+                    .map(StringOrNumber)
+            }
+        }
+
+        Ok(Option::<StringOrNumber>::deserialize(deserializer)?.map(|amount| amount.0))
+    }
+
+    /// Formats the amount with trailing fractional zeros removed,
+    /// e.g. `1.5000` becomes `1.5` and `100.0000` becomes `100`.
+    pub fn to_trimmed_string(self) -> String {
+        let formatted = self.to_string();
+        formatted
+            .trim_end_matches('0')
+            .trim_end_matches('.')
+            .to_string()
+    }
+}
+
+/// Always shows four decimal places, matching the spec's output precision.
+impl fmt::Display for Money {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let sign = if self.0 < 0 { "-" } else { "" };
+        let abs = self.0.unsigned_abs();
+        let scale = SCALE as u64;
+        write!(
+            f,
+            "{}{}.{:0width$}",
+            sign,
+            abs / scale,
+            abs % scale,
+            width = DECIMAL_PLACES
+        )
+    }
+}
+
+/// Parses a decimal string with at most four places past the decimal point.
+/// Amounts with more precision are rejected rather than silently rounded.
+impl FromStr for Money {
+    type Err = PaymentsTransactionError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let invalid = || PaymentsTransactionError::InvalidAmount(s.to_string());
+        let overflow = || PaymentsTransactionError::AmountOverflow(s.to_string());
+
+        let trimmed = s.trim();
+        let (is_negative, unsigned) = match trimmed.strip_prefix('-') {
+            Some(rest) => (true, rest),
+            None => (false, trimmed.strip_prefix('+').unwrap_or(trimmed)),
+        };
+        let (whole, fraction) = unsigned.split_once('.').unwrap_or((unsigned, ""));
+
+        let is_digits = |part: &str| part.bytes().all(|b| b.is_ascii_digit());
+        if (whole.is_empty() && fraction.is_empty())
+            || !is_digits(whole)
+            || !is_digits(fraction)
+            || fraction.len() > DECIMAL_PLACES
+        {
+            return Err(invalid());
+        }
+
+        let whole_units: i64 = if whole.is_empty() {
+            0
+        } else {
+            whole.parse().map_err(|_| overflow())?
+        };
+        // Right-pad the fraction so "5" means 5000 ten-thousandths.
+        let fraction_units: i64 = format!("{:0<width$}", fraction, width = DECIMAL_PLACES)
+            .parse()
+            .map_err(|_| invalid())?;
+
+        let scaled = whole_units
+            .checked_mul(SCALE)
+            .and_then(|units| units.checked_add(fraction_units))
+            .ok_or_else(overflow)?;
+        Ok(Money(if is_negative { -scaled } else { scaled }))
+    }
+}
+
+/// Serializes with trailing zeros trimmed, as the spec allows up to four places.
+impl Serialize for Money {
+    fn serialize<S>(&self, s: S) -> Result<S::Ok, S::Error>
+    where
+        S: Serializer,
+    {
+        s.serialize_str(&self.to_trimmed_string())
+    }
+}
+
+impl<'de> Deserialize<'de> for Money {
+    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
+    where
+        D: Deserializer<'de>,
+    {
+        deserializer.deserialize_str(MoneyVisitor)
+    }
+}
+
+struct MoneyVisitor;
+
+impl Visitor<'_> for MoneyVisitor {
+    type Value = Money;
+
+    fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "a decimal amount with at most four decimal places")
+    }
+
+    fn visit_str<E>(self, v: &str) -> Result<Self::Value, E>
+    where
+        E: de::Error,
+    {
+        v.parse().map_err(E::custom)
+    }
+
+    fn visit_u64<E>(self, v: u64) -> Result<Self::Value, E>
+    where
+        E: de::Error,
+    {
+        self.visit_str(&v.to_string())
+    }
+
+    fn visit_i64<E>(self, v: i64) -> Result<Self::Value, E>
+    where
+        E: de::Error,
+    {
+        self.visit_str(&v.to_string())
+    }
+
+    fn visit_f64<E>(self, v: f64) -> Result<Self::Value, E>
+    where
+        E: de::Error,
+    {
+        // `Display` for `f64` never uses exponent notation and prints the
+        // shortest digits that round-trip, so 2.7182 stays "2.7182".
+        self.visit_str(&v.to_string())
+    }
+}
+
+#[cfg(test)]
+mod money_tests {
+    use super::*;
+
+    fn money(amount: &str) -> Money {
+        amount.parse().unwrap()
+    }
+
+    #[test]
+    fn test_parse_exact_four_decimals() {
+        assert_eq!(money("2.7182").scaled(), 27_182);
+    }
+
+    #[test]
+    fn test_parse_fewer_than_four_decimals() {
+        assert_eq!(money("1.5").scaled(), 15_000);
+        assert_eq!(money("100").scaled(), 1_000_000);
+        assert_eq!(money(".5").scaled(), 5_000);
+    }
+
+    #[test]
+    fn test_parse_rejects_more_than_four_decimals() {
+        assert!(matches!(
+            "3.14159".parse::<Money>(),
+            Err(PaymentsTransactionError::InvalidAmount(_))
+        ));
+    }
+
+    #[test]
+    fn test_parse_rejects_garbage() {
+        for input in ["", ".", "abc", "1.2.3", "1e5", "--1"] {
+            assert!(
+                input.parse::<Money>().is_err(),
+                "{input:?} should not parse"
+            );
+        }
+    }
+
+    #[test]
+    fn test_parse_overflow() {
+        assert!(matches!(
+            "99999999999999999999".parse::<Money>(),
+            Err(PaymentsTransactionError::AmountOverflow(_))
+        ));
+    }
+
+    /// Repeated small deposits should not drift the way `f64` sums do.
+    #[test]
+    fn test_repeated_addition_is_exact() {
+        let mut total = Money::ZERO;
+        for _ in 0..10 {
+            total = total.checked_add(money("0.1")).unwrap();
+        }
+        assert_eq!(total, money("1"));
+    }
+
+    #[test]
+    fn test_checked_add_overflow() {
+        let max = Money::from_scaled(i64::MAX);
+        assert!(matches!(
+            max.checked_add(money("0.0001")),
+            Err(PaymentsTransactionError::AmountOverflow(_))
+        ));
+    }
+
+    #[test]
+    fn test_display_four_decimals() {
+        assert_eq!(money("10.1234").to_string(), "10.1234");
+        assert_eq!(money("5.5").to_string(), "5.5000");
+        assert_eq!(Money::ZERO.to_string(), "0.0000");
+    }
+
+    #[test]
+    fn test_serialize_trims_trailing_zeros() {
+        let cases = [
+            ("2.7182", r#""2.7182""#),
+            ("1.5", r#""1.5""#),
+            ("100", r#""100""#),
+            ("0.0001", r#""0.0001""#),
+            ("0", r#""0""#),
+            ("2.5000", r#""2.5""#),
+        ];
+        for (input, expected) in cases {
+            assert_eq!(serde_json::to_string(&money(input)).unwrap(), expected);
+        }
+    }
+
+    #[test]
+    fn test_deserialize_from_csv_field() {
+        let mut reader = csv::ReaderBuilder::new()
+            .has_headers(false)
+            .from_reader("1.2345\n".as_bytes());
+        let row: (Money,) = reader.deserialize().next().unwrap().unwrap();
+        assert_eq!(row.0, money("1.2345"));
+    }
+
+    /// JSON amounts may be strings or numbers, and numbers are held to the
+    /// same four decimal places as strings. Zero and negative amounts parse.
+    #[test]
+    fn test_deserialize_json_amount() {
+        #[derive(Deserialize)]
+        struct Row {
+            #[serde(default, deserialize_with = "Money::deserialize_json_amount")]
+            amount: Option<Money>,
+        }
+        let amount = |json: &str| serde_json::from_str::<Row>(json).map(|row| row.amount);
+
+        assert_eq!(
+            amount(r#"{"amount":2.7182}"#).unwrap(),
+            Some(money("2.7182"))
+        );
+        assert_eq!(amount(r#"{"amount":"1.5"}"#).unwrap(), Some(money("1.5")));
+        assert_eq!(amount(r#"{"amount":100}"#).unwrap(), Some(money("100")));
+        assert_eq!(amount(r#"{"amount":null}"#).unwrap(), None);
+        assert_eq!(amount("{}").unwrap(), None);
+        assert!(amount(r#"{"amount":3.14159}"#).is_err());
+        assert_eq!(amount(r#"{"amount":-0.5}"#).unwrap(), Some(money("-0.5")));
+        assert_eq!(amount(r#"{"amount":"-0.5"}"#).unwrap(), Some(money("-0.5")));
+        assert_eq!(amount(r#"{"amount":0}"#).unwrap(), Some(Money::ZERO));
+        assert!(amount(r#"{"amount":true}"#).is_err());
+    }
+}
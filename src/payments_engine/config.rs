@@ -0,0 +1,203 @@
+/// This file defines the configuration knobs for the payments engine and
+/// the policies they select between.
+use std::str::FromStr;
+
+use crate::account::transaction_state::TransactionDetail;
+use crate::transaction::TransactionType;
+
+/// How the engine treats a deposit or withdrawal whose transaction ID has
+/// already been used by a different client. Our partners guarantee globally
+/// unique transaction IDs, so a cross-client reuse is treated as a fraud signal.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum DuplicateTransactionPolicy {
+    /// Reject the transaction with `CrossClientDuplicateTransactionId`.
+    #[default]
+    Reject,
+    /// Log a warning and apply the transaction to the new client anyway.
+    Warn,
+    /// Silently apply the transaction to the new client.
+    Allow,
+}
+
+/// Which transaction types are refused on an account locked by a chargeback.
+/// By default deposits and withdrawals are refused, while disputes, resolves
+/// and chargebacks still go through so open disputes can be settled.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct LockPolicy {
+    /// Transaction types rejected with `AccountLocked` while the account is locked.
+    pub blocked: Vec<TransactionType>,
+}
+
+impl Default for LockPolicy {
+    fn default() -> Self {
+        LockPolicy {
+            blocked: vec![TransactionType::Deposit, TransactionType::Withdrawal],
+        }
+    }
+}
+
+impl LockPolicy {
+    /// A policy that refuses every transaction type on a locked account.
+    pub fn block_all() -> Self {
+        LockPolicy {
+            blocked: vec![
+                TransactionType::Deposit,
+                TransactionType::Withdrawal,
+                TransactionType::Dispute,
+                TransactionType::Resolve,
+                TransactionType::Chargeback,
+            ],
+        }
+    }
+
+    /// Whether this policy refuses the given transaction type on a locked account.
+    pub fn blocks(&self, tx_type: TransactionType) -> bool {
+        self.blocked.contains(&tx_type)
+    }
+}
+
+/// How the engine treats a dispute that references a withdrawal rather than
+/// a deposit.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum WithdrawalDisputePolicy {
+    /// Withdrawals cannot be disputed; the dispute is rejected with
+    /// `WithdrawalNotDisputable`.
+    #[default]
+    Reject,
+    /// Hold a disputed withdrawal exactly like a disputed deposit: available
+    /// funds decrease and held funds increase by the amount. A chargeback then
+    /// removes the amount from held and total funds a second time.
+    MirrorDeposit,
+    /// Treat the dispute as a provisional reversal of the withdrawal: held and
+    /// total funds increase by the amount while available funds are untouched.
+    /// A resolve removes it again; a chargeback releases it into available funds.
+    Reversal,
+}
+
+/// How long after a deposit or withdrawal it can still be disputed. Archived
+/// transactions that fall outside the window are evicted, so the archive stays
+/// bounded however long the input is. Their IDs stay in the transaction index
+/// for one more window, and are then forgotten and can be used again, so the
+/// index stays bounded too.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum DisputeWindow {
+    /// Transactions can be disputed at any time and are never evicted.
+    #[default]
+    Unbounded,
+    /// Transactions can be disputed until this many further rows have been
+    /// processed by the engine, counting rejected rows. A transaction under
+    /// dispute when its window closes is kept until it is resolved or charged
+    /// back.
+    Transactions(u64),
+    /// Transactions can be disputed until this many seconds have passed, by
+    /// the latest timestamp the engine has seen on any row. Transactions whose
+    /// row had no timestamp never expire.
+    Seconds(u64),
+}
+
+impl DisputeWindow {
+    /// Whether the window of an archived transaction has closed for a row at
+    /// position `rows`, when the latest timestamp seen is `clock`.
+    pub fn has_expired(self, detail: &TransactionDetail, rows: u64, clock: Option<u64>) -> bool {
+        self.expiry_key(detail)
+            .is_some_and(|key| self.key_has_expired(key, rows, clock))
+    }
+
+    /// Whether transactions ever fall outside this window.
+    pub fn is_bounded(self) -> bool {
+        self != DisputeWindow::Unbounded
+    }
+
+    /// What the window of an archived transaction is measured from: its
+    /// position for a row count, its timestamp for a duration. `None` if it
+    /// never expires.
+    pub(crate) fn expiry_key(self, detail: &TransactionDetail) -> Option<u64> {
+        match self {
+            DisputeWindow::Unbounded => None,
+            DisputeWindow::Transactions(_) => Some(detail.position),
+            DisputeWindow::Seconds(_) => detail.timestamp,
+        }
+    }
+
+    /// When the window measured from `key` closes, in the same terms as `key`.
+    pub(crate) fn closing_key(self, key: u64) -> u64 {
+        match self {
+            DisputeWindow::Unbounded => key,
+            DisputeWindow::Transactions(n) | DisputeWindow::Seconds(n) => key.saturating_add(n),
+        }
+    }
+
+    /// A key for the row at position `rows`, when the latest timestamp seen
+    /// is `clock`, in the same terms as `expiry_key`.
+    pub(crate) fn current_key(self, rows: u64, clock: Option<u64>) -> Option<u64> {
+        match self {
+            DisputeWindow::Unbounded => None,
+            DisputeWindow::Transactions(_) => Some(rows),
+            DisputeWindow::Seconds(_) => clock,
+        }
+    }
+
+    /// Whether a window measured from `key` has closed for a row at position
+    /// `rows`, when the latest timestamp seen is `clock`.
+    pub(crate) fn key_has_expired(self, key: u64, rows: u64, clock: Option<u64>) -> bool {
+        match self {
+            DisputeWindow::Unbounded => false,
+            DisputeWindow::Transactions(n) => rows.saturating_sub(key) > n,
+            DisputeWindow::Seconds(n) => clock.is_some_and(|now| now.saturating_sub(key) > n),
+        }
+    }
+}
+
+/// Parses `unbounded`, a row count for `Transactions`, or a duration such as
+/// `45s`, `30m`, `12h` or `90d` for `Seconds`.
+impl FromStr for DisputeWindow {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let invalid = || {
+            format!(
+                "expected unbounded, a transaction count or a duration like 90d, got {:?}",
+                s
+            )
+        };
+        let window = s.trim().to_ascii_lowercase();
+        if window == "unbounded" {
+            return Ok(DisputeWindow::Unbounded);
+        }
+        let (count, unit) = match window.find(|c: char| !c.is_ascii_digit()) {
+            Some(i) => window.split_at(i),
+            None => {
+                return window
+                    .parse()
+                    .map(DisputeWindow::Transactions)
+                    .map_err(|_| invalid());
+            }
+        };
+        let scale = match unit {
+            "s" => 1,
+            "m" => 60,
+            "h" => 60 * 60,
+            "d" => 24 * 60 * 60,
+            _ => return Err(invalid()),
+        };
+        count
+            .parse::<u64>()
+            .ok()
+            .and_then(|count| count.checked_mul(scale))
+            .map(DisputeWindow::Seconds)
+            .ok_or_else(invalid)
+    }
+}
+
+/// Configuration for a `PaymentsEngine`.
+#[derive(Debug, Default, Clone)]
+pub struct EngineConfig {
+    /// Policy for transaction IDs reused across clients.
+    pub duplicate_policy: DuplicateTransactionPolicy,
+    /// Policy for transactions against accounts locked by a chargeback.
+    pub lock_policy: LockPolicy,
+    /// Policy for disputes that reference a withdrawal.
+    pub withdrawal_dispute_policy: WithdrawalDisputePolicy,
+    /// How long transactions can be disputed and stay archived.
+    pub dispute_window: DisputeWindow,
+}
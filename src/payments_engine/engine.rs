@@ -1,66 +1,760 @@
 /// This file defines the payments engine interface and behavior
 /// for processing a deserialized `Transaction`.
-use anyhow::Result;
-use std::collections::HashMap;
+use anyhow::{Context, Result};
+use log::warn;
+use std::borrow::Cow;
+use std::collections::BTreeSet;
 use std::fmt;
+use std::fs::File;
+use std::io::{BufReader, Read, Write};
+use std::path::Path;
 
-use crate::account::client_account::ClientAccount;
+use crate::account::client_account::{ClientAccount, ClientAccountDetails};
+use crate::account::store::{AccountStore, InMemoryAccountStore};
+use crate::account::transaction_state::TransactionState;
 use crate::errors::PaymentsTransactionError;
+use crate::payments_engine::config::{
+    DisputeWindow, DuplicateTransactionPolicy, EngineConfig, LockPolicy, WithdrawalDisputePolicy,
+};
+use crate::payments_engine::events::{DomainEvent, EventSubscriber};
+use crate::payments_engine::journal::{Journal, JournalSync, Recovery};
+use crate::payments_engine::outcome::TransactionOutcome;
+use crate::payments_engine::report::{AccountSummary, ReportOrder, write_csv_report};
+use crate::payments_engine::sharded::process_sharded;
+use crate::payments_engine::snapshot::{read_snapshot, write_snapshot, write_snapshot_file};
 use crate::transaction::{Transaction, TransactionType};
 
 /// Representation of the payments engine.
 #[derive(Debug)]
 pub struct PaymentsEngine {
-    /// Maps a client ID to its account.
-    pub client_account_lookup: HashMap<u16, ClientAccount>,
+    /// Where client accounts and their archived transactions are kept.
+    pub(crate) store: Box<dyn AccountStore>,
+    /// Client IDs in the order the engine first opened their accounts. There
+    /// can be no more of them than there are client IDs.
+    pub(crate) client_order: Vec<u16>,
+    /// Policies this engine was configured with.
+    pub(crate) config: EngineConfig,
+    /// Number of worker threads `process_batch` shards clients across.
+    /// Zero and one both mean sequential processing.
+    workers: usize,
+    /// Journal every transaction is appended to before it is applied, if any.
+    pub(crate) journal: Option<Journal>,
+    /// Sequence number of the last journal record applied to this state.
+    pub(crate) journal_seq: u64,
+    /// Number of rows applied to this state, counting rejected ones. Archived
+    /// transactions record it as their position for the dispute window.
+    pub(crate) applied_rows: u64,
+    /// Latest timestamp on any row applied to this state, which windows
+    /// measured in seconds are checked against.
+    pub(crate) clock: Option<u64>,
+    /// Archived transactions awaiting eviction under a bounded dispute window,
+    /// as `(expiry key, client, tx)`. See `DisputeWindow::expiry_key`.
+    pub(crate) expiry_queue: BTreeSet<(u64, u16, u32)>,
+    /// Evicted transactions whose IDs are still held in the index, awaiting
+    /// release, as `(key, client, tx)`, where the key is when the window they
+    /// are released after starts, in `DisputeWindow::expiry_key` terms.
+    pub(crate) retired_queue: BTreeSet<(u64, u16, u32)>,
+    /// Index entries released since this was last drained, as
+    /// `(client, tx)`, if the engine is collecting them. Only shard workers
+    /// do, to pass them on to the other shards.
+    pub(crate) released: Option<Vec<(u16, u32)>>,
+    /// Subscribers every domain event is passed to.
+    subscribers: Vec<Box<dyn EventSubscriber>>,
+    /// Events raised by the row being processed, not yet passed to subscribers.
+    events: Vec<DomainEvent>,
+    /// Transaction ID a shard worker panics on, to test worker failures.
+    #[cfg(test)]
+    pub(crate) panic_on_tx: Option<u32>,
 }
 
+impl Default for PaymentsEngine {
+    fn default() -> Self {
+        PaymentsEngine {
+            store: Box::new(InMemoryAccountStore::new()),
+            client_order: Vec::new(),
+            config: EngineConfig::default(),
+            workers: 0,
+            journal: None,
+            journal_seq: 0,
+            applied_rows: 0,
+            clock: None,
+            expiry_queue: BTreeSet::new(),
+            retired_queue: BTreeSet::new(),
+            released: None,
+            subscribers: Vec::new(),
+            events: Vec::new(),
+            #[cfg(test)]
+            panic_on_tx: None,
+        }
+    }
+}
+
+/// Outputs the CSV account report ordered by client ID.
 impl fmt::Display for PaymentsEngine {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        writeln!(f, "client,available,held,total,locked")?;
-        for (client_id, client_account) in &self.client_account_lookup {
-            writeln!(f, "{},{}", client_id, client_account.account_details)?;
-        }
-        Ok(())
+        let mut buf = Vec::new();
+        self.write_report(ReportOrder::ClientId, &mut buf)
+            .map_err(|_| fmt::Error)?;
+        f.write_str(&String::from_utf8_lossy(&buf))
     }
 }
 
 impl PaymentsEngine {
-    /// Processes a `Transaction` based on its `TransactionType`.
-    pub fn process_transaction(&mut self, tx: Transaction) -> Result<(), PaymentsTransactionError> {
-        // First check if this client ID has been seen before. If not, create
-        // a new client account. Then get a mutable reference to the underlying
-        // `ClientAccount` for transaction processing.
-        let selected_account = self.client_account_lookup.entry(tx.client).or_default();
-
-        // Ignore duplicate transaction IDs that have been seen before.
-        let transaction_id_not_seen_before = !selected_account
-            .account_transaction_archive
-            .history
-            .contains(&tx.tx);
-        if transaction_id_not_seen_before {
-            match tx.tx_type {
-                TransactionType::Deposit => selected_account.handle_deposit(tx)?,
-                TransactionType::Withdrawal => {
-                    // If a client doesn't have enough funds, a withdrawal will fail.
-                    // Operationally, isn't of stopping, we'll ack the erroneous withdrawal
-                    // in a log and ignore it with an .ok() and continue processing other transactions.
-                    selected_account.handle_withdrawal(tx).ok();
+    /// Creates an engine with no client accounts.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Creates an engine with no client accounts using the given configuration.
+    pub fn with_config(config: EngineConfig) -> Self {
+        PaymentsEngine {
+            config,
+            ..Self::default()
+        }
+    }
+
+    /// Sets the policy for transaction IDs reused across clients.
+    pub fn with_duplicate_policy(mut self, policy: DuplicateTransactionPolicy) -> Self {
+        self.config.duplicate_policy = policy;
+        self
+    }
+
+    /// Sets which transaction types are refused on locked accounts.
+    pub fn with_lock_policy(mut self, policy: LockPolicy) -> Self {
+        self.config.lock_policy = policy;
+        self
+    }
+
+    /// Sets whether, and how, disputes against withdrawals are applied.
+    pub fn with_withdrawal_dispute_policy(mut self, policy: WithdrawalDisputePolicy) -> Self {
+        self.config.withdrawal_dispute_policy = policy;
+        self
+    }
+
+    /// Sets how long transactions can be disputed before they are evicted
+    /// from the archive.
+    pub fn with_dispute_window(mut self, window: DisputeWindow) -> Self {
+        self.config.dispute_window = window;
+        self
+    }
+
+    /// Sets how many worker threads `process_batch` uses.
+    pub fn with_workers(mut self, workers: usize) -> Self {
+        self.workers = workers;
+        self
+    }
+
+    /// Keeps client accounts and their archived transactions in `store`
+    /// instead of in memory. The store should be empty.
+    pub fn with_store<S: AccountStore + 'static>(mut self, store: S) -> Self {
+        self.store = Box::new(store);
+        self
+    }
+
+    /// Passes every domain event raised from now on to `subscriber`, after
+    /// any subscribers added before it.
+    pub fn with_subscriber<S: EventSubscriber + 'static>(mut self, subscriber: S) -> Self {
+        self.subscribers.push(Box::new(subscriber));
+        self
+    }
+
+    /// Returns how many worker threads `process_batch` uses.
+    pub fn workers(&self) -> usize {
+        self.workers.max(1)
+    }
+
+    /// Returns the configuration this engine is running with.
+    pub fn config(&self) -> &EngineConfig {
+        &self.config
+    }
+
+    /// Returns the client that owns a deposit or withdrawal transaction ID, if
+    /// any. Fails only if the account store can't read the index.
+    pub fn transaction_owner(&self, tx: u32) -> Result<Option<u16>, PaymentsTransactionError> {
+        self.store.owner(tx)
+    }
+
+    /// Returns the account for a client ID, with its whole transaction
+    /// archive, if the engine has seen that client. Fails only if the account
+    /// store can't read the archive back.
+    pub fn account(
+        &self,
+        client: u16,
+    ) -> Result<Option<Cow<'_, ClientAccount>>, PaymentsTransactionError> {
+        self.store.account(client)
+    }
+
+    /// Returns the balances and lock status for a client ID, if the engine has
+    /// seen that client.
+    pub fn account_details(&self, client: u16) -> Option<&ClientAccountDetails> {
+        self.store.details(client)
+    }
+
+    /// Returns every client ID the engine has seen, in the order it first saw them.
+    pub fn clients(&self) -> &[u16] {
+        &self.client_order
+    }
+
+    /// Returns the number of client accounts the engine is tracking.
+    pub fn account_count(&self) -> usize {
+        self.store.len()
+    }
+
+    /// Returns a summary row for every client account, in the order the engine
+    /// first saw each client.
+    pub fn account_summaries(&self) -> Vec<AccountSummary> {
+        self.sorted_account_summaries(ReportOrder::FirstSeen)
+    }
+
+    /// Returns a summary row for every client account, in the given order.
+    pub fn sorted_account_summaries(&self, order: ReportOrder) -> Vec<AccountSummary> {
+        let mut summaries: Vec<AccountSummary> = self
+            .client_order
+            .iter()
+            .filter_map(|client| {
+                self.store
+                    .details(*client)
+                    .map(|details| AccountSummary::from_details(*client, details))
+            })
+            .collect();
+        order.sort(&mut summaries);
+        summaries
+    }
+
+    /// Writes the CSV account report in the given order.
+    pub fn write_report<W: Write>(&self, order: ReportOrder, writer: W) -> Result<()> {
+        write_csv_report(&self.sorted_account_summaries(order), writer)
+    }
+
+    /// Administratively unlocks a client's account that was frozen by a chargeback.
+    /// The unlock and its reason are recorded in the account's lock history.
+    ///
+    /// This is library-only: neither the CLI nor the HTTP API exposes it. An
+    /// unlock isn't a transaction, so it isn't journaled; save a snapshot
+    /// afterwards if the engine is recovered from a journal.
+    pub fn unlock_account(
+        &mut self,
+        client: u16,
+        reason: &str,
+    ) -> Result<(), PaymentsTransactionError> {
+        let mut account = self
+            .store
+            .take(client, None)?
+            .ok_or(PaymentsTransactionError::ClientDoesNotExist(client))?;
+        account.unlock(reason);
+        self.store.put(client, account)
+    }
+
+    /// Looks up the referenced transaction of a dispute, resolve or chargeback
+    /// row across the engine and takes the account that owns it out of the
+    /// store. Fails if the transaction was never applied, was applied for a
+    /// different client, or has been evicted.
+    fn referenced_account(
+        &mut self,
+        tx: &Transaction,
+    ) -> Result<ClientAccount, PaymentsTransactionError> {
+        // Under the warn and allow duplicate policies a second client may hold
+        // the same ID in its own history, so that also counts as ownership,
+        // and still does once the first owner's copy has been evicted.
+        let owner = self.store.owner(tx.tx)?;
+        let is_owned_by_client =
+            owner == Some(tx.client) || self.store.transaction(tx.client, tx.tx)?.is_some();
+        match owner {
+            _ if is_owned_by_client => {}
+            Some(owner) => {
+                warn!(
+                    "{} for client {} references transaction {} owned by client {}",
+                    tx.tx_type, tx.client, tx.tx, owner
+                );
+                return Err(PaymentsTransactionError::TransactionClientMismatch {
+                    tx: tx.tx,
+                    client: tx.client,
+                    owner,
+                });
+            }
+            None => {
+                warn!("{} references unknown transaction {}", tx.tx_type, tx.tx);
+                return Err(PaymentsTransactionError::TransactionDetailDoesNotExist(
+                    tx.tx.to_string(),
+                ));
+            }
+        }
+
+        let account = self.store.take(tx.client, Some(tx.tx))?.ok_or_else(|| {
+            PaymentsTransactionError::TransactionDetailDoesNotExist(tx.tx.to_string())
+        })?;
+        // The client applied this ID and it's still indexed, so if it's gone
+        // from the archive it was evicted when its dispute window closed.
+        if owner == Some(tx.client) && !account.account_transaction_archive.contains(tx.tx) {
+            self.store.put(tx.client, account)?;
+            warn!("{} references expired transaction {}", tx.tx_type, tx.tx);
+            return Err(PaymentsTransactionError::DisputeWindowExpired(tx.tx));
+        }
+        Ok(account)
+    }
+
+    /// Returns the journal transactions are appended to, if `recover` opened one.
+    pub fn journal(&self) -> Option<&Journal> {
+        self.journal.as_ref()
+    }
+
+    /// Processes a `Transaction` based on its `TransactionType` and reports
+    /// whether it was applied, ignored or rejected.
+    ///
+    /// If the engine is journaling, the transaction is appended to the journal
+    /// first and is rejected without being applied if that fails.
+    ///
+    /// The domain events the row raised are passed to every subscriber before
+    /// this returns, followed by a `TransactionRejected` event if the row
+    /// wasn't applied.
+    pub fn process_transaction(&mut self, tx: Transaction) -> TransactionOutcome {
+        let journaled = self
+            .journal
+            .as_mut()
+            .map(|journal| journal.append(&tx))
+            .transpose();
+        let outcome = match journaled {
+            Ok(seq) => {
+                if let Some(seq) = seq {
+                    self.journal_seq = seq;
+                }
+                self.apply_transaction(tx)
+                    .unwrap_or_else(TransactionOutcome::from_error)
+            }
+            Err(e) => TransactionOutcome::Rejected { error: e.into() },
+        };
+        self.publish_events(tx, &outcome);
+        outcome
+    }
+
+    /// Passes the events raised by `tx` to every subscriber, adding a
+    /// `TransactionRejected` event if it wasn't applied.
+    fn publish_events(&mut self, tx: Transaction, outcome: &TransactionOutcome) {
+        if let Some(error) = outcome.error() {
+            self.events.push(DomainEvent::TransactionRejected {
+                client: tx.client,
+                tx: tx.tx,
+                outcome: outcome.status(),
+                error: error.kind(),
+                message: error.to_string(),
+            });
+        }
+        for event in self.events.drain(..) {
+            for subscriber in &mut self.subscribers {
+                subscriber.on_event(&event);
+            }
+        }
+    }
+
+    /// Applies a `Transaction` to the engine's state without journaling it.
+    /// Only ever returns `TransactionOutcome::Applied`; everything else is an
+    /// error.
+    pub(crate) fn apply_transaction(
+        &mut self,
+        tx: Transaction,
+    ) -> Result<TransactionOutcome, PaymentsTransactionError> {
+        self.applied_rows += 1;
+        if let Some(timestamp) = tx.timestamp {
+            self.clock = self.clock.max(Some(timestamp));
+        }
+        let result = self.apply_row(tx);
+        // Eviction tidies up after the row rather than being part of it, so a
+        // failure doesn't change the row's outcome. The transactions it
+        // couldn't evict are tried again after the next row.
+        if let Err(e) = self.evict_expired() {
+            warn!("Error evicting expired transactions after {}: {}", tx, e);
+        }
+        result
+    }
+
+    /// Applies the row at position `applied_rows`.
+    fn apply_row(
+        &mut self,
+        tx: Transaction,
+    ) -> Result<TransactionOutcome, PaymentsTransactionError> {
+        let is_new_funds_movement = matches!(
+            tx.tx_type,
+            TransactionType::Deposit | TransactionType::Withdrawal
+        );
+        let owner = if is_new_funds_movement {
+            self.store.owner(tx.tx)?
+        } else {
+            None
+        };
+        if is_new_funds_movement {
+            // Transaction IDs are globally unique, so check the engine-wide index
+            // before touching any account. A repeat from the same client is always
+            // rejected; a reuse by a different client is handled by policy.
+            if let Some(owner) = owner {
+                if owner == tx.client {
+                    warn!("Duplicate transaction ID seen, rejecting: {}", &tx);
+                    return Err(PaymentsTransactionError::DuplicateTransactionId(
+                        tx.to_string(),
+                    ));
+                }
+                match self.config.duplicate_policy {
+                    DuplicateTransactionPolicy::Reject => {
+                        warn!(
+                            "Transaction ID {} already used by client {}, rejecting: {}",
+                            tx.tx, owner, &tx
+                        );
+                        return Err(
+                            PaymentsTransactionError::CrossClientDuplicateTransactionId {
+                                tx: tx.tx,
+                                client: tx.client,
+                                owner,
+                            },
+                        );
+                    }
+                    DuplicateTransactionPolicy::Warn => {
+                        warn!(
+                            "Transaction ID {} already used by client {}, applying anyway: {}",
+                            tx.tx, owner, &tx
+                        );
+                    }
+                    DuplicateTransactionPolicy::Allow => {}
                 }
-                TransactionType::Dispute => {
-                    // If a dispute transaction references a transaction that doesn't exist, we return an
-                    // error. But, we don't want to stop processing transactions. Do the same thing
-                    // we do for the withdrawal variant here.
-                    selected_account.handle_dispute(tx).ok();
+            }
+        }
+
+        let mut selected_account = if is_new_funds_movement {
+            // First check if this client ID has been seen before. If not, create
+            // a new client account. Then take the underlying `ClientAccount` out
+            // of the store for transaction processing.
+            match self.store.take(tx.client, Some(tx.tx))? {
+                Some(account) => account,
+                None => {
+                    self.client_order.push(tx.client);
+                    ClientAccount::open(tx.client)
+                }
+            }
+        } else {
+            // Dispute, resolve and chargeback rows must reference a transaction the
+            // row's own client made. They never open a new account.
+            self.referenced_account(&tx)?
+        };
+
+        let before = selected_account.details().clone();
+        let result = self.apply_to_account(&mut selected_account, tx);
+
+        // Only IDs that made it into the account's history claim a slot in the index,
+        // so a withdrawal rejected for insufficient funds doesn't reserve its ID.
+        let mut claim = false;
+        let mut expired_settlement = None;
+        if let Some(detail) = selected_account
+            .account_transaction_archive
+            .details
+            .get_mut(&tx.tx)
+        {
+            if is_new_funds_movement {
+                claim = owner.is_none();
+                if result.is_ok() {
+                    detail.position = self.applied_rows;
+                    detail.timestamp = tx.timestamp;
+                    if let Some(key) = self.config.dispute_window.expiry_key(detail) {
+                        self.expiry_queue.insert((key, tx.client, tx.tx));
+                    }
                 }
-                TransactionType::Resolve => {
-                    selected_account.handle_resolve(tx).ok();
+            } else {
+                // A dispute settled after its window closed was skipped by
+                // eviction while it was open, so evict it now.
+                let window = self.config.dispute_window;
+                if result.is_ok()
+                    && detail.state != TransactionState::Disputed
+                    && window.has_expired(detail, self.applied_rows + 1, self.clock)
+                {
+                    expired_settlement = window.current_key(self.applied_rows, self.clock);
                 }
-                TransactionType::Chargeback => {
-                    selected_account.handle_chargeback(tx).ok();
+            }
+        }
+        // Handlers leave the account untouched when they fail, so it goes back
+        // into the store either way.
+        let after = selected_account.details().clone();
+        let events = selected_account.take_events();
+        self.store.put(tx.client, selected_account)?;
+        self.events.extend(events);
+        if claim {
+            self.store.claim(tx.tx, tx.client)?;
+        }
+        if let Some(now) = expired_settlement {
+            self.evict(now, tx.client, tx.tx)?;
+        }
+        result.map(|()| TransactionOutcome::Applied {
+            client: tx.client,
+            before,
+            after,
+        })
+    }
+
+    /// Evicts archived transactions whose dispute window closes before the
+    /// next row. Transactions still under dispute are kept until they are
+    /// resolved or charged back. A transaction the store fails to evict stays
+    /// queued, along with every one after it.
+    ///
+    /// Then releases from the index the IDs of evicted transactions whose
+    /// second window closes before the next row.
+    pub(crate) fn evict_expired(&mut self) -> Result<(), PaymentsTransactionError> {
+        let window = self.config.dispute_window;
+        while let Some(&(key, client, tx)) = self.expiry_queue.first() {
+            if !window.key_has_expired(key, self.applied_rows + 1, self.clock) {
+                break;
+            }
+            let is_disputed = self
+                .store
+                .transaction(client, tx)?
+                .is_some_and(|detail| detail.state == TransactionState::Disputed);
+            if !is_disputed {
+                self.evict(window.closing_key(key), client, tx)?;
+            }
+            self.expiry_queue.pop_first();
+        }
+        while let Some(&(key, client, tx)) = self.retired_queue.first() {
+            if !window.key_has_expired(key, self.applied_rows + 1, self.clock) {
+                break;
+            }
+            if self.store.owner(tx)? == Some(client) {
+                self.store.release(tx)?;
+                if let Some(released) = &mut self.released {
+                    released.push((client, tx));
                 }
             }
+            self.retired_queue.pop_first();
+        }
+        Ok(())
+    }
+
+    /// Drops a transaction whose window has closed from a client's archive.
+    /// If the client owns its ID, the ID stays in the index for a second
+    /// window, measured from `closed`, so a late dispute is still reported as
+    /// expired and a late reuse as a duplicate, and is then released.
+    fn evict(&mut self, closed: u64, client: u16, tx: u32) -> Result<(), PaymentsTransactionError> {
+        self.store.evict(client, tx)?;
+        if self.store.owner(tx)? == Some(client) {
+            self.retired_queue.insert((closed, client, tx));
         }
         Ok(())
     }
+
+    /// Applies a `Transaction` to the account it was taken out of the store for.
+    fn apply_to_account(
+        &self,
+        selected_account: &mut ClientAccount,
+        tx: Transaction,
+    ) -> Result<(), PaymentsTransactionError> {
+        let is_new_funds_movement = matches!(
+            tx.tx_type,
+            TransactionType::Deposit | TransactionType::Withdrawal
+        );
+
+        // Reject duplicate transaction IDs that this client has used before.
+        // Dispute, resolve and chargeback rows reference an existing ID on purpose.
+        let transaction_id_seen_before =
+            selected_account.account_transaction_archive.contains(tx.tx);
+        if is_new_funds_movement && transaction_id_seen_before {
+            warn!("Duplicate transaction ID seen, rejecting: {}", &tx);
+            return Err(PaymentsTransactionError::DuplicateTransactionId(
+                tx.to_string(),
+            ));
+        }
+
+        // Each client's rows must be in time order; a reorder buffer in front
+        // of the engine can straighten out a feed that is slightly off.
+        if let (Some(timestamp), Some(last)) = (tx.timestamp, selected_account.last_timestamp)
+            && timestamp < last
+        {
+            warn!(
+                "Timestamp {} is before client {}'s last transaction at {}, rejecting: {}",
+                timestamp, tx.client, last, &tx
+            );
+            return Err(PaymentsTransactionError::TimestampOutOfOrder {
+                client: tx.client,
+                timestamp,
+                last,
+            });
+        }
+
+        // Past its window a transaction can't be disputed, and one that isn't
+        // under dispute is treated as evicted even if eviction hasn't got to it.
+        if !is_new_funds_movement
+            && let Some(detail) = selected_account.account_transaction_archive.get(tx.tx)
+            && self
+                .config
+                .dispute_window
+                .has_expired(detail, self.applied_rows, self.clock)
+            && (tx.tx_type == TransactionType::Dispute
+                || detail.state != TransactionState::Disputed)
+        {
+            warn!(
+                "Dispute window for transaction {} has closed: {}",
+                tx.tx, &tx
+            );
+            return Err(PaymentsTransactionError::DisputeWindowExpired(tx.tx));
+        }
+
+        // A chargeback freezes the account; refuse whatever the lock policy blocks.
+        if selected_account.account_details.is_account_locked
+            && self.config.lock_policy.blocks(tx.tx_type)
+        {
+            warn!(
+                "Account for client {} is locked, rejecting: {}",
+                tx.client, &tx
+            );
+            return Err(PaymentsTransactionError::AccountLocked(tx.client));
+        }
+
+        // Any handler error is returned to the caller, who decides whether to record
+        // the rejection and keep processing the rest of the input.
+        match tx.tx_type {
+            TransactionType::Deposit => selected_account.handle_deposit(tx),
+            TransactionType::Withdrawal => selected_account.handle_withdrawal(tx),
+            TransactionType::Dispute => selected_account
+                .handle_dispute_with_policy(tx, self.config.withdrawal_dispute_policy),
+            TransactionType::Resolve => selected_account.handle_resolve(tx),
+            TransactionType::Chargeback => selected_account.handle_chargeback(tx),
+        }?;
+        if tx.timestamp.is_some() {
+            selected_account.last_timestamp = tx.timestamp;
+        }
+        Ok(())
+    }
+
+    /// Writes every account, its archive and open disputes, the first-seen
+    /// client order, the transaction index and the evicted IDs it still holds
+    /// as a versioned snapshot.
+    /// Configuration and worker count aren't included.
+    pub fn save_snapshot<W: Write>(&self, writer: W) -> Result<()> {
+        write_snapshot(self, writer).context("Error writing engine snapshot")
+    }
+
+    /// Replaces this engine's state with a snapshot written by `save_snapshot`,
+    /// keeping its configuration and worker count. Applying further
+    /// transactions gives the same results as replaying every transaction the
+    /// snapshot was taken after. The state is left untouched on error.
+    pub fn load_snapshot<R: Read>(&mut self, reader: R) -> Result<(), PaymentsTransactionError> {
+        let restored = read_snapshot(reader)?;
+        // The expiry queue isn't saved. Rebuilding it from every archived
+        // transaction also brings back disputed ones eviction already passed
+        // over, which it passes over again.
+        let window = self.config.dispute_window;
+        let expiry_queue = restored
+            .client_account_lookup
+            .iter()
+            .flat_map(|(&client, account)| {
+                account
+                    .transaction_archive()
+                    .transactions()
+                    .filter_map(move |(tx, detail)| {
+                        window.expiry_key(detail).map(|key| (key, client, tx))
+                    })
+            })
+            .collect();
+        // The store swaps its accounts in all at once, and nothing else is
+        // touched until it has.
+        self.store
+            .replace(restored.client_account_lookup, restored.transaction_index)?;
+        self.client_order = restored.client_order;
+        self.journal_seq = restored.journal_seq;
+        self.applied_rows = restored.applied_rows;
+        self.clock = restored.clock;
+        self.expiry_queue = expiry_queue;
+        self.retired_queue = restored.retired.into_iter().collect();
+        Ok(())
+    }
+
+    /// Rebuilds this engine from the snapshot at `snapshot`, if given, plus
+    /// every record in the journal at `journal` written after it, then keeps
+    /// the journal open so further transactions are appended to it. With no
+    /// snapshot and no journal file yet, this just starts a fresh journal.
+    ///
+    /// A torn final record left by a crash mid-write is truncated away. A
+    /// journal that doesn't carry on from where the snapshot ends is refused,
+    /// since transactions between the two would be missing.
+    pub fn recover<P: AsRef<Path>>(
+        &mut self,
+        snapshot: Option<&Path>,
+        journal: P,
+        sync: JournalSync,
+    ) -> Result<Recovery, PaymentsTransactionError> {
+        if let Some(snapshot) = snapshot {
+            self.load_snapshot(BufReader::new(File::open(snapshot)?))?;
+        }
+        let (mut journal, records, truncated_bytes) = Journal::open(journal, sync)?;
+
+        let mut replayed = 0;
+        for record in records {
+            if record.seq <= self.journal_seq {
+                // Already in the snapshot; the checkpoint that took it crashed
+                // before it could empty the journal.
+                continue;
+            }
+            if record.seq != self.journal_seq + 1 {
+                return Err(PaymentsTransactionError::CorruptJournal(format!(
+                    "journal resumes at record {} but the engine state ends at record {}",
+                    record.seq, self.journal_seq
+                )));
+            }
+            // Rejections were already reported when the record was first applied.
+            let _ = self.apply_transaction(record.tx);
+            self.journal_seq = record.seq;
+            replayed += 1;
+        }
+        // Subscribers were told about replayed rows when they were first applied.
+        self.events.clear();
+
+        journal.continue_after(self.journal_seq);
+        self.journal = Some(journal);
+        Ok(Recovery {
+            replayed,
+            truncated_bytes,
+        })
+    }
+
+    /// Saves a snapshot to `path`, replacing any earlier one only once the new
+    /// one is safely on disk, then empties the journal, which the snapshot now
+    /// covers.
+    pub fn checkpoint<P: AsRef<Path>>(&mut self, path: P) -> Result<()> {
+        write_snapshot_file(self, path.as_ref())?;
+        if let Some(journal) = &mut self.journal {
+            journal.truncate().context("Error truncating journal")?;
+        }
+        Ok(())
+    }
+
+    /// Processes a batch of transactions in order, returning the tag and error
+    /// of every transaction that was rejected, in input order. The tag lets the
+    /// caller tie a rejection back to its input row.
+    ///
+    /// With more than one worker, clients are sharded across threads by client
+    /// ID. Each client's transactions are still applied in input order, and
+    /// the accounts, reports and rejections match sequential processing.
+    /// Engines with an on-disk account store or event subscribers always
+    /// process sequentially, the latter so events arrive in input order.
+    ///
+    /// Fails with `ShardFailed` if a worker thread panics. The batch has then
+    /// stopped part way, and the failed shard's clients are missing from the
+    /// engine, so recover it from its snapshot and journal before going on.
+    pub fn process_batch<T, I>(
+        &mut self,
+        transactions: I,
+    ) -> Result<Vec<(T, PaymentsTransactionError)>, PaymentsTransactionError>
+    where
+        T: Send,
+        I: IntoIterator<Item = (T, Transaction)>,
+    {
+        if self.workers() > 1
+            && self.subscribers.is_empty()
+            && self.store.as_in_memory_mut().is_some()
+        {
+            return process_sharded(self, self.workers(), transactions);
+        }
+        Ok(transactions
+            .into_iter()
+            .filter_map(|(tag, tx)| {
+                self.process_transaction(tx)
+                    .into_result()
+                    .err()
+                    .map(|e| (tag, e))
+            })
+            .collect())
+    }
 }
@@ -0,0 +1,168 @@
+/// This file defines the domain events the payments engine raises as rows
+/// change client accounts, the trait subscribers implement to receive them,
+/// and the built-in JSONL file and in-memory sinks.
+use log::warn;
+use serde::Serialize;
+use std::fmt;
+use std::fs::{File, OpenOptions};
+use std::io::{self, BufWriter, Write};
+use std::path::Path;
+use std::sync::{Arc, Mutex, MutexGuard};
+
+use crate::money::Money;
+
+/// Something that happened to a client account. Account events are raised by
+/// the `ClientAccount` handlers as they apply a row; `TransactionRejected` is
+/// raised by the engine for rows that weren't applied.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+#[serde(tag = "event")]
+pub enum DomainEvent {
+    /// The engine opened an account for a client it hadn't seen before.
+    AccountOpened { client: u16 },
+    /// A deposit added `amount` to the client's available funds.
+    FundsCredited { client: u16, tx: u32, amount: Money },
+    /// A withdrawal took `amount` from the client's available funds.
+    FundsDebited { client: u16, tx: u32, amount: Money },
+    /// A dispute of transaction `tx` moved `amount` into held funds.
+    FundsHeld { client: u16, tx: u32, amount: Money },
+    /// A resolve of transaction `tx` released `amount` from held funds.
+    FundsReleased { client: u16, tx: u32, amount: Money },
+    /// A chargeback of transaction `tx` reversed `amount` out of held funds.
+    ChargedBack { client: u16, tx: u32, amount: Money },
+    /// A chargeback of transaction `tx` locked the client's account.
+    AccountLocked { client: u16, tx: u32 },
+    /// Row `tx` was ignored or rejected, as `outcome` says, with the error
+    /// kind and message it failed with. No balances changed.
+    TransactionRejected {
+        client: u16,
+        tx: u32,
+        outcome: &'static str,
+        error: &'static str,
+        message: String,
+    },
+}
+
+/// Receives every domain event an engine raises, in the order the rows that
+/// raised them were applied.
+pub trait EventSubscriber: fmt::Debug + Send {
+    /// Called once for each event, after the row that raised it was applied.
+    fn on_event(&mut self, event: &DomainEvent);
+}
+
+/// Writes every event as one line of JSON, flushing after each so readers
+/// tailing the output see events as they happen. Write errors are logged and
+/// otherwise ignored, since the row that raised the event has already been
+/// applied.
+#[derive(Debug)]
+pub struct JsonlEventSink<W: Write> {
+    writer: W,
+}
+
+impl JsonlEventSink<BufWriter<File>> {
+    /// Appends events to the file at `path`, creating it if it doesn't exist.
+    pub fn open<P: AsRef<Path>>(path: P) -> io::Result<Self> {
+        let file = OpenOptions::new().create(true).append(true).open(path)?;
+        Ok(Self::new(BufWriter::new(file)))
+    }
+}
+
+impl<W: Write> JsonlEventSink<W> {
+    /// Writes events to `writer`.
+    pub fn new(writer: W) -> Self {
+        JsonlEventSink { writer }
+    }
+
+    /// Returns the writer events are written to.
+    pub fn into_inner(self) -> W {
+        self.writer
+    }
+
+    fn write_event(&mut self, event: &DomainEvent) -> io::Result<()> {
+        serde_json::to_writer(&mut self.writer, event)?;
+        writeln!(self.writer)?;
+        self.writer.flush()
+    }
+}
+
+impl<W: Write + fmt::Debug + Send> EventSubscriber for JsonlEventSink<W> {
+    fn on_event(&mut self, event: &DomainEvent) {
+        if let Err(e) = self.write_event(event) {
+            warn!("Error writing event {:?}: {}", event, e);
+        }
+    }
+}
+
+/// Keeps every event in memory. Clones share the same buffer, so a test can
+/// hand one clone to the engine and read events back through another.
+#[derive(Debug, Clone, Default)]
+pub struct MemoryEventSink {
+    events: Arc<Mutex<Vec<DomainEvent>>>,
+}
+
+impl MemoryEventSink {
+    /// Creates a sink with no events.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    fn buffer(&self) -> MutexGuard<'_, Vec<DomainEvent>> {
+        self.events
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
+
+    /// Returns every event received so far, oldest first.
+    pub fn events(&self) -> Vec<DomainEvent> {
+        self.buffer().clone()
+    }
+
+    /// Returns every event received so far, oldest first, and empties the buffer.
+    pub fn take(&self) -> Vec<DomainEvent> {
+        std::mem::take(&mut *self.buffer())
+    }
+}
+
+impl EventSubscriber for MemoryEventSink {
+    fn on_event(&mut self, event: &DomainEvent) {
+        self.buffer().push(event.clone());
+    }
+}
+
+#[cfg(test)]
+mod events_tests {
+    use super::*;
+
+    /// The JSONL sink writes one tagged object per event, with amounts in the
+    /// same trimmed form the engine reads them in.
+    #[test]
+    fn test_jsonl_event_sink() {
+        let mut sink = JsonlEventSink::new(Vec::new());
+        sink.on_event(&DomainEvent::AccountOpened { client: 1 });
+        sink.on_event(&DomainEvent::FundsCredited {
+            client: 1,
+            tx: 7,
+            amount: Money::from_scaled(15_000),
+        });
+
+        assert_eq!(
+            String::from_utf8(sink.into_inner()).unwrap(),
+            "{\"event\":\"AccountOpened\",\"client\":1}\n\
+             {\"event\":\"FundsCredited\",\"client\":1,\"tx\":7,\"amount\":\"1.5\"}\n"
+        );
+    }
+
+    /// Clones of the memory sink share one buffer, which `take` empties.
+    #[test]
+    fn test_memory_event_sink() {
+        let sink = MemoryEventSink::new();
+        let mut subscriber = sink.clone();
+        subscriber.on_event(&DomainEvent::AccountOpened { client: 2 });
+
+        assert_eq!(
+            sink.events(),
+            vec![DomainEvent::AccountOpened { client: 2 }]
+        );
+        assert_eq!(sink.take().len(), 1);
+        assert!(sink.events().is_empty());
+    }
+}
@@ -0,0 +1,476 @@
+/// This file defines the payments engine's write-ahead journal: an append-only
+/// file of every transaction the engine accepts, written before the transaction
+/// is applied, so a crashed run can be rebuilt from its last snapshot.
+use std::fs::{File, OpenOptions};
+use std::io::{self, BufRead, BufReader, Write};
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+
+use crate::errors::PaymentsTransactionError;
+use crate::transaction::Transaction;
+
+/// Bytes before each record's payload: its length, its checksum and its
+/// sequence number, all little-endian.
+const RECORD_HEADER_LEN: usize = 4 + 4 + 8;
+
+/// Largest payload a record may have. A transaction serializes to a small
+/// fraction of this, so a longer length field can only be corruption.
+const MAX_PAYLOAD_LEN: usize = 64 * 1024;
+
+/// When the journal asks the OS to flush appended records to disk.
+///
+/// Records are written straight to the file either way, so a crash of the
+/// process alone never loses one; this only matters if the machine goes down.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum JournalSync {
+    /// Sync after every record.
+    #[default]
+    Always,
+    /// Sync after every `n` records.
+    Every(u32),
+    /// Leave flushing to the OS.
+    Never,
+}
+
+/// Parses `always`, `never`, or a record count to sync after every `n` records.
+impl FromStr for JournalSync {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.trim().to_ascii_lowercase().as_str() {
+            "always" => Ok(JournalSync::Always),
+            "never" => Ok(JournalSync::Never),
+            n => match n.parse() {
+                Ok(0) | Err(_) => Err(format!(
+                    "expected always, never or a record count, got {:?}",
+                    s
+                )),
+                Ok(n) => Ok(JournalSync::Every(n)),
+            },
+        }
+    }
+}
+
+/// What `PaymentsEngine::recover` found in the journal.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Recovery {
+    /// Records applied on top of the snapshot.
+    pub replayed: u64,
+    /// Bytes of a torn final record that were truncated away.
+    pub truncated_bytes: u64,
+}
+
+/// A transaction read back from the journal.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct JournalRecord {
+    /// Position of the record in the journal, starting at 1 and never reused,
+    /// even after the journal is truncated by a checkpoint.
+    pub seq: u64,
+    /// The transaction as it was accepted.
+    pub tx: Transaction,
+}
+
+/// An open journal file, positioned to append after its last whole record.
+#[derive(Debug)]
+pub struct Journal {
+    file: File,
+    path: PathBuf,
+    sync: JournalSync,
+    /// Sequence number of the last record appended or read back.
+    last_seq: u64,
+    /// Length of the file, which ends after the last whole record.
+    len: u64,
+    /// Records appended since the last sync.
+    unsynced: u32,
+    /// How many bytes of the next record to write before failing it, so
+    /// tests can see what a failed write leaves behind.
+    #[cfg(test)]
+    fail_after_bytes: Option<usize>,
+}
+
+impl Journal {
+    /// Opens the journal at `path`, creating it if it doesn't exist, and reads
+    /// back every record in it.
+    ///
+    /// A final record cut short by a crash mid-write (a partial header, a
+    /// partial payload, or a checksum mismatch at the end of the file) is
+    /// truncated away, and the number of bytes dropped is returned alongside
+    /// the records. A bad record followed by further records can't be the
+    /// result of a torn write and is reported as `CorruptJournal` instead,
+    /// leaving the file as it was.
+    ///
+    /// The file is read through a buffer rather than loaded whole.
+    pub fn open<P: AsRef<Path>>(
+        path: P,
+        sync: JournalSync,
+    ) -> Result<(Journal, Vec<JournalRecord>, u64), PaymentsTransactionError> {
+        let path = path.as_ref().to_path_buf();
+        let file = OpenOptions::new()
+            .read(true)
+            .append(true)
+            .create(true)
+            .open(&path)?;
+        let file_len = file.metadata()?.len();
+        let (records, valid_len) = read_records(BufReader::new(&file), file_len)?;
+
+        let truncated = file_len - valid_len;
+        if truncated > 0 {
+            file.set_len(valid_len)?;
+            file.sync_all()?;
+        }
+        let journal = Journal {
+            file,
+            path,
+            sync,
+            last_seq: records.last().map_or(0, |record| record.seq),
+            len: valid_len,
+            unsynced: 0,
+            #[cfg(test)]
+            fail_after_bytes: None,
+        };
+        Ok((journal, records, truncated))
+    }
+
+    /// Path of the journal file.
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Sequence number of the last record appended or read back, or zero if
+    /// there hasn't been one.
+    pub fn last_seq(&self) -> u64 {
+        self.last_seq
+    }
+
+    /// Makes sure the next record appended is numbered after `seq`. Used on
+    /// recovery, when a checkpoint has emptied the journal but the snapshot
+    /// records how far numbering had got.
+    pub(crate) fn continue_after(&mut self, seq: u64) {
+        self.last_seq = self.last_seq.max(seq);
+    }
+
+    /// Appends a record for `tx`, syncing it if the sync policy says to, and
+    /// returns its sequence number.
+    ///
+    /// If the write or the sync fails, whatever part of the record reached
+    /// the file is truncated away again before the error is returned, so the
+    /// next append doesn't land after a torn record.
+    pub fn append(&mut self, tx: &Transaction) -> io::Result<u64> {
+        let seq = self.last_seq + 1;
+        let payload = serde_json::to_vec(tx)?;
+        if payload.len() > MAX_PAYLOAD_LEN {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "journal record too large",
+            ));
+        }
+        let len = payload.len() as u32;
+
+        let mut record = Vec::with_capacity(RECORD_HEADER_LEN + payload.len());
+        record.extend_from_slice(&len.to_le_bytes());
+        record.extend_from_slice(&checksum(seq, &payload).to_le_bytes());
+        record.extend_from_slice(&seq.to_le_bytes());
+        record.extend_from_slice(&payload);
+        let unsynced = self.unsynced;
+        if let Err(e) = self.write_record(&record) {
+            self.file.set_len(self.len)?;
+            self.file.sync_all()?;
+            self.unsynced = unsynced;
+            return Err(e);
+        }
+        self.len += record.len() as u64;
+        self.last_seq = seq;
+        Ok(seq)
+    }
+
+    /// Writes a whole record in a single write, so a crash leaves at most one
+    /// torn record at the end, then syncs if the sync policy says to.
+    fn write_record(&mut self, record: &[u8]) -> io::Result<()> {
+        #[cfg(test)]
+        if let Some(written) = self.fail_after_bytes.take() {
+            self.file.write_all(&record[..written.min(record.len())])?;
+            return Err(io::Error::other("injected write failure"));
+        }
+        self.file.write_all(record)?;
+        self.unsynced += 1;
+        match self.sync {
+            JournalSync::Always => self.sync(),
+            JournalSync::Every(n) if self.unsynced >= n => self.sync(),
+            JournalSync::Every(_) | JournalSync::Never => Ok(()),
+        }
+    }
+
+    /// Flushes every appended record to disk.
+    pub fn sync(&mut self) -> io::Result<()> {
+        self.file.sync_data()?;
+        self.unsynced = 0;
+        Ok(())
+    }
+
+    /// Empties the journal once a snapshot covers everything in it. Sequence
+    /// numbers carry on from where they were.
+    pub fn truncate(&mut self) -> io::Result<()> {
+        self.file.set_len(0)?;
+        self.file.sync_all()?;
+        self.len = 0;
+        self.unsynced = 0;
+        Ok(())
+    }
+}
+
+/// Checksum of a record's sequence number and payload.
+fn checksum(seq: u64, payload: &[u8]) -> u32 {
+    let mut hasher = crc32fast::Hasher::new();
+    hasher.update(&seq.to_le_bytes());
+    hasher.update(payload);
+    hasher.finalize()
+}
+
+/// Reads every whole record from a journal `file_len` bytes long, returning
+/// them with the length of the prefix they occupy. Anything after that prefix
+/// is a torn final record.
+fn read_records<R: BufRead>(
+    mut reader: R,
+    file_len: u64,
+) -> Result<(Vec<JournalRecord>, u64), PaymentsTransactionError> {
+    let corrupt = |offset: u64, reason: String| {
+        PaymentsTransactionError::CorruptJournal(format!("record at byte {}: {}", offset, reason))
+    };
+
+    let mut records: Vec<JournalRecord> = Vec::new();
+    let mut header = [0; RECORD_HEADER_LEN];
+    let mut payload = Vec::new();
+    let mut offset = 0;
+    while file_len - offset >= RECORD_HEADER_LEN as u64 {
+        reader.read_exact(&mut header)?;
+        let len = u32::from_le_bytes(header[0..4].try_into().unwrap()) as usize;
+        let crc = u32::from_le_bytes(header[4..8].try_into().unwrap());
+        let seq = u64::from_le_bytes(header[8..16].try_into().unwrap());
+        // The checksum doesn't cover the length, so a damaged length has to
+        // be caught before it is trusted to find the end of the record.
+        if len > MAX_PAYLOAD_LEN {
+            return Err(corrupt(offset, format!("length {} is too large", len)));
+        }
+        let end = offset + (RECORD_HEADER_LEN + len) as u64;
+        if end > file_len {
+            // Only the last record can be cut short. If a whole record follows
+            // this one, its length was damaged instead.
+            let mut rest = Vec::new();
+            reader.read_to_end(&mut rest)?;
+            if holds_whole_record(&rest) {
+                return Err(corrupt(
+                    offset,
+                    "length runs over the records after it".to_string(),
+                ));
+            }
+            break;
+        }
+
+        payload.resize(len, 0);
+        reader.read_exact(&mut payload)?;
+        if checksum(seq, &payload) != crc {
+            if end == file_len {
+                break;
+            }
+            return Err(corrupt(offset, "checksum mismatch".to_string()));
+        }
+        if records.last().is_some_and(|last| seq <= last.seq) {
+            return Err(corrupt(
+                offset,
+                format!("sequence number {} out of order", seq),
+            ));
+        }
+        let tx = serde_json::from_slice(&payload).map_err(|e| corrupt(offset, e.to_string()))?;
+        records.push(JournalRecord { seq, tx });
+        offset = end;
+    }
+    Ok((records, offset))
+}
+
+/// Whether a record with a valid checksum starts anywhere in `bytes`.
+fn holds_whole_record(bytes: &[u8]) -> bool {
+    (0..bytes.len()).any(|start| {
+        let Some(header) = bytes.get(start..start + RECORD_HEADER_LEN) else {
+            return false;
+        };
+        let len = u32::from_le_bytes(header[0..4].try_into().unwrap()) as usize;
+        let crc = u32::from_le_bytes(header[4..8].try_into().unwrap());
+        let seq = u64::from_le_bytes(header[8..16].try_into().unwrap());
+        let payload_start = start + RECORD_HEADER_LEN;
+        bytes
+            .get(payload_start..payload_start + len)
+            .is_some_and(|payload| checksum(seq, payload) == crc)
+    })
+}
+
+#[cfg(test)]
+mod journal_tests {
+    use super::*;
+    use crate::transaction::TransactionType;
+    use std::fs;
+    use tempfile::TempDir;
+
+    fn deposit(tx: u32) -> Transaction {
+        Transaction {
+            tx_type: TransactionType::Deposit,
+            client: 1,
+            tx,
+            amount: Some("1.5".parse().unwrap()),
+            timestamp: None,
+        }
+    }
+
+    /// Writes a journal of `count` deposits and returns its path.
+    fn journal_with(dir: &TempDir, count: u32) -> PathBuf {
+        let path = dir.path().join("engine.journal");
+        let (mut journal, _, _) = Journal::open(&path, JournalSync::Never).unwrap();
+        for tx in 1..=count {
+            journal.append(&deposit(tx)).unwrap();
+        }
+        path
+    }
+
+    /// Appended records are read back in order when the journal is reopened,
+    /// and numbering carries on after them.
+    #[test]
+    fn test_journal_round_trip() {
+        let dir = TempDir::new().unwrap();
+        let path = journal_with(&dir, 3);
+
+        let (mut journal, records, truncated) = Journal::open(&path, JournalSync::Always).unwrap();
+
+        assert_eq!(truncated, 0);
+        assert_eq!(
+            records,
+            (1..=3)
+                .map(|tx| JournalRecord {
+                    seq: u64::from(tx),
+                    tx: deposit(tx),
+                })
+                .collect::<Vec<_>>()
+        );
+        assert_eq!(journal.append(&deposit(4)).unwrap(), 4);
+    }
+
+    /// A crash partway through writing the last record, at any byte, drops
+    /// just that record, and the next append replaces it.
+    #[test]
+    fn test_journal_truncates_torn_final_record() {
+        let dir = TempDir::new().unwrap();
+        let path = journal_with(&dir, 3);
+        let whole = fs::read(&path).unwrap();
+        let record_len = whole.len() / 3;
+
+        for cut in 1..record_len {
+            fs::write(&path, &whole[..whole.len() - cut]).unwrap();
+
+            let (mut journal, records, truncated) =
+                Journal::open(&path, JournalSync::Always).unwrap();
+
+            assert_eq!(records.len(), 2);
+            assert_eq!(truncated, (record_len - cut) as u64);
+            assert_eq!(fs::metadata(&path).unwrap().len(), 2 * record_len as u64);
+            assert_eq!(journal.append(&deposit(3)).unwrap(), 3);
+            assert_eq!(fs::read(&path).unwrap(), whole);
+        }
+    }
+
+    /// A final record whose bytes were written out of order and only partly
+    /// reached disk fails its checksum and is dropped.
+    #[test]
+    fn test_journal_drops_final_record_with_bad_checksum() {
+        let dir = TempDir::new().unwrap();
+        let path = journal_with(&dir, 2);
+        let mut bytes = fs::read(&path).unwrap();
+        let last = bytes.len() - 1;
+        bytes[last] ^= 0xff;
+        fs::write(&path, &bytes).unwrap();
+
+        let (_, records, truncated) = Journal::open(&path, JournalSync::Always).unwrap();
+
+        assert_eq!(records.len(), 1);
+        assert_eq!(truncated, (bytes.len() / 2) as u64);
+    }
+
+    /// Damage to a record that isn't the last can't be a torn write, so the
+    /// journal refuses to open rather than silently dropping later records.
+    #[test]
+    fn test_journal_reports_corruption_before_the_end() {
+        let dir = TempDir::new().unwrap();
+        let path = journal_with(&dir, 3);
+        let mut bytes = fs::read(&path).unwrap();
+        bytes[RECORD_HEADER_LEN + 2] ^= 0xff;
+        fs::write(&path, &bytes).unwrap();
+
+        assert!(matches!(
+            Journal::open(&path, JournalSync::Always),
+            Err(PaymentsTransactionError::CorruptJournal(_))
+        ));
+        assert_eq!(fs::read(&path).unwrap(), bytes);
+    }
+
+    /// A damaged length field on a record that isn't the last makes it look
+    /// like it runs off the end of the file. That isn't a torn write, so the
+    /// journal refuses to open rather than truncating the records after it.
+    #[test]
+    fn test_journal_reports_damaged_length_before_the_end() {
+        let dir = TempDir::new().unwrap();
+        let path = journal_with(&dir, 3);
+        let whole = fs::read(&path).unwrap();
+        let record_len = whole.len() / 3;
+        let len = u32::from_le_bytes(whole[record_len..record_len + 4].try_into().unwrap());
+
+        for damaged in [!len, len + record_len as u32 + 5] {
+            let mut bytes = whole.clone();
+            bytes[record_len..record_len + 4].copy_from_slice(&damaged.to_le_bytes());
+            fs::write(&path, &bytes).unwrap();
+
+            assert!(matches!(
+                Journal::open(&path, JournalSync::Always),
+                Err(PaymentsTransactionError::CorruptJournal(_))
+            ));
+            assert_eq!(fs::read(&path).unwrap(), bytes);
+        }
+    }
+
+    /// A write that fails partway through a record is taken back off the
+    /// file, so the next append follows the last whole record and the
+    /// journal still opens cleanly.
+    #[test]
+    fn test_journal_failed_append_is_rolled_back() {
+        let dir = TempDir::new().unwrap();
+        let path = journal_with(&dir, 1);
+        let (mut journal, _, _) = Journal::open(&path, JournalSync::Always).unwrap();
+
+        journal.fail_after_bytes = Some(RECORD_HEADER_LEN + 3);
+        assert!(journal.append(&deposit(2)).is_err());
+        assert_eq!(journal.append(&deposit(3)).unwrap(), 2);
+        drop(journal);
+
+        let (_, records, truncated) = Journal::open(&path, JournalSync::Always).unwrap();
+        assert_eq!(truncated, 0);
+        assert_eq!(
+            records,
+            vec![
+                JournalRecord {
+                    seq: 1,
+                    tx: deposit(1),
+                },
+                JournalRecord {
+                    seq: 2,
+                    tx: deposit(3),
+                },
+            ]
+        );
+    }
+
+    /// Sync policies parse from their CLI spellings.
+    #[test]
+    fn test_journal_sync_from_str() {
+        assert_eq!("always".parse(), Ok(JournalSync::Always));
+        assert_eq!("Never".parse(), Ok(JournalSync::Never));
+        assert_eq!("100".parse(), Ok(JournalSync::Every(100)));
+        assert!("0".parse::<JournalSync>().is_err());
+        assert!("sometimes".parse::<JournalSync>().is_err());
+    }
+}
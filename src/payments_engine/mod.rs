@@ -1,4 +1,14 @@
+pub mod config;
 pub mod engine;
+pub mod events;
+pub mod journal;
+pub mod outcome;
+pub mod rejections;
+pub mod reorder;
+pub mod report;
+mod sharded;
+pub mod snapshot;
+pub mod stream;
 
 #[cfg(test)]
 mod tests;
@@ -0,0 +1,129 @@
+/// This file defines what processing one row did to the engine, as returned
+/// by `PaymentsEngine::process_transaction`.
+use serde::Serialize;
+use strum::IntoStaticStr;
+
+use crate::account::client_account::ClientAccountDetails;
+use crate::errors::PaymentsTransactionError;
+use crate::payments_engine::report::AccountSummary;
+
+/// The outcome of processing one transaction row.
+#[derive(Debug, IntoStaticStr)]
+pub enum TransactionOutcome {
+    /// The row was applied to its client's account. `before` and `after` are
+    /// that account's balances either side of the row; `before` is all zeros
+    /// for an account the row opened.
+    Applied {
+        client: u16,
+        before: ClientAccountDetails,
+        after: ClientAccountDetails,
+    },
+    /// The row was one the spec says to ignore as an error on the partner's
+    /// side: a transaction ID its client already used, a reference to a
+    /// transaction that doesn't exist, or a dispute step the transaction isn't
+    /// in a state for. No balances changed.
+    Ignored { reason: PaymentsTransactionError },
+    /// The row was refused: it was invalid, broke a policy (such as reusing
+    /// another client's transaction ID), couldn't be afforded, or couldn't be
+    /// recorded. No balances changed.
+    Rejected { error: PaymentsTransactionError },
+}
+
+impl TransactionOutcome {
+    /// The outcome of a row that failed with `error`, sorted into ignored and
+    /// rejected by its cause.
+    pub fn from_error(error: PaymentsTransactionError) -> Self {
+        match error {
+            PaymentsTransactionError::DuplicateTransactionId(_)
+            | PaymentsTransactionError::TransactionDetailDoesNotExist(_)
+            | PaymentsTransactionError::IllegalStateTransition { .. } => {
+                TransactionOutcome::Ignored { reason: error }
+            }
+            error => TransactionOutcome::Rejected { error },
+        }
+    }
+
+    /// Name of the outcome: `Applied`, `Ignored` or `Rejected`.
+    pub fn status(&self) -> &'static str {
+        self.into()
+    }
+
+    /// Whether the row was applied.
+    pub fn is_applied(&self) -> bool {
+        matches!(self, TransactionOutcome::Applied { .. })
+    }
+
+    /// Whether the row was ignored.
+    pub fn is_ignored(&self) -> bool {
+        matches!(self, TransactionOutcome::Ignored { .. })
+    }
+
+    /// Whether the row was rejected.
+    pub fn is_rejected(&self) -> bool {
+        matches!(self, TransactionOutcome::Rejected { .. })
+    }
+
+    /// Why the row wasn't applied, if it wasn't.
+    pub fn error(&self) -> Option<&PaymentsTransactionError> {
+        match self {
+            TransactionOutcome::Applied { .. } => None,
+            TransactionOutcome::Ignored { reason } => Some(reason),
+            TransactionOutcome::Rejected { error } => Some(error),
+        }
+    }
+
+    /// `Ok` if the row was applied, or the error it was ignored or rejected
+    /// with, for callers that only need to know whether it went through.
+    pub fn into_result(self) -> Result<(), PaymentsTransactionError> {
+        match self {
+            TransactionOutcome::Applied { .. } => Ok(()),
+            TransactionOutcome::Ignored { reason } => Err(reason),
+            TransactionOutcome::Rejected { error } => Err(error),
+        }
+    }
+}
+
+/// A `TransactionOutcome` as the servers answer it: its status, then either
+/// the account's balances either side of an applied row, in the report's
+/// shape, or the error kind and message of a row that wasn't applied.
+#[derive(Debug, Serialize)]
+pub(crate) struct OutcomeReport {
+    pub(crate) outcome: &'static str,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub(crate) before: Option<AccountSummary>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub(crate) after: Option<AccountSummary>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub(crate) error: Option<&'static str>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub(crate) message: Option<String>,
+}
+
+impl OutcomeReport {
+    /// Reports `outcome`.
+    pub(crate) fn new(outcome: &TransactionOutcome) -> Self {
+        let (before, after) = match outcome {
+            TransactionOutcome::Applied {
+                client,
+                before,
+                after,
+            } => (
+                Some(AccountSummary::from_details(*client, before)),
+                Some(AccountSummary::from_details(*client, after)),
+            ),
+            _ => (None, None),
+        };
+        OutcomeReport {
+            outcome: outcome.status(),
+            before,
+            after,
+            error: outcome.error().map(PaymentsTransactionError::kind),
+            message: outcome.error().map(ToString::to_string),
+        }
+    }
+
+    /// Whether the row was applied.
+    pub(crate) fn is_applied(&self) -> bool {
+        self.error.is_none()
+    }
+}
@@ -0,0 +1,227 @@
+/// This file defines the structured record of input rows the payments engine
+/// refused to apply, and the writers used to export them after a run.
+use anyhow::{Context, Result};
+use serde::Serialize;
+use std::collections::BTreeMap;
+use std::io::Write;
+
+use crate::errors::PaymentsTransactionError;
+use crate::transaction::Transaction;
+
+/// A single input row the engine refused to apply, and why.
+#[derive(Debug)]
+pub struct Rejection {
+    /// Line number of the row in the input, starting at 1 for the header.
+    pub line: u64,
+    /// The row as it appeared in the input.
+    pub raw: String,
+    /// Client ID named by the row, if the row could be parsed.
+    pub client: Option<u16>,
+    /// Transaction ID named by the row, if the row could be parsed.
+    pub tx: Option<u32>,
+    /// Why the row was rejected.
+    pub error: PaymentsTransactionError,
+}
+
+impl Rejection {
+    /// Builds a rejection for a row that parsed into `tx` but failed to apply.
+    pub fn new(line: u64, raw: String, tx: &Transaction, error: PaymentsTransactionError) -> Self {
+        Rejection {
+            line,
+            raw,
+            client: Some(tx.client),
+            tx: Some(tx.tx),
+            error,
+        }
+    }
+
+    /// Builds a rejection for a row that could not be parsed into a transaction.
+    pub fn malformed(line: u64, raw: String, error: PaymentsTransactionError) -> Self {
+        Rejection {
+            line,
+            raw,
+            client: None,
+            tx: None,
+            error,
+        }
+    }
+}
+
+/// Flattened, serializable view of a `Rejection` used by the CSV and JSON writers.
+#[derive(Serialize)]
+struct RejectionRecord<'a> {
+    line: u64,
+    client: Option<u16>,
+    tx: Option<u32>,
+    error: &'static str,
+    message: String,
+    raw: &'a str,
+}
+
+impl<'a> From<&'a Rejection> for RejectionRecord<'a> {
+    fn from(rejection: &'a Rejection) -> Self {
+        RejectionRecord {
+            line: rejection.line,
+            client: rejection.client,
+            tx: rejection.tx,
+            error: rejection.error.kind(),
+            message: rejection.error.to_string(),
+            raw: &rejection.raw,
+        }
+    }
+}
+
+/// Every rejection recorded during a run, in input order.
+#[derive(Debug, Default)]
+pub struct RejectionReport {
+    rejections: Vec<Rejection>,
+}
+
+impl RejectionReport {
+    /// Creates an empty report.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Records a rejected row.
+    pub fn record(&mut self, rejection: Rejection) {
+        self.rejections.push(rejection);
+    }
+
+    /// Every recorded rejection, in input order.
+    pub fn rejections(&self) -> &[Rejection] {
+        &self.rejections
+    }
+
+    /// Number of rejected rows.
+    pub fn len(&self) -> usize {
+        self.rejections.len()
+    }
+
+    /// Whether no rows were rejected.
+    pub fn is_empty(&self) -> bool {
+        self.rejections.is_empty()
+    }
+
+    /// Number of rejections per `PaymentsTransactionError` variant, by variant name.
+    pub fn summary(&self) -> BTreeMap<&'static str, usize> {
+        let mut counts = BTreeMap::new();
+        for rejection in &self.rejections {
+            *counts.entry(rejection.error.kind()).or_insert(0) += 1;
+        }
+        counts
+    }
+
+    /// Writes every rejection as a CSV row with a header.
+    pub fn write_csv<W: Write>(&self, writer: W) -> Result<()> {
+        let mut wtr = csv::Writer::from_writer(writer);
+        for rejection in &self.rejections {
+            wtr.serialize(RejectionRecord::from(rejection))
+                .context("Error writing rejection to CSV")?;
+        }
+        wtr.flush().context("Error flushing rejections CSV")?;
+        Ok(())
+    }
+
+    /// Writes every rejection as a single JSON array.
+    pub fn write_json<W: Write>(&self, writer: W) -> Result<()> {
+        let records: Vec<RejectionRecord> = self.rejections.iter().map(Into::into).collect();
+        serde_json::to_writer_pretty(writer, &records).context("Error writing rejections JSON")?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod rejections_tests {
+    use super::*;
+    use crate::transaction::TransactionType;
+
+    fn sample_report() -> RejectionReport {
+        let withdrawal = Transaction {
+            tx_type: TransactionType::Withdrawal,
+            client: 2,
+            tx: 5,
+            amount: Some("3.0".parse().unwrap()),
+            timestamp: None,
+        };
+        let dispute = Transaction {
+            tx_type: TransactionType::Dispute,
+            client: 1,
+            tx: 9,
+            amount: None,
+            timestamp: None,
+        };
+        let mut report = RejectionReport::new();
+        report.record(Rejection::new(
+            6,
+            "withdrawal,2,5,3.0".to_string(),
+            &withdrawal,
+            PaymentsTransactionError::NotEnoughAvailableFunds("2".to_string()),
+        ));
+        report.record(Rejection::new(
+            7,
+            "dispute,1,9,".to_string(),
+            &dispute,
+            PaymentsTransactionError::TransactionDetailDoesNotExist("9".to_string()),
+        ));
+        report.record(Rejection::malformed(
+            8,
+            "deposit,x,1,1.0".to_string(),
+            PaymentsTransactionError::MalformedRow("invalid digit found in string".to_string()),
+        ));
+        report.record(Rejection::new(
+            9,
+            "withdrawal,2,5,3.0".to_string(),
+            &withdrawal,
+            PaymentsTransactionError::NotEnoughAvailableFunds("2".to_string()),
+        ));
+        report
+    }
+
+    /// The summary counts rejections per error variant.
+    #[test]
+    fn test_summary_counts_per_variant() {
+        let summary = sample_report().summary();
+
+        assert_eq!(summary.len(), 3);
+        assert_eq!(summary["NotEnoughAvailableFunds"], 2);
+        assert_eq!(summary["TransactionDetailDoesNotExist"], 1);
+        assert_eq!(summary["MalformedRow"], 1);
+    }
+
+    /// The CSV writer emits a header and one row per rejection, in input order,
+    /// leaving the client and tx columns empty for rows that couldn't be parsed.
+    #[test]
+    fn test_write_csv() {
+        let mut out = Vec::new();
+        sample_report().write_csv(&mut out).unwrap();
+        let csv = String::from_utf8(out).unwrap();
+        let lines: Vec<&str> = csv.lines().collect();
+
+        assert_eq!(lines.len(), 5);
+        assert_eq!(lines[0], "line,client,tx,error,message,raw");
+        assert_eq!(
+            lines[1],
+            "6,2,5,NotEnoughAvailableFunds,Not enough available funds for client 2,\"withdrawal,2,5,3.0\""
+        );
+        assert_eq!(
+            lines[3],
+            "8,,,MalformedRow,Malformed row: invalid digit found in string,\"deposit,x,1,1.0\""
+        );
+    }
+
+    /// The JSON writer emits an array of records with the variant name.
+    #[test]
+    fn test_write_json() {
+        let mut out = Vec::new();
+        sample_report().write_json(&mut out).unwrap();
+        let value: serde_json::Value = serde_json::from_slice(&out).unwrap();
+
+        assert_eq!(value.as_array().unwrap().len(), 4);
+        assert_eq!(value[1]["error"], "TransactionDetailDoesNotExist");
+        assert_eq!(value[1]["line"], 7);
+        assert_eq!(value[1]["raw"], "dispute,1,9,");
+        assert!(value[2]["client"].is_null());
+        assert_eq!(value[2]["error"], "MalformedRow");
+    }
+}
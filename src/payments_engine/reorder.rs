@@ -0,0 +1,143 @@
+/// This file defines a bounded reorder buffer that puts a slightly
+/// out-of-order feed of timestamped rows back into time order before they
+/// reach the payments engine.
+use std::cmp::{Ordering, Reverse};
+use std::collections::BinaryHeap;
+
+/// An item held in the buffer, ordered by timestamp and then by arrival.
+struct Buffered<T> {
+    timestamp: u64,
+    arrival: u64,
+    item: T,
+}
+
+impl<T> PartialEq for Buffered<T> {
+    fn eq(&self, other: &Self) -> bool {
+        self.cmp(other) == Ordering::Equal
+    }
+}
+
+impl<T> Eq for Buffered<T> {}
+
+impl<T> PartialOrd for Buffered<T> {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl<T> Ord for Buffered<T> {
+    fn cmp(&self, other: &Self) -> Ordering {
+        (self.timestamp, self.arrival).cmp(&(other.timestamp, other.arrival))
+    }
+}
+
+/// Holds back up to `capacity` items and releases them earliest timestamp
+/// first, so an item that arrives up to `capacity` places late is still
+/// released in time order. Items with equal timestamps keep their arrival
+/// order.
+///
+/// Items without a timestamp, such as rows that failed to parse, are released
+/// in arrival order relative to the items before them. A capacity of zero
+/// passes every item straight through.
+pub struct ReorderBuffer<T, I, K> {
+    inner: I,
+    timestamp_of: K,
+    capacity: usize,
+    buffered: BinaryHeap<Reverse<Buffered<T>>>,
+    /// Number of items taken from `inner` so far.
+    arrivals: u64,
+    /// Latest timestamp taken from `inner` so far.
+    latest: u64,
+}
+
+impl<T, I, K> ReorderBuffer<T, I, K>
+where
+    I: Iterator<Item = T>,
+    K: FnMut(&T) -> Option<u64>,
+{
+    /// Reorders `items` by the timestamp `timestamp_of` returns for each one,
+    /// holding back at most `capacity` of them at a time.
+    pub fn new<S>(items: S, capacity: usize, timestamp_of: K) -> Self
+    where
+        S: IntoIterator<IntoIter = I>,
+    {
+        ReorderBuffer {
+            inner: items.into_iter(),
+            timestamp_of,
+            capacity,
+            buffered: BinaryHeap::with_capacity(capacity + 1),
+            arrivals: 0,
+            latest: 0,
+        }
+    }
+}
+
+impl<T, I, K> Iterator for ReorderBuffer<T, I, K>
+where
+    I: Iterator<Item = T>,
+    K: FnMut(&T) -> Option<u64>,
+{
+    type Item = T;
+
+    fn next(&mut self) -> Option<T> {
+        while self.buffered.len() <= self.capacity {
+            let Some(item) = self.inner.next() else {
+                break;
+            };
+            // An item without a timestamp sorts as if it carried the latest
+            // one so far, so nothing that arrived before it is held behind it.
+            let timestamp = match (self.timestamp_of)(&item) {
+                Some(timestamp) => timestamp,
+                None => self.latest,
+            };
+            self.latest = self.latest.max(timestamp);
+            self.buffered.push(Reverse(Buffered {
+                timestamp,
+                arrival: self.arrivals,
+                item,
+            }));
+            self.arrivals += 1;
+        }
+        self.buffered.pop().map(|Reverse(buffered)| buffered.item)
+    }
+}
+
+#[cfg(test)]
+mod reorder_tests {
+    use super::*;
+
+    /// Reorders `(timestamp, label)` pairs with the given capacity.
+    fn reorder(items: &[(Option<u64>, char)], capacity: usize) -> String {
+        ReorderBuffer::new(items.iter().copied(), capacity, |(timestamp, _)| *timestamp)
+            .map(|(_, label)| label)
+            .collect()
+    }
+
+    /// Items up to `capacity` places late are put back in time order, ties
+    /// keep their arrival order, and nothing is dropped.
+    #[test]
+    fn test_reorder_within_capacity() {
+        let items = [
+            (Some(10), 'a'),
+            (Some(30), 'c'),
+            (Some(20), 'b'),
+            (Some(30), 'd'),
+            (Some(15), 'e'),
+        ];
+
+        assert_eq!(reorder(&items, 0), "acbde");
+        assert_eq!(reorder(&items, 1), "abced");
+        assert_eq!(reorder(&items, 2), "abecd");
+        assert_eq!(reorder(&items, 10), "aebcd");
+    }
+
+    /// Items without a timestamp stay behind everything that arrived before
+    /// them.
+    #[test]
+    fn test_reorder_items_without_timestamp() {
+        let items = [(Some(20), 'b'), (None, 'x'), (Some(10), 'a')];
+
+        assert_eq!(reorder(&items, 5), "abx");
+        assert_eq!(reorder(&[(None, 'x'), (None, 'y')], 5), "xy");
+    }
+}
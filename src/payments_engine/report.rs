@@ -0,0 +1,193 @@
+/// This file defines the per-client summary rows the payments engine reports
+/// once transactions have been processed, and the writer that outputs them.
+use anyhow::{Context, Result};
+use serde::Serialize;
+use std::io::Write;
+use strum::{Display, EnumString};
+
+use crate::account::client_account::{ClientAccount, ClientAccountDetails};
+use crate::money::Money;
+
+/// A point-in-time summary of one client account, in the spec's output shape.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+pub struct AccountSummary {
+    /// Client ID.
+    pub client: u16,
+    /// Funds available for trading, staking, withdrawal, etc.
+    #[serde(serialize_with = "Money::serialize_fixed")]
+    pub available: Money,
+    /// Funds held for dispute.
+    #[serde(serialize_with = "Money::serialize_fixed")]
+    pub held: Money,
+    /// Available plus held funds.
+    #[serde(serialize_with = "Money::serialize_fixed")]
+    pub total: Money,
+    /// Whether the account is locked after a chargeback.
+    pub locked: bool,
+}
+
+impl AccountSummary {
+    /// Builds the summary row for a client's account.
+    pub fn new(client: u16, account: &ClientAccount) -> Self {
+        Self::from_details(client, account.details())
+    }
+
+    /// Builds the summary row for a client from its account's balances.
+    pub fn from_details(client: u16, details: &ClientAccountDetails) -> Self {
+        AccountSummary {
+            client,
+            available: details.available_funds(),
+            held: details.held_funds(),
+            total: details.total_funds(),
+            locked: details.is_locked(),
+        }
+    }
+}
+
+/// Order in which account rows appear in a report.
+#[derive(Debug, Default, Display, EnumString, Clone, Copy, PartialEq, Eq)]
+#[strum(serialize_all = "kebab-case")]
+pub enum ReportOrder {
+    /// Ascending client ID.
+    #[default]
+    ClientId,
+    /// Descending total funds, ties broken by ascending client ID.
+    Total,
+    /// The order in which the engine first saw each client.
+    FirstSeen,
+}
+
+impl ReportOrder {
+    /// Sorts summaries into this order. `FirstSeen` relies on the summaries
+    /// already being in first-seen order and leaves them untouched.
+    pub fn sort(self, summaries: &mut [AccountSummary]) {
+        match self {
+            ReportOrder::ClientId => summaries.sort_by_key(|summary| summary.client),
+            ReportOrder::Total => summaries
+                .sort_by(|a, b| b.total.cmp(&a.total).then_with(|| a.client.cmp(&b.client))),
+            ReportOrder::FirstSeen => {}
+        }
+    }
+}
+
+/// Writes account summaries as CSV with a `client,available,held,total,locked`
+/// header, in the order given.
+pub fn write_csv_report<W: Write>(summaries: &[AccountSummary], writer: W) -> Result<()> {
+    let mut wtr = csv::Writer::from_writer(writer);
+    for summary in summaries {
+        wtr.serialize(summary)
+            .context("Error writing account to CSV report")?;
+    }
+    // The header is normally written with the first row; write it ourselves
+    // so an engine with no accounts still produces a valid report.
+    if summaries.is_empty() {
+        wtr.write_record(["client", "available", "held", "total", "locked"])
+            .context("Error writing CSV report header")?;
+    }
+    wtr.flush().context("Error flushing CSV report")?;
+    Ok(())
+}
+
+/// Writes account summaries as a single JSON array, in the order given.
+pub fn write_json_report<W: Write>(summaries: &[AccountSummary], writer: W) -> Result<()> {
+    serde_json::to_writer_pretty(writer, summaries).context("Error writing JSON report")?;
+    Ok(())
+}
+
+/// Writes account summaries as JSON Lines, one account object per line, in
+/// the order given.
+pub fn write_jsonl_report<W: Write>(summaries: &[AccountSummary], mut writer: W) -> Result<()> {
+    for summary in summaries {
+        serde_json::to_writer(&mut writer, summary).context("Error writing JSONL report")?;
+        writeln!(writer).context("Error writing JSONL report")?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod report_tests {
+    use super::*;
+
+    fn summary(client: u16, total: i64) -> AccountSummary {
+        AccountSummary {
+            client,
+            available: Money::from_scaled(total),
+            held: Money::ZERO,
+            total: Money::from_scaled(total),
+            locked: false,
+        }
+    }
+
+    /// Each ordering sorts as documented, with ties on total broken by client ID.
+    #[test]
+    fn test_report_order_sort() {
+        let first_seen = vec![summary(3, 10), summary(1, 50), summary(2, 50)];
+
+        let mut by_client = first_seen.clone();
+        ReportOrder::ClientId.sort(&mut by_client);
+        let clients: Vec<u16> = by_client.iter().map(|s| s.client).collect();
+        assert_eq!(clients, vec![1, 2, 3]);
+
+        let mut by_total = first_seen.clone();
+        ReportOrder::Total.sort(&mut by_total);
+        let clients: Vec<u16> = by_total.iter().map(|s| s.client).collect();
+        assert_eq!(clients, vec![1, 2, 3]);
+
+        let mut unchanged = first_seen.clone();
+        ReportOrder::FirstSeen.sort(&mut unchanged);
+        assert_eq!(unchanged, first_seen);
+    }
+
+    /// Orderings parse from their kebab-case CLI names.
+    #[test]
+    fn test_report_order_from_str() {
+        assert_eq!("client-id".parse(), Ok(ReportOrder::ClientId));
+        assert_eq!("total".parse(), Ok(ReportOrder::Total));
+        assert_eq!("first-seen".parse(), Ok(ReportOrder::FirstSeen));
+        assert!("random".parse::<ReportOrder>().is_err());
+    }
+
+    /// The CSV writer uses four decimal places and writes a header even when empty.
+    #[test]
+    fn test_write_csv_report() {
+        let mut out = Vec::new();
+        write_csv_report(&[summary(1, 15_000)], &mut out).unwrap();
+        assert_eq!(
+            String::from_utf8(out).unwrap(),
+            "client,available,held,total,locked\n1,1.5000,0.0000,1.5000,false\n"
+        );
+
+        let mut out = Vec::new();
+        write_csv_report(&[], &mut out).unwrap();
+        assert_eq!(
+            String::from_utf8(out).unwrap(),
+            "client,available,held,total,locked\n"
+        );
+    }
+
+    /// The JSON writer emits an array of summaries with four-decimal amounts.
+    #[test]
+    fn test_write_json_report() {
+        let mut out = Vec::new();
+        write_json_report(&[summary(1, 15_000)], &mut out).unwrap();
+        let value: serde_json::Value = serde_json::from_slice(&out).unwrap();
+
+        assert_eq!(value[0]["client"], 1);
+        assert_eq!(value[0]["total"], "1.5000");
+        assert_eq!(value[0]["locked"], false);
+    }
+
+    /// The JSONL writer emits one summary object per line, with the same
+    /// four-decimal amounts as the other formats.
+    #[test]
+    fn test_write_jsonl_report() {
+        let mut out = Vec::new();
+        write_jsonl_report(&[summary(1, 15_000), summary(2, 1)], &mut out).unwrap();
+
+        assert_eq!(
+            String::from_utf8(out).unwrap(),
+            "{\"client\":1,\"available\":\"1.5000\",\"held\":\"0.0000\",\"total\":\"1.5000\",\"locked\":false}\n\
+             {\"client\":2,\"available\":\"0.0001\",\"held\":\"0.0000\",\"total\":\"0.0001\",\"locked\":false}\n"
+        );
+    }
+}
@@ -0,0 +1,538 @@
+/// This file defines how the payments engine processes a batch of
+/// transactions across worker threads, each owning a shard of the clients.
+use log::warn;
+use std::any::Any;
+use std::collections::HashMap;
+use std::sync::Mutex;
+use std::sync::mpsc::{self, Receiver, SyncSender};
+use std::thread;
+
+use crate::account::client_account::ClientAccount;
+use crate::account::store::InMemoryAccountStore;
+use crate::errors::PaymentsTransactionError;
+use crate::payments_engine::config::DuplicateTransactionPolicy;
+use crate::payments_engine::engine::PaymentsEngine;
+use crate::transaction::{Transaction, TransactionType};
+
+/// Rows buffered per worker before the router blocks.
+const SHARD_QUEUE_DEPTH: usize = 1024;
+
+/// A client's claim on a deposit or withdrawal ID, with the positions in the
+/// batch it was made at and, once its owner's copy has been evicted and the
+/// ID released from the index, released after. Positions let a worker ignore
+/// claims and releases made by rows that come later in the input than its own.
+#[derive(Debug, Clone, Copy)]
+struct Claim {
+    owner: u16,
+    claimed_at: u64,
+    released_after: Option<u64>,
+}
+
+impl Claim {
+    fn new(owner: u16, claimed_at: u64) -> Self {
+        Claim {
+            owner,
+            claimed_at,
+            released_after: None,
+        }
+    }
+
+    /// Whether the claim holds for the row at position `seq`.
+    fn holds_at(&self, seq: u64) -> bool {
+        self.claimed_at < seq && self.released_after.is_none_or(|released| released >= seq)
+    }
+}
+
+/// Maps every claimed deposit and withdrawal ID to its latest claim.
+type Claims = Mutex<HashMap<u32, Claim>>;
+
+/// Work sent from the router to a shard worker.
+enum ShardMessage<T> {
+    /// A row to apply, with its position in the batch, and the position the
+    /// engine applies it at and the engine's clock before it.
+    Row {
+        seq: u64,
+        position: u64,
+        clock: Option<u64>,
+        tag: T,
+        tx: Transaction,
+    },
+    /// Acknowledge once every earlier row has been applied, and the shard has
+    /// evicted what the engine would have before the row at position `seq`,
+    /// given the engine's position and clock after the row before it.
+    Barrier {
+        seq: u64,
+        applied_rows: u64,
+        clock: Option<u64>,
+        ack: SyncSender<()>,
+    },
+}
+
+/// What a shard worker hands back once the batch is done.
+struct ShardResult<T> {
+    engine: PaymentsEngine,
+    rejected: Vec<(u64, T, PaymentsTransactionError)>,
+    /// Clients whose account this shard opened, with the position that opened it.
+    first_seen: Vec<(u64, u16)>,
+}
+
+/// The store of an engine that keeps it in memory, as every shard does.
+fn in_memory(
+    engine: &mut PaymentsEngine,
+) -> Result<&mut InMemoryAccountStore, PaymentsTransactionError> {
+    engine.store.as_in_memory_mut().ok_or_else(|| {
+        PaymentsTransactionError::AccountStore(
+            "batches are only sharded across in-memory stores".to_string(),
+        )
+    })
+}
+
+/// The accounts of an engine whose store is kept in memory.
+fn accounts_mut(
+    engine: &mut PaymentsEngine,
+) -> Result<&mut HashMap<u16, ClientAccount>, PaymentsTransactionError> {
+    Ok(&mut in_memory(engine)?.accounts)
+}
+
+/// The transaction index of an engine whose store is kept in memory.
+fn owners_mut(
+    engine: &mut PaymentsEngine,
+) -> Result<&mut HashMap<u32, u16>, PaymentsTransactionError> {
+    Ok(&mut in_memory(engine)?.owners)
+}
+
+/// Which shard owns a client.
+fn shard_of(client: u16, workers: usize) -> usize {
+    usize::from(client) % workers
+}
+
+/// The client that claimed `tx` before position `seq`, if it isn't `client`.
+fn earlier_foreign_claim(claims: &Claims, tx: u32, client: u16, seq: u64) -> Option<u16> {
+    let claims = claims
+        .lock()
+        .unwrap_or_else(|poisoned| poisoned.into_inner());
+    claims
+        .get(&tx)
+        .filter(|claim| claim.owner != client && claim.holds_at(seq))
+        .map(|claim| claim.owner)
+}
+
+/// Marks the claims on IDs the shard has released from its index since this
+/// was last called as released after position `seq`.
+fn release_claims(shard: &mut PaymentsEngine, claims: &Claims, seq: u64) {
+    let Some(released) = shard
+        .released
+        .as_mut()
+        .filter(|released| !released.is_empty())
+    else {
+        return;
+    };
+    let mut claims = claims
+        .lock()
+        .unwrap_or_else(|poisoned| poisoned.into_inner());
+    for (client, tx) in released.drain(..) {
+        if let Some(claim) = claims.get_mut(&tx)
+            && claim.owner == client
+            && claim.released_after.is_none()
+        {
+            claim.released_after = Some(seq);
+        }
+    }
+}
+
+/// Applies one row to a shard, checking it against claims made by other shards
+/// the way the sequential engine checks its own transaction index.
+fn apply_to_shard(
+    shard: &mut PaymentsEngine,
+    claims: &Claims,
+    seq: u64,
+    tx: Transaction,
+) -> Result<(), PaymentsTransactionError> {
+    let is_new_funds_movement = matches!(
+        tx.tx_type,
+        TransactionType::Deposit | TransactionType::Withdrawal
+    );
+    if is_new_funds_movement
+        && let Some(owner) = earlier_foreign_claim(claims, tx.tx, tx.client, seq)
+    {
+        match shard.config.duplicate_policy {
+            DuplicateTransactionPolicy::Reject => {
+                warn!(
+                    "Transaction ID {} already used by client {}, rejecting: {}",
+                    tx.tx, owner, &tx
+                );
+                return Err(
+                    PaymentsTransactionError::CrossClientDuplicateTransactionId {
+                        tx: tx.tx,
+                        client: tx.client,
+                        owner,
+                    },
+                );
+            }
+            DuplicateTransactionPolicy::Warn => {
+                warn!(
+                    "Transaction ID {} already used by client {}, applying anyway: {}",
+                    tx.tx, owner, &tx
+                );
+            }
+            DuplicateTransactionPolicy::Allow => {}
+        }
+    }
+
+    let result = shard.process_transaction(tx).into_result();
+    // An ID that made it into the client's history is claimed if no claim on
+    // it holds, just as the sequential engine indexes it.
+    let held = accounts_mut(shard)?
+        .get(&tx.client)
+        .is_some_and(|account| account.transaction_archive().contains(tx.tx));
+    if is_new_funds_movement && held {
+        let mut claims = claims
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner());
+        let claim = claims.entry(tx.tx).or_insert(Claim::new(tx.client, seq));
+        if seq < claim.claimed_at || claim.released_after.is_some_and(|released| released < seq) {
+            *claim = Claim::new(tx.client, seq);
+        }
+        // The shard couldn't see another shard's claim when it indexed the
+        // ID, so it may have claimed it for this client instead.
+        if claim.owner != tx.client {
+            owners_mut(shard)?.insert(tx.tx, claim.owner);
+        }
+    }
+    match result {
+        // The shard only indexes its own clients, so a reference to another
+        // shard's transaction looks unknown, or expired if this client reused
+        // the ID and its copy was evicted. Report it as the mismatch it is.
+        Err(
+            PaymentsTransactionError::TransactionDetailDoesNotExist(_)
+            | PaymentsTransactionError::DisputeWindowExpired(_),
+        ) if !is_new_funds_movement
+            && let Some(owner) = earlier_foreign_claim(claims, tx.tx, tx.client, seq) =>
+        {
+            Err(PaymentsTransactionError::TransactionClientMismatch {
+                tx: tx.tx,
+                client: tx.client,
+                owner,
+            })
+        }
+        result => result.map(|_| ()),
+    }
+}
+
+/// Brings a shard up to the engine's position and clock after the row before
+/// the one at position `seq`, evicting what the sequential engine would have
+/// evicted after that row. A shard is otherwise only told about positions
+/// when one of its own rows comes up.
+fn catch_up(
+    shard: &mut PaymentsEngine,
+    claims: &Claims,
+    seq: u64,
+    applied_rows: u64,
+    clock: Option<u64>,
+) {
+    shard.applied_rows = applied_rows;
+    shard.clock = clock;
+    // As in sequential processing, a failed eviction is retried after the
+    // next row rather than failing this one.
+    if let Err(e) = shard.evict_expired() {
+        warn!("Error evicting expired transactions: {}", e);
+    }
+    release_claims(shard, claims, seq - 1);
+}
+
+/// Runs a shard worker until the router hangs up.
+fn run_shard<T>(
+    mut shard: PaymentsEngine,
+    claims: &Claims,
+    rows: Receiver<ShardMessage<T>>,
+) -> ShardResult<T> {
+    let mut rejected = Vec::new();
+    let mut first_seen = Vec::new();
+    for message in rows {
+        match message {
+            ShardMessage::Row {
+                seq,
+                position,
+                clock,
+                tag,
+                tx,
+            } => {
+                // Archived transactions record the row's position, and windows
+                // are checked against the clock, across the whole engine, so
+                // dispute windows match sequential processing.
+                catch_up(&mut shard, claims, seq, position - 1, clock);
+                #[cfg(test)]
+                if shard.panic_on_tx == Some(tx.tx) {
+                    panic!("injected failure on transaction {}", tx.tx);
+                }
+                let is_new_client = shard.account_details(tx.client).is_none();
+                if let Err(e) = apply_to_shard(&mut shard, claims, seq, tx) {
+                    rejected.push((seq, tag, e));
+                }
+                release_claims(&mut shard, claims, seq);
+                if is_new_client && shard.account_details(tx.client).is_some() {
+                    first_seen.push((seq, tx.client));
+                }
+            }
+            ShardMessage::Barrier {
+                seq,
+                applied_rows,
+                clock,
+                ack,
+            } => {
+                // Rows on other shards are about to look at this shard's
+                // claims, so they must reflect every eviction before them.
+                catch_up(&mut shard, claims, seq, applied_rows, clock);
+                let _ = ack.send(());
+            }
+        }
+    }
+    ShardResult {
+        engine: shard,
+        rejected,
+        first_seen,
+    }
+}
+
+/// Whether a row has to wait for every earlier row to be applied first.
+///
+/// Rows only interact across clients through transaction IDs, so a row must
+/// wait if a different client has already used its ID in a deposit or
+/// withdrawal. `users` tracks the sole client to have done so, or `None`
+/// once several have.
+fn needs_barrier(users: &mut HashMap<u32, Option<u16>>, tx: &Transaction) -> bool {
+    let is_new_funds_movement = matches!(
+        tx.tx_type,
+        TransactionType::Deposit | TransactionType::Withdrawal
+    );
+    match users.get_mut(&tx.tx) {
+        Some(user) if *user == Some(tx.client) => false,
+        Some(user) => {
+            if is_new_funds_movement {
+                *user = None;
+            }
+            true
+        }
+        None => {
+            if is_new_funds_movement {
+                users.insert(tx.tx, Some(tx.client));
+            }
+            false
+        }
+    }
+}
+
+/// Records that `client` used `tx` before the batch, for `needs_barrier`.
+fn add_user(users: &mut HashMap<u32, Option<u16>>, tx: u32, client: u16) {
+    users
+        .entry(tx)
+        .and_modify(|user| {
+            if *user != Some(client) {
+                *user = None;
+            }
+        })
+        .or_insert(Some(client));
+}
+
+/// Splits the engine's clients across `workers` shards, applies the batch on
+/// one thread per shard, then merges every shard back into `engine`.
+///
+/// Rows for one client always go to the same shard in input order. Rows that
+/// touch an ID another client has used wait for every earlier row first, so
+/// the result matches applying the batch sequentially.
+///
+/// If a worker fails, the rows routed after it stop and the rest of the shards
+/// are merged back before the error is returned.
+pub(crate) fn process_sharded<T, I>(
+    engine: &mut PaymentsEngine,
+    workers: usize,
+    transactions: I,
+) -> Result<Vec<(T, PaymentsTransactionError)>, PaymentsTransactionError>
+where
+    T: Send,
+    I: IntoIterator<Item = (T, Transaction)>,
+{
+    let mut shards: Vec<PaymentsEngine> = (0..workers)
+        .map(|_| {
+            let mut shard = PaymentsEngine::with_config(engine.config.clone());
+            shard.released = Some(Vec::new());
+            #[cfg(test)]
+            {
+                shard.panic_on_tx = engine.panic_on_tx;
+            }
+            shard
+        })
+        .collect();
+    let mut accounts: Vec<(u16, ClientAccount)> = accounts_mut(engine)?.drain().collect();
+    accounts.sort_by_key(|(client, _)| *client);
+    for (client, account) in accounts {
+        accounts_mut(&mut shards[shard_of(client, workers)])?.insert(client, account);
+    }
+
+    // Claims made before this batch count as coming before every row in it.
+    // Each shard indexes the IDs its own clients hold under their global
+    // owner. An ID the index has already released stays unowned.
+    let mut users: HashMap<u32, Option<u16>> = HashMap::new();
+    let mut existing_claims = HashMap::new();
+    // An ID can still be indexed after its transaction has been evicted, so
+    // the index counts as use too.
+    let index = std::mem::take(owners_mut(engine)?);
+    for (&tx, &client) in &index {
+        owners_mut(&mut shards[shard_of(client, workers)])?.insert(tx, client);
+        existing_claims.insert(tx, Claim::new(client, 0));
+        add_user(&mut users, tx, client);
+    }
+    // Each shard evicts its own clients' transactions, and releases their IDs.
+    for entry in std::mem::take(&mut engine.expiry_queue) {
+        shards[shard_of(entry.1, workers)]
+            .expiry_queue
+            .insert(entry);
+    }
+    for entry in std::mem::take(&mut engine.retired_queue) {
+        shards[shard_of(entry.1, workers)]
+            .retired_queue
+            .insert(entry);
+    }
+    for shard in &mut shards {
+        let mut held = Vec::new();
+        for (&client, account) in accounts_mut(shard)?.iter() {
+            for (tx, _) in account.transaction_archive().transactions() {
+                held.push((tx, client));
+                add_user(&mut users, tx, client);
+            }
+        }
+        let owners = owners_mut(shard)?;
+        for (tx, _) in held {
+            if let Some(&owner) = index.get(&tx) {
+                owners.entry(tx).or_insert(owner);
+            }
+        }
+    }
+    let claims: Claims = Mutex::new(existing_claims);
+    // The router journals each row before handing it to a worker, so rows are
+    // journaled in input order and before they are applied.
+    let mut journal = engine.journal.take();
+    let mut journal_seq = engine.journal_seq;
+    let mut unjournaled = Vec::new();
+    let mut applied_rows = engine.applied_rows;
+    let mut clock = engine.clock;
+
+    let results: Vec<Result<ShardResult<T>, PaymentsTransactionError>> = thread::scope(|scope| {
+        let mut senders = Vec::with_capacity(workers);
+        let mut handles = Vec::with_capacity(workers);
+        for shard in shards {
+            let (sender, receiver) = mpsc::sync_channel(SHARD_QUEUE_DEPTH);
+            senders.push(sender);
+            let claims = &claims;
+            handles.push(scope.spawn(move || run_shard(shard, claims, receiver)));
+        }
+
+        for (seq, (tag, tx)) in (1..).zip(transactions) {
+            if let Some(journal) = &mut journal {
+                match journal.append(&tx) {
+                    Ok(appended) => journal_seq = appended,
+                    Err(e) => {
+                        unjournaled.push((seq, tag, e.into()));
+                        continue;
+                    }
+                }
+            }
+            if needs_barrier(&mut users, &tx) {
+                let (ack_sender, ack_receiver) = mpsc::sync_channel(workers);
+                for sender in &senders {
+                    let _ = sender.send(ShardMessage::Barrier {
+                        seq,
+                        applied_rows,
+                        clock,
+                        ack: ack_sender.clone(),
+                    });
+                }
+                drop(ack_sender);
+                // Ends early only if a worker has panicked, which the join below reports.
+                for _ in ack_receiver.iter().take(workers) {}
+            }
+            applied_rows += 1;
+            let row = ShardMessage::Row {
+                seq,
+                position: applied_rows,
+                clock,
+                tag,
+                tx,
+            };
+            if let Some(timestamp) = tx.timestamp {
+                clock = clock.max(Some(timestamp));
+            }
+            if senders[shard_of(tx.client, workers)].send(row).is_err() {
+                break;
+            }
+        }
+        drop(senders);
+
+        handles
+            .into_iter()
+            .map(|handle| handle.join().map_err(shard_panic))
+            .collect()
+    });
+
+    let claims = claims
+        .into_inner()
+        .unwrap_or_else(|poisoned| poisoned.into_inner());
+    *owners_mut(engine)? = claims
+        .into_iter()
+        .filter(|(_, claim)| claim.released_after.is_none())
+        .map(|(tx, claim)| (tx, claim.owner))
+        .collect();
+
+    engine.journal = journal;
+    engine.journal_seq = journal_seq;
+    engine.applied_rows = applied_rows;
+    engine.clock = clock;
+
+    let mut rejected = unjournaled;
+    let mut first_seen = Vec::new();
+    let mut failure = None;
+    for result in results {
+        // A failed shard takes its clients with it, but the rest of the batch
+        // is still merged so the engine keeps what the other shards applied.
+        let result = match result {
+            Ok(result) => result,
+            Err(e) => {
+                failure.get_or_insert(e);
+                continue;
+            }
+        };
+        let mut shard = result.engine;
+        accounts_mut(engine)?.extend(accounts_mut(&mut shard)?.drain());
+        engine.expiry_queue.append(&mut shard.expiry_queue);
+        engine.retired_queue.append(&mut shard.retired_queue);
+        rejected.extend(result.rejected);
+        first_seen.extend(result.first_seen);
+    }
+    // Each shard only evicted as far as its own last row, so catch up with
+    // the end of the batch.
+    if let Err(e) = engine.evict_expired() {
+        warn!("Error evicting expired transactions: {}", e);
+    }
+    first_seen.sort_unstable();
+    engine
+        .client_order
+        .extend(first_seen.into_iter().map(|(_, client)| client));
+    if let Some(e) = failure {
+        return Err(e);
+    }
+    rejected.sort_unstable_by_key(|(seq, _, _)| *seq);
+    Ok(rejected
+        .into_iter()
+        .map(|(_, tag, error)| (tag, error))
+        .collect())
+}
+
+/// The error reported for a shard worker that panicked, with its message.
+fn shard_panic(payload: Box<dyn Any + Send>) -> PaymentsTransactionError {
+    let message = payload
+        .downcast_ref::<&str>()
+        .map(|message| message.to_string())
+        .or_else(|| payload.downcast_ref::<String>().cloned())
+        .unwrap_or_else(|| "worker panicked".to_string());
+    PaymentsTransactionError::ShardFailed(message)
+}
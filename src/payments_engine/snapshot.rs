@@ -0,0 +1,306 @@
+/// This file defines the on-disk snapshot format for the payments engine's
+/// state, so a run can resume from an earlier run instead of replaying it.
+use anyhow::{Context, Result};
+use serde::ser::{Error as _, SerializeMap, SerializeSeq, Serializer};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use std::collections::{BTreeMap, HashMap};
+use std::ffi::OsString;
+use std::fs::{self, File};
+use std::io::{BufWriter, Read, Write};
+use std::path::{Path, PathBuf};
+
+use crate::account::client_account::ClientAccount;
+use crate::errors::PaymentsTransactionError;
+use crate::payments_engine::engine::PaymentsEngine;
+
+/// Version of the snapshot format written by this build. Bump it whenever the
+/// shape of the snapshot changes; older versions are refused rather than
+/// misread.
+///
+/// Version 2 added each archived transaction's position and timestamp, the
+/// applied row count, the clock and the retired index entries. Version 1
+/// snapshots can't restore dispute windows, so they are refused too.
+pub const SNAPSHOT_VERSION: u64 = 2;
+
+/// One client's account within a snapshot.
+#[derive(Debug, Serialize, Deserialize)]
+struct AccountEntry<A> {
+    client: u16,
+    #[serde(flatten)]
+    account: A,
+}
+
+/// Everything the engine has learned from the transactions it has applied.
+/// Configuration isn't part of the snapshot, so a resumed run uses whatever
+/// policies it was started with.
+#[derive(Debug, Serialize, Deserialize)]
+struct EngineSnapshot<A, I> {
+    version: u64,
+    /// Client IDs in the order the engine first opened their accounts.
+    client_order: Vec<u16>,
+    /// Every account, ordered by client ID.
+    accounts: A,
+    /// Owner of every applied deposit and withdrawal ID, ordered by ID.
+    transaction_index: I,
+    /// Sequence number of the last journal record the state includes, or zero
+    /// if the engine wasn't journaling.
+    journal_seq: u64,
+    /// Number of rows applied to the state, which archived transactions'
+    /// positions count up to.
+    applied_rows: u64,
+    /// Latest timestamp on any row applied to the state.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    clock: Option<u64>,
+    /// Evicted transactions whose IDs are still in the index, as
+    /// `(key, client, tx)`, where the key is when the window they are released
+    /// after starts.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    retired: Vec<(u64, u16, u32)>,
+}
+
+/// Every account in an engine's store, serialized one at a time in client ID
+/// order so a store that keeps archives on disk never has to load them all.
+struct StoredAccounts<'a>(&'a PaymentsEngine);
+
+impl Serialize for StoredAccounts<'_> {
+    fn serialize<S>(&self, s: S) -> Result<S::Ok, S::Error>
+    where
+        S: Serializer,
+    {
+        let mut clients = self.0.store.clients();
+        clients.sort_unstable();
+        let mut seq = s.serialize_seq(Some(clients.len()))?;
+        for client in clients {
+            let account = self.0.account(client).map_err(S::Error::custom)?;
+            if let Some(account) = account {
+                seq.serialize_element(&AccountEntry { client, account })?;
+            }
+        }
+        seq.end()
+    }
+}
+
+/// The transaction index in an engine's store, serialized one entry at a time
+/// in ID order so a store that keeps it on disk never has to load it all.
+struct StoredOwners<'a>(&'a PaymentsEngine);
+
+impl Serialize for StoredOwners<'_> {
+    fn serialize<S>(&self, s: S) -> Result<S::Ok, S::Error>
+    where
+        S: Serializer,
+    {
+        let mut map = s.serialize_map(None)?;
+        for owner in self.0.store.owners().map_err(S::Error::custom)? {
+            let (tx, client) = owner.map_err(S::Error::custom)?;
+            map.serialize_entry(&tx, &client)?;
+        }
+        map.end()
+    }
+}
+
+/// The engine state restored from a snapshot.
+pub(crate) struct RestoredState {
+    pub(crate) client_account_lookup: HashMap<u16, ClientAccount>,
+    pub(crate) client_order: Vec<u16>,
+    pub(crate) transaction_index: HashMap<u32, u16>,
+    pub(crate) journal_seq: u64,
+    pub(crate) applied_rows: u64,
+    pub(crate) clock: Option<u64>,
+    pub(crate) retired: Vec<(u64, u16, u32)>,
+}
+
+/// Writes the engine's state as a versioned JSON snapshot. Accounts, archive
+/// entries and index entries are written in ID order, so the same state always
+/// produces the same bytes.
+pub(crate) fn write_snapshot<W: Write>(
+    engine: &PaymentsEngine,
+    writer: W,
+) -> serde_json::Result<()> {
+    let snapshot = EngineSnapshot {
+        version: SNAPSHOT_VERSION,
+        client_order: engine.client_order.clone(),
+        accounts: StoredAccounts(engine),
+        transaction_index: StoredOwners(engine),
+        journal_seq: engine.journal_seq,
+        applied_rows: engine.applied_rows,
+        clock: engine.clock,
+        retired: engine.retired_queue.iter().copied().collect(),
+    };
+    serde_json::to_writer(writer, &snapshot)
+}
+
+/// Writes a snapshot to `path` without ever leaving a partial one there: it is
+/// written and synced beside `path` first, then renamed into place.
+pub(crate) fn write_snapshot_file(engine: &PaymentsEngine, path: &Path) -> Result<()> {
+    let mut partial = OsString::from(path.as_os_str());
+    partial.push(".partial");
+    let partial = PathBuf::from(partial);
+
+    let file =
+        File::create(&partial).with_context(|| format!("error creating {}", partial.display()))?;
+    let mut writer = BufWriter::new(file);
+    engine.save_snapshot(&mut writer)?;
+    let file = writer.into_inner().map_err(|e| e.into_error())?;
+    file.sync_all()?;
+    fs::rename(&partial, path)
+        .with_context(|| format!("error saving snapshot {}", path.display()))?;
+    // Sync the directory too, so the rename itself survives a crash.
+    #[cfg(unix)]
+    if let Some(dir) = path.parent() {
+        let dir = if dir.as_os_str().is_empty() {
+            Path::new(".")
+        } else {
+            dir
+        };
+        File::open(dir)?.sync_all()?;
+    }
+    Ok(())
+}
+
+/// Reads a snapshot written by `write_snapshot`, refusing one written in a
+/// different format version or whose client order doesn't match its accounts.
+pub(crate) fn read_snapshot<R: Read>(reader: R) -> Result<RestoredState, PaymentsTransactionError> {
+    let invalid = |e: serde_json::Error| PaymentsTransactionError::InvalidSnapshot(e.to_string());
+    // Check the version before the rest, so a newer format is reported as such
+    // rather than as whichever field first fails to parse.
+    let value: Value = serde_json::from_reader(reader).map_err(invalid)?;
+    let version = value
+        .get("version")
+        .and_then(Value::as_u64)
+        .ok_or_else(|| {
+            PaymentsTransactionError::InvalidSnapshot("missing format version".to_string())
+        })?;
+    if version != SNAPSHOT_VERSION {
+        return Err(PaymentsTransactionError::UnsupportedSnapshotVersion {
+            found: version,
+            expected: SNAPSHOT_VERSION,
+        });
+    }
+    let snapshot: EngineSnapshot<Vec<AccountEntry<ClientAccount>>, BTreeMap<u32, u16>> =
+        serde_json::from_value(value).map_err(invalid)?;
+
+    let client_account_lookup: HashMap<u16, ClientAccount> = snapshot
+        .accounts
+        .into_iter()
+        .map(|entry| (entry.client, entry.account))
+        .collect();
+    let mut ordered: Vec<u16> = snapshot.client_order.clone();
+    ordered.sort_unstable();
+    ordered.dedup();
+    if ordered.len() != snapshot.client_order.len()
+        || ordered.len() != client_account_lookup.len()
+        || !ordered
+            .iter()
+            .all(|client| client_account_lookup.contains_key(client))
+    {
+        return Err(PaymentsTransactionError::InvalidSnapshot(
+            "client order doesn't match the accounts".to_string(),
+        ));
+    }
+
+    Ok(RestoredState {
+        client_account_lookup,
+        client_order: snapshot.client_order,
+        transaction_index: snapshot.transaction_index.into_iter().collect(),
+        journal_seq: snapshot.journal_seq,
+        applied_rows: snapshot.applied_rows,
+        clock: snapshot.clock,
+        retired: snapshot.retired,
+    })
+}
+
+#[cfg(test)]
+mod snapshot_tests {
+    use super::*;
+    use crate::transaction::{Transaction, TransactionType};
+
+    fn engine_with_dispute() -> PaymentsEngine {
+        let mut engine = PaymentsEngine::new();
+        for (tx_type, client, tx, amount) in [
+            (TransactionType::Deposit, 2, 1, Some("5.0")),
+            (TransactionType::Deposit, 1, 2, Some("1.25")),
+            (TransactionType::Dispute, 2, 1, None),
+        ] {
+            engine
+                .process_transaction(Transaction {
+                    tx_type,
+                    client,
+                    tx,
+                    amount: amount.map(|amount| amount.parse().unwrap()),
+                    timestamp: None,
+                })
+                .into_result()
+                .unwrap();
+        }
+        engine
+    }
+
+    /// A snapshot round-trips to the same bytes and the same report.
+    #[test]
+    fn test_snapshot_round_trip() {
+        let engine = engine_with_dispute();
+        let mut saved = Vec::new();
+        engine.save_snapshot(&mut saved).unwrap();
+
+        let mut restored = PaymentsEngine::new();
+        restored.load_snapshot(saved.as_slice()).unwrap();
+        let mut resaved = Vec::new();
+        restored.save_snapshot(&mut resaved).unwrap();
+
+        assert_eq!(resaved, saved);
+        assert_eq!(restored.to_string(), engine.to_string());
+        assert_eq!(restored.transaction_owner(1).unwrap(), Some(2));
+        assert!(
+            restored
+                .account(2)
+                .unwrap()
+                .unwrap()
+                .transaction_archive()
+                .is_disputed(1)
+        );
+    }
+
+    /// A snapshot from another format version, including the first one, is
+    /// refused, and leaves the engine's state untouched.
+    #[test]
+    fn test_snapshot_version_mismatch() {
+        let mut engine = engine_with_dispute();
+        let before = engine.to_string();
+        let snapshot = r#"{"version":1,"client_order":[],"accounts":[],"transaction_index":{}}"#;
+
+        let result = engine.load_snapshot(snapshot.as_bytes());
+
+        assert!(matches!(
+            result,
+            Err(PaymentsTransactionError::UnsupportedSnapshotVersion {
+                found: 1,
+                expected: SNAPSHOT_VERSION
+            })
+        ));
+        assert_eq!(engine.to_string(), before);
+    }
+
+    /// Truncated or inconsistent snapshots are reported as invalid.
+    #[test]
+    fn test_invalid_snapshot() {
+        let mut saved = Vec::new();
+        engine_with_dispute().save_snapshot(&mut saved).unwrap();
+        let truncated = &saved[..saved.len() / 2];
+        let missing_account = r#"{"version":2,"client_order":[7],"accounts":[],"transaction_index":{},"journal_seq":0,"applied_rows":0}"#;
+        let missing_rows =
+            r#"{"version":2,"client_order":[],"accounts":[],"transaction_index":{}}"#;
+
+        for snapshot in [
+            truncated,
+            missing_account.as_bytes(),
+            missing_rows.as_bytes(),
+            b"{}",
+        ] {
+            assert!(matches!(
+                PaymentsEngine::new().load_snapshot(snapshot),
+                Err(PaymentsTransactionError::InvalidSnapshot(_))
+            ));
+        }
+    }
+}
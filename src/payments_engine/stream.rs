@@ -0,0 +1,660 @@
+/// This file defines an asynchronous interface to the payments engine: a
+/// stream of transactions goes in and a stream of per-row outcomes comes out,
+/// through bounded channels so a slow consumer holds back the producer.
+///
+/// The engine runs on a dedicated thread, so applying rows (including
+/// journal writes and disk store reads) never blocks an async executor. Only
+/// `std::task` wakers are used, so the types work under tokio or any other
+/// executor. The ends implement `futures_core::Stream` and
+/// `futures_sink::Sink`, so the `futures` and tokio stream combinators apply.
+use futures_core::Stream;
+use futures_sink::Sink;
+use log::error;
+use std::collections::VecDeque;
+use std::fmt;
+use std::future::Future;
+use std::pin::Pin;
+use std::sync::{Arc, Condvar, Mutex, MutexGuard, PoisonError};
+use std::task::{Context, Poll, Waker};
+use std::thread::{self, JoinHandle};
+
+use crate::errors::PaymentsTransactionError;
+use crate::payments_engine::engine::PaymentsEngine;
+use crate::payments_engine::outcome::TransactionOutcome;
+use crate::transaction::Transaction;
+
+/// The outcome of one row: the transaction and what applying it did.
+#[derive(Debug)]
+pub struct RowOutcome {
+    /// The transaction that was applied.
+    pub transaction: Transaction,
+    /// Whether it was applied, ignored or rejected.
+    pub outcome: TransactionOutcome,
+}
+
+/// Items queued in a bounded channel, and who is waiting on it.
+struct ChannelState<T> {
+    queue: VecDeque<T>,
+    capacity: usize,
+    /// The sending side has finished.
+    closed: bool,
+    /// The receiving side is gone, so nothing sent will be read.
+    abandoned: bool,
+    /// Task waiting for an item to arrive.
+    receive_waker: Option<Waker>,
+    /// Task waiting for room to send.
+    send_waker: Option<Waker>,
+}
+
+/// A bounded single-producer, single-consumer channel whose ends can each be
+/// used from async code or from a thread that blocks.
+struct Channel<T> {
+    state: Mutex<ChannelState<T>>,
+    /// Signalled whenever the state changes, for the blocking ends.
+    changed: Condvar,
+}
+
+impl<T> Channel<T> {
+    fn new(capacity: usize) -> Arc<Self> {
+        Arc::new(Channel {
+            state: Mutex::new(ChannelState {
+                queue: VecDeque::with_capacity(capacity),
+                capacity,
+                closed: false,
+                abandoned: false,
+                receive_waker: None,
+                send_waker: None,
+            }),
+            changed: Condvar::new(),
+        })
+    }
+
+    /// Locks the state. Every change leaves it consistent before anything
+    /// that can panic, such as a waker, runs, so a poisoned lock is recovered.
+    fn lock(&self) -> MutexGuard<'_, ChannelState<T>> {
+        self.state.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    /// Waits on `changed`, recovering a poisoned lock as `lock` does.
+    fn wait<'a>(&self, state: MutexGuard<'a, ChannelState<T>>) -> MutexGuard<'a, ChannelState<T>> {
+        self.changed
+            .wait(state)
+            .unwrap_or_else(PoisonError::into_inner)
+    }
+
+    /// Wakes every waiter after a change to `state`.
+    fn notify(&self, state: &mut ChannelState<T>) {
+        if let Some(waker) = state.receive_waker.take() {
+            waker.wake();
+        }
+        if let Some(waker) = state.send_waker.take() {
+            waker.wake();
+        }
+        self.changed.notify_all();
+    }
+
+    /// Resolves to `true` once there is room to send, or `false` if the
+    /// receiving side is gone.
+    fn poll_ready(&self, cx: &mut Context<'_>) -> Poll<bool> {
+        let mut state = self.lock();
+        if state.abandoned {
+            return Poll::Ready(false);
+        }
+        if state.queue.len() < state.capacity {
+            return Poll::Ready(true);
+        }
+        state.send_waker = Some(cx.waker().clone());
+        Poll::Pending
+    }
+
+    /// Queues an item once `poll_ready` has found room for it.
+    fn push(&self, item: T) {
+        let mut state = self.lock();
+        state.queue.push_back(item);
+        self.notify(&mut state);
+    }
+
+    /// Queues an item, blocking while the channel is full. Gives the item
+    /// back if the receiving side is gone.
+    fn send_blocking(&self, item: T) -> Result<(), T> {
+        let mut state = self.lock();
+        while !state.abandoned && state.queue.len() >= state.capacity {
+            state = self.wait(state);
+        }
+        if state.abandoned {
+            return Err(item);
+        }
+        state.queue.push_back(item);
+        self.notify(&mut state);
+        Ok(())
+    }
+
+    /// Resolves to the next item, or `None` once the channel is closed and
+    /// drained.
+    fn poll_receive(&self, cx: &mut Context<'_>) -> Poll<Option<T>> {
+        let mut state = self.lock();
+        if let Some(item) = state.queue.pop_front() {
+            self.notify(&mut state);
+            return Poll::Ready(Some(item));
+        }
+        if state.closed {
+            return Poll::Ready(None);
+        }
+        state.receive_waker = Some(cx.waker().clone());
+        Poll::Pending
+    }
+
+    /// Takes the next item, blocking while the channel is empty. Returns
+    /// `None` once the channel is closed and drained.
+    fn receive_blocking(&self) -> Option<T> {
+        let mut state = self.lock();
+        loop {
+            if let Some(item) = state.queue.pop_front() {
+                self.notify(&mut state);
+                return Some(item);
+            }
+            if state.closed {
+                return None;
+            }
+            state = self.wait(state);
+        }
+    }
+
+    /// Marks the sending side as finished.
+    fn close(&self) {
+        let mut state = self.lock();
+        state.closed = true;
+        self.notify(&mut state);
+    }
+
+    /// Marks the receiving side as gone and drops anything still queued.
+    fn abandon(&self) {
+        let mut state = self.lock();
+        state.abandoned = true;
+        state.queue.clear();
+        self.notify(&mut state);
+    }
+}
+
+/// Sends transactions to an engine started with `spawn_engine`. Closing or
+/// dropping it tells the engine no more are coming. As a `Sink`, it fails
+/// with `EngineStopped` once the engine has stopped.
+pub struct TransactionSink {
+    channel: Arc<Channel<Transaction>>,
+}
+
+impl fmt::Debug for TransactionSink {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("TransactionSink").finish_non_exhaustive()
+    }
+}
+
+impl TransactionSink {
+    /// Sends a transaction, waiting while the engine's input is full. Gives
+    /// the transaction back if the engine has stopped.
+    pub fn send(&mut self, transaction: Transaction) -> SendTransaction<'_> {
+        SendTransaction {
+            sink: self,
+            transaction: Some(transaction),
+        }
+    }
+}
+
+impl Sink<Transaction> for TransactionSink {
+    type Error = PaymentsTransactionError;
+
+    fn poll_ready(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.channel.poll_ready(cx).map(|ready| {
+            ready
+                .then_some(())
+                .ok_or(PaymentsTransactionError::EngineStopped)
+        })
+    }
+
+    fn start_send(self: Pin<&mut Self>, transaction: Transaction) -> Result<(), Self::Error> {
+        self.channel.push(transaction);
+        Ok(())
+    }
+
+    /// Transactions are handed to the engine as soon as they are sent, so
+    /// there is nothing to flush.
+    fn poll_flush(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn poll_close(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.channel.close();
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl Drop for TransactionSink {
+    fn drop(&mut self) {
+        self.channel.close();
+    }
+}
+
+/// Future returned by `TransactionSink::send`.
+#[derive(Debug)]
+pub struct SendTransaction<'a> {
+    sink: &'a mut TransactionSink,
+    transaction: Option<Transaction>,
+}
+
+impl Future for SendTransaction<'_> {
+    type Output = Result<(), Transaction>;
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        let ready = match self.sink.channel.poll_ready(cx) {
+            Poll::Ready(ready) => ready,
+            Poll::Pending => return Poll::Pending,
+        };
+        let transaction = self
+            .transaction
+            .take()
+            .expect("SendTransaction polled after completion");
+        if ready {
+            self.sink.channel.push(transaction);
+            Poll::Ready(Ok(()))
+        } else {
+            Poll::Ready(Err(transaction))
+        }
+    }
+}
+
+/// The outcome of every row sent to an engine started with `spawn_engine`, in
+/// the order they were sent. Ends once the `TransactionSink` has been dropped
+/// and every row has been applied.
+pub struct OutcomeStream {
+    channel: Arc<Channel<RowOutcome>>,
+}
+
+impl fmt::Debug for OutcomeStream {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("OutcomeStream").finish_non_exhaustive()
+    }
+}
+
+impl Stream for OutcomeStream {
+    type Item = RowOutcome;
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<RowOutcome>> {
+        self.channel.poll_receive(cx)
+    }
+}
+
+impl Drop for OutcomeStream {
+    fn drop(&mut self) {
+        // The engine keeps applying rows; their outcomes are just discarded.
+        self.channel.abandon();
+    }
+}
+
+/// The thread an engine started with `spawn_engine` runs on.
+#[derive(Debug)]
+pub struct EngineTask {
+    thread: JoinHandle<PaymentsEngine>,
+}
+
+impl EngineTask {
+    /// Waits for the engine to apply every row it was sent and returns it.
+    /// This blocks until the `TransactionSink` has been dropped, so call it
+    /// once the `OutcomeStream` has ended. Fails with `EngineStopped` if the
+    /// engine thread panicked, taking the engine with it.
+    pub fn join(self) -> Result<PaymentsEngine, PaymentsTransactionError> {
+        self.thread.join().map_err(|_| {
+            error!("Engine thread panicked");
+            PaymentsTransactionError::EngineStopped
+        })
+    }
+}
+
+/// Abandons a channel when dropped.
+struct AbandonOnDrop<T>(Arc<Channel<T>>);
+
+impl<T> Drop for AbandonOnDrop<T> {
+    fn drop(&mut self) {
+        self.0.abandon();
+    }
+}
+
+/// Closes a channel when dropped.
+struct CloseOnDrop<T>(Arc<Channel<T>>);
+
+impl<T> Drop for CloseOnDrop<T> {
+    fn drop(&mut self) {
+        self.0.close();
+    }
+}
+
+/// Moves `engine` onto its own thread and returns the ends of its bounded
+/// input and output channels, each holding up to `capacity` rows (at least
+/// one). Once both are full the sender waits, so the producer never gets
+/// further than about twice `capacity` rows ahead of the consumer.
+pub fn spawn_engine(
+    mut engine: PaymentsEngine,
+    capacity: usize,
+) -> (TransactionSink, OutcomeStream, EngineTask) {
+    let capacity = capacity.max(1);
+    let input = Channel::new(capacity);
+    let output = Channel::new(capacity);
+    let sink = TransactionSink {
+        channel: Arc::clone(&input),
+    };
+    let outcomes = OutcomeStream {
+        channel: Arc::clone(&output),
+    };
+    let thread = thread::spawn(move || {
+        // Tells the sender the engine has stopped, and ends the outcomes,
+        // even if it panics.
+        let input = AbandonOnDrop(input);
+        let output = CloseOnDrop(output);
+        while let Some(transaction) = input.0.receive_blocking() {
+            let outcome = engine.process_transaction(transaction);
+            // An error means no one is reading outcomes anymore, which
+            // doesn't stop the rows from being applied.
+            let _ = output.0.send_blocking(RowOutcome {
+                transaction,
+                outcome,
+            });
+        }
+        engine
+    });
+    (sink, outcomes, EngineTask { thread })
+}
+
+/// Applies every transaction of `input` to `engine` and yields the outcome of
+/// each, in order, through channels of `capacity` rows. The input is only
+/// polled while there is room, so a slow consumer holds back the source.
+pub fn process_stream<S>(engine: PaymentsEngine, input: S, capacity: usize) -> ProcessStream<S>
+where
+    S: Stream<Item = Transaction> + Unpin,
+{
+    let (sink, outcomes, task) = spawn_engine(engine, capacity);
+    ProcessStream {
+        input,
+        pending: None,
+        sink: Some(sink),
+        outcomes,
+        task,
+    }
+}
+
+/// Stream returned by `process_stream`.
+#[derive(Debug)]
+pub struct ProcessStream<S> {
+    input: S,
+    /// A transaction taken from `input` that the engine had no room for yet.
+    pending: Option<Transaction>,
+    /// Dropped once `input` has ended.
+    sink: Option<TransactionSink>,
+    outcomes: OutcomeStream,
+    task: EngineTask,
+}
+
+impl<S> ProcessStream<S> {
+    /// Stops feeding the engine and returns it once it has applied every row
+    /// already sent to it. Outcomes not yet read are discarded. Fails with
+    /// `EngineStopped` if the engine thread panicked.
+    pub fn into_engine(self) -> Result<PaymentsEngine, PaymentsTransactionError> {
+        let ProcessStream {
+            sink,
+            outcomes,
+            task,
+            ..
+        } = self;
+        drop(sink);
+        drop(outcomes);
+        task.join()
+    }
+}
+
+impl<S> Stream for ProcessStream<S>
+where
+    S: Stream<Item = Transaction> + Unpin,
+{
+    type Item = RowOutcome;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<RowOutcome>> {
+        let this = &mut *self;
+        // Feed the engine until the input runs dry or the channel fills up.
+        while let Some(sink) = &mut this.sink {
+            if this.pending.is_none() {
+                match Pin::new(&mut this.input).poll_next(cx) {
+                    Poll::Ready(Some(transaction)) => this.pending = Some(transaction),
+                    Poll::Ready(None) => {
+                        this.sink = None;
+                        break;
+                    }
+                    Poll::Pending => break,
+                }
+            }
+            match sink.channel.poll_ready(cx) {
+                Poll::Ready(true) => {
+                    if let Some(transaction) = this.pending.take() {
+                        sink.channel.push(transaction);
+                    }
+                }
+                Poll::Ready(false) => {
+                    this.sink = None;
+                    break;
+                }
+                Poll::Pending => break,
+            }
+        }
+        Pin::new(&mut this.outcomes).poll_next(cx)
+    }
+}
+
+#[cfg(test)]
+mod stream_tests {
+    use super::*;
+    use crate::payments_engine::events::{DomainEvent, EventSubscriber};
+    use crate::transaction::TransactionType;
+    use futures::executor::block_on;
+    use futures::{SinkExt, StreamExt, stream};
+    use std::sync::Arc;
+    use std::task::Wake;
+
+    /// Wakes a parked thread.
+    struct ThreadWaker(thread::Thread);
+
+    impl Wake for ThreadWaker {
+        fn wake(self: Arc<Self>) {
+            self.0.unpark();
+        }
+    }
+
+    fn row(tx_type: TransactionType, client: u16, tx: u32, amount: Option<&str>) -> Transaction {
+        Transaction {
+            tx_type,
+            client,
+            tx,
+            amount: amount.map(|amount| amount.parse().unwrap()),
+            timestamp: None,
+        }
+    }
+
+    /// Every row of a `futures` input stream comes out with its outcome, in
+    /// order, and the engine ends up where a synchronous run would.
+    #[test]
+    fn test_process_stream() {
+        let rows: Vec<Transaction> = (1..=500)
+            .map(|tx| {
+                let client = (tx % 7) as u16;
+                if tx % 3 == 0 {
+                    row(TransactionType::Withdrawal, client, tx, Some("2.0"))
+                } else {
+                    row(TransactionType::Deposit, client, tx, Some("1.5"))
+                }
+            })
+            .collect();
+        let mut expected = PaymentsEngine::new();
+        let expected_statuses: Vec<&str> = rows
+            .iter()
+            .map(|tx| expected.process_transaction(*tx).status())
+            .collect();
+
+        let mut outcomes = process_stream(PaymentsEngine::new(), stream::iter(rows.clone()), 4);
+        let statuses = block_on(async {
+            let mut statuses = Vec::new();
+            while let Some(row) = outcomes.next().await {
+                assert_eq!(row.transaction, rows[statuses.len()]);
+                statuses.push(row.outcome.status());
+            }
+            statuses
+        });
+
+        assert_eq!(statuses, expected_statuses);
+        assert!(statuses.contains(&"Rejected"));
+        let engine = outcomes.into_engine().unwrap();
+        assert_eq!(engine.account_summaries(), expected.account_summaries());
+    }
+
+    /// A consumer that stops reading outcomes holds the sender back once both
+    /// channels are full and the engine holds one row, and the sender resumes
+    /// once outcomes are read.
+    #[test]
+    fn test_backpressure() {
+        const CAPACITY: usize = 2;
+        const MAX_AHEAD: u32 = 2 * CAPACITY as u32 + 1;
+        let (mut sink, mut outcomes, task) = spawn_engine(PaymentsEngine::new(), CAPACITY);
+        let waker = Waker::from(Arc::new(ThreadWaker(thread::current())));
+        let mut cx = Context::from_waker(&waker);
+
+        // Whenever the sender is held back, park until the engine takes a row
+        // and wakes it. The engine can hold at most `MAX_AHEAD` rows unread.
+        let mut sent = 0;
+        while sent < MAX_AHEAD {
+            match Pin::new(&mut sink).poll_ready(&mut cx) {
+                Poll::Ready(ready) => {
+                    ready.unwrap();
+                    sent += 1;
+                    let deposit = row(TransactionType::Deposit, 1, sent, Some("1.0"));
+                    Pin::new(&mut sink).start_send(deposit).unwrap();
+                }
+                Poll::Pending => thread::park(),
+            }
+        }
+        // With `MAX_AHEAD` rows unread, both channels are full and the engine
+        // is blocked on the row in hand, so the sender must wait.
+        assert!(
+            Pin::new(&mut sink)
+                .poll_ready(&mut Context::from_waker(Waker::noop()))
+                .is_pending()
+        );
+
+        let received = block_on(async {
+            let mut received = 0;
+            for tx in sent + 1..=20 {
+                assert!(outcomes.next().await.unwrap().outcome.is_applied());
+                received += 1;
+                let deposit = row(TransactionType::Deposit, 1, tx, Some("1.0"));
+                sink.feed(deposit).await.unwrap();
+            }
+            sink.close().await.unwrap();
+            while let Some(row) = outcomes.next().await {
+                assert!(row.outcome.is_applied());
+                received += 1;
+            }
+            received
+        });
+        assert_eq!(received, 20);
+        let engine = task.join().unwrap();
+        assert_eq!(
+            engine.account_details(1).unwrap().total_funds(),
+            "20".parse().unwrap()
+        );
+    }
+
+    /// Dropping the outcome stream doesn't stop the engine from applying what
+    /// it is sent.
+    #[test]
+    fn test_dropped_outcome_stream() {
+        let (mut sink, outcomes, task) = spawn_engine(PaymentsEngine::new(), 1);
+        block_on(sink.send(row(TransactionType::Deposit, 1, 1, Some("1.0")))).unwrap();
+        drop(outcomes);
+        for tx in 2..=5 {
+            block_on(sink.send(row(TransactionType::Deposit, 1, tx, Some("1.0")))).unwrap();
+        }
+        drop(sink);
+
+        let engine = task.join().unwrap();
+        assert_eq!(
+            engine.account_details(1).unwrap().total_funds(),
+            "5".parse().unwrap()
+        );
+    }
+
+    /// Once the engine has stopped, the sink fails with `EngineStopped`.
+    #[test]
+    fn test_sink_after_engine_stopped() {
+        let (mut sink, outcomes, task) = spawn_engine(PaymentsEngine::new(), 1);
+        // Dropping the outcomes doesn't stop the engine, but losing its input
+        // channel does, as when the engine thread panics.
+        drop(outcomes);
+        sink.channel.abandon();
+
+        let result = block_on(SinkExt::send(
+            &mut sink,
+            row(TransactionType::Deposit, 1, 1, Some("1.0")),
+        ));
+        assert!(matches!(
+            result,
+            Err(PaymentsTransactionError::EngineStopped)
+        ));
+        drop(sink);
+        task.join().unwrap();
+    }
+
+    /// Panics on the first event it sees.
+    #[derive(Debug)]
+    struct PanickingSubscriber;
+
+    impl EventSubscriber for PanickingSubscriber {
+        fn on_event(&mut self, _event: &DomainEvent) {
+            panic!("subscriber failed");
+        }
+    }
+
+    /// A panic on the engine thread ends the outcomes, stops the sink and is
+    /// reported by `join` instead of panicking the caller.
+    #[test]
+    fn test_engine_thread_panic() {
+        let engine = PaymentsEngine::new().with_subscriber(PanickingSubscriber);
+        let (mut sink, mut outcomes, task) = spawn_engine(engine, 1);
+        block_on(sink.send(row(TransactionType::Deposit, 1, 1, Some("1.0")))).unwrap();
+
+        assert!(block_on(outcomes.next()).is_none());
+        let result = block_on(SinkExt::send(
+            &mut sink,
+            row(TransactionType::Deposit, 1, 2, Some("1.0")),
+        ));
+        assert!(matches!(
+            result,
+            Err(PaymentsTransactionError::EngineStopped)
+        ));
+        assert!(matches!(
+            task.join(),
+            Err(PaymentsTransactionError::EngineStopped)
+        ));
+    }
+
+    /// A channel whose lock was poisoned by a panicking holder keeps working.
+    #[test]
+    fn test_poisoned_channel_lock() {
+        let channel = Channel::new(1);
+        let holder = Arc::clone(&channel);
+        let _ = thread::spawn(move || {
+            let _state = holder.lock();
+            panic!("holder failed");
+        })
+        .join();
+        assert!(channel.state.is_poisoned());
+
+        channel.send_blocking(1).unwrap();
+        channel.close();
+        assert_eq!(channel.receive_blocking(), Some(1));
+        assert_eq!(channel.receive_blocking(), None);
+    }
+}
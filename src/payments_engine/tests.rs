@@ -1,27 +1,60 @@
 /// Tests for the payments engine's transaction processing behavior and Display implementation.
 /// These tests were generated using ChatGPT.
 #[cfg(test)]
-mod tests {
+mod engine_tests {
     use crate::PaymentsEngine;
+    use crate::account::client_account::{ClientAccount, ClientAccountDetails, LockEvent};
+    use crate::account::store::{AccountStore, InMemoryAccountStore, Owners};
+    use crate::account::transaction_state::TransactionDetail;
+    use crate::errors::PaymentsTransactionError;
+    use crate::generator::{GeneratorConfig, TransactionGenerator};
+    use crate::money::Money;
+    use crate::payments_engine::config::{
+        DisputeWindow, DuplicateTransactionPolicy, EngineConfig, LockPolicy,
+        WithdrawalDisputePolicy,
+    };
+    use crate::payments_engine::events::{DomainEvent, MemoryEventSink};
+    use crate::payments_engine::journal::{JournalSync, Recovery};
+    use crate::payments_engine::outcome::TransactionOutcome;
+    use crate::payments_engine::report::ReportOrder;
     use crate::transaction::{Transaction, TransactionType};
+    use std::borrow::Cow;
+    use std::collections::{BTreeSet, HashMap};
+
+    fn money(amount: &str) -> Money {
+        amount.parse().unwrap()
+    }
 
     /// Helper to create a deposit transaction
-    fn make_deposit_tx(id: u32, client: u16, amount: Option<f64>) -> Transaction {
+    fn make_deposit_tx(id: u32, client: u16, amount: Option<Money>) -> Transaction {
         Transaction {
             tx_type: TransactionType::Deposit,
             client,
             tx: id,
             amount,
+            timestamp: None,
         }
     }
 
     /// Helper to create a withdrawal transaction
-    fn make_withdrawal_tx(id: u32, client: u16, amount: Option<f64>) -> Transaction {
+    fn make_withdrawal_tx(id: u32, client: u16, amount: Option<Money>) -> Transaction {
         Transaction {
             tx_type: TransactionType::Withdrawal,
             client,
             tx: id,
             amount,
+            timestamp: None,
+        }
+    }
+
+    /// Helper to create a dispute, resolve or chargeback transaction
+    fn make_dispute_family_tx(tx_type: TransactionType, id: u32, client: u16) -> Transaction {
+        Transaction {
+            tx_type,
+            client,
+            tx: id,
+            amount: None,
+            timestamp: None,
         }
     }
 
@@ -29,106 +62,140 @@ mod tests {
     /// if the client does not exist yet, and updates balances correctly.
     #[test]
     fn test_process_transaction_deposit_creates_client() {
-        let mut engine = PaymentsEngine {
-            client_account_lookup: Default::default(),
-        };
+        let mut engine = PaymentsEngine::new();
 
-        let deposit = make_deposit_tx(1, 1, Some(100.0));
-        engine.process_transaction(deposit).unwrap();
+        let deposit = make_deposit_tx(1, 1, Some(money("100.0")));
+        engine.process_transaction(deposit).into_result().unwrap();
 
-        let acct = engine.client_account_lookup.get(&1).unwrap();
-        assert_eq!(acct.account_details.available_funds, 100.0);
-        assert_eq!(acct.account_details.total_funds, 100.0);
+        let acct = engine.account(1).unwrap().unwrap();
+        assert_eq!(acct.details().available_funds(), money("100.0"));
+        assert_eq!(acct.details().total_funds(), money("100.0"));
     }
 
     /// Test that a withdrawal transaction deducts funds from an existing client account
     /// when sufficient funds are available.
     #[test]
     fn test_process_transaction_withdrawal_succeeds() {
-        let mut engine = PaymentsEngine {
-            client_account_lookup: Default::default(),
-        };
+        let mut engine = PaymentsEngine::new();
 
-        let deposit = make_deposit_tx(1, 1, Some(100.0));
-        engine.process_transaction(deposit).unwrap();
+        let deposit = make_deposit_tx(1, 1, Some(money("100.0")));
+        engine.process_transaction(deposit).into_result().unwrap();
 
-        let withdrawal = make_withdrawal_tx(2, 1, Some(40.0));
-        engine.process_transaction(withdrawal).unwrap();
+        let withdrawal = make_withdrawal_tx(2, 1, Some(money("40.0")));
+        engine
+            .process_transaction(withdrawal)
+            .into_result()
+            .unwrap();
 
-        let acct = engine.client_account_lookup.get(&1).unwrap();
-        assert_eq!(acct.account_details.available_funds, 60.0);
-        assert_eq!(acct.account_details.total_funds, 60.0);
+        let acct = engine.account(1).unwrap().unwrap();
+        assert_eq!(acct.details().available_funds(), money("60.0"));
+        assert_eq!(acct.details().total_funds(), money("60.0"));
     }
 
-    /// Test that a withdrawal transaction does not fail the engine even if
-    /// the client has insufficient funds; the error is ignored.
+    /// Test that a withdrawal with insufficient funds is reported back to the
+    /// caller as `NotEnoughAvailableFunds` and leaves the balances unchanged.
     #[test]
     fn test_process_transaction_withdrawal_insufficient_funds() {
-        let mut engine = PaymentsEngine {
-            client_account_lookup: Default::default(),
-        };
+        let mut engine = PaymentsEngine::new();
 
-        let deposit = make_deposit_tx(1, 1, Some(50.0));
-        engine.process_transaction(deposit).unwrap();
+        let deposit = make_deposit_tx(1, 1, Some(money("50.0")));
+        engine.process_transaction(deposit).into_result().unwrap();
 
-        let withdrawal = make_withdrawal_tx(2, 1, Some(100.0));
-        engine.process_transaction(withdrawal).unwrap(); // should be ignored
+        let withdrawal = make_withdrawal_tx(2, 1, Some(money("100.0")));
+        assert!(matches!(
+            engine.process_transaction(withdrawal).into_result(),
+            Err(PaymentsTransactionError::NotEnoughAvailableFunds(_))
+        ));
 
-        let acct = engine.client_account_lookup.get(&1).unwrap();
-        assert_eq!(acct.account_details.available_funds, 50.0);
-        assert_eq!(acct.account_details.total_funds, 50.0);
+        let acct = engine.account(1).unwrap().unwrap();
+        assert_eq!(acct.details().available_funds(), money("50.0"));
+        assert_eq!(acct.details().total_funds(), money("50.0"));
+    }
+
+    /// Test that negative deposits and withdrawals are rejected as
+    /// `InvalidAmount` rather than moving funds the wrong way.
+    #[test]
+    fn test_negative_amounts_rejected() {
+        let mut engine = PaymentsEngine::new();
+        engine
+            .process_transaction(make_deposit_tx(1, 1, Some(money("10.0"))))
+            .into_result()
+            .unwrap();
+
+        for tx in [
+            make_withdrawal_tx(2, 1, Some(money("-1000"))),
+            make_deposit_tx(3, 2, Some(money("-5"))),
+        ] {
+            let outcome = engine.process_transaction(tx);
+            assert!(outcome.is_rejected());
+            assert!(matches!(
+                outcome.error(),
+                Some(PaymentsTransactionError::InvalidAmount(_))
+            ));
+        }
+
+        assert_eq!(
+            engine.account_details(1).unwrap().total_funds(),
+            money("10.0")
+        );
+        assert_eq!(
+            engine.account_details(2).unwrap().total_funds(),
+            Money::ZERO
+        );
+        assert_eq!(engine.transaction_owner(2).unwrap(), None);
     }
 
     /// Test that a deposit followed by a withdrawal results in correct
     /// available and total balances for a client.
     #[test]
     fn test_deposit_then_withdrawal_combined() {
-        let mut engine = PaymentsEngine {
-            client_account_lookup: Default::default(),
-        };
+        let mut engine = PaymentsEngine::new();
 
         engine
-            .process_transaction(make_deposit_tx(1, 1, Some(200.0)))
+            .process_transaction(make_deposit_tx(1, 1, Some(money("200.0"))))
+            .into_result()
             .unwrap();
         engine
-            .process_transaction(make_withdrawal_tx(2, 1, Some(50.0)))
+            .process_transaction(make_withdrawal_tx(2, 1, Some(money("50.0"))))
+            .into_result()
             .unwrap();
 
-        let acct = engine.client_account_lookup.get(&1).unwrap();
-        assert_eq!(acct.account_details.available_funds, 150.0);
-        assert_eq!(acct.account_details.total_funds, 150.0);
+        let acct = engine.account(1).unwrap().unwrap();
+        assert_eq!(acct.details().available_funds(), money("150.0"));
+        assert_eq!(acct.details().total_funds(), money("150.0"));
     }
 
-    /// Test that `process_transaction` ignores duplicate transaction IDs
+    /// Test that `process_transaction` rejects duplicate transaction IDs
     /// and does not double-apply the same transaction.
     #[test]
     fn test_duplicate_transaction_is_ignored() {
-        let mut engine = PaymentsEngine {
-            client_account_lookup: Default::default(),
-        };
+        let mut engine = PaymentsEngine::new();
 
-        let deposit = make_deposit_tx(1, 1, Some(100.0));
-        engine.process_transaction(deposit.clone()).unwrap();
-        engine.process_transaction(deposit.clone()).unwrap(); // duplicate
+        let deposit = make_deposit_tx(1, 1, Some(money("100.0")));
+        engine.process_transaction(deposit).into_result().unwrap();
+        assert!(matches!(
+            engine.process_transaction(deposit).into_result(),
+            Err(PaymentsTransactionError::DuplicateTransactionId(_))
+        )); // duplicate
 
-        let acct = engine.client_account_lookup.get(&1).unwrap();
-        assert_eq!(acct.account_details.available_funds, 100.0);
-        assert_eq!(acct.account_details.total_funds, 100.0);
+        let acct = engine.account(1).unwrap().unwrap();
+        assert_eq!(acct.details().available_funds(), money("100.0"));
+        assert_eq!(acct.details().total_funds(), money("100.0"));
     }
 
     /// Test that the `Display` implementation correctly formats
     /// the client ID and account details as CSV-style output.
     #[test]
     fn test_display_outputs_correct_format() {
-        let mut engine = PaymentsEngine {
-            client_account_lookup: Default::default(),
-        };
+        let mut engine = PaymentsEngine::new();
 
         engine
-            .process_transaction(make_deposit_tx(1, 1, Some(100.0)))
+            .process_transaction(make_deposit_tx(1, 1, Some(money("100.0"))))
+            .into_result()
             .unwrap();
         engine
-            .process_transaction(make_deposit_tx(2, 2, Some(200.0)))
+            .process_transaction(make_deposit_tx(2, 2, Some(money("200.0"))))
+            .into_result()
             .unwrap();
 
         let output = format!("{}", engine);
@@ -141,20 +208,1568 @@ mod tests {
     /// and balances are tracked separately for each client.
     #[test]
     fn test_multiple_clients_transactions() {
-        let mut engine = PaymentsEngine {
-            client_account_lookup: Default::default(),
+        let mut engine = PaymentsEngine::new();
+
+        engine
+            .process_transaction(make_deposit_tx(1, 1, Some(money("100.0"))))
+            .into_result()
+            .unwrap();
+        engine
+            .process_transaction(make_deposit_tx(2, 2, Some(money("300.0"))))
+            .into_result()
+            .unwrap();
+
+        let acct1 = engine.account(1).unwrap().unwrap();
+        let acct2 = engine.account(2).unwrap().unwrap();
+        assert_eq!(acct1.details().available_funds(), money("100.0"));
+        assert_eq!(acct2.details().available_funds(), money("300.0"));
+    }
+
+    /// Test that account summaries report one row per client with
+    /// the same balances as the underlying accounts.
+    #[test]
+    fn test_account_summaries() {
+        let mut engine = PaymentsEngine::new();
+
+        engine
+            .process_transaction(make_deposit_tx(1, 1, Some(money("100.0"))))
+            .into_result()
+            .unwrap();
+        engine
+            .process_transaction(make_deposit_tx(2, 2, Some(money("300.0"))))
+            .into_result()
+            .unwrap();
+
+        let mut summaries = engine.account_summaries();
+        summaries.sort_by_key(|summary| summary.client);
+        assert_eq!(engine.account_count(), 2);
+        assert_eq!(summaries.len(), 2);
+        assert_eq!(summaries[0].client, 1);
+        assert_eq!(summaries[0].available, money("100.0"));
+        assert_eq!(summaries[1].total, money("300.0"));
+        assert!(!summaries[1].locked);
+    }
+
+    /// Test that a dispute routed through the engine reaches the account,
+    /// even though its transaction ID is already in the account's history.
+    #[test]
+    fn test_process_transaction_dispute_holds_funds() {
+        let mut engine = PaymentsEngine::new();
+
+        engine
+            .process_transaction(make_deposit_tx(1, 1, Some(money("100.0"))))
+            .into_result()
+            .unwrap();
+        engine
+            .process_transaction(Transaction {
+                tx_type: TransactionType::Dispute,
+                client: 1,
+                tx: 1,
+                amount: None,
+                timestamp: None,
+            })
+            .into_result()
+            .unwrap();
+
+        let acct = engine.account(1).unwrap().unwrap();
+        assert_eq!(acct.details().available_funds(), Money::ZERO);
+        assert_eq!(acct.details().held_funds(), money("100.0"));
+    }
+
+    /// Test that an applied row reports its client's balances either side of
+    /// it, starting from zero for the account it opens.
+    #[test]
+    fn test_applied_outcome_carries_balances() {
+        let mut engine = PaymentsEngine::new();
+
+        let outcome = engine.process_transaction(make_deposit_tx(1, 1, Some(money("100.0"))));
+        let TransactionOutcome::Applied {
+            client,
+            before,
+            after,
+        } = outcome
+        else {
+            panic!("deposit was not applied: {outcome:?}");
+        };
+        assert_eq!(client, 1);
+        assert_eq!(before.total_funds(), Money::ZERO);
+        assert_eq!(after.total_funds(), money("100.0"));
+
+        let outcome =
+            engine.process_transaction(make_dispute_family_tx(TransactionType::Dispute, 1, 1));
+        let TransactionOutcome::Applied { before, after, .. } = outcome else {
+            panic!("dispute was not applied: {outcome:?}");
+        };
+        assert_eq!(before.available_funds(), money("100.0"));
+        assert_eq!(after.available_funds(), Money::ZERO);
+        assert_eq!(after.held_funds(), money("100.0"));
+        assert_eq!(before.total_funds(), after.total_funds());
+    }
+
+    /// Test that a client's own duplicates, references to unknown transactions
+    /// and dispute steps out of order are ignored, while rows that reuse
+    /// another client's ID, can't be afforded or carry a bad amount are
+    /// rejected.
+    #[test]
+    fn test_ignored_and_rejected_outcomes() {
+        let mut engine = PaymentsEngine::new();
+        engine
+            .process_transaction(make_deposit_tx(1, 1, Some(money("50.0"))))
+            .into_result()
+            .unwrap();
+
+        let ignored = [
+            (
+                make_deposit_tx(1, 1, Some(money("50.0"))),
+                "DuplicateTransactionId",
+            ),
+            (
+                make_dispute_family_tx(TransactionType::Dispute, 9, 1),
+                "TransactionDetailDoesNotExist",
+            ),
+            (
+                make_dispute_family_tx(TransactionType::Resolve, 1, 1),
+                "IllegalStateTransition",
+            ),
+        ];
+        for (tx, kind) in ignored {
+            let outcome = engine.process_transaction(tx);
+            assert!(outcome.is_ignored(), "{tx:?} gave {outcome:?}");
+            assert_eq!(outcome.status(), "Ignored");
+            assert_eq!(
+                outcome.error().map(PaymentsTransactionError::kind),
+                Some(kind)
+            );
+        }
+
+        let rejected = [
+            (
+                make_deposit_tx(1, 2, Some(money("50.0"))),
+                "CrossClientDuplicateTransactionId",
+            ),
+            (
+                make_withdrawal_tx(2, 1, Some(money("100.0"))),
+                "NotEnoughAvailableFunds",
+            ),
+            (make_deposit_tx(3, 1, None), "MissingAmount"),
+        ];
+        for (tx, kind) in rejected {
+            let outcome = engine.process_transaction(tx);
+            assert!(outcome.is_rejected(), "{tx:?} gave {outcome:?}");
+            assert_eq!(
+                outcome.error().map(PaymentsTransactionError::kind),
+                Some(kind)
+            );
+        }
+
+        assert_eq!(
+            engine.account_details(1).unwrap().available_funds(),
+            money("50.0")
+        );
+    }
+
+    /// Test that, by default, a transaction ID replayed under a different client
+    /// is rejected and leaves no trace on the second client.
+    #[test]
+    fn test_cross_client_duplicate_rejected_by_default() {
+        let mut engine = PaymentsEngine::new();
+
+        engine
+            .process_transaction(make_deposit_tx(1, 1, Some(money("100.0"))))
+            .into_result()
+            .unwrap();
+        let result = engine
+            .process_transaction(make_deposit_tx(1, 2, Some(money("50.0"))))
+            .into_result();
+
+        assert!(matches!(
+            result,
+            Err(
+                PaymentsTransactionError::CrossClientDuplicateTransactionId {
+                    tx: 1,
+                    client: 2,
+                    owner: 1
+                }
+            )
+        ));
+        assert!(engine.account_details(2).is_none());
+        assert_eq!(engine.transaction_owner(1).unwrap(), Some(1));
+    }
+
+    /// Test that a withdrawal replaying another client's deposit ID is rejected.
+    #[test]
+    fn test_cross_client_duplicate_withdrawal_rejected() {
+        let mut engine = PaymentsEngine::new();
+
+        engine
+            .process_transaction(make_deposit_tx(1, 1, Some(money("100.0"))))
+            .into_result()
+            .unwrap();
+        engine
+            .process_transaction(make_deposit_tx(2, 2, Some(money("100.0"))))
+            .into_result()
+            .unwrap();
+        let result = engine
+            .process_transaction(make_withdrawal_tx(1, 2, Some(money("10.0"))))
+            .into_result();
+
+        assert!(result.is_err());
+        let acct = engine.account(2).unwrap().unwrap();
+        assert_eq!(acct.details().available_funds(), money("100.0"));
+    }
+
+    /// Test that the warn and allow policies apply a cross-client duplicate
+    /// to the second client while keeping the original owner in the index.
+    #[test]
+    fn test_cross_client_duplicate_warn_and_allow_policies() {
+        for policy in [
+            DuplicateTransactionPolicy::Warn,
+            DuplicateTransactionPolicy::Allow,
+        ] {
+            let mut engine = PaymentsEngine::new().with_duplicate_policy(policy);
+
+            engine
+                .process_transaction(make_deposit_tx(1, 1, Some(money("100.0"))))
+                .into_result()
+                .unwrap();
+            engine
+                .process_transaction(make_deposit_tx(1, 2, Some(money("50.0"))))
+                .into_result()
+                .unwrap();
+
+            let acct = engine.account(2).unwrap().unwrap();
+            assert_eq!(acct.details().available_funds(), money("50.0"));
+            assert_eq!(engine.transaction_owner(1).unwrap(), Some(1));
+        }
+    }
+
+    /// Test that a withdrawal rejected for insufficient funds does not reserve
+    /// its transaction ID, so another client may still use it.
+    #[test]
+    fn test_failed_withdrawal_does_not_reserve_transaction_id() {
+        let mut engine = PaymentsEngine::new();
+
+        assert!(
+            engine
+                .process_transaction(make_withdrawal_tx(1, 1, Some(money("10.0"))))
+                .into_result()
+                .is_err()
+        );
+        engine
+            .process_transaction(make_deposit_tx(1, 2, Some(money("10.0"))))
+            .into_result()
+            .unwrap();
+
+        assert_eq!(engine.transaction_owner(1).unwrap(), Some(2));
+    }
+
+    /// Test that a dispute for an unknown transaction is rejected and does not
+    /// create an empty account for the client it names.
+    #[test]
+    fn test_dispute_unknown_transaction_does_not_create_account() {
+        let mut engine = PaymentsEngine::new();
+
+        let result = engine
+            .process_transaction(make_dispute_family_tx(TransactionType::Dispute, 9, 7))
+            .into_result();
+
+        assert!(matches!(
+            result,
+            Err(PaymentsTransactionError::TransactionDetailDoesNotExist(_))
+        ));
+        assert!(engine.account_details(7).is_none());
+        assert_eq!(engine.account_count(), 0);
+    }
+
+    /// Test that dispute-family rows naming a client other than the owner of
+    /// the referenced transaction are rejected without touching either account.
+    #[test]
+    fn test_dispute_family_client_mismatch_rejected() {
+        let mut engine = PaymentsEngine::new();
+
+        engine
+            .process_transaction(make_deposit_tx(1, 1, Some(money("100.0"))))
+            .into_result()
+            .unwrap();
+        engine
+            .process_transaction(make_deposit_tx(2, 2, Some(money("20.0"))))
+            .into_result()
+            .unwrap();
+
+        for tx_type in [
+            TransactionType::Dispute,
+            TransactionType::Resolve,
+            TransactionType::Chargeback,
+        ] {
+            let result = engine
+                .process_transaction(make_dispute_family_tx(tx_type, 1, 2))
+                .into_result();
+            assert!(matches!(
+                result,
+                Err(PaymentsTransactionError::TransactionClientMismatch {
+                    tx: 1,
+                    client: 2,
+                    owner: 1
+                })
+            ));
+        }
+
+        let owner = engine.account(1).unwrap().unwrap();
+        assert_eq!(owner.details().available_funds(), money("100.0"));
+        assert_eq!(owner.details().held_funds(), Money::ZERO);
+        let other = engine.account(2).unwrap().unwrap();
+        assert_eq!(other.details().available_funds(), money("20.0"));
+        assert!(!other.details().is_locked());
+    }
+
+    /// Test that a dispute naming a client the engine has never seen, for a
+    /// transaction another client owns, is a mismatch and opens no account.
+    #[test]
+    fn test_dispute_mismatch_does_not_create_account() {
+        let mut engine = PaymentsEngine::new();
+
+        engine
+            .process_transaction(make_deposit_tx(1, 1, Some(money("100.0"))))
+            .into_result()
+            .unwrap();
+        let result = engine
+            .process_transaction(make_dispute_family_tx(TransactionType::Dispute, 1, 3))
+            .into_result();
+
+        assert!(matches!(
+            result,
+            Err(PaymentsTransactionError::TransactionClientMismatch { .. })
+        ));
+        assert!(engine.account_details(3).is_none());
+    }
+
+    /// Helper that builds an engine where client 1 deposited 100.0 (tx 1) and
+    /// 50.0 (tx 2), disputed both, and charged back tx 1, locking the account.
+    fn engine_with_locked_account(engine: &mut PaymentsEngine) {
+        engine
+            .process_transaction(make_deposit_tx(1, 1, Some(money("100.0"))))
+            .into_result()
+            .unwrap();
+        engine
+            .process_transaction(make_deposit_tx(2, 1, Some(money("50.0"))))
+            .into_result()
+            .unwrap();
+        for (tx_type, id) in [
+            (TransactionType::Dispute, 2),
+            (TransactionType::Dispute, 1),
+            (TransactionType::Chargeback, 1),
+        ] {
+            engine
+                .process_transaction(make_dispute_family_tx(tx_type, id, 1))
+                .into_result()
+                .unwrap();
+        }
+        assert!(engine.account(1).unwrap().unwrap().details().is_locked());
+    }
+
+    /// Test that a locked account refuses deposits and withdrawals by default
+    /// and leaves its balances untouched.
+    #[test]
+    fn test_locked_account_rejects_deposits_and_withdrawals() {
+        let mut engine = PaymentsEngine::new();
+        engine_with_locked_account(&mut engine);
+
+        let deposit = engine
+            .process_transaction(make_deposit_tx(3, 1, Some(money("10.0"))))
+            .into_result();
+        let withdrawal = engine
+            .process_transaction(make_withdrawal_tx(4, 1, Some(money("10.0"))))
+            .into_result();
+
+        assert!(matches!(
+            deposit,
+            Err(PaymentsTransactionError::AccountLocked(1))
+        ));
+        assert!(matches!(
+            withdrawal,
+            Err(PaymentsTransactionError::AccountLocked(1))
+        ));
+        let acct = engine.account(1).unwrap().unwrap();
+        assert_eq!(acct.details().total_funds(), money("50.0"));
+        assert_eq!(engine.transaction_owner(3).unwrap(), None);
+    }
+
+    /// Test that an open dispute on a locked account can still be resolved by default.
+    #[test]
+    fn test_locked_account_allows_resolving_open_disputes() {
+        let mut engine = PaymentsEngine::new();
+        engine_with_locked_account(&mut engine);
+
+        engine
+            .process_transaction(make_dispute_family_tx(TransactionType::Resolve, 2, 1))
+            .into_result()
+            .unwrap();
+
+        let acct = engine.account(1).unwrap().unwrap();
+        assert_eq!(acct.details().available_funds(), money("50.0"));
+        assert_eq!(acct.details().held_funds(), Money::ZERO);
+    }
+
+    /// Test that a custom lock policy can refuse dispute-family rows too.
+    #[test]
+    fn test_block_all_lock_policy_rejects_resolves() {
+        let mut engine = PaymentsEngine::new().with_lock_policy(LockPolicy::block_all());
+        engine_with_locked_account(&mut engine);
+
+        let result = engine
+            .process_transaction(make_dispute_family_tx(TransactionType::Resolve, 2, 1))
+            .into_result();
+
+        assert!(matches!(
+            result,
+            Err(PaymentsTransactionError::AccountLocked(1))
+        ));
+        let acct = engine.account(1).unwrap().unwrap();
+        assert_eq!(acct.details().held_funds(), money("50.0"));
+    }
+
+    /// Test that an admin unlock re-enables deposits and is recorded in the
+    /// account's lock history after the chargeback that locked it.
+    #[test]
+    fn test_unlock_account_records_history() {
+        let mut engine = PaymentsEngine::new();
+        engine_with_locked_account(&mut engine);
+
+        engine.unlock_account(1, "chargeback reviewed").unwrap();
+        engine
+            .process_transaction(make_deposit_tx(3, 1, Some(money("10.0"))))
+            .into_result()
+            .unwrap();
+
+        let acct = engine.account(1).unwrap().unwrap();
+        assert!(!acct.details().is_locked());
+        assert_eq!(acct.details().total_funds(), money("60.0"));
+        assert_eq!(
+            acct.lock_history(),
+            &[
+                LockEvent::Locked { tx: 1 },
+                LockEvent::Unlocked {
+                    reason: "chargeback reviewed".to_string()
+                }
+            ]
+        );
+    }
+
+    /// Test that a chargeback on an account that is already locked doesn't
+    /// record a second lock, matching the single `AccountLocked` event.
+    #[test]
+    fn test_second_chargeback_records_one_lock() {
+        let mut engine = PaymentsEngine::new();
+        engine_with_locked_account(&mut engine);
+
+        engine
+            .process_transaction(make_dispute_family_tx(TransactionType::Chargeback, 2, 1))
+            .into_result()
+            .unwrap();
+
+        let acct = engine.account(1).unwrap().unwrap();
+        assert!(acct.details().is_locked());
+        assert_eq!(acct.lock_history(), &[LockEvent::Locked { tx: 1 }]);
+    }
+
+    /// Test that unlocking a client the engine has never seen is an error.
+    #[test]
+    fn test_unlock_unknown_client() {
+        let mut engine = PaymentsEngine::new();
+
+        assert!(matches!(
+            engine.unlock_account(5, "no such client"),
+            Err(PaymentsTransactionError::ClientDoesNotExist(5))
+        ));
+    }
+
+    /// Test that the engine applies its configured withdrawal dispute policy.
+    #[test]
+    fn test_engine_withdrawal_dispute_policy() {
+        let mut rejecting = PaymentsEngine::new();
+        let mut reversing =
+            PaymentsEngine::new().with_withdrawal_dispute_policy(WithdrawalDisputePolicy::Reversal);
+
+        for engine in [&mut rejecting, &mut reversing] {
+            engine
+                .process_transaction(make_deposit_tx(1, 1, Some(money("100.0"))))
+                .into_result()
+                .unwrap();
+            engine
+                .process_transaction(make_withdrawal_tx(2, 1, Some(money("40.0"))))
+                .into_result()
+                .unwrap();
+        }
+        assert!(matches!(
+            rejecting
+                .process_transaction(make_dispute_family_tx(TransactionType::Dispute, 2, 1))
+                .into_result(),
+            Err(PaymentsTransactionError::WithdrawalNotDisputable(2))
+        ));
+        reversing
+            .process_transaction(make_dispute_family_tx(TransactionType::Dispute, 2, 1))
+            .into_result()
+            .unwrap();
+
+        let acct = rejecting.account(1).unwrap().unwrap();
+        assert_eq!(acct.details().held_funds(), Money::ZERO);
+        assert!(!acct.transaction_archive().is_disputed(2));
+
+        let acct = reversing.account(1).unwrap().unwrap();
+        assert_eq!(acct.details().available_funds(), money("60.0"));
+        assert_eq!(acct.details().held_funds(), money("40.0"));
+        assert_eq!(acct.details().total_funds(), money("100.0"));
+    }
+
+    /// Test that resolves and chargebacks of undisputed transactions are
+    /// reported back to the caller instead of being silently dropped.
+    #[test]
+    fn test_resolve_and_chargeback_errors_are_returned() {
+        let mut engine = PaymentsEngine::new();
+
+        engine
+            .process_transaction(make_deposit_tx(1, 1, Some(money("100.0"))))
+            .into_result()
+            .unwrap();
+
+        for tx_type in [TransactionType::Resolve, TransactionType::Chargeback] {
+            assert!(matches!(
+                engine
+                    .process_transaction(make_dispute_family_tx(tx_type, 1, 1))
+                    .into_result(),
+                Err(PaymentsTransactionError::IllegalStateTransition { tx: 1, .. })
+            ));
+        }
+        assert!(!engine.account(1).unwrap().unwrap().details().is_locked());
+    }
+
+    /// Test that a deposit without an amount is rejected.
+    #[test]
+    fn test_deposit_without_amount_is_rejected() {
+        let mut engine = PaymentsEngine::new();
+
+        assert!(matches!(
+            engine
+                .process_transaction(make_deposit_tx(1, 1, None))
+                .into_result(),
+            Err(PaymentsTransactionError::MissingAmount(1))
+        ));
+        assert_eq!(engine.transaction_owner(1).unwrap(), None);
+    }
+
+    /// Feeds a fixed mix of clients and transactions to a fresh engine.
+    fn engine_with_many_clients() -> PaymentsEngine {
+        let mut engine = PaymentsEngine::new();
+        for (tx, client) in [(1, 7u16), (2, 3), (3, 42), (4, 1), (5, 19)] {
+            engine
+                .process_transaction(make_deposit_tx(tx, client, Some(money("2.5"))))
+                .into_result()
+                .unwrap();
+        }
+        engine
+            .process_transaction(make_deposit_tx(100, 19, Some(money("10.0"))))
+            .into_result()
+            .unwrap();
+        engine
+            .process_transaction(make_withdrawal_tx(101, 42, Some(money("1.25"))))
+            .into_result()
+            .unwrap();
+        engine
+    }
+
+    /// Test that two engines fed the same input produce byte-identical reports
+    /// in every ordering, and that `Display` matches the client ID ordering.
+    #[test]
+    fn test_report_is_byte_identical_for_identical_input() {
+        let first = engine_with_many_clients();
+        let second = engine_with_many_clients();
+
+        for order in [
+            ReportOrder::ClientId,
+            ReportOrder::Total,
+            ReportOrder::FirstSeen,
+        ] {
+            let mut first_out = Vec::new();
+            let mut second_out = Vec::new();
+            first.write_report(order, &mut first_out).unwrap();
+            second.write_report(order, &mut second_out).unwrap();
+            assert_eq!(first_out, second_out);
+        }
+
+        let mut by_client = Vec::new();
+        first
+            .write_report(ReportOrder::ClientId, &mut by_client)
+            .unwrap();
+        assert_eq!(first.to_string().into_bytes(), by_client);
+    }
+
+    /// Test that each report ordering lists clients in the expected order.
+    #[test]
+    fn test_report_orderings() {
+        let engine = engine_with_many_clients();
+        let clients = |order| -> Vec<u16> {
+            engine
+                .sorted_account_summaries(order)
+                .iter()
+                .map(|summary| summary.client)
+                .collect()
+        };
+
+        assert_eq!(clients(ReportOrder::ClientId), vec![1, 3, 7, 19, 42]);
+        assert_eq!(clients(ReportOrder::Total), vec![19, 1, 3, 7, 42]);
+        assert_eq!(clients(ReportOrder::FirstSeen), vec![7, 3, 42, 1, 19]);
+
+        let mut out = Vec::new();
+        engine.write_report(ReportOrder::Total, &mut out).unwrap();
+        assert_eq!(
+            String::from_utf8(out).unwrap(),
+            "client,available,held,total,locked\n\
+             19,12.5000,0.0000,12.5000,false\n\
+             1,2.5000,0.0000,2.5000,false\n\
+             3,2.5000,0.0000,2.5000,false\n\
+             7,2.5000,0.0000,2.5000,false\n\
+             42,1.2500,0.0000,1.2500,false\n"
+        );
+    }
+
+    /// Everything observable about an engine after a run: its report in every
+    /// order and the owner of every transaction ID it has seen.
+    fn engine_outcome(engine: &PaymentsEngine, rows: &[Transaction]) -> Vec<String> {
+        let mut outcome = Vec::new();
+        for order in [
+            ReportOrder::ClientId,
+            ReportOrder::Total,
+            ReportOrder::FirstSeen,
+        ] {
+            let mut out = Vec::new();
+            engine.write_report(order, &mut out).unwrap();
+            outcome.push(String::from_utf8(out).unwrap());
+        }
+        for tx in rows {
+            outcome.push(format!("{:?}", engine.transaction_owner(tx.tx).unwrap()));
+        }
+        outcome
+    }
+
+    /// Applies generated rows with a sequential engine and a sharded engine
+    /// and asserts that the reports, ownership, rejections and snapshots are
+    /// identical.
+    fn assert_sharded_matches_sequential(config: EngineConfig, generator: GeneratorConfig) {
+        let rows: Vec<Transaction> = TransactionGenerator::new(generator).collect();
+        assert_sharded_rows_match_sequential(config, &rows);
+    }
+
+    fn assert_sharded_rows_match_sequential(config: EngineConfig, rows: &[Transaction]) {
+        let mut sequential = PaymentsEngine::with_config(config.clone());
+        let sequential_rejections: Vec<(usize, &'static str)> = rows
+            .iter()
+            .enumerate()
+            .filter_map(|(i, tx)| {
+                sequential
+                    .process_transaction(*tx)
+                    .into_result()
+                    .err()
+                    .map(|e| (i, e.kind()))
+            })
+            .collect();
+
+        for workers in [2, 3, 8] {
+            // Split the rows over two batches so the second starts from existing state.
+            let mut sharded = PaymentsEngine::with_config(config.clone()).with_workers(workers);
+            let (first_half, second_half) = rows.split_at(rows.len() / 2);
+            let mut sharded_rejections: Vec<(usize, &'static str)> = Vec::new();
+            for (offset, batch) in [(0, first_half), (first_half.len(), second_half)] {
+                let batch = batch.iter().copied().enumerate();
+                sharded_rejections.extend(
+                    sharded
+                        .process_batch(batch.map(|(i, tx)| (offset + i, tx)))
+                        .unwrap()
+                        .into_iter()
+                        .map(|(i, e)| (i, e.kind())),
+                );
+            }
+
+            assert_eq!(sharded_rejections, sequential_rejections);
+            assert_eq!(
+                engine_outcome(&sharded, rows),
+                engine_outcome(&sequential, rows)
+            );
+            let (mut sequential_snapshot, mut sharded_snapshot) = (Vec::new(), Vec::new());
+            sequential.save_snapshot(&mut sequential_snapshot).unwrap();
+            sharded.save_snapshot(&mut sharded_snapshot).unwrap();
+            assert_eq!(sharded_snapshot, sequential_snapshot);
+        }
+    }
+
+    /// Test that sharded processing matches sequential processing over
+    /// generated inputs, including injected duplicates and foreign disputes
+    /// that cross shard boundaries.
+    #[test]
+    fn test_sharded_engine_matches_sequential() {
+        for seed in 0..4 {
+            assert_sharded_matches_sequential(
+                EngineConfig::default(),
+                GeneratorConfig {
+                    transactions: 3_000,
+                    clients: 40,
+                    error_rate: 0.15,
+                    seed: Some(seed),
+                    ..GeneratorConfig::default()
+                },
+            );
+        }
+    }
+
+    /// Test that sharded processing matches sequential processing under the
+    /// non-default duplicate, lock and withdrawal dispute policies and a
+    /// bounded dispute window.
+    #[test]
+    fn test_sharded_engine_matches_sequential_with_policies() {
+        let generator = GeneratorConfig {
+            transactions: 2_000,
+            clients: 6,
+            error_rate: 0.3,
+            seed: Some(11),
+            ..GeneratorConfig::default()
         };
+        for duplicate_policy in [
+            DuplicateTransactionPolicy::Reject,
+            DuplicateTransactionPolicy::Warn,
+            DuplicateTransactionPolicy::Allow,
+        ] {
+            let config = EngineConfig {
+                duplicate_policy,
+                lock_policy: LockPolicy::block_all(),
+                withdrawal_dispute_policy: WithdrawalDisputePolicy::Reversal,
+                dispute_window: DisputeWindow::Transactions(150),
+            };
+            assert_sharded_matches_sequential(config, generator.clone());
+        }
+    }
+
+    /// Test that a batch picks up where earlier processing left off, and that
+    /// a single worker processes the batch sequentially.
+    #[test]
+    fn test_process_batch_continues_existing_state() {
+        let mut engine = PaymentsEngine::new().with_workers(4);
+        assert_eq!(engine.workers(), 4);
+        engine
+            .process_transaction(make_deposit_tx(1, 1, Some(money("10.0"))))
+            .into_result()
+            .unwrap();
+
+        let rejected = engine
+            .process_batch([
+                ("dup", make_deposit_tx(1, 2, Some(money("5.0")))),
+                (
+                    "dispute",
+                    make_dispute_family_tx(TransactionType::Dispute, 1, 1),
+                ),
+                ("withdraw", make_withdrawal_tx(2, 2, Some(money("1.0")))),
+            ])
+            .unwrap();
+        let rejected: Vec<(&str, &str)> =
+            rejected.iter().map(|(tag, e)| (*tag, e.kind())).collect();
+        assert_eq!(
+            rejected,
+            vec![
+                ("dup", "CrossClientDuplicateTransactionId"),
+                ("withdraw", "NotEnoughAvailableFunds")
+            ]
+        );
+        assert_eq!(
+            engine.account(1).unwrap().unwrap().details().held_funds(),
+            money("10.0")
+        );
+        assert_eq!(engine.transaction_owner(1).unwrap(), Some(1));
+        assert_eq!(
+            engine
+                .sorted_account_summaries(ReportOrder::FirstSeen)
+                .iter()
+                .map(|summary| summary.client)
+                .collect::<Vec<_>>(),
+            vec![1, 2]
+        );
+        assert_eq!(PaymentsEngine::new().workers(), 1);
+    }
+
+    /// Test that a panicking shard worker fails the batch instead of the
+    /// caller's thread, and that the other shards are still merged back.
+    #[test]
+    fn test_process_batch_reports_failed_shard() {
+        let mut engine = PaymentsEngine::new().with_workers(2);
+        engine.panic_on_tx = Some(3);
+
+        let result = engine.process_batch([
+            ((), make_deposit_tx(1, 1, Some(money("10.0")))),
+            ((), make_deposit_tx(2, 2, Some(money("5.0")))),
+            ((), make_deposit_tx(3, 2, Some(money("5.0")))),
+            ((), make_deposit_tx(4, 1, Some(money("1.0")))),
+        ]);
+        match result {
+            Err(PaymentsTransactionError::ShardFailed(message)) => {
+                assert!(message.contains("injected failure"), "{message}")
+            }
+            other => panic!("expected ShardFailed, got {other:?}"),
+        }
+        assert_eq!(
+            engine
+                .account(1)
+                .unwrap()
+                .unwrap()
+                .details()
+                .available_funds(),
+            money("11.0")
+        );
+        assert!(engine.account(2).unwrap().is_none());
+    }
+
+    /// Test that resuming from a snapshot taken partway through the input and
+    /// applying the rest gives the same rejections, report, ownership and
+    /// snapshot as applying the whole input in one run, with and without a
+    /// dispute window.
+    #[test]
+    fn test_resume_from_snapshot_matches_full_replay() {
+        for (seed, dispute_window) in (0..4).zip([
+            DisputeWindow::Unbounded,
+            DisputeWindow::Transactions(200),
+            DisputeWindow::Unbounded,
+            DisputeWindow::Transactions(50),
+        ]) {
+            let config = EngineConfig {
+                lock_policy: LockPolicy::block_all(),
+                dispute_window,
+                ..EngineConfig::default()
+            };
+            let rows: Vec<Transaction> = TransactionGenerator::new(GeneratorConfig {
+                transactions: 3_000,
+                clients: 40,
+                error_rate: 0.15,
+                seed: Some(seed),
+                ..GeneratorConfig::default()
+            })
+            .collect();
+            let (yesterday, today) = rows.split_at(rows.len() / 2);
+
+            let mut full = PaymentsEngine::with_config(config.clone());
+            for tx in yesterday {
+                let _ = full.process_transaction(*tx).into_result();
+            }
+            let full_rejections: Vec<&str> = today
+                .iter()
+                .filter_map(|tx| {
+                    full.process_transaction(*tx)
+                        .into_result()
+                        .err()
+                        .map(|e| e.kind())
+                })
+                .collect();
+
+            let mut first_run = PaymentsEngine::with_config(config.clone());
+            for tx in yesterday {
+                let _ = first_run.process_transaction(*tx).into_result();
+            }
+            let mut snapshot = Vec::new();
+            first_run.save_snapshot(&mut snapshot).unwrap();
+
+            let mut resumed = PaymentsEngine::with_config(config.clone());
+            resumed.load_snapshot(snapshot.as_slice()).unwrap();
+            let resumed_rejections: Vec<&str> = today
+                .iter()
+                .filter_map(|tx| {
+                    resumed
+                        .process_transaction(*tx)
+                        .into_result()
+                        .err()
+                        .map(|e| e.kind())
+                })
+                .collect();
+
+            assert_eq!(resumed_rejections, full_rejections);
+            assert_eq!(
+                engine_outcome(&resumed, &rows),
+                engine_outcome(&full, &rows)
+            );
+            let (mut full_snapshot, mut resumed_snapshot) = (Vec::new(), Vec::new());
+            full.save_snapshot(&mut full_snapshot).unwrap();
+            resumed.save_snapshot(&mut resumed_snapshot).unwrap();
+            assert_eq!(resumed_snapshot, full_snapshot);
+        }
+    }
+
+    fn generated_rows(seed: u64) -> Vec<Transaction> {
+        TransactionGenerator::new(GeneratorConfig {
+            transactions: 1_500,
+            clients: 20,
+            error_rate: 0.15,
+            seed: Some(seed),
+            ..GeneratorConfig::default()
+        })
+        .collect()
+    }
+
+    /// An engine that applied `rows` without journaling, to compare against.
+    fn uninterrupted(rows: &[Transaction]) -> PaymentsEngine {
+        let mut engine = PaymentsEngine::new();
+        for tx in rows {
+            let _ = engine.process_transaction(*tx).into_result();
+        }
+        engine
+    }
+
+    /// Test that an engine recovered from its last checkpoint and journal after
+    /// a crash mid-write, then fed the rest of the input starting with the row
+    /// that was being written, ends up where an uninterrupted run does.
+    #[test]
+    fn test_recover_after_crash_mid_write() {
+        let dir = tempfile::TempDir::new().unwrap();
+        let (snapshot, journal) = (dir.path().join("state.snap"), dir.path().join("wal"));
+        let rows = generated_rows(1);
+        let (checkpointed, rest) = rows.split_at(500);
+        let (journaled, unseen) = rest.split_at(500);
+
+        let mut crashed = PaymentsEngine::new();
+        crashed
+            .recover(None, &journal, JournalSync::Always)
+            .unwrap();
+        for tx in checkpointed {
+            let _ = crashed.process_transaction(*tx).into_result();
+        }
+        crashed.checkpoint(&snapshot).unwrap();
+        for tx in journaled {
+            let _ = crashed.process_transaction(*tx).into_result();
+        }
+        drop(crashed);
+        // The crash tore the last record, so its row was never accepted.
+        let len = std::fs::metadata(&journal).unwrap().len();
+        std::fs::OpenOptions::new()
+            .write(true)
+            .open(&journal)
+            .unwrap()
+            .set_len(len - 7)
+            .unwrap();
+
+        let mut recovered = PaymentsEngine::new();
+        let recovery = recovered
+            .recover(Some(&snapshot), &journal, JournalSync::Always)
+            .unwrap();
+        assert_eq!(recovery.replayed, 499);
+        assert!(recovery.truncated_bytes > 0);
+        for tx in journaled[499..].iter().chain(unseen) {
+            let _ = recovered.process_transaction(*tx).into_result();
+        }
+
+        assert_eq!(
+            engine_outcome(&recovered, &rows),
+            engine_outcome(&uninterrupted(&rows), &rows)
+        );
+        assert_eq!(recovered.journal().unwrap().last_seq(), 1_500);
+    }
+
+    /// Test that records a snapshot already covers are skipped, as when a
+    /// checkpoint crashes after saving the snapshot but before emptying the
+    /// journal.
+    #[test]
+    fn test_recover_skips_records_in_snapshot() {
+        let dir = tempfile::TempDir::new().unwrap();
+        let (snapshot, journal) = (dir.path().join("state.snap"), dir.path().join("wal"));
+        let rows = generated_rows(2);
+
+        let mut crashed = PaymentsEngine::new();
+        crashed.recover(None, &journal, JournalSync::Never).unwrap();
+        for (i, tx) in rows.iter().enumerate() {
+            let _ = crashed.process_transaction(*tx).into_result();
+            if i == 999 {
+                let file = std::fs::File::create(&snapshot).unwrap();
+                crashed.save_snapshot(file).unwrap();
+            }
+        }
+        drop(crashed);
+
+        let mut recovered = PaymentsEngine::new();
+        let recovery = recovered
+            .recover(Some(&snapshot), &journal, JournalSync::Never)
+            .unwrap();
+
+        assert_eq!(
+            recovery,
+            Recovery {
+                replayed: 500,
+                truncated_bytes: 0
+            }
+        );
+        assert_eq!(
+            engine_outcome(&recovered, &rows),
+            engine_outcome(&uninterrupted(&rows), &rows)
+        );
+    }
+
+    /// Test that recovering from a snapshot older than the journal's last
+    /// checkpoint is refused rather than silently skipping transactions.
+    #[test]
+    fn test_recover_refuses_gap_between_snapshot_and_journal() {
+        let dir = tempfile::TempDir::new().unwrap();
+        let journal = dir.path().join("wal");
+        let (older, newer) = (dir.path().join("older.snap"), dir.path().join("newer.snap"));
+        let rows = generated_rows(3);
+
+        let mut engine = PaymentsEngine::new();
+        engine.recover(None, &journal, JournalSync::Never).unwrap();
+        for (i, tx) in rows.iter().enumerate() {
+            let _ = engine.process_transaction(*tx).into_result();
+            match i {
+                499 => engine.checkpoint(&older).unwrap(),
+                999 => engine.checkpoint(&newer).unwrap(),
+                _ => {}
+            }
+        }
+        drop(engine);
+
+        let result = PaymentsEngine::new().recover(Some(&older), &journal, JournalSync::Never);
+        assert!(matches!(
+            result,
+            Err(PaymentsTransactionError::CorruptJournal(_))
+        ));
+        let mut recovered = PaymentsEngine::new();
+        recovered
+            .recover(Some(&newer), &journal, JournalSync::Never)
+            .unwrap();
+        assert_eq!(
+            engine_outcome(&recovered, &rows),
+            engine_outcome(&uninterrupted(&rows), &rows)
+        );
+    }
+
+    /// Test that a sharded batch journals every row, so the journal alone
+    /// rebuilds the same state.
+    #[test]
+    fn test_sharded_batch_is_journaled() {
+        let dir = tempfile::TempDir::new().unwrap();
+        let journal = dir.path().join("wal");
+        let rows = generated_rows(4);
 
+        let mut sharded = PaymentsEngine::new().with_workers(4);
+        sharded
+            .recover(None, &journal, JournalSync::Every(100))
+            .unwrap();
+        sharded
+            .process_batch(rows.iter().map(|tx| ((), *tx)))
+            .unwrap();
+        drop(sharded);
+
+        let mut recovered = PaymentsEngine::new();
+        let recovery = recovered
+            .recover(None, &journal, JournalSync::Always)
+            .unwrap();
+        assert_eq!(recovery.replayed, 1_500);
+        assert_eq!(
+            engine_outcome(&recovered, &rows),
+            engine_outcome(&uninterrupted(&rows), &rows)
+        );
+    }
+
+    /// Test that a dispute is accepted only while its transaction is inside
+    /// the window, that a transaction disputed in time can still be settled
+    /// after the window closes, that it is evicted once settled, and that an
+    /// evicted ID is forgotten once a second window has closed.
+    #[test]
+    fn test_dispute_window_expires_transactions() {
+        let mut engine = PaymentsEngine::new().with_dispute_window(DisputeWindow::Transactions(3));
+        for tx in [
+            make_deposit_tx(1, 1, Some(money("10.0"))),
+            make_deposit_tx(2, 1, Some(money("5.0"))),
+            make_dispute_family_tx(TransactionType::Dispute, 1, 1),
+            make_deposit_tx(3, 1, Some(money("1.0"))),
+            make_deposit_tx(4, 1, Some(money("1.0"))),
+        ] {
+            engine.process_transaction(tx).into_result().unwrap();
+        }
+
+        // Transaction 2 has been evicted; transaction 1 is kept while disputed.
+        let archive = engine
+            .account(1)
+            .unwrap()
+            .unwrap()
+            .transaction_archive()
+            .clone();
+        assert!(!archive.contains(2));
+        assert!(archive.is_disputed(1));
+
+        engine
+            .process_transaction(make_dispute_family_tx(TransactionType::Resolve, 1, 1))
+            .into_result()
+            .unwrap();
+        // Deposit 3 leaves the window while these are processed.
+        for id in [1, 2, 3] {
+            assert!(matches!(
+                engine.process_transaction(make_dispute_family_tx(
+                    TransactionType::Dispute,
+                    id,
+                    1
+                )).into_result(),
+                Err(PaymentsTransactionError::DisputeWindowExpired(expired)) if expired == id
+            ));
+        }
+
+        let archive = engine
+            .account(1)
+            .unwrap()
+            .unwrap()
+            .transaction_archive()
+            .clone();
+        assert_eq!(archive.len(), 0);
+        assert_eq!(
+            engine.account_details(1).unwrap().available_funds(),
+            money("17.0")
+        );
+        // Expired IDs are remembered for a second window, so they can't be
+        // reused until it closes.
+        assert!(matches!(
+            engine
+                .process_transaction(make_deposit_tx(3, 1, Some(money("1.0"))))
+                .into_result(),
+            Err(PaymentsTransactionError::DuplicateTransactionId(_))
+        ));
+        // After that they leave the index, and are unknown until reused.
+        assert_eq!(engine.transaction_owner(2).unwrap(), None);
+        assert!(matches!(
+            engine
+                .process_transaction(make_dispute_family_tx(TransactionType::Dispute, 2, 1))
+                .into_result(),
+            Err(PaymentsTransactionError::TransactionDetailDoesNotExist(_))
+        ));
+        engine
+            .process_transaction(make_deposit_tx(2, 3, Some(money("1.0"))))
+            .into_result()
+            .unwrap();
+        assert_eq!(engine.transaction_owner(2).unwrap(), Some(3));
+    }
+
+    /// Test that the archive stays within the dispute window, plus whatever
+    /// is still under dispute, however long the input runs, and that the
+    /// transaction index shrinks along with it.
+    #[test]
+    fn test_dispute_window_bounds_archive() {
+        let mut engine =
+            PaymentsEngine::new().with_dispute_window(DisputeWindow::Transactions(100));
+        let rows = TransactionGenerator::new(GeneratorConfig {
+            transactions: 20_000,
+            clients: 50,
+            error_rate: 0.1,
+            seed: Some(5),
+            ..GeneratorConfig::default()
+        });
+
+        let mut expired = 0;
+        for (i, tx) in rows.enumerate() {
+            if let Err(PaymentsTransactionError::DisputeWindowExpired(_)) =
+                engine.process_transaction(tx).into_result()
+            {
+                expired += 1;
+            }
+            if i % 1_000 == 0 {
+                let (mut archived, mut disputed) = (0, 0);
+                for client in engine.clients() {
+                    let account = engine.account(*client).unwrap().unwrap();
+                    archived += account.transaction_archive().len();
+                    disputed += account.transaction_archive().open_disputes().len();
+                }
+                // Evicted IDs stay indexed for a second window.
+                let indexed = engine.store.owners().unwrap().count();
+                assert!(archived <= 100 + disputed, "{} archived", archived);
+                assert!(indexed <= 200 + disputed, "{} indexed", indexed);
+            }
+        }
+        assert!(expired > 0);
+    }
+
+    fn timestamped(tx: Transaction, timestamp: u64) -> Transaction {
+        Transaction {
+            timestamp: Some(timestamp),
+            ..tx
+        }
+    }
+
+    /// Test that each client's timestamps must not go backwards, that other
+    /// clients and rows without a timestamp aren't affected, and that the
+    /// archive keeps each transaction's timestamp.
+    #[test]
+    fn test_timestamps_must_not_decrease_per_client() {
+        let mut engine = PaymentsEngine::new();
+        for tx in [
+            timestamped(make_deposit_tx(1, 1, Some(money("10.0"))), 100),
+            timestamped(make_deposit_tx(2, 2, Some(money("10.0"))), 50),
+            timestamped(make_withdrawal_tx(3, 1, Some(money("1.0"))), 100),
+            make_withdrawal_tx(4, 1, Some(money("1.0"))),
+        ] {
+            engine.process_transaction(tx).into_result().unwrap();
+        }
+
+        let result = engine
+            .process_transaction(timestamped(
+                make_dispute_family_tx(TransactionType::Dispute, 1, 1),
+                99,
+            ))
+            .into_result();
+        assert!(matches!(
+            result,
+            Err(PaymentsTransactionError::TimestampOutOfOrder {
+                client: 1,
+                timestamp: 99,
+                last: 100
+            })
+        ));
+        // A rejected row doesn't move the client's clock.
+        assert!(matches!(
+            engine
+                .process_transaction(timestamped(
+                    make_withdrawal_tx(5, 1, Some(money("100.0"))),
+                    200
+                ))
+                .into_result(),
+            Err(PaymentsTransactionError::NotEnoughAvailableFunds(_))
+        ));
+        let account = engine.account(1).unwrap().unwrap();
+        assert_eq!(account.last_timestamp(), Some(100));
+        let archive = account.transaction_archive();
+        assert_eq!(archive.get(1).unwrap().timestamp(), Some(100));
+        assert_eq!(archive.get(4).unwrap().timestamp(), None);
+        assert!(!archive.is_disputed(1));
+    }
+
+    /// Test that a window measured in seconds closes by the latest timestamp
+    /// the engine has seen, and that transactions without a timestamp never
+    /// expire.
+    #[test]
+    fn test_dispute_window_in_seconds() {
+        let mut engine = PaymentsEngine::new().with_dispute_window("1m".parse().unwrap());
+        for tx in [
+            timestamped(make_deposit_tx(1, 1, Some(money("10.0"))), 1_000),
+            timestamped(make_deposit_tx(2, 1, Some(money("5.0"))), 1_030),
+            make_deposit_tx(3, 1, Some(money("1.0"))),
+            timestamped(
+                make_dispute_family_tx(TransactionType::Dispute, 1, 1),
+                1_060,
+            ),
+            // Another client's row moves the engine's clock on.
+            timestamped(make_deposit_tx(4, 2, Some(money("1.0"))), 1_100),
+        ] {
+            engine.process_transaction(tx).into_result().unwrap();
+        }
+
+        let archive = engine
+            .account(1)
+            .unwrap()
+            .unwrap()
+            .transaction_archive()
+            .clone();
+        assert_eq!(
+            archive
+                .transactions()
+                .map(|(tx, _)| tx)
+                .collect::<BTreeSet<_>>(),
+            BTreeSet::from([1, 3])
+        );
         engine
-            .process_transaction(make_deposit_tx(1, 1, Some(100.0)))
+            .process_transaction(make_dispute_family_tx(TransactionType::Chargeback, 1, 1))
+            .into_result()
+            .unwrap();
+        assert!(matches!(
+            engine
+                .process_transaction(make_dispute_family_tx(TransactionType::Dispute, 2, 1))
+                .into_result(),
+            Err(PaymentsTransactionError::DisputeWindowExpired(2))
+        ));
+        engine
+            .process_transaction(make_dispute_family_tx(TransactionType::Dispute, 3, 1))
+            .into_result()
+            .unwrap();
+        let archive = engine
+            .account(1)
+            .unwrap()
+            .unwrap()
+            .transaction_archive()
+            .clone();
+        assert_eq!(archive.len(), 1);
+        assert!(archive.is_disputed(3));
+    }
+
+    /// Test that sharded processing matches sequential processing on a
+    /// timestamped feed with some rows out of order and a window in seconds.
+    #[test]
+    fn test_sharded_engine_matches_sequential_with_timestamps() {
+        let rows: Vec<Transaction> = TransactionGenerator::new(GeneratorConfig {
+            transactions: 3_000,
+            clients: 40,
+            error_rate: 0.15,
+            seed: Some(21),
+            ..GeneratorConfig::default()
+        })
+        .enumerate()
+        .map(|(i, tx)| {
+            let timestamp = 10 * i as u64;
+            match i % 17 {
+                0 => tx,
+                5 => timestamped(tx, timestamp.saturating_sub(100)),
+                _ => timestamped(tx, timestamp),
+            }
+        })
+        .collect();
+
+        assert_sharded_rows_match_sequential(
+            EngineConfig {
+                dispute_window: DisputeWindow::Seconds(2_000),
+                ..EngineConfig::default()
+            },
+            &rows,
+        );
+    }
+
+    /// Name of a domain event, as written in its `event` tag.
+    fn event_name(event: &DomainEvent) -> String {
+        serde_json::to_value(event).unwrap()["event"]
+            .as_str()
+            .unwrap()
+            .to_string()
+    }
+
+    /// Test that subscribers see each row's events in order: the account
+    /// openings and balance changes raised by the handlers, and a
+    /// `TransactionRejected` event for every row that wasn't applied.
+    #[test]
+    fn test_domain_events_in_row_order() {
+        let sink = MemoryEventSink::new();
+        let mut engine = PaymentsEngine::new().with_subscriber(sink.clone());
+        let rows = [
+            make_deposit_tx(1, 1, Some(money("100.0"))),
+            make_withdrawal_tx(2, 1, Some(money("30.0"))),
+            make_dispute_family_tx(TransactionType::Dispute, 1, 1),
+            make_dispute_family_tx(TransactionType::Resolve, 1, 1),
+            make_deposit_tx(3, 1, Some(money("5.0"))),
+            make_dispute_family_tx(TransactionType::Dispute, 3, 1),
+            make_dispute_family_tx(TransactionType::Chargeback, 3, 1),
+            make_deposit_tx(1, 1, Some(money("100.0"))),
+            make_withdrawal_tx(4, 2, Some(money("1.0"))),
+        ];
+        for tx in rows {
+            let _ = engine.process_transaction(tx);
+        }
+
+        let events = sink.take();
+        let names: Vec<String> = events.iter().map(event_name).collect();
+        assert_eq!(
+            names,
+            [
+                "AccountOpened",
+                "FundsCredited",
+                "FundsDebited",
+                "FundsHeld",
+                "FundsReleased",
+                "FundsCredited",
+                "FundsHeld",
+                "ChargedBack",
+                "AccountLocked",
+                "TransactionRejected",
+                "AccountOpened",
+                "TransactionRejected",
+            ]
+        );
+        assert_eq!(
+            events[3],
+            DomainEvent::FundsHeld {
+                client: 1,
+                tx: 1,
+                amount: money("100.0"),
+            }
+        );
+        assert_eq!(events[8], DomainEvent::AccountLocked { client: 1, tx: 3 });
+        assert!(matches!(
+            &events[9],
+            DomainEvent::TransactionRejected {
+                client: 1,
+                tx: 1,
+                outcome: "Ignored",
+                error: "DuplicateTransactionId",
+                ..
+            }
+        ));
+        assert!(matches!(
+            &events[11],
+            DomainEvent::TransactionRejected {
+                client: 2,
+                tx: 4,
+                outcome: "Rejected",
+                error: "NotEnoughAvailableFunds",
+                ..
+            }
+        ));
+    }
+
+    /// Keeps accounts in memory, but fails every eviction.
+    #[derive(Debug, Default)]
+    struct EvictionFailingStore(InMemoryAccountStore);
+
+    impl AccountStore for EvictionFailingStore {
+        fn len(&self) -> usize {
+            self.0.len()
+        }
+
+        fn clients(&self) -> Vec<u16> {
+            self.0.clients()
+        }
+
+        fn details(&self, client: u16) -> Option<&ClientAccountDetails> {
+            self.0.details(client)
+        }
+
+        fn account(
+            &self,
+            client: u16,
+        ) -> Result<Option<Cow<'_, ClientAccount>>, PaymentsTransactionError> {
+            self.0.account(client)
+        }
+
+        fn transaction(
+            &self,
+            client: u16,
+            tx: u32,
+        ) -> Result<Option<Cow<'_, TransactionDetail>>, PaymentsTransactionError> {
+            self.0.transaction(client, tx)
+        }
+
+        fn take(
+            &mut self,
+            client: u16,
+            tx: Option<u32>,
+        ) -> Result<Option<ClientAccount>, PaymentsTransactionError> {
+            self.0.take(client, tx)
+        }
+
+        fn put(
+            &mut self,
+            client: u16,
+            account: ClientAccount,
+        ) -> Result<(), PaymentsTransactionError> {
+            self.0.put(client, account)
+        }
+
+        fn evict(&mut self, _client: u16, _tx: u32) -> Result<(), PaymentsTransactionError> {
+            Err(PaymentsTransactionError::AccountStore(
+                "eviction failed".to_string(),
+            ))
+        }
+
+        fn owner(&self, tx: u32) -> Result<Option<u16>, PaymentsTransactionError> {
+            self.0.owner(tx)
+        }
+
+        fn claim(&mut self, tx: u32, client: u16) -> Result<(), PaymentsTransactionError> {
+            self.0.claim(tx, client)
+        }
+
+        fn release(&mut self, tx: u32) -> Result<(), PaymentsTransactionError> {
+            self.0.release(tx)
+        }
+
+        fn owners(&self) -> Result<Owners<'_>, PaymentsTransactionError> {
+            self.0.owners()
+        }
+
+        fn replace(
+            &mut self,
+            accounts: HashMap<u16, ClientAccount>,
+            owners: HashMap<u32, u16>,
+        ) -> Result<(), PaymentsTransactionError> {
+            self.0.replace(accounts, owners)
+        }
+    }
+
+    /// Test that a row stays applied, and raises only its own events, when
+    /// evicting expired transactions after it fails.
+    #[test]
+    fn test_eviction_failure_keeps_row_applied() {
+        let sink = MemoryEventSink::new();
+        let mut engine = PaymentsEngine::new()
+            .with_store(EvictionFailingStore::default())
+            .with_dispute_window(DisputeWindow::Transactions(1))
+            .with_subscriber(sink.clone());
+        for tx in 1..=3 {
+            let outcome = engine.process_transaction(make_deposit_tx(tx, 1, Some(money("1.0"))));
+            assert!(outcome.is_applied(), "{tx} gave {outcome:?}");
+        }
+
+        let names: Vec<String> = sink.take().iter().map(event_name).collect();
+        assert_eq!(
+            names,
+            [
+                "AccountOpened",
+                "FundsCredited",
+                "FundsCredited",
+                "FundsCredited"
+            ]
+        );
+        assert_eq!(
+            engine.account_details(1).unwrap().total_funds(),
+            money("3.0")
+        );
+    }
+
+    /// Test that rows replayed from the journal on recovery don't raise events
+    /// again, since subscribers saw them when they were first applied.
+    #[test]
+    fn test_recover_does_not_replay_events() {
+        let dir = tempfile::TempDir::new().unwrap();
+        let journal = dir.path().join("wal");
+        let mut crashed = PaymentsEngine::new();
+        crashed.recover(None, &journal, JournalSync::Never).unwrap();
+        let _ = crashed.process_transaction(make_deposit_tx(1, 1, Some(money("10.0"))));
+        let _ = crashed.process_transaction(make_withdrawal_tx(2, 1, Some(money("50.0"))));
+        drop(crashed);
+
+        let sink = MemoryEventSink::new();
+        let mut recovered = PaymentsEngine::new().with_subscriber(sink.clone());
+        let recovery = recovered
+            .recover(None, &journal, JournalSync::Never)
             .unwrap();
+        assert_eq!(recovery.replayed, 2);
+        assert!(sink.events().is_empty());
+
+        let _ = recovered.process_transaction(make_withdrawal_tx(3, 1, Some(money("4.0"))));
+        assert_eq!(
+            sink.take(),
+            vec![DomainEvent::FundsDebited {
+                client: 1,
+                tx: 3,
+                amount: money("4.0"),
+            }]
+        );
+    }
+
+    /// Test that a batch on an engine with subscribers and several workers
+    /// raises the same events, in the same order, as sequential processing.
+    #[test]
+    fn test_batch_events_match_sequential() {
+        let rows = generated_rows(5);
+        let sequential = MemoryEventSink::new();
+        let mut engine = PaymentsEngine::new().with_subscriber(sequential.clone());
+        for tx in &rows {
+            let _ = engine.process_transaction(*tx);
+        }
+
+        let batched = MemoryEventSink::new();
+        let mut engine = PaymentsEngine::new()
+            .with_workers(4)
+            .with_subscriber(batched.clone());
         engine
-            .process_transaction(make_deposit_tx(2, 2, Some(300.0)))
+            .process_batch(rows.iter().map(|tx| ((), *tx)))
             .unwrap();
 
-        let acct1 = engine.client_account_lookup.get(&1).unwrap();
-        let acct2 = engine.client_account_lookup.get(&2).unwrap();
-        assert_eq!(acct1.account_details.available_funds, 100.0);
-        assert_eq!(acct2.account_details.available_funds, 300.0);
+        let events = batched.take();
+        assert!(
+            events
+                .iter()
+                .any(|event| matches!(event, DomainEvent::FundsHeld { .. }))
+        );
+        assert_eq!(events, sequential.take());
     }
 }
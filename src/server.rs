@@ -0,0 +1,765 @@
+/// This file defines a TCP ingestion server that applies transaction rows
+/// streamed over any number of concurrent connections to one shared
+/// `PaymentsEngine`.
+///
+/// Each connection is a line-based stream. Its first line picks the format:
+/// a line starting with `{` makes it a JSON Lines stream, anything else must
+/// be a CSV header naming the `type`, `client` and `tx` columns. Every data
+/// row is then answered with one acknowledgement line, in the stream's format:
+///
+/// - CSV: `line,outcome,error,message`, e.g. `2,Applied,,` or
+///   `3,Rejected,NotEnoughAvailableFunds,Not enough available funds for client 1`.
+/// - JSON Lines: `{"line":2,"outcome":"Applied","before":{...},"after":{...}}`,
+///   with the account's balances either side of the row, or `error` and
+///   `message` fields for a row that wasn't applied.
+///
+/// The outcome is `Applied`, `Ignored` or `Rejected`, and the error is the
+/// name of the error a row that wasn't applied failed with. Line numbers
+/// count every line of the connection from 1. A `REPORT` line, optionally
+/// followed by `csv` or `jsonl`, is answered with the current account report
+/// instead, followed by an empty line. It may be sent at any point, including
+/// as the first line of a connection that streams nothing else. Lines longer
+/// than 8 KiB are rejected as malformed rows, and at most 256 connections are
+/// served at once by default; further connections are closed as soon as
+/// they're accepted. A connection that sends nothing, or reads none of its
+/// acknowledgements, for 60 seconds by default is closed.
+use csv::{ReaderBuilder, StringRecord, Trim};
+use log::{error, info, warn};
+use serde::Serialize;
+use std::io::{self, BufRead, BufReader, BufWriter, Write};
+use std::net::{SocketAddr, TcpListener, TcpStream, ToSocketAddrs};
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::thread::{self, JoinHandle};
+use std::time::Duration;
+
+use crate::errors::PaymentsTransactionError;
+use crate::payments_engine::engine::PaymentsEngine;
+use crate::payments_engine::outcome::{OutcomeReport, TransactionOutcome};
+use crate::payments_engine::report::{
+    AccountSummary, ReportOrder, write_csv_report, write_jsonl_report,
+};
+use crate::transaction::Transaction;
+use crate::utils::{
+    csv_reader_from, parse_transaction_json, parse_transaction_record, read_line_limited,
+};
+
+/// Command that asks for the account report instead of applying a row.
+const REPORT_COMMAND: &str = "REPORT";
+
+/// Longest line accepted, in bytes.
+const MAX_LINE_BYTES: usize = 8 * 1024;
+
+/// Number of connections served at once by default.
+pub(crate) const DEFAULT_MAX_CONNECTIONS: usize = 256;
+
+/// How long a connection may go without a read or write completing before
+/// it's closed, by default.
+pub(crate) const DEFAULT_IDLE_TIMEOUT: Duration = Duration::from_secs(60);
+
+/// Listens for transaction streams and applies them to a shared engine.
+///
+/// Rows are applied one at a time under a lock on the engine, so rows for the
+/// same client are never applied concurrently and each connection's rows are
+/// applied in the order they were sent. Rows from different connections
+/// interleave in whatever order they arrive.
+#[derive(Debug)]
+pub struct IngestServer {
+    listener: TcpListener,
+    engine: Arc<Mutex<PaymentsEngine>>,
+    max_connections: usize,
+    idle_timeout: Duration,
+    shutdown: Arc<AtomicBool>,
+}
+
+impl IngestServer {
+    /// Binds the server to `addr`, applying rows to `engine`. Bind to port 0
+    /// to have the OS pick a free port, and read it back with `local_addr`.
+    pub fn bind<A: ToSocketAddrs>(addr: A, engine: PaymentsEngine) -> io::Result<Self> {
+        Self::bind_shared(addr, Arc::new(Mutex::new(engine)))
+    }
+
+    /// Binds the server to `addr`, applying rows to an engine that other
+    /// servers may be sharing.
+    pub fn bind_shared<A: ToSocketAddrs>(
+        addr: A,
+        engine: Arc<Mutex<PaymentsEngine>>,
+    ) -> io::Result<Self> {
+        Ok(IngestServer {
+            listener: TcpListener::bind(addr)?,
+            engine,
+            max_connections: DEFAULT_MAX_CONNECTIONS,
+            idle_timeout: DEFAULT_IDLE_TIMEOUT,
+            shutdown: Arc::new(AtomicBool::new(false)),
+        })
+    }
+
+    /// Sets how many connections are served at once. Connections accepted
+    /// while that many are open are closed straight away.
+    pub fn with_max_connections(mut self, max_connections: usize) -> Self {
+        self.max_connections = max_connections;
+        self
+    }
+
+    /// Sets how long a connection may wait on a read or a write before it's
+    /// closed, freeing its slot. Must not be zero.
+    pub fn with_idle_timeout(mut self, idle_timeout: Duration) -> Self {
+        self.idle_timeout = idle_timeout;
+        self
+    }
+
+    /// Returns the address the server is listening on.
+    pub fn local_addr(&self) -> io::Result<SocketAddr> {
+        self.listener.local_addr()
+    }
+
+    /// Returns a handle to the engine rows are applied to.
+    pub fn engine(&self) -> Arc<Mutex<PaymentsEngine>> {
+        Arc::clone(&self.engine)
+    }
+
+    /// Accepts connections until the server is shut down, serving each one on
+    /// its own thread. Connections still open at shutdown are served until
+    /// their peers close them.
+    pub fn serve(self) -> io::Result<()> {
+        let engine = self.engine;
+        let max_connections = self.max_connections;
+        let idle_timeout = self.idle_timeout;
+        accept_connections(
+            &self.listener,
+            &self.shutdown,
+            max_connections,
+            move |stream| {
+                let peer = stream.peer_addr().ok();
+                match serve_connection(&stream, &engine, idle_timeout) {
+                    Ok(rows) => info!("Connection {:?} closed after {} row(s)", peer, rows),
+                    Err(e) => warn!("Connection {:?} failed: {}", peer, e),
+                }
+            },
+        );
+        Ok(())
+    }
+
+    /// Serves connections on a background thread, returning a handle that
+    /// reaches the engine and stops the server.
+    pub fn spawn(self) -> io::Result<ServerHandle> {
+        let addr = self.local_addr()?;
+        let engine = self.engine();
+        let shutdown = Arc::clone(&self.shutdown);
+        Ok(ServerHandle::new(addr, engine, shutdown, move || {
+            self.serve()
+        }))
+    }
+}
+
+/// Accepts connections on `listener` until `shutdown` is set, handing each one
+/// to `handle` on its own thread. Connections accepted while
+/// `max_connections` are already being handled are closed without reading
+/// from them.
+pub(crate) fn accept_connections<F>(
+    listener: &TcpListener,
+    shutdown: &AtomicBool,
+    max_connections: usize,
+    handle: F,
+) where
+    F: Fn(TcpStream) + Send + Sync + 'static,
+{
+    let handle = Arc::new(handle);
+    let open = Arc::new(AtomicUsize::new(0));
+    for stream in listener.incoming() {
+        if shutdown.load(Ordering::SeqCst) {
+            break;
+        }
+        match stream {
+            Ok(stream) => {
+                if open.load(Ordering::SeqCst) >= max_connections {
+                    warn!(
+                        "Closing connection {:?}: {} connection(s) already open",
+                        stream.peer_addr().ok(),
+                        max_connections
+                    );
+                    continue;
+                }
+                let slot = ConnectionSlot::take(&open);
+                let handle = Arc::clone(&handle);
+                thread::spawn(move || {
+                    let _slot = slot;
+                    handle(stream)
+                });
+            }
+            Err(e) => warn!("Error accepting connection: {}", e),
+        }
+    }
+}
+
+/// Counts one open connection for as long as it's held, including when the
+/// thread handling the connection panics.
+struct ConnectionSlot(Arc<AtomicUsize>);
+
+impl ConnectionSlot {
+    fn take(open: &Arc<AtomicUsize>) -> Self {
+        open.fetch_add(1, Ordering::SeqCst);
+        ConnectionSlot(Arc::clone(open))
+    }
+}
+
+impl Drop for ConnectionSlot {
+    fn drop(&mut self) {
+        self.0.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+/// A server running on a background thread, from `IngestServer::spawn` or
+/// `ApiServer::spawn`.
+#[derive(Debug)]
+pub struct ServerHandle {
+    addr: SocketAddr,
+    engine: Arc<Mutex<PaymentsEngine>>,
+    shutdown: Arc<AtomicBool>,
+    thread: JoinHandle<io::Result<()>>,
+}
+
+impl ServerHandle {
+    /// Runs `serve`, an accept loop watching `shutdown`, on a background thread.
+    pub(crate) fn new<F>(
+        addr: SocketAddr,
+        engine: Arc<Mutex<PaymentsEngine>>,
+        shutdown: Arc<AtomicBool>,
+        serve: F,
+    ) -> Self
+    where
+        F: FnOnce() -> io::Result<()> + Send + 'static,
+    {
+        ServerHandle {
+            addr,
+            engine,
+            shutdown,
+            thread: thread::spawn(serve),
+        }
+    }
+
+    /// Returns the address the server is listening on.
+    pub fn local_addr(&self) -> SocketAddr {
+        self.addr
+    }
+
+    /// Returns a handle to the engine rows are applied to.
+    pub fn engine(&self) -> Arc<Mutex<PaymentsEngine>> {
+        Arc::clone(&self.engine)
+    }
+
+    /// Stops accepting connections and waits for the accept loop to exit.
+    pub fn shutdown(self) -> io::Result<()> {
+        self.shutdown.store(true, Ordering::SeqCst);
+        // Wake the accept loop so it sees the flag.
+        TcpStream::connect(self.addr)?;
+        self.thread
+            .join()
+            .map_err(|_| io::Error::other("server thread panicked"))?
+    }
+}
+
+/// Format of one connection's stream, fixed by its first line.
+enum StreamFormat {
+    /// CSV rows under the given (lowercased) header.
+    Csv(StringRecord),
+    /// One JSON transaction object per line.
+    Jsonl,
+}
+
+/// One acknowledgement line of a JSON Lines stream.
+#[derive(Serialize)]
+struct JsonAck {
+    line: u64,
+    #[serde(flatten)]
+    outcome: OutcomeReport,
+}
+
+/// Reads rows from `stream` until it closes, goes idle for `idle_timeout` or
+/// the engine stops, applying each one to `engine` and acknowledging it.
+/// Returns how many data rows were acknowledged.
+fn serve_connection(
+    stream: &TcpStream,
+    engine: &Mutex<PaymentsEngine>,
+    idle_timeout: Duration,
+) -> io::Result<u64> {
+    stream.set_nodelay(true)?;
+    stream.set_read_timeout(Some(idle_timeout))?;
+    stream.set_write_timeout(Some(idle_timeout))?;
+    let mut reader = BufReader::new(stream);
+    let mut writer = BufWriter::new(stream);
+    let mut format = None;
+    let mut line = 0;
+    let mut rows = 0;
+    let mut text = String::new();
+    loop {
+        // Acknowledgements are batched while more input is already buffered,
+        // and flushed before blocking on the next read.
+        if reader.buffer().is_empty() {
+            writer.flush()?;
+        }
+        text.clear();
+        match read_line_limited(&mut reader, &mut text, MAX_LINE_BYTES) {
+            Ok(0) => break,
+            Ok(_) => line += 1,
+            Err(e)
+                if matches!(
+                    e.kind(),
+                    io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut
+                ) =>
+            {
+                info!(
+                    "Closing connection {:?}: idle for {:?}",
+                    stream.peer_addr().ok(),
+                    idle_timeout
+                );
+                break;
+            }
+            Err(e) if e.kind() == io::ErrorKind::InvalidData => {
+                // Drop the rest of the line, then carry on with the next one.
+                reader.skip_until(b'\n')?;
+                line += 1;
+                let outcome = TransactionOutcome::from_error(
+                    PaymentsTransactionError::MalformedRow(e.to_string()),
+                );
+                let jsonl = matches!(format, Some(StreamFormat::Jsonl));
+                write_ack(&mut writer, jsonl, line, &outcome)?;
+                rows += 1;
+                continue;
+            }
+            Err(e) => return Err(e),
+        }
+        let trimmed = text.trim();
+        if trimmed.is_empty() {
+            continue;
+        }
+        if let Some(report_format) = report_command(trimmed) {
+            let jsonl = match report_format {
+                Some(requested) => requested.eq_ignore_ascii_case("jsonl"),
+                None => matches!(format, Some(StreamFormat::Jsonl)),
+            };
+            write_report(engine, jsonl, &mut writer)?;
+            continue;
+        }
+        let parsed = match &format {
+            Some(StreamFormat::Csv(headers)) => parse_csv_line(trimmed, headers),
+            Some(StreamFormat::Jsonl) => parse_transaction_json(trimmed),
+            None if trimmed.starts_with('{') => {
+                format = Some(StreamFormat::Jsonl);
+                parse_transaction_json(trimmed)
+            }
+            None => {
+                // A bad header leaves nothing the rest of the stream can be
+                // read against, so it ends the connection.
+                let headers = csv_reader_from(trimmed.as_bytes())
+                    .and_then(|mut reader| Ok(reader.headers()?.clone()));
+                match headers {
+                    Ok(headers) => format = Some(StreamFormat::Csv(headers)),
+                    Err(e) => {
+                        let outcome = TransactionOutcome::from_error(e);
+                        write_ack(&mut writer, false, line, &outcome)?;
+                        break;
+                    }
+                }
+                continue;
+            }
+        };
+        let outcome = match parsed {
+            Ok(tx) => apply(engine, tx),
+            Err(e) => TransactionOutcome::from_error(e),
+        };
+        let jsonl = matches!(format, Some(StreamFormat::Jsonl));
+        write_ack(&mut writer, jsonl, line, &outcome)?;
+        rows += 1;
+        // Nothing more can be applied, so don't wait for more rows.
+        if matches!(
+            outcome.error(),
+            Some(PaymentsTransactionError::EngineStopped)
+        ) {
+            break;
+        }
+    }
+    writer.flush()?;
+    Ok(rows)
+}
+
+/// Returns the requested format, if any, if `line` is a `REPORT` command.
+fn report_command(line: &str) -> Option<Option<&str>> {
+    let mut words = line.split_whitespace();
+    let command = words.next()?;
+    if !command.eq_ignore_ascii_case(REPORT_COMMAND) {
+        return None;
+    }
+    match (words.next(), words.next()) {
+        (None, _) => Some(None),
+        (Some(format), None)
+            if format.eq_ignore_ascii_case("csv") || format.eq_ignore_ascii_case("jsonl") =>
+        {
+            Some(Some(format))
+        }
+        _ => None,
+    }
+}
+
+/// Parses one CSV data line against the stream's header.
+fn parse_csv_line(
+    line: &str,
+    headers: &StringRecord,
+) -> Result<Transaction, PaymentsTransactionError> {
+    let mut reader = ReaderBuilder::new()
+        .has_headers(false)
+        .trim(Trim::All)
+        .flexible(true)
+        .from_reader(line.as_bytes());
+    let mut record = StringRecord::new();
+    reader
+        .read_record(&mut record)
+        .map_err(|e| PaymentsTransactionError::MalformedRow(e.to_string()))?;
+    parse_transaction_record(&record, headers)
+}
+
+/// Locks the engine. A thread that panicked while holding the lock may have
+/// left the engine half way through a row, so a poisoned lock is reported as
+/// the engine having stopped rather than used.
+pub(crate) fn lock_engine(
+    engine: &Mutex<PaymentsEngine>,
+) -> Result<MutexGuard<'_, PaymentsEngine>, PaymentsTransactionError> {
+    engine.lock().map_err(|_| {
+        error!("Engine lock poisoned by a panic, refusing to apply rows");
+        PaymentsTransactionError::EngineStopped
+    })
+}
+
+/// Applies one transaction under the engine lock.
+fn apply(engine: &Mutex<PaymentsEngine>, tx: Transaction) -> TransactionOutcome {
+    match lock_engine(engine) {
+        Ok(mut engine) => engine.process_transaction(tx),
+        Err(e) => TransactionOutcome::from_error(e),
+    }
+}
+
+/// Writes the acknowledgement for the row at `line`.
+fn write_ack<W: Write>(
+    writer: &mut W,
+    jsonl: bool,
+    line: u64,
+    outcome: &TransactionOutcome,
+) -> io::Result<()> {
+    let report = OutcomeReport::new(outcome);
+    if jsonl {
+        let ack = JsonAck {
+            line,
+            outcome: report,
+        };
+        serde_json::to_writer(&mut *writer, &ack)?;
+        writeln!(writer)
+    } else {
+        let mut wtr = csv::WriterBuilder::new()
+            .has_headers(false)
+            .from_writer(&mut *writer);
+        wtr.write_record([
+            line.to_string().as_str(),
+            report.outcome,
+            report.error.unwrap_or(""),
+            report.message.as_deref().unwrap_or(""),
+        ])?;
+        wtr.flush()
+    }
+}
+
+/// Writes the current account report, ordered by client ID, followed by an
+/// empty line marking its end.
+fn write_report<W: Write>(
+    engine: &Mutex<PaymentsEngine>,
+    jsonl: bool,
+    writer: &mut W,
+) -> io::Result<()> {
+    let summaries: Vec<AccountSummary> = lock_engine(engine)
+        .map_err(io::Error::other)?
+        .sorted_account_summaries(ReportOrder::ClientId);
+    let written = if jsonl {
+        write_jsonl_report(&summaries, &mut *writer)
+    } else {
+        write_csv_report(&summaries, &mut *writer)
+    };
+    written.map_err(io::Error::other)?;
+    writeln!(writer)
+}
+
+#[cfg(test)]
+mod server_tests {
+    use super::*;
+    use std::io::Read;
+    use std::net::Shutdown;
+
+    /// Sends `input` over a new connection to `addr`, closes the sending half,
+    /// and returns everything the server wrote back.
+    fn exchange(addr: SocketAddr, input: &str) -> String {
+        try_exchange(addr, input).unwrap()
+    }
+
+    /// `exchange`, for a connection the server may close or reset.
+    fn try_exchange(addr: SocketAddr, input: &str) -> io::Result<String> {
+        let mut stream = TcpStream::connect(addr)?;
+        stream.write_all(input.as_bytes())?;
+        stream.shutdown(Shutdown::Write)?;
+        let mut output = String::new();
+        stream.read_to_string(&mut output)?;
+        Ok(output)
+    }
+
+    /// Every CSV row is acknowledged with its line number and outcome, a bad
+    /// row doesn't end the stream, and a report can be asked for mid-stream.
+    #[test]
+    fn test_csv_stream_acknowledgements() {
+        let server = IngestServer::bind("127.0.0.1:0", PaymentsEngine::new())
+            .unwrap()
+            .spawn()
+            .unwrap();
+        let output = exchange(
+            server.local_addr(),
+            "type, client, tx, amount\n\
+             deposit, 1, 1, 2.0\n\
+             withdrawal, 1, 2, 5.0\n\
+             \n\
+             deposit, one, 3, 1.0\n\
+             report\n\
+             withdrawal, 1, 4, 0.5\n",
+        );
+
+        assert_eq!(
+            output,
+            "2,Applied,,\n\
+             3,Rejected,NotEnoughAvailableFunds,Not enough available funds for client 1\n\
+             5,Rejected,MalformedRow,\"Malformed row: CSV deserialize error: record 0 (line: 1, byte: 0): field 1: invalid digit found in string\"\n\
+             client,available,held,total,locked\n\
+             1,2.0000,0.0000,2.0000,false\n\
+             \n\
+             7,Applied,,\n"
+        );
+        server.shutdown().unwrap();
+    }
+
+    /// A stream starting with a JSON object is read and acknowledged as JSON
+    /// Lines, and a bad CSV header ends the connection with an error.
+    #[test]
+    fn test_jsonl_stream_and_bad_header() {
+        let server = IngestServer::bind("127.0.0.1:0", PaymentsEngine::new())
+            .unwrap()
+            .spawn()
+            .unwrap();
+        let output = exchange(
+            server.local_addr(),
+            "{\"type\":\"deposit\",\"client\":1,\"tx\":1,\"amount\":1.5}\n\
+             {\"type\":\"dispute\",\"client\":1,\"tx\":9}\n\
+             REPORT\n",
+        );
+        assert_eq!(
+            output,
+            "{\"line\":1,\"outcome\":\"Applied\",\
+             \"before\":{\"client\":1,\"available\":\"0.0000\",\"held\":\"0.0000\",\"total\":\"0.0000\",\"locked\":false},\
+             \"after\":{\"client\":1,\"available\":\"1.5000\",\"held\":\"0.0000\",\"total\":\"1.5000\",\"locked\":false}}\n\
+             {\"line\":2,\"outcome\":\"Ignored\",\"error\":\"TransactionDetailDoesNotExist\",\"message\":\"Transaction details not found for transaction 9\"}\n\
+             {\"client\":1,\"available\":\"1.5000\",\"held\":\"0.0000\",\"total\":\"1.5000\",\"locked\":false}\n\
+             \n"
+        );
+
+        let output = exchange(server.local_addr(), "type,client,amount\ndeposit,1,1.0\n");
+        assert_eq!(
+            output,
+            "1,Rejected,InvalidTransactionHeader,Transaction input is missing required header column(s): tx\n"
+        );
+        server.shutdown().unwrap();
+    }
+
+    /// A line longer than the limit is rejected as a malformed row without
+    /// ending the stream.
+    #[test]
+    fn test_long_line_rejected() {
+        let server = IngestServer::bind("127.0.0.1:0", PaymentsEngine::new())
+            .unwrap()
+            .spawn()
+            .unwrap();
+        let output = exchange(
+            server.local_addr(),
+            &format!(
+                "type,client,tx,amount\ndeposit,1,1,{}\ndeposit,1,2,1.0\n",
+                "1".repeat(MAX_LINE_BYTES)
+            ),
+        );
+        assert_eq!(
+            output,
+            format!(
+                "2,Rejected,MalformedRow,Malformed row: Line is longer than {} bytes\n3,Applied,,\n",
+                MAX_LINE_BYTES
+            )
+        );
+        server.shutdown().unwrap();
+    }
+
+    /// Connections over the limit are closed straight away, and a slot frees
+    /// up once an open connection ends.
+    #[test]
+    fn test_connection_limit() {
+        let server = IngestServer::bind("127.0.0.1:0", PaymentsEngine::new())
+            .unwrap()
+            .with_max_connections(1)
+            .spawn()
+            .unwrap();
+        let addr = server.local_addr();
+
+        // Wait for an acknowledgement so the first connection is known to be
+        // served before the second one is made.
+        let mut open = TcpStream::connect(addr).unwrap();
+        open.write_all(b"type,client,tx,amount\ndeposit,1,1,1.0\n")
+            .unwrap();
+        let mut ack = String::new();
+        BufReader::new(&open).read_line(&mut ack).unwrap();
+        assert_eq!(ack, "2,Applied,,\n");
+
+        let mut refused = TcpStream::connect(addr).unwrap();
+        let mut output = String::new();
+        refused.read_to_string(&mut output).unwrap();
+        assert_eq!(output, "");
+
+        open.shutdown(Shutdown::Write).unwrap();
+        open.read_to_string(&mut output).unwrap();
+        // The slot is given back once the server thread finishes, so retry
+        // until it has; a refused attempt may also be reset mid-write.
+        let mut output = String::new();
+        for _ in 0..100 {
+            output =
+                try_exchange(addr, "type,client,tx,amount\ndeposit,1,2,1.0\n").unwrap_or_default();
+            if !output.is_empty() {
+                break;
+            }
+            thread::sleep(Duration::from_millis(10));
+        }
+        assert_eq!(output, "2,Applied,,\n");
+        server.shutdown().unwrap();
+    }
+
+    /// An idle connection is closed once the idle timeout passes, giving its
+    /// slot to the next connection.
+    #[test]
+    fn test_idle_connection_closed() {
+        let server = IngestServer::bind("127.0.0.1:0", PaymentsEngine::new())
+            .unwrap()
+            .with_max_connections(1)
+            .with_idle_timeout(Duration::from_millis(100))
+            .spawn()
+            .unwrap();
+        let addr = server.local_addr();
+
+        let mut idle = TcpStream::connect(addr).unwrap();
+        idle.write_all(b"type,client,tx,amount\n").unwrap();
+        let mut output = String::new();
+        idle.read_to_string(&mut output).unwrap();
+        assert_eq!(output, "");
+
+        // Retry until the server thread has given the slot back.
+        let mut output = String::new();
+        for _ in 0..100 {
+            output =
+                try_exchange(addr, "type,client,tx,amount\ndeposit,1,1,1.0\n").unwrap_or_default();
+            if !output.is_empty() {
+                break;
+            }
+            thread::sleep(Duration::from_millis(10));
+        }
+        assert_eq!(output, "2,Applied,,\n");
+        server.shutdown().unwrap();
+    }
+
+    /// Once a panic has poisoned the engine lock, a row is answered as
+    /// `EngineStopped` and the connection closed, and a report isn't written.
+    #[test]
+    fn test_poisoned_engine_lock() {
+        let server = IngestServer::bind("127.0.0.1:0", PaymentsEngine::new())
+            .unwrap()
+            .spawn()
+            .unwrap();
+        let engine = server.engine();
+        let _ = thread::spawn(move || {
+            let _engine = engine.lock().unwrap();
+            panic!("poisoning the engine lock");
+        })
+        .join();
+
+        let output = exchange(
+            server.local_addr(),
+            "type,client,tx,amount\ndeposit,1,1,1.0\ndeposit,1,2,1.0\n",
+        );
+        assert_eq!(
+            output,
+            "2,Rejected,EngineStopped,The engine has stopped and is no longer accepting transactions\n"
+        );
+        assert_eq!(exchange(server.local_addr(), "REPORT\n"), "");
+        server.shutdown().unwrap();
+    }
+
+    /// Many concurrent streams on loopback each have every row acknowledged
+    /// in order, and the shared engine ends up where a sequential run of the
+    /// same rows would.
+    #[test]
+    fn test_concurrent_streams() {
+        const STREAMS: u16 = 32;
+        const ROWS: u32 = 100;
+        let server = IngestServer::bind("127.0.0.1:0", PaymentsEngine::new())
+            .unwrap()
+            .spawn()
+            .unwrap();
+        let addr = server.local_addr();
+        let mut expected = PaymentsEngine::new();
+
+        // Each stream owns its clients and transaction IDs, and withdraws
+        // each deposit right after making it, so every row must be applied
+        // in stream order to succeed.
+        let inputs: Vec<String> = (0..STREAMS)
+            .map(|stream| {
+                let mut input = String::from("type,client,tx,amount\n");
+                for row in 0..ROWS {
+                    let client = stream * 4 + (row / 2 % 4) as u16;
+                    let tx = u32::from(stream) * ROWS + row;
+                    let (tx_type, amount) = if row % 2 == 0 {
+                        ("deposit", "1.0")
+                    } else {
+                        ("withdrawal", "0.75")
+                    };
+                    input.push_str(&format!("{},{},{},{}\n", tx_type, client, tx, amount));
+                }
+                input
+            })
+            .collect();
+        let headers = StringRecord::from(vec!["type", "client", "tx", "amount"]);
+        for input in &inputs {
+            for line in input.lines().skip(1) {
+                let tx = parse_csv_line(line, &headers).unwrap();
+                expected.process_transaction(tx).into_result().unwrap();
+            }
+        }
+
+        let clients: Vec<_> = inputs
+            .into_iter()
+            .map(|input| thread::spawn(move || exchange(addr, &input)))
+            .collect();
+        for client in clients {
+            let output = client.join().unwrap();
+            let lines: Vec<&str> = output.lines().collect();
+            assert_eq!(lines.len(), ROWS as usize);
+            for (row, ack) in lines.iter().enumerate() {
+                assert_eq!(*ack, format!("{},Applied,,", row + 2));
+            }
+        }
+
+        let engine = server.engine();
+        assert_eq!(
+            engine
+                .lock()
+                .unwrap()
+                .sorted_account_summaries(ReportOrder::ClientId),
+            expected.sorted_account_summaries(ReportOrder::ClientId)
+        );
+        server.shutdown().unwrap();
+    }
+}
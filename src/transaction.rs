@@ -1,12 +1,17 @@
 /// This file defines the `Transaction` struct and associated methods and utilities
 /// for it in the payments engine.
-use serde::{Deserialize, Serialize, Serializer};
+use serde::de::{self, Visitor};
+use serde::{Deserialize, Deserializer, Serialize};
 use std::fmt;
-use strum::EnumIter;
+use strum::{EnumIter, EnumString};
+
+use crate::money::Money;
 
 /// Representation of all transaction variants supported.
-#[derive(Debug, Deserialize, Serialize, EnumIter, Clone, Copy, PartialEq)]
+/// Parsing is case-insensitive, so `Deposit` and `DEPOSIT` are accepted too.
+#[derive(Debug, Serialize, EnumIter, EnumString, Clone, Copy, PartialEq, Eq, Hash)]
 #[serde(rename_all = "lowercase")] // Sample tx files have lowercase tx types
+#[strum(serialize_all = "lowercase", ascii_case_insensitive)]
 pub enum TransactionType {
     Deposit,
     Withdrawal,
@@ -37,8 +42,38 @@ impl fmt::Display for TransactionType {
     }
 }
 
+impl<'de> Deserialize<'de> for TransactionType {
+    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
+    where
+        D: Deserializer<'de>,
+    {
+        deserializer.deserialize_str(TransactionTypeVisitor)
+    }
+}
+
+struct TransactionTypeVisitor;
+
+impl Visitor<'_> for TransactionTypeVisitor {
+    type Value = TransactionType;
+
+    fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "one of deposit, withdrawal, dispute, resolve or chargeback"
+        )
+    }
+
+    fn visit_str<E>(self, v: &str) -> Result<Self::Value, E>
+    where
+        E: de::Error,
+    {
+        v.parse()
+            .map_err(|_| E::invalid_value(de::Unexpected::Str(v), &self))
+    }
+}
+
 /// Representation of a transaction.
-#[derive(Debug, Deserialize, Serialize, Copy, Clone)]
+#[derive(Debug, Deserialize, Serialize, Copy, Clone, PartialEq, Eq)]
 pub struct Transaction {
     /// Type of Transaction.
     #[serde(rename = "type")]
@@ -47,118 +82,23 @@ pub struct Transaction {
     pub client: u16,
     /// Transaction ID. Assumed type from assignment spec.
     pub tx: u32,
-    /// Transaction amount. Assumed type from assignment spec.
-    #[serde(serialize_with = "serialize_up_to_four_decimal_places", default)]
-    pub amount: Option<f64>,
+    /// Transaction amount, with up to four decimal places of precision.
+    #[serde(default)]
+    pub amount: Option<Money>,
+    /// When the transaction happened, as a Unix timestamp in seconds. Inputs
+    /// without a `timestamp` column are assumed to be in chronological order.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub timestamp: Option<u64>,
 }
 
 /// Output formatting for a transaction, based on the spec doc.
 impl fmt::Display for Transaction {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        write!(
-            f,
-            "{}, {}, {}, {:.4}",
-            self.tx_type,
-            self.client,
-            self.tx,
-            self.amount.unwrap()
-        )
-    }
-}
-
-/// Custom serializer function for floats. The spec doc states that
-/// decimal precisions are assumed to be up to four places and should
-/// output values with the same level of precison. This function handles
-/// that decismal precision for output.
-fn serialize_up_to_four_decimal_places<S>(x: &Option<f64>, s: S) -> Result<S::Ok, S::Error>
-where
-    S: Serializer,
-{
-    match x {
-        Some(val) => {
-            // Format to 4 decimals, then trim trailing zeros and dot
-            let formatted = format!("{:.4}", val);
-            let trimmed = formatted.trim_end_matches('0').trim_end_matches('.');
-            let result = if trimmed.is_empty() { "0" } else { trimmed };
-            s.serialize_str(result)
+        write!(f, "{}, {}, {}, ", self.tx_type, self.client, self.tx)?;
+        // Dispute, resolve and chargeback rows carry no amount.
+        match self.amount {
+            Some(amount) => write!(f, "{}", amount),
+            None => Ok(()),
         }
-        None => s.serialize_str(""), // serialize None as empty string
-    }
-}
-
-#[cfg(test)]
-mod transaction_serialization_tests {
-    use super::*;
-    use serde::Serialize;
-    use serde_json;
-
-    #[derive(Serialize)]
-    struct TestStruct<'a> {
-        #[serde(serialize_with = "serialize_up_to_four_decimal_places")]
-        value: &'a Option<f64>,
-    }
-
-    #[test]
-    fn test_serialize_some_rounding_more_than_four_decimals() {
-        let x = Some(3.14159265);
-        let wrapper = TestStruct { value: &x };
-        let serialized = serde_json::to_string(&wrapper).unwrap();
-        assert_eq!(serialized, r#"{"value":"3.1416"}"#);
-    }
-
-    #[test]
-    fn test_serialize_some_exact_four_decimals() {
-        let x = Some(2.7182);
-        let wrapper = TestStruct { value: &x };
-        let serialized = serde_json::to_string(&wrapper).unwrap();
-        assert_eq!(serialized, r#"{"value":"2.7182"}"#);
-    }
-
-    #[test]
-    fn test_serialize_some_fewer_than_four_decimals() {
-        let x = Some(1.5);
-        let wrapper = TestStruct { value: &x };
-        let serialized = serde_json::to_string(&wrapper).unwrap();
-        assert_eq!(serialized, r#"{"value":"1.5"}"#);
-    }
-
-    #[test]
-    fn test_serialize_some_integer() {
-        let x = Some(100.0);
-        let wrapper = TestStruct { value: &x };
-        let serialized = serde_json::to_string(&wrapper).unwrap();
-        assert_eq!(serialized, r#"{"value":"100"}"#);
-    }
-
-    #[test]
-    fn test_serialize_some_small_decimal() {
-        let x = Some(0.0001);
-        let wrapper = TestStruct { value: &x };
-        let serialized = serde_json::to_string(&wrapper).unwrap();
-        assert_eq!(serialized, r#"{"value":"0.0001"}"#);
-    }
-
-    #[test]
-    fn test_serialize_some_zero() {
-        let x = Some(0.0);
-        let wrapper = TestStruct { value: &x };
-        let serialized = serde_json::to_string(&wrapper).unwrap();
-        assert_eq!(serialized, r#"{"value":"0"}"#);
-    }
-
-    #[test]
-    fn test_serialize_some_four_decimals_with_trailing_zeros() {
-        let x = Some(2.5000);
-        let wrapper = TestStruct { value: &x };
-        let serialized = serde_json::to_string(&wrapper).unwrap();
-        assert_eq!(serialized, r#"{"value":"2.5"}"#);
-    }
-
-    #[test]
-    fn test_serialize_none() {
-        let x: Option<f64> = None;
-        let wrapper = TestStruct { value: &x };
-        let serialized = serde_json::to_string(&wrapper).unwrap();
-        assert_eq!(serialized, r#"{"value":""}"#); // None serializes as empty string
     }
 }
@@ -1,65 +1,443 @@
 /// This file defines general helper funtions for the payments engine.
-use crate::{
-    errors::PaymentsTransactionError,
-    transaction::{Transaction, TransactionType},
-};
-use anyhow::Context;
-use anyhow::Result;
+use crate::money::Money;
+use crate::transaction::TransactionType;
+use crate::{errors::PaymentsTransactionError, transaction::Transaction};
 use csv::Reader;
-use csv::Writer;
-use rand::{Rng, rng, seq::IndexedRandom};
+use csv::ReaderBuilder;
+use csv::StringRecord;
+use csv::Trim;
+use flate2::read::MultiGzDecoder;
+use serde::Deserialize;
 use std::fs::File;
-use std::io::BufWriter;
+use std::io::{self, BufRead, BufReader, Read};
 use std::path::Path;
-use strum::IntoEnumIterator;
 
-pub const MAX_CLI_ARGS: usize = 2;
+/// Argument naming standard input as the transaction source.
+pub const STDIN_SOURCE: &str = "-";
 
-/// Reads and returns a csv::Reader<File> over a file if
-/// the file exists and ends with ".csv".
-pub fn initialize_csv_reader(filename: &str) -> Result<Reader<File>, PaymentsTransactionError> {
-    let path = Path::new(filename);
+/// Header columns every transaction input must have. `amount` may be left out
+/// entirely since dispute, resolve and chargeback rows don't carry one.
+const REQUIRED_HEADER_COLUMNS: [&str; 3] = ["type", "client", "tx"];
+
+/// The two magic bytes every gzip stream starts with.
+const GZIP_MAGIC: [u8; 2] = [0x1f, 0x8b];
+
+/// Opens a transaction source for reading: `-` is stdin, anything else is a
+/// path that must exist. Gzip-compressed input is decompressed transparently.
+pub fn open_transaction_source(source: &str) -> Result<Box<dyn Read>, PaymentsTransactionError> {
+    if source == STDIN_SOURCE {
+        return Ok(decompress_if_gzipped(io::stdin().lock())?);
+    }
+    let path = Path::new(source);
     if !path.exists() {
         return Err(PaymentsTransactionError::TransactionCsvDoesNotExist(
-            filename.to_string(),
+            source.to_string(),
         ));
     }
-    if path.extension().and_then(|ext| ext.to_str()) != Some("csv") {
-        return Err(PaymentsTransactionError::InvalidTransactionFileExtension(
-            filename.to_string(),
-        ));
+    Ok(decompress_if_gzipped(File::open(path)?)?)
+}
+
+/// Wraps `reader` in a gzip decoder if its content starts with the gzip magic
+/// bytes, so `.csv.gz` files and compressed pipes need no extra flag.
+pub fn decompress_if_gzipped<R: Read + 'static>(reader: R) -> io::Result<Box<dyn Read>> {
+    let mut buffered = BufReader::new(reader);
+    if buffered.fill_buf()?.starts_with(&GZIP_MAGIC) {
+        Ok(Box::new(MultiGzDecoder::new(buffered)))
+    } else {
+        Ok(Box::new(buffered))
     }
-    let file = File::open(filename)?;
-    Ok(Reader::from_reader(file))
 }
 
-/// Writes a randomized test CSV given a number of transactions and clients
-/// to initialize the CSV with. Transaction min/max amounts are hardcoded.
+/// Builds a csv::Reader over any `Read` source and checks that its header row
+/// names the columns a `Transaction` needs.
 ///
-/// Note: This function is currently unused and prepended with a '_' to
-/// sidestep the clippy lint when building. If more time is allowed, I would opt
-/// to build this function into the engine as an additional CLI flag handler function.
-pub fn _generate_transaction_csv(total_transactions: u32, total_clients: u16) -> Result<()> {
-    let min_transaction_amount: f64 = 0.00;
-    let max_transaction_amount: f64 = 100.00;
-
-    // Open a file and wrap it in a buffered writer
-    let file = File::create("transactions.csv").context("error creating transactions.csv")?;
-    let buf_writer = BufWriter::new(file);
-    let mut wtr = Writer::from_writer(buf_writer);
-
-    let mut rng = rng();
-    let tx_types: Vec<TransactionType> = TransactionType::iter().collect();
-    for tx in 0..total_transactions {
-        let curr_tx = Transaction {
-            tx_type: *tx_types.choose(&mut rng).unwrap(),
-            client: rng.random_range(0..total_clients),
-            tx,
-            amount: Some(rng.random_range(min_transaction_amount..max_transaction_amount)),
-        };
-        wtr.serialize(curr_tx)
-            .context("Error writing transaction to CSV")?;
-        wtr.flush().context("Error flushing CSV writer")?;
-    }
-    Ok(())
+/// The reader trims whitespace around headers and fields, lowercases the
+/// header names, and accepts rows with fewer columns than the header so
+/// dispute rows may leave out the trailing amount.
+pub fn csv_reader_from<R: Read>(reader: R) -> Result<Reader<R>, PaymentsTransactionError> {
+    let mut csv_reader = ReaderBuilder::new()
+        .trim(Trim::All)
+        .flexible(true)
+        .from_reader(reader);
+    let headers: StringRecord = csv_reader
+        .headers()?
+        .iter()
+        .map(str::to_ascii_lowercase)
+        .collect();
+    let missing: Vec<&str> = REQUIRED_HEADER_COLUMNS
+        .into_iter()
+        .filter(|column| !headers.iter().any(|header| header == *column))
+        .collect();
+    if !missing.is_empty() {
+        return Err(PaymentsTransactionError::InvalidTransactionHeader(
+            missing.join(","),
+        ));
+    }
+    csv_reader.set_headers(headers);
+    Ok(csv_reader)
+}
+
+/// Deserializes one record into a `Transaction`. A row with unparsable fields
+/// is reported as `MalformedRow` so the caller can record it and carry on.
+pub fn parse_transaction_record(
+    record: &StringRecord,
+    headers: &StringRecord,
+) -> Result<Transaction, PaymentsTransactionError> {
+    record
+        .deserialize(Some(headers))
+        .map_err(|e| PaymentsTransactionError::MalformedRow(e.to_string()))
+}
+
+/// One data row of the input: where it was, what it said, and the transaction
+/// it parsed into or why it couldn't be parsed.
+#[derive(Debug)]
+pub struct InputRow {
+    /// Line number of the row in the input, starting at 1. For CSV input,
+    /// line 1 is the header.
+    pub line: u64,
+    /// The row's fields joined back together with commas.
+    pub raw: String,
+    /// The parsed transaction, or a `MalformedRow` error.
+    pub parsed: Result<Transaction, PaymentsTransactionError>,
+}
+
+/// Iterates over the data rows of a reader built by `csv_reader_from`.
+/// Unreadable and malformed rows are yielded as `InputRow`s carrying a
+/// `MalformedRow` error; only I/O errors, after which nothing more can be
+/// read, are yielded as `Err`.
+pub fn input_rows<R: Read>(
+    mut reader: Reader<R>,
+) -> Result<impl Iterator<Item = Result<InputRow, csv::Error>>, PaymentsTransactionError> {
+    let headers = reader.headers()?.clone();
+    Ok(reader.into_records().map(move |res| match res {
+        Ok(record) => Ok(InputRow {
+            line: record.position().map_or(0, |pos| pos.line()),
+            raw: record.iter().collect::<Vec<_>>().join(","),
+            parsed: parse_transaction_record(&record, &headers),
+        }),
+        Err(e) if e.is_io_error() => Err(e),
+        Err(e) => Ok(InputRow {
+            line: e.position().map_or(0, |pos| pos.line()),
+            raw: String::new(),
+            parsed: Err(PaymentsTransactionError::MalformedRow(e.to_string())),
+        }),
+    }))
+}
+
+/// A transaction as it appears on a line of JSON input. Amounts may be given
+/// as strings or as numbers.
+#[derive(Deserialize)]
+struct JsonTransaction {
+    #[serde(rename = "type")]
+    tx_type: TransactionType,
+    client: u16,
+    tx: u32,
+    #[serde(default, deserialize_with = "Money::deserialize_json_amount")]
+    amount: Option<Money>,
+    #[serde(default)]
+    timestamp: Option<u64>,
+}
+
+impl From<JsonTransaction> for Transaction {
+    fn from(row: JsonTransaction) -> Self {
+        Transaction {
+            tx_type: row.tx_type,
+            client: row.client,
+            tx: row.tx,
+            amount: row.amount,
+            timestamp: row.timestamp,
+        }
+    }
+}
+
+/// Parses one line of JSON Lines input into a `Transaction`. A line that isn't
+/// a valid transaction object is reported as `MalformedRow`.
+pub fn parse_transaction_json(line: &str) -> Result<Transaction, PaymentsTransactionError> {
+    serde_json::from_str::<JsonTransaction>(line)
+        .map(Transaction::from)
+        .map_err(|e| PaymentsTransactionError::MalformedRow(e.to_string()))
+}
+
+/// Converts an already-parsed JSON value, such as one element of a JSON array,
+/// into a `Transaction`. A value that isn't a valid transaction object is
+/// reported as `MalformedRow`.
+pub fn transaction_from_json_value(
+    value: serde_json::Value,
+) -> Result<Transaction, PaymentsTransactionError> {
+    serde_json::from_value::<JsonTransaction>(value)
+        .map(Transaction::from)
+        .map_err(|e| PaymentsTransactionError::MalformedRow(e.to_string()))
+}
+
+/// Iterates over the transactions of JSON Lines (NDJSON) input, one object
+/// per line. Blank lines are skipped, and lines that don't parse are yielded
+/// as `InputRow`s carrying a `MalformedRow` error; only I/O errors are
+/// yielded as `Err`.
+pub fn jsonl_rows<R: BufRead>(reader: R) -> impl Iterator<Item = io::Result<InputRow>> {
+    (1..)
+        .zip(reader.lines())
+        .filter_map(|(line, res)| match res {
+            Ok(text) if text.trim().is_empty() => None,
+            Ok(text) => {
+                let raw = text.trim().to_string();
+                Some(Ok(InputRow {
+                    line,
+                    parsed: parse_transaction_json(&raw),
+                    raw,
+                }))
+            }
+            Err(e) => Some(Err(e)),
+        })
+}
+
+/// Reads one line into `buf` like `BufRead::read_line`, but never buffers
+/// more than `limit` bytes (newline included) of it. A longer line is an
+/// `InvalidData` error, with the rest of it left unread. Bytes that aren't
+/// valid UTF-8 are replaced rather than refused.
+pub fn read_line_limited<R: BufRead>(
+    reader: &mut R,
+    buf: &mut String,
+    limit: usize,
+) -> io::Result<usize> {
+    let mut bytes = Vec::new();
+    let read = reader
+        .take(limit as u64 + 1)
+        .read_until(b'\n', &mut bytes)?;
+    if read > limit {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("Line is longer than {} bytes", limit),
+        ));
+    }
+    buf.push_str(&String::from_utf8_lossy(&bytes));
+    Ok(read)
+}
+
+/// Opens `source` (a path, or `-` for stdin) and returns a csv::Reader over it
+/// once the header row has been validated.
+pub fn initialize_csv_reader(
+    source: &str,
+) -> Result<Reader<Box<dyn Read>>, PaymentsTransactionError> {
+    csv_reader_from(open_transaction_source(source)?)
+}
+
+#[cfg(test)]
+mod utils_tests {
+    use super::*;
+    use crate::PaymentsEngine;
+    use crate::transaction::TransactionType;
+    use flate2::{Compression, write::GzEncoder};
+    use std::io::Write;
+    use tempfile::NamedTempFile;
+
+    const SAMPLE: &str = "type,client,tx,amount\ndeposit,1,1,1.0\n";
+
+    /// Test that any `Read` source can be used and its header row is kept.
+    #[test]
+    fn test_csv_reader_from_in_memory_source() {
+        let mut reader = csv_reader_from(SAMPLE.as_bytes()).unwrap();
+        assert_eq!(reader.records().count(), 1);
+    }
+
+    /// Test that a header missing required columns is rejected regardless of
+    /// where the input came from, and that `amount` is optional.
+    #[test]
+    fn test_csv_reader_from_validates_header() {
+        let result = csv_reader_from("type,client,amount\ndeposit,1,1.0\n".as_bytes());
+        assert!(matches!(
+            result,
+            Err(PaymentsTransactionError::InvalidTransactionHeader(ref missing)) if missing == "tx"
+        ));
+        assert!(csv_reader_from("type, client, tx\ndispute,1,1\n".as_bytes()).is_ok());
+    }
+
+    /// Test that gzip content is decompressed transparently and plain content
+    /// is passed through untouched.
+    #[test]
+    fn test_decompress_if_gzipped() {
+        let mut encoder = GzEncoder::new(Vec::new(), Compression::default());
+        encoder.write_all(SAMPLE.as_bytes()).unwrap();
+        let compressed = encoder.finish().unwrap();
+
+        for input in [compressed, SAMPLE.as_bytes().to_vec()] {
+            let mut out = String::new();
+            decompress_if_gzipped(io::Cursor::new(input))
+                .unwrap()
+                .read_to_string(&mut out)
+                .unwrap();
+            assert_eq!(out, SAMPLE);
+        }
+    }
+
+    /// Test that padded fields and headers, mixed-case types and dispute rows
+    /// without an amount column all parse, and that a bad row is reported as
+    /// malformed without affecting the rows after it.
+    #[test]
+    fn test_lenient_row_parsing() {
+        let input = " Type , Client , TX , Amount \n\
+                     deposit, 1, 1, 1.5\n\
+                     DEPOSIT,1,2,2.0\n\
+                     Dispute, 1, 1\n\
+                     deposit, one, 3, 1.0\n\
+                     withdrawal,1,4,0.5\n";
+        let mut reader = csv_reader_from(input.as_bytes()).unwrap();
+        let headers = reader.headers().unwrap().clone();
+        let parsed: Vec<_> = reader
+            .records()
+            .map(|record| parse_transaction_record(&record.unwrap(), &headers))
+            .collect();
+
+        assert_eq!(parsed.len(), 5);
+        let first = parsed[0].as_ref().unwrap();
+        assert_eq!(first.tx_type, TransactionType::Deposit);
+        assert_eq!(first.amount, Some("1.5".parse().unwrap()));
+        assert_eq!(
+            parsed[1].as_ref().unwrap().tx_type,
+            TransactionType::Deposit
+        );
+        let dispute = parsed[2].as_ref().unwrap();
+        assert_eq!(dispute.tx_type, TransactionType::Dispute);
+        assert_eq!(dispute.amount, None);
+        assert!(matches!(
+            parsed[3],
+            Err(PaymentsTransactionError::MalformedRow(_))
+        ));
+        assert_eq!(parsed[4].as_ref().unwrap().tx, 4);
+    }
+
+    /// Test that input rows carry their line numbers and raw text, and that an
+    /// unparsable row doesn't end the iteration.
+    #[test]
+    fn test_input_rows() {
+        let input = "type,client,tx,amount\ndeposit,1,1,1.0\ndeposit,1,x,1.0\nwithdrawal,1,2,0.5\n";
+        let rows: Vec<InputRow> = input_rows(csv_reader_from(input.as_bytes()).unwrap())
+            .unwrap()
+            .map(Result::unwrap)
+            .collect();
+
+        assert_eq!(rows.len(), 3);
+        assert_eq!(rows[0].line, 2);
+        assert_eq!(rows[1].raw, "deposit,1,x,1.0");
+        assert!(matches!(
+            rows[1].parsed,
+            Err(PaymentsTransactionError::MalformedRow(_))
+        ));
+        assert_eq!(rows[2].line, 4);
+        assert_eq!(rows[2].parsed.as_ref().unwrap().tx, 2);
+    }
+
+    /// Test that JSON Lines rows parse with string or numeric amounts and an
+    /// optional timestamp, that blank lines are skipped, and that a bad line
+    /// is reported as malformed without ending the iteration.
+    #[test]
+    fn test_jsonl_rows() {
+        let input = "{\"type\":\"deposit\",\"client\":1,\"tx\":1,\"amount\":1.5}\n\
+                     \n\
+                     {\"type\":\"Withdrawal\",\"client\":1,\"tx\":2,\"amount\":\"0.25\",\"timestamp\":60}\n\
+                     {\"type\":\"deposit\",\"client\":1,\"tx\":3,\"amount\":0.12345}\n\
+                     not json\n\
+                     {\"type\":\"dispute\",\"client\":1,\"tx\":1}\n";
+        let rows: Vec<InputRow> = jsonl_rows(input.as_bytes()).map(Result::unwrap).collect();
+
+        assert_eq!(rows.len(), 5);
+        assert_eq!(rows[0].line, 1);
+        assert_eq!(
+            rows[0].parsed.as_ref().unwrap().amount,
+            Some("1.5".parse().unwrap())
+        );
+        let withdrawal = rows[1].parsed.as_ref().unwrap();
+        assert_eq!(rows[1].line, 3);
+        assert_eq!(withdrawal.tx_type, TransactionType::Withdrawal);
+        assert_eq!(withdrawal.amount, Some("0.25".parse().unwrap()));
+        assert_eq!(withdrawal.timestamp, Some(60));
+        for malformed in &rows[2..4] {
+            assert!(matches!(
+                malformed.parsed,
+                Err(PaymentsTransactionError::MalformedRow(_))
+            ));
+        }
+        assert_eq!(rows[3].raw, "not json");
+        let dispute = rows[4].parsed.as_ref().unwrap();
+        assert_eq!(dispute.amount, None);
+        assert_eq!(dispute.timestamp, None);
+    }
+
+    /// Test that a JSON row with a zero or negative amount parses like a CSV
+    /// one, so the engine rejects such a deposit and ignores the stray amount
+    /// on a dispute, just as it does for CSV input.
+    #[test]
+    fn test_json_amount_sign_left_to_engine() {
+        let csv = "type,client,tx,amount\n\
+                   deposit,1,1,2.0\n\
+                   deposit,1,2,0\n\
+                   dispute,1,1,-1.0\n";
+        let jsonl = "{\"type\":\"deposit\",\"client\":1,\"tx\":1,\"amount\":2.0}\n\
+                     {\"type\":\"deposit\",\"client\":1,\"tx\":2,\"amount\":0}\n\
+                     {\"type\":\"dispute\",\"client\":1,\"tx\":1,\"amount\":-1.0}\n";
+        let csv_rows: Vec<InputRow> = input_rows(csv_reader_from(csv.as_bytes()).unwrap())
+            .unwrap()
+            .map(Result::unwrap)
+            .collect();
+        let json_rows: Vec<InputRow> = jsonl_rows(jsonl.as_bytes()).map(Result::unwrap).collect();
+
+        let mut reports = Vec::new();
+        for rows in [csv_rows, json_rows] {
+            let mut engine = PaymentsEngine::new();
+            let outcomes: Vec<_> = rows
+                .into_iter()
+                .map(|row| {
+                    let outcome = engine.process_transaction(row.parsed.unwrap());
+                    outcome.error().map(PaymentsTransactionError::kind)
+                })
+                .collect();
+            assert_eq!(outcomes, [None, Some("InvalidAmount"), None]);
+            reports.push(engine.to_string());
+        }
+        assert_eq!(reports[0], reports[1]);
+    }
+
+    /// Test that a `.csv.gz` file on disk is opened and validated, and that
+    /// the file extension no longer matters.
+    #[test]
+    fn test_initialize_csv_reader_from_files() {
+        let mut gz_file = tempfile::Builder::new()
+            .suffix(".csv.gz")
+            .tempfile()
+            .unwrap();
+        let mut encoder = GzEncoder::new(gz_file.as_file_mut(), Compression::default());
+        encoder.write_all(SAMPLE.as_bytes()).unwrap();
+        encoder.finish().unwrap();
+        let mut reader = initialize_csv_reader(gz_file.path().to_str().unwrap()).unwrap();
+        assert_eq!(reader.records().count(), 1);
+
+        let mut txt_file = NamedTempFile::with_suffix(".txt").unwrap();
+        txt_file.write_all(SAMPLE.as_bytes()).unwrap();
+        let mut reader = initialize_csv_reader(txt_file.path().to_str().unwrap()).unwrap();
+        assert_eq!(reader.records().count(), 1);
+    }
+
+    /// Test that a missing file is reported as such.
+    #[test]
+    fn test_initialize_csv_reader_missing_file() {
+        assert!(matches!(
+            initialize_csv_reader("does_not_exist.csv"),
+            Err(PaymentsTransactionError::TransactionCsvDoesNotExist(_))
+        ));
+    }
+
+    /// Test that lines up to the limit are read whole, and a longer line is
+    /// refused without reading past the limit.
+    #[test]
+    fn test_read_line_limited() {
+        let mut reader = "abc\nabcdefgh\n".as_bytes();
+        let mut line = String::new();
+        assert_eq!(read_line_limited(&mut reader, &mut line, 4).unwrap(), 4);
+        assert_eq!(line, "abc\n");
+
+        line.clear();
+        let err = read_line_limited(&mut reader, &mut line, 4).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+        assert!(line.is_empty());
+        assert_eq!(reader, b"fgh\n");
+    }
 }